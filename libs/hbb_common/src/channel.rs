@@ -0,0 +1,244 @@
+//! Priority classes for messages sharing a single peer connection.
+//!
+//! Every [`Message`] is tagged with a [`Class`] and queued in a [`Scheduler`], which hands
+//! messages back in deficit round robin order weighted per class. Messages of the same class
+//! keep their relative order, messages of different classes may be reordered, so this is only
+//! enabled when the peer advertises `Features::channels`.
+use crate::{
+    message_proto::{message, misc, Message},
+    ResultType, Stream,
+};
+use futures::future::BoxFuture;
+use std::collections::VecDeque;
+
+/// Bytes credited to a class per round, multiplied by [`Class::weight`].
+const QUANTUM: usize = 16 * 1024;
+const CLASSES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    /// Input events, cursor and other small control messages.
+    Input = 0,
+    Video = 1,
+    Audio = 2,
+    /// File transfer and file clipboard.
+    Bulk = 3,
+    /// Terminal input and output, whose bursts must not hold up the input events.
+    Terminal = 4,
+}
+
+impl Class {
+    /// Also picks the QUIC lane, see `quic::Lane::of`.
+    pub fn of(msg: &Message) -> Self {
+        match &msg.union {
+            Some(message::Union::VideoFrame(_)) => Class::Video,
+            Some(message::Union::AudioFrame(_)) => Class::Audio,
+            Some(message::Union::FileAction(_))
+            | Some(message::Union::FileResponse(_))
+            | Some(message::Union::Cliprdr(_)) => Class::Bulk,
+            Some(message::Union::TerminalAction(_)) | Some(message::Union::TerminalResponse(_)) => {
+                Class::Terminal
+            }
+            // Keep these in the same class as the frames they describe.
+            Some(message::Union::Misc(m)) => match &m.union {
+                Some(misc::Union::SwitchDisplay(_)) => Class::Video,
+                Some(misc::Union::AudioFormat(_)) => Class::Audio,
+                _ => Class::Input,
+            },
+            _ => Class::Input,
+        }
+    }
+
+    #[inline]
+    pub fn weight(self) -> usize {
+        match self {
+            Class::Input => 8,
+            Class::Video => 4,
+            Class::Terminal => 4,
+            Class::Audio => 2,
+            Class::Bulk => 1,
+        }
+    }
+
+    #[inline]
+    fn from_index(i: usize) -> Self {
+        match i {
+            0 => Class::Input,
+            1 => Class::Video,
+            2 => Class::Audio,
+            3 => Class::Bulk,
+            _ => Class::Terminal,
+        }
+    }
+}
+
+/// Weighted fair queue over the message classes.
+pub struct Scheduler<T> {
+    queues: [VecDeque<(T, usize)>; CLASSES],
+    deficit: [usize; CLASSES],
+    cursor: usize,
+    // Whether the class under the cursor got its quantum for this round.
+    credited: bool,
+}
+
+impl<T> Default for Scheduler<T> {
+    fn default() -> Self {
+        Self {
+            queues: Default::default(),
+            deficit: [0; CLASSES],
+            cursor: 0,
+            credited: false,
+        }
+    }
+}
+
+impl<T> Scheduler<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn push(&mut self, class: Class, item: T, size: usize) {
+        self.queues[class as usize].push_back((item, size));
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        loop {
+            let i = self.cursor;
+            match self.queues[i].front() {
+                Some((_, size)) if *size <= self.deficit[i] => {
+                    self.deficit[i] -= *size;
+                    let item = self.queues[i].pop_front().map(|(item, _)| item);
+                    if self.queues[i].is_empty() {
+                        self.deficit[i] = 0;
+                    }
+                    return item;
+                }
+                Some(_) if !self.credited => {
+                    self.deficit[i] += Class::from_index(i).weight() * QUANTUM;
+                    self.credited = true;
+                    continue;
+                }
+                Some(_) => {}
+                None => self.deficit[i] = 0,
+            }
+            self.cursor = (i + 1) % CLASSES;
+            self.credited = false;
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.len()).sum()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|q| q.is_empty())
+    }
+
+    #[inline]
+    pub fn pending(&self, class: Class) -> usize {
+        self.queues[class as usize].len()
+    }
+}
+
+impl Scheduler<Message> {
+    #[inline]
+    pub fn push_message(&mut self, msg: Message) {
+        let class = Class::of(&msg);
+        let size = protobuf::Message::compute_size(&msg) as usize;
+        self.push(class, msg, size);
+    }
+}
+
+/// Where messages are written to: the stream itself, or a [`Scheduler`] drained into it later.
+pub trait Sink: Send {
+    fn send<'a>(&'a mut self, msg: &'a Message) -> BoxFuture<'a, ResultType<()>>;
+}
+
+impl Sink for Stream {
+    #[inline]
+    fn send<'a>(&'a mut self, msg: &'a Message) -> BoxFuture<'a, ResultType<()>> {
+        Box::pin(Stream::send(self, msg))
+    }
+}
+
+impl Sink for Scheduler<Message> {
+    #[inline]
+    fn send<'a>(&'a mut self, msg: &'a Message) -> BoxFuture<'a, ResultType<()>> {
+        self.push_message(msg.clone());
+        Box::pin(futures::future::ready(Ok(())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_proto::*;
+
+    #[test]
+    fn test_class_of() {
+        let mut msg = Message::new();
+        msg.set_video_frame(VideoFrame::new());
+        assert_eq!(Class::of(&msg), Class::Video);
+        msg.set_file_response(FileResponse::new());
+        assert_eq!(Class::of(&msg), Class::Bulk);
+        msg.set_cursor_position(CursorPosition::new());
+        assert_eq!(Class::of(&msg), Class::Input);
+        msg.set_terminal_response(TerminalResponse::new());
+        assert_eq!(Class::of(&msg), Class::Terminal);
+        let mut misc = Misc::new();
+        misc.set_audio_format(AudioFormat::new());
+        msg.set_misc(misc);
+        assert_eq!(Class::of(&msg), Class::Audio);
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let mut s = Scheduler::new();
+        for i in 0..8 {
+            s.push(Class::Bulk, ("bulk", i), 128 * 1024);
+        }
+        s.push(Class::Input, ("input", 0), 32);
+        s.push(Class::Video, ("video", 0), 48 * 1024);
+        assert_eq!(s.len(), 10);
+        // Small input and video messages don't wait behind the file blocks.
+        assert_eq!(s.pop(), Some(("input", 0)));
+        assert_eq!(s.pop(), Some(("video", 0)));
+        // Order within a class is preserved.
+        for i in 0..8 {
+            assert_eq!(s.pop(), Some(("bulk", i)));
+        }
+        assert!(s.pop().is_none());
+
+        // Under load every class keeps getting a share proportional to its weight.
+        for i in 0..64 {
+            s.push(Class::Video, ("video", i), QUANTUM);
+            s.push(Class::Bulk, ("bulk", i), QUANTUM);
+        }
+        let video = (0..20)
+            .filter_map(|_| s.pop())
+            .filter(|(c, _)| *c == "video")
+            .count();
+        assert_eq!(video, 16);
+        assert_eq!(s.pending(Class::Bulk), 60);
+    }
+
+    #[tokio::test]
+    async fn test_scheduler_sink() {
+        let mut s = Scheduler::new();
+        let mut block = Message::new();
+        block.set_file_response(FileResponse::new());
+        let mut input = Message::new();
+        input.set_key_event(KeyEvent::new());
+        Sink::send(&mut s, &block).await.unwrap();
+        Sink::send(&mut s, &input).await.unwrap();
+        assert_eq!(s.pending(Class::Bulk), 1);
+        assert_eq!(s.pop(), Some(input));
+        assert_eq!(s.pop(), Some(block));
+    }
+}
@@ -0,0 +1,312 @@
+//! Client keys in a token or platform keystore, through PKCS#11.
+//!
+//! The certificate option is a RFC 7512 URI, e.g.
+//! "pkcs11:token=My%20Token;object=device?module-path=/usr/lib/opensc-pkcs11.so".
+//! Without `module-path`, p11-kit-proxy is used, which exposes every module registered with p11-kit.
+use crate::{bail, ResultType};
+use cryptoki::{
+    context::{CInitializeArgs, Pkcs11},
+    error::{Error, RvError},
+    mechanism::{
+        rsa::{PkcsMgfType, PkcsPssParams},
+        Mechanism, MechanismType,
+    },
+    object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle},
+    session::{Session, UserType},
+    types::AuthPin,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio_rustls::rustls::{
+    self,
+    sign::{CertifiedKey, Signer, SigningKey},
+    SignatureAlgorithm, SignatureScheme,
+};
+
+const DEFAULT_MODULE: &str = "p11-kit-proxy.so";
+
+lazy_static::lazy_static! {
+    static ref MODULES: Mutex<HashMap<String, Pkcs11>> = Default::default();
+}
+
+// The path and query attributes of the URI, percent-decoded.
+fn parse_uri(uri: &str) -> ResultType<HashMap<String, Vec<u8>>> {
+    let Some(rest) = uri.strip_prefix("pkcs11:") else {
+        bail!("Not a PKCS#11 URI: {}", uri);
+    };
+    let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let mut attrs = HashMap::new();
+    for attr in path.split(';').chain(query.split('&')) {
+        if let Some((k, v)) = attr.split_once('=') {
+            attrs.insert(
+                k.to_owned(),
+                percent_encoding::percent_decode_str(v).collect(),
+            );
+        }
+    }
+    Ok(attrs)
+}
+
+// A module can be initialized once per process, so it is kept for the reloads of the certificate.
+fn init_module(path: &str) -> ResultType<Pkcs11> {
+    let mut modules = MODULES.lock().unwrap();
+    if let Some(pkcs11) = modules.get(path) {
+        return Ok(pkcs11.clone());
+    }
+    let pkcs11 = Pkcs11::new(path)?;
+    match pkcs11.initialize(CInitializeArgs::OsThreads) {
+        // by another library of the process
+        Err(Error::Pkcs11(RvError::CryptokiAlreadyInitialized, _)) => {}
+        res => res?,
+    }
+    modules.insert(path.to_owned(), pkcs11.clone());
+    Ok(pkcs11)
+}
+
+pub(super) fn load(uri: &str, pin: &str) -> ResultType<CertifiedKey> {
+    let attrs = parse_uri(uri)?;
+    let text = |k: &str| {
+        attrs
+            .get(k)
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+    };
+    let module = text("module-path").unwrap_or_else(|| DEFAULT_MODULE.to_owned());
+    let pkcs11 = init_module(&module)?;
+    let mut slot = None;
+    for s in pkcs11.get_slots_with_token()? {
+        let info = pkcs11.get_token_info(s)?;
+        if text("token").map_or(true, |token| info.label() == token) {
+            slot = Some(s);
+            break;
+        }
+    }
+    let Some(slot) = slot else {
+        bail!("No PKCS#11 token found for {}", uri);
+    };
+    let session = pkcs11.open_ro_session(slot)?;
+    let pin = text("pin-value").unwrap_or_else(|| pin.to_owned());
+    if !pin.is_empty() {
+        session.login(UserType::User, Some(&AuthPin::new(pin.into())))?;
+    }
+
+    let mut template = vec![Attribute::Class(ObjectClass::CERTIFICATE)];
+    if let Some(label) = attrs.get("object") {
+        template.push(Attribute::Label(label.clone()));
+    }
+    if let Some(id) = attrs.get("id") {
+        template.push(Attribute::Id(id.clone()));
+    }
+    let Some(cert) = session.find_objects(&template)?.into_iter().next() else {
+        bail!("No certificate found for {}", uri);
+    };
+    let mut der = None;
+    let mut id = None;
+    for attr in session.get_attributes(cert, &[AttributeType::Value, AttributeType::Id])? {
+        match attr {
+            Attribute::Value(v) => der = Some(v),
+            Attribute::Id(v) => id = Some(v),
+            _ => {}
+        }
+    }
+    let Some(der) = der else {
+        bail!("Empty certificate for {}", uri);
+    };
+    // The key of a certificate has the same CKA_ID.
+    let mut template = vec![Attribute::Class(ObjectClass::PRIVATE_KEY)];
+    if let Some(id) = id.or_else(|| attrs.get("id").cloned()) {
+        template.push(Attribute::Id(id));
+    }
+    let Some(key) = session.find_objects(&template)?.into_iter().next() else {
+        bail!("No private key found for {}", uri);
+    };
+    let key_type = session
+        .get_attributes(key, &[AttributeType::KeyType])?
+        .into_iter()
+        .find_map(|attr| match attr {
+            Attribute::KeyType(t) => Some(t),
+            _ => None,
+        });
+    let algorithm = match key_type {
+        Some(KeyType::RSA) => SignatureAlgorithm::RSA,
+        Some(KeyType::EC) => SignatureAlgorithm::ECDSA,
+        _ => bail!("Unsupported key type {:?} for {}", key_type, uri),
+    };
+    let key = Arc::new(Pkcs11Key {
+        session: Arc::new(Mutex::new(session)),
+        key,
+        algorithm,
+    });
+    Ok(CertifiedKey::new(vec![der.into()], key))
+}
+
+struct Pkcs11Key {
+    // A session is not Sync, and a token does one operation at a time anyway.
+    session: Arc<Mutex<Session>>,
+    key: ObjectHandle,
+    algorithm: SignatureAlgorithm,
+}
+
+impl std::fmt::Debug for Pkcs11Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pkcs11Key")
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SigningKey for Pkcs11Key {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        // TLS 1.3 does not allow PKCS#1 v1.5 signatures, so PSS goes first.
+        let supported: &[SignatureScheme] = match self.algorithm {
+            SignatureAlgorithm::RSA => &[
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::RSA_PSS_SHA384,
+                SignatureScheme::RSA_PSS_SHA512,
+                SignatureScheme::RSA_PKCS1_SHA256,
+                SignatureScheme::RSA_PKCS1_SHA384,
+                SignatureScheme::RSA_PKCS1_SHA512,
+            ],
+            _ => &[
+                SignatureScheme::ECDSA_NISTP256_SHA256,
+                SignatureScheme::ECDSA_NISTP384_SHA384,
+            ],
+        };
+        let scheme = supported.iter().find(|s| offered.contains(s))?;
+        Some(Box::new(Pkcs11Signer {
+            session: self.session.clone(),
+            key: self.key,
+            scheme: *scheme,
+        }))
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        self.algorithm
+    }
+}
+
+struct Pkcs11Signer {
+    session: Arc<Mutex<Session>>,
+    key: ObjectHandle,
+    scheme: SignatureScheme,
+}
+
+impl std::fmt::Debug for Pkcs11Signer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pkcs11Signer")
+            .field("scheme", &self.scheme)
+            .finish_non_exhaustive()
+    }
+}
+
+fn pss(hash_alg: MechanismType, mgf: PkcsMgfType, len: u64) -> PkcsPssParams {
+    PkcsPssParams {
+        hash_alg,
+        mgf,
+        s_len: len.into(),
+    }
+}
+
+impl Signer for Pkcs11Signer {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+        let mechanism = match self.scheme {
+            SignatureScheme::RSA_PSS_SHA256 => Mechanism::Sha256RsaPkcsPss(pss(
+                MechanismType::SHA256,
+                PkcsMgfType::MGF1_SHA256,
+                32,
+            )),
+            SignatureScheme::RSA_PSS_SHA384 => Mechanism::Sha384RsaPkcsPss(pss(
+                MechanismType::SHA384,
+                PkcsMgfType::MGF1_SHA384,
+                48,
+            )),
+            SignatureScheme::RSA_PSS_SHA512 => Mechanism::Sha512RsaPkcsPss(pss(
+                MechanismType::SHA512,
+                PkcsMgfType::MGF1_SHA512,
+                64,
+            )),
+            SignatureScheme::RSA_PKCS1_SHA256 => Mechanism::Sha256RsaPkcs,
+            SignatureScheme::RSA_PKCS1_SHA384 => Mechanism::Sha384RsaPkcs,
+            SignatureScheme::RSA_PKCS1_SHA512 => Mechanism::Sha512RsaPkcs,
+            SignatureScheme::ECDSA_NISTP256_SHA256 => Mechanism::EcdsaSha256,
+            SignatureScheme::ECDSA_NISTP384_SHA384 => Mechanism::EcdsaSha384,
+            _ => return Err(rustls::Error::General("unsupported scheme".to_owned())),
+        };
+        let signature = self
+            .session
+            .lock()
+            .unwrap()
+            .sign(&mechanism, self.key, message)
+            .map_err(|e| rustls::Error::General(format!("PKCS#11 signing failed: {}", e)))?;
+        match self.scheme {
+            SignatureScheme::ECDSA_NISTP256_SHA256 | SignatureScheme::ECDSA_NISTP384_SHA384 => {
+                Ok(ecdsa_raw_to_der(&signature))
+            }
+            _ => Ok(signature),
+        }
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+}
+
+// PKCS#11 gives r || s, TLS wants the DER SEQUENCE of two INTEGERs.
+fn ecdsa_raw_to_der(raw: &[u8]) -> Vec<u8> {
+    fn integer(out: &mut Vec<u8>, mut v: &[u8]) {
+        while v.len() > 1 && v[0] == 0 {
+            v = &v[1..];
+        }
+        let pad = v.first().map_or(false, |b| b & 0x80 != 0);
+        out.push(0x02);
+        out.push((v.len() + pad as usize) as u8);
+        if pad {
+            out.push(0);
+        }
+        out.extend_from_slice(v);
+    }
+    let (r, s) = raw.split_at(raw.len() / 2);
+    let mut body = Vec::with_capacity(raw.len() + 6);
+    integer(&mut body, r);
+    integer(&mut body, s);
+    let mut der = vec![0x30];
+    // P-384 signatures go beyond 127 bytes.
+    if body.len() > 0x7f {
+        der.push(0x81);
+    }
+    der.push(body.len() as u8);
+    der.extend(body);
+    der
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_uri() {
+        let attrs = parse_uri(
+            "pkcs11:token=My%20Token;object=device;id=%01%02?module-path=/usr/lib/x.so&pin-value=1234",
+        )
+        .unwrap();
+        assert_eq!(attrs["token"], b"My Token");
+        assert_eq!(attrs["object"], b"device");
+        assert_eq!(attrs["id"], [1, 2]);
+        assert_eq!(attrs["module-path"], b"/usr/lib/x.so");
+        assert_eq!(attrs["pin-value"], b"1234");
+        assert!(parse_uri("file:///a").is_err());
+    }
+
+    #[test]
+    fn test_ecdsa_raw_to_der() {
+        let mut raw = vec![0u8; 64];
+        raw[0] = 0x80;
+        raw[32] = 0;
+        raw[33] = 0x01;
+        let der = ecdsa_raw_to_der(&raw);
+        assert_eq!(&der[..5], &[0x30, 35 + 33, 0x02, 33, 0x00]);
+        assert_eq!(&der[37..40], &[0x02, 31, 0x01]);
+        assert_eq!(der.len(), 2 + 35 + 33);
+    }
+}
@@ -1,4 +1,7 @@
-use std::{cell::RefCell, io};
+use std::{
+    cell::RefCell,
+    io::{self, Read},
+};
 use zstd::bulk::Compressor;
 
 // The library supports regular compression levels from 1 up to ZSTD_maxCLevel(),
@@ -9,6 +12,11 @@ thread_local! {
     static COMPRESSOR: RefCell<io::Result<Compressor<'static>>> = RefCell::new(Compressor::new(crate::config::COMPRESS_LEVEL));
 }
 
+// Bytes looked at to estimate the entropy of data.
+const ENTROPY_SAMPLE_SIZE: usize = 4096;
+// Compressed or random data is close to 8 bits per byte.
+const MAX_COMPRESSIBLE_ENTROPY: f64 = 7.5;
+
 pub fn compress(data: &[u8]) -> Vec<u8> {
     let mut out = Vec::new();
     COMPRESSOR.with(|c| {
@@ -29,6 +37,147 @@ pub fn compress(data: &[u8]) -> Vec<u8> {
     out
 }
 
+/// A compressor of its own, whose level and dictionary change without affecting [`compress`].
+#[derive(Default)]
+pub struct BlockCompressor {
+    compressor: Option<Compressor<'static>>,
+    // The level and dictionary `compressor` is set to.
+    level: i32,
+    dictionary: Vec<u8>,
+}
+
+impl std::fmt::Debug for BlockCompressor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockCompressor")
+            .field("level", &self.level)
+            .field("dictionary", &self.dictionary.len())
+            .finish()
+    }
+}
+
+impl BlockCompressor {
+    /// Compresses at `level`, with `dictionary` if it is not empty, see [`decompress_with`].
+    pub fn compress(&mut self, data: &[u8], level: i32, dictionary: &[u8]) -> Vec<u8> {
+        if let Err(err) = self.set_params(level, dictionary) {
+            crate::log::debug!("Failed to set the compression level: {}", err);
+            return Vec::new();
+        }
+        let Some(c) = self.compressor.as_mut() else {
+            return Vec::new();
+        };
+        c.compress(data).unwrap_or_else(|err| {
+            crate::log::debug!("Failed to compress: {}", err);
+            Vec::new()
+        })
+    }
+
+    // Loading a dictionary is not free, only done when it changes.
+    fn set_params(&mut self, level: i32, dictionary: &[u8]) -> io::Result<()> {
+        match self.compressor.as_mut() {
+            Some(c) if self.level != level || self.dictionary != dictionary => {
+                // Set again on failure.
+                self.dictionary.clear();
+                self.level = 0;
+                c.set_dictionary(level, dictionary)?;
+            }
+            Some(_) => return Ok(()),
+            None => {
+                self.compressor = Some(Compressor::with_dictionary(level, dictionary)?);
+            }
+        }
+        self.level = level;
+        self.dictionary = dictionary.to_vec();
+        Ok(())
+    }
+}
+
 pub fn decompress(data: &[u8]) -> Vec<u8> {
     zstd::decode_all(data).unwrap_or_default()
 }
+
+/// Decompresses data compressed with `dictionary`, see [`BlockCompressor::compress`].
+pub fn decompress_with(data: &[u8], dictionary: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    match zstd::stream::read::Decoder::with_dictionary(data, dictionary) {
+        Ok(mut decoder) => {
+            if let Err(err) = decoder.read_to_end(&mut out) {
+                crate::log::debug!("Failed to decompress: {}", err);
+                out.clear();
+            }
+        }
+        Err(err) => {
+            crate::log::debug!("Failed to get decompressor: {}", err);
+        }
+    }
+    out
+}
+
+/// Shannon entropy of a sample of `data`, in bits per byte.
+pub fn entropy(data: &[u8]) -> f64 {
+    let step = (data.len() / ENTROPY_SAMPLE_SIZE).max(1);
+    let mut counts = [0u32; 256];
+    let mut n = 0;
+    for &b in data.iter().step_by(step) {
+        counts[b as usize] += 1;
+        n += 1;
+    }
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / n as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Whether `data` is worth compressing, judging by a sample of it.
+#[inline]
+pub fn is_compressible(data: &[u8]) -> bool {
+    entropy(data) < MAX_COMPRESSIBLE_ENTROPY
+}
+
+/// A dictionary of at most `max_size` bytes for many small data like `samples`.
+#[inline]
+pub fn train_dictionary<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> io::Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, max_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entropy() {
+        assert_eq!(entropy(&[]), 0.0);
+        assert_eq!(entropy(&[7u8; 10000]), 0.0);
+        assert!(is_compressible(b"fn main() { println!(\"hello\"); }"));
+        let random: Vec<u8> = (0..100_000).map(|_| rand::random::<u8>()).collect();
+        assert!(!is_compressible(&random));
+        assert!(!is_compressible(&compress(&random)));
+    }
+
+    #[test]
+    fn test_dictionary() {
+        let samples: Vec<Vec<u8>> = (0..200)
+            .map(|i| {
+                format!(
+                    "use std::io;\n\npub fn f{i}(x: u32) -> io::Result<u32> {{\n    Ok(x + {i})\n}}\n"
+                )
+                .into_bytes()
+            })
+            .collect();
+        let dictionary = train_dictionary(&samples, 4096).unwrap();
+        let data = &samples[7];
+        let plain = compress(data);
+        let mut c = BlockCompressor::default();
+        let compressed = c.compress(data, 3, &dictionary);
+        assert!(compressed.len() < plain.len());
+        assert_eq!(&decompress_with(&compressed, &dictionary), data);
+        // Back to no dictionary.
+        assert_eq!(&decompress(&c.compress(data, 3, &[])), data);
+        // The shared compressor is not affected.
+        c.compress(data, 19, &dictionary);
+        assert_eq!(compress(data), plain);
+    }
+}
@@ -58,6 +58,7 @@ lazy_static::lazy_static! {
     static ref ONLINE: Mutex<HashMap<String, i64>> = Default::default();
     pub static ref PROD_RENDEZVOUS_SERVER: RwLock<String> = RwLock::new("38.181.2.76".to_owned());
     pub static ref EXE_RENDEZVOUS_SERVER: RwLock<String> = Default::default();
+    pub static ref EXE_TLS_PINS: RwLock<String> = Default::default();
     pub static ref APP_NAME: RwLock<String> = RwLock::new("HibtDesk".to_owned());
     static ref KEY_PAIR: Mutex<Option<KeyPair>> = Default::default();
     static ref USER_DEFAULT_CONFIG: RwLock<(UserDefaultConfig, Instant)> = RwLock::new((UserDefaultConfig::load(), Instant::now()));
@@ -499,6 +500,14 @@ impl Config2 {
             decrypt_str_or_original(&config.unlock_pin, PASSWORD_ENC_VERSION);
         config.unlock_pin = unlock_pin;
         store |= store2;
+        if let Some(v) = config
+            .options
+            .get_mut(keys::OPTION_TLS_CLIENT_CERT_PASSWORD)
+        {
+            let (password, _, store2) = decrypt_str_or_original(v, PASSWORD_ENC_VERSION);
+            *v = password;
+            store |= store2;
+        }
         if store {
             config.store();
         }
@@ -518,6 +527,12 @@ impl Config2 {
         }
         config.unlock_pin =
             encrypt_str_or_original(&config.unlock_pin, PASSWORD_ENC_VERSION, ENCRYPT_MAX_LEN);
+        if let Some(v) = config
+            .options
+            .get_mut(keys::OPTION_TLS_CLIENT_CERT_PASSWORD)
+        {
+            *v = encrypt_str_or_original(v, PASSWORD_ENC_VERSION, ENCRYPT_MAX_LEN);
+        }
         Config::store_(&config, "2");
     }
 
@@ -544,6 +559,12 @@ impl Config2 {
         }
         config.unlock_pin =
             encrypt_str_or_original(&config.unlock_pin, PASSWORD_ENC_VERSION, ENCRYPT_MAX_LEN);
+        if let Some(v) = config
+            .options
+            .get_mut(keys::OPTION_TLS_CLIENT_CERT_PASSWORD)
+        {
+            *v = encrypt_str_or_original(v, PASSWORD_ENC_VERSION, ENCRYPT_MAX_LEN);
+        }
         store_path(path, config)
     }
 }
@@ -2726,6 +2747,19 @@ pub mod keys {
     pub const OPTION_ALLOW_ONLY_CONN_WINDOW_OPEN: &str = "allow-only-conn-window-open";
     pub const OPTION_ALLOW_AUTO_RECORD_INCOMING: &str = "allow-auto-record-incoming";
     pub const OPTION_ALLOW_AUTO_RECORD_OUTGOING: &str = "allow-auto-record-outgoing";
+    pub const OPTION_ALLOW_AUTO_RECORD_TERMINAL: &str = "allow-auto-record-terminal";
+    // Comma separated, the shells terminals may run, instead of all those installed.
+    pub const OPTION_TERMINAL_SHELLS: &str = "terminal-shells";
+    // Whether clients may set the working directory and environment of the terminals they open.
+    pub const OPTION_ENABLE_TERMINAL_CLIENT_CWD: &str = "enable-terminal-client-cwd";
+    pub const OPTION_ENABLE_TERMINAL_CLIENT_ENV: &str = "enable-terminal-client-env";
+    // Comma separated, the environment variables clients may set, "*" ending a prefix.
+    pub const OPTION_TERMINAL_CLIENT_ENV_ALLOWED: &str = "terminal-client-env-allowed";
+    pub const OPTION_TERMINAL_SCROLLBACK_LINES: &str = "terminal-scrollback-lines";
+    // Connections may join the terminals of a service whose shells run as another user.
+    pub const OPTION_ALLOW_JOIN_TERMINAL_OTHER_USER: &str = "allow-join-terminal-other-user";
+    // Connections watching a terminal may take it over from its writer, not only once left.
+    pub const OPTION_ALLOW_TERMINAL_TAKEOVER: &str = "allow-terminal-takeover";
     pub const OPTION_VIDEO_SAVE_DIRECTORY: &str = "video-save-directory";
     pub const OPTION_ENABLE_ABR: &str = "enable-abr";
     pub const OPTION_ALLOW_REMOVE_WALLPAPER: &str = "allow-remove-wallpaper";
@@ -2766,14 +2800,32 @@ pub mod keys {
     /// - If set to 0, a safe built-in default is used (see DEFAULT_MAX_VALIDATED_FILES).
     /// - If unset, negative, or non-integer, no explicit limit is enforced for backward compatibility.
     pub const OPTION_FILE_TRANSFER_MAX_FILES: &str = "file-transfer-max-files";
+    // In KB/s, of all the files sent by the process together, see `fs::limit`.
+    pub const OPTION_FILE_TRANSFER_RATE_LIMIT: &str = "file-transfer-rate-limit";
+    // "HH:MM-HH:MM" in local time, files are only sent within it.
+    pub const OPTION_FILE_TRANSFER_WINDOW: &str = "file-transfer-window";
     pub const OPTION_DISABLE_UDP: &str = "disable-udp";
     pub const OPTION_ALLOW_INSECURE_TLS_FALLBACK: &str = "allow-insecure-tls-fallback";
+    // "host=pin,pin;host2=pin", see `tls::parse_tls_pins`.
+    // A pinned host never falls back to native-tls or to accepting invalid certs.
+    pub const OPTION_TLS_PINS: &str = "tls-pins";
+    // A PEM file, a PKCS#12 (.p12, .pfx) file with the pkcs12 feature,
+    // or a "pkcs11:" URI on Linux with the pkcs11 feature, see `client_cert`.
+    pub const OPTION_TLS_CLIENT_CERT: &str = "tls-client-cert";
+    // The PEM private key, if not in the certificate file.
+    pub const OPTION_TLS_CLIENT_KEY: &str = "tls-client-key";
+    // The PKCS#12 password or the PKCS#11 PIN.
+    pub const OPTION_TLS_CLIENT_CERT_PASSWORD: &str = "tls-client-cert-password";
     pub const OPTION_SHOW_VIRTUAL_MOUSE: &str = "show-virtual-mouse";
     // joystick is the virtual mouse.
     // So `OPTION_SHOW_VIRTUAL_MOUSE` should also be set if `OPTION_SHOW_VIRTUAL_JOYSTICK` is set.
     pub const OPTION_SHOW_VIRTUAL_JOYSTICK: &str = "show-virtual-joystick";
     pub const OPTION_ENABLE_FLUTTER_HTTP_ON_RUST: &str = "enable-flutter-http-on-rust";
     pub const OPTION_ALLOW_ASK_FOR_NOTE: &str = "allow-ask-for-note";
+    // Permissions, ownership, symlinks and extended attributes, see `fs::get_recursive_files_with_metadata`.
+    pub const OPTION_FILE_TRANSFER_PRESERVE_METADATA: &str = "file-transfer-preserve-metadata";
+    // The received files keep the sender's owner too, only if running as root.
+    pub const OPTION_FILE_TRANSFER_PRESERVE_OWNER: &str = "file-transfer-preserve-owner";
 
     // built-in options
     pub const OPTION_DISPLAY_NAME: &str = "display-name";
@@ -2791,6 +2843,12 @@ pub mod keys {
     // Connection punch-through options
     pub const OPTION_ENABLE_UDP_PUNCH: &str = "enable-udp-punch";
     pub const OPTION_ENABLE_IPV6_PUNCH: &str = "enable-ipv6-punch";
+    pub const OPTION_ENABLE_QUIC: &str = "enable-quic";
+    pub const OPTION_KCP_PROFILE: &str = "kcp-profile";
+    pub const OPTION_KCP_FEC: &str = "kcp-fec";
+    pub const OPTION_ICE_TRANSPORT_POLICY: &str = "ice-transport-policy";
+    pub const OPTION_ALLOW_PROXY_AUTO_DETECT: &str = "allow-proxy-auto-detect";
+    pub const OPTION_PROXY_PAC_URL: &str = "proxy-pac-url";
     pub const OPTION_HIDE_USERNAME_ON_CARD: &str = "hide-username-on-card";
     pub const OPTION_HIDE_HELP_CARDS: &str = "hide-help-cards";
     pub const OPTION_DEFAULT_CONNECT_PASSWORD: &str = "default-connect-password";
@@ -2843,6 +2901,7 @@ pub mod keys {
     // proxy settings
     // The following options are not real keys, they are just used for custom client advanced settings.
     // The real keys are in Config2::socks.
+    // The url may be a comma separated chain of proxies, see `proxy::split_proxy_list`.
     pub const OPTION_PROXY_URL: &str = "proxy-url";
     pub const OPTION_PROXY_USERNAME: &str = "proxy-username";
     pub const OPTION_PROXY_PASSWORD: &str = "proxy-password";
@@ -2916,11 +2975,16 @@ pub mod keys {
         OPTION_VIDEO_SAVE_DIRECTORY,
         OPTION_ENABLE_UDP_PUNCH,
         OPTION_ENABLE_IPV6_PUNCH,
+        OPTION_ENABLE_QUIC,
         OPTION_TOUCH_MODE,
         OPTION_SHOW_VIRTUAL_MOUSE,
         OPTION_SHOW_VIRTUAL_JOYSTICK,
         OPTION_ENABLE_FLUTTER_HTTP_ON_RUST,
         OPTION_ALLOW_ASK_FOR_NOTE,
+        OPTION_FILE_TRANSFER_PRESERVE_METADATA,
+        OPTION_FILE_TRANSFER_PRESERVE_OWNER,
+        OPTION_FILE_TRANSFER_RATE_LIMIT,
+        OPTION_FILE_TRANSFER_WINDOW,
     ];
     // DEFAULT_SETTINGS, OVERWRITE_SETTINGS
     pub const KEYS_SETTINGS: &[&str] = &[
@@ -2946,6 +3010,14 @@ pub mod keys {
         OPTION_AUTO_DISCONNECT_TIMEOUT,
         OPTION_ALLOW_ONLY_CONN_WINDOW_OPEN,
         OPTION_ALLOW_AUTO_RECORD_INCOMING,
+        OPTION_ALLOW_AUTO_RECORD_TERMINAL,
+        OPTION_TERMINAL_SHELLS,
+        OPTION_ENABLE_TERMINAL_CLIENT_CWD,
+        OPTION_ENABLE_TERMINAL_CLIENT_ENV,
+        OPTION_TERMINAL_CLIENT_ENV_ALLOWED,
+        OPTION_TERMINAL_SCROLLBACK_LINES,
+        OPTION_ALLOW_JOIN_TERMINAL_OTHER_USER,
+        OPTION_ALLOW_TERMINAL_TAKEOVER,
         OPTION_ENABLE_ABR,
         OPTION_ALLOW_REMOVE_WALLPAPER,
         OPTION_ALLOW_ALWAYS_SOFTWARE_RENDER,
@@ -2974,9 +3046,18 @@ pub mod keys {
         OPTION_ENABLE_TRUSTED_DEVICES,
         OPTION_RELAY_SERVER,
         OPTION_ICE_SERVERS,
+        OPTION_ICE_TRANSPORT_POLICY,
+        OPTION_ALLOW_PROXY_AUTO_DETECT,
+        OPTION_PROXY_PAC_URL,
         OPTION_DISABLE_UDP,
         OPTION_ALLOW_INSECURE_TLS_FALLBACK,
+        OPTION_TLS_PINS,
+        OPTION_TLS_CLIENT_CERT,
+        OPTION_TLS_CLIENT_KEY,
+        OPTION_TLS_CLIENT_CERT_PASSWORD,
         OPTION_KEEP_AWAKE_DURING_INCOMING_SESSIONS,
+        OPTION_KCP_PROFILE,
+        OPTION_KCP_FEC,
     ];
 
     // BUILDIN_SETTINGS
@@ -3005,6 +3086,9 @@ pub mod keys {
         OPTION_HIDE_POWERED_BY_ME,
         OPTION_MAIN_WINDOW_ALWAYS_ON_TOP,
         OPTION_FILE_TRANSFER_MAX_FILES,
+        OPTION_FILE_TRANSFER_PRESERVE_OWNER,
+        OPTION_FILE_TRANSFER_RATE_LIMIT,
+        OPTION_FILE_TRANSFER_WINDOW,
         OPTION_DISABLE_CHANGE_PERMANENT_PASSWORD,
         OPTION_DISABLE_CHANGE_ID,
         OPTION_DISABLE_UNLOCK_PIN,
@@ -3227,6 +3311,30 @@ mod tests {
         LOCAL_CONFIG.write().unwrap().options.clear();
     }
 
+    #[test]
+    fn test_client_cert_password_encrypted() {
+        let path = std::env::temp_dir().join(format!(
+            "hbb_common_client_cert_password_{}.toml",
+            std::process::id()
+        ));
+        let mut config = Config2::default();
+        config.options.insert(
+            keys::OPTION_TLS_CLIENT_CERT_PASSWORD.to_owned(),
+            "p12 secret".to_owned(),
+        );
+        config.store_at(path.clone()).unwrap();
+        let raw = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert!(!raw.contains("p12 secret"));
+        let stored: Config2 = toml::from_str(&raw).unwrap();
+        let (password, decrypted, _) = decrypt_str_or_original(
+            &stored.options[keys::OPTION_TLS_CLIENT_CERT_PASSWORD],
+            PASSWORD_ENC_VERSION,
+        );
+        assert!(decrypted);
+        assert_eq!(password, "p12 secret");
+    }
+
     #[test]
     fn test_config_deserialize() {
         let wrong_type_str = r#"
@@ -1,27 +1,37 @@
 #[cfg(windows)]
 use std::os::windows::prelude::*;
 use std::{
+    collections::{BTreeMap, HashMap},
     fmt::{Debug, Display},
     io::Cursor,
     path::{Path, PathBuf},
     sync::atomic::{AtomicI32, Ordering},
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use tokio::{
     fs::{File, OpenOptions},
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufStream as TokioBufStream},
 };
 
-use crate::{anyhow::anyhow, bail, get_version_number, message_proto::*, ResultType, Stream};
+use crate::{
+    anyhow::anyhow, bail, channel::Sink, get_version_number, message_proto::*,
+    protobuf::Message as _, ResultType,
+};
 // https://doc.rust-lang.org/std/os/windows/fs/trait.MetadataExt.html
 use crate::{
-    compress::{compress, decompress},
-    config::Config,
+    compress::{decompress, decompress_with, is_compressible, train_dictionary, BlockCompressor},
+    config::{Config, COMPRESS_LEVEL},
 };
 
+pub mod archive;
+mod delta;
+pub mod limit;
+pub mod search;
+
 static NEXT_JOB_ID: AtomicI32 = AtomicI32::new(1);
 
 pub fn get_next_job_id() -> i32 {
@@ -140,38 +150,63 @@ pub fn get_home_as_string() -> String {
     get_string(&Config::get_home())
 }
 
+// Symlinks are skipped, or listed as links if `links`.
 fn read_dir_recursive(
     path: &Path,
     prefix: &Path,
     include_hidden: bool,
+    links: bool,
 ) -> ResultType<Vec<FileEntry>> {
     let mut files = Vec::new();
+    visit_dir_recursive(path, prefix, include_hidden, links, &mut |entry| {
+        files.push(entry);
+        true
+    })?;
+    Ok(files)
+}
+
+// As `read_dir_recursive`, passing the files one by one to `f`, which returns false to stop.
+// Returns false if stopped.
+fn visit_dir_recursive(
+    path: &Path,
+    prefix: &Path,
+    include_hidden: bool,
+    links: bool,
+    f: &mut dyn FnMut(FileEntry) -> bool,
+) -> ResultType<bool> {
     if path.is_dir() {
-        // to-do: symbol link handling, cp the link rather than the content
-        // to-do: file mode, for unix
         let fd = read_dir(path, include_hidden)?;
-        for entry in fd.entries.iter() {
+        for entry in fd.entries.into_iter() {
             match entry.entry_type.enum_value() {
                 Ok(FileType::File) => {
-                    let mut entry = entry.clone();
+                    let mut entry = entry;
+                    entry.name = get_string(&prefix.join(entry.name));
+                    if !f(entry) {
+                        return Ok(false);
+                    }
+                }
+                Ok(FileType::FileLink | FileType::DirLink) if links => {
+                    let mut entry = entry;
                     entry.name = get_string(&prefix.join(entry.name));
-                    files.push(entry);
+                    if !f(entry) {
+                        return Ok(false);
+                    }
                 }
                 Ok(FileType::Dir) => {
-                    if let Ok(mut tmp) = read_dir_recursive(
+                    if let Ok(false) = visit_dir_recursive(
                         &path.join(&entry.name),
                         &prefix.join(&entry.name),
                         include_hidden,
+                        links,
+                        f,
                     ) {
-                        for entry in tmp.drain(0..) {
-                            files.push(entry);
-                        }
+                        return Ok(false);
                     }
                 }
                 _ => {}
             }
         }
-        Ok(files)
+        Ok(true)
     } else if path.is_file() {
         let (size, modified_time) = if let Ok(meta) = std::fs::metadata(path) {
             (
@@ -187,20 +222,158 @@ fn read_dir_recursive(
         } else {
             (0, 0)
         };
-        files.push(FileEntry {
+        Ok(f(FileEntry {
             entry_type: FileType::File.into(),
             size,
             modified_time,
             ..Default::default()
-        });
-        Ok(files)
+        }))
     } else {
         bail!("Not exists");
     }
 }
 
 pub fn get_recursive_files(path: &str, include_hidden: bool) -> ResultType<Vec<FileEntry>> {
-    read_dir_recursive(&get_path(path), &get_path(""), include_hidden)
+    read_dir_recursive(&get_path(path), &get_path(""), include_hidden, false)
+}
+
+/// As [`get_recursive_files`], with the symlinks as links and the metadata of the files.
+pub fn get_recursive_files_with_metadata(
+    path: &str,
+    include_hidden: bool,
+) -> ResultType<Vec<FileEntry>> {
+    let path = get_path(path);
+    let mut files = read_dir_recursive(&path, &get_path(""), include_hidden, true)?;
+    #[cfg(unix)]
+    let mut inodes = HashMap::new();
+    for (_i, entry) in files.iter_mut().enumerate() {
+        let p = TransferJob::join(&path, &entry.name);
+        let Ok(meta) = std::fs::symlink_metadata(&p) else {
+            continue;
+        };
+        let mut metadata = FileMetadata::new();
+        if meta.file_type().is_symlink() {
+            metadata.link_target = std::fs::read_link(&p)
+                .map(|t| get_string(&t))
+                .unwrap_or_default();
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            metadata.mode = meta.mode() & 0o7777;
+            metadata.uid = meta.uid();
+            metadata.gid = meta.gid();
+            // The first of the hard links is sent, the others are links to it.
+            if meta.is_file() && meta.nlink() > 1 {
+                match inodes.entry((meta.dev(), meta.ino())) {
+                    std::collections::hash_map::Entry::Occupied(e) => {
+                        metadata.hard_link = *e.get() as u32 + 1;
+                    }
+                    std::collections::hash_map::Entry::Vacant(e) => {
+                        e.insert(_i);
+                    }
+                }
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if let Ok(names) = xattr::list(&p) {
+            for name in names {
+                if let (Some(n), Ok(Some(value))) = (name.to_str(), xattr::get(&p, &name)) {
+                    metadata.xattrs.push(FileXattr {
+                        name: n.to_owned(),
+                        value: value.into(),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+        #[cfg(windows)]
+        {
+            metadata.attributes = meta.file_attributes();
+        }
+        entry.metadata = Some(metadata).into();
+    }
+    Ok(files)
+}
+
+// Symlinks and hard links are created by the receiver, no data is sent for them.
+#[inline]
+fn is_link(entry: &FileEntry) -> bool {
+    entry
+        .metadata
+        .as_ref()
+        .is_some_and(|m| !m.link_target.is_empty() || m.hard_link > 0)
+}
+
+#[inline]
+fn has_symlinks(files: &[FileEntry]) -> bool {
+    files.iter().any(|f| {
+        f.metadata
+            .as_ref()
+            .is_some_and(|m| !m.link_target.is_empty())
+    })
+}
+
+// Errors are ignored. The setuid, setgid and sticky bits are not set, nor is the owner
+// unless asked for and running as root.
+#[allow(unused_variables)]
+fn apply_metadata(path: &Path, metadata: &FileMetadata, is_symlink: bool, preserve_owner: bool) {
+    #[cfg(unix)]
+    let privileged = preserve_owner && unsafe { libc::geteuid() } == 0;
+    #[cfg(unix)]
+    if metadata.mode != 0 {
+        use std::os::unix::fs::PermissionsExt;
+        if privileged {
+            std::os::unix::fs::lchown(path, Some(metadata.uid), Some(metadata.gid)).ok();
+        }
+        if !is_symlink {
+            let mode = metadata.mode & 0o777;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).ok();
+        }
+    }
+    #[cfg(target_os = "linux")]
+    for x in metadata.xattrs.iter() {
+        if !is_xattr_allowed(&x.name, privileged) {
+            log::debug!("Skip {} of {}", x.name, path.display());
+            continue;
+        }
+        if let Err(e) = xattr::set(path, &x.name, &x.value) {
+            log::debug!("Failed to set {} of {}: {}", x.name, path.display(), e);
+        }
+    }
+    #[cfg(windows)]
+    if metadata.attributes != 0 && !is_symlink {
+        use std::os::windows::ffi::OsStrExt;
+        let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+        unsafe {
+            winapi::um::fileapi::SetFileAttributesW(wide.as_ptr(), metadata.attributes);
+        }
+    }
+}
+
+// Only the user namespace is set, the others (ACLs, SELinux labels, ...) also when the owner is
+// preserved as root. File capabilities never are, they would grant privileges like setuid does.
+#[cfg(any(target_os = "linux", test))]
+fn is_xattr_allowed(name: &str, privileged: bool) -> bool {
+    if name == "security.capability" {
+        return false;
+    }
+    privileged || name.starts_with("user.")
+}
+
+// Files are not written through the symlinks of the same job.
+fn check_symlinks(root: &Path, path: &Path) -> ResultType<()> {
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if d == root || !d.starts_with(root) {
+            break;
+        }
+        if std::fs::symlink_metadata(d).is_ok_and(|m| m.file_type().is_symlink()) {
+            bail!("{} is a symlink", d.display());
+        }
+        dir = d.parent();
+    }
+    Ok(())
 }
 
 fn read_empty_dirs_recursive(
@@ -258,6 +431,42 @@ pub fn can_enable_overwrite_detection(version: i64) -> bool {
     version >= get_version_number("1.1.10")
 }
 
+#[inline]
+pub fn can_enable_pipelining(peer_features: Option<&Features>) -> bool {
+    peer_features.is_some_and(|f| f.file_pipelining)
+}
+
+#[inline]
+pub fn can_preserve_metadata(peer_features: Option<&Features>) -> bool {
+    peer_features.is_some_and(|f| f.file_metadata)
+}
+
+/// Holes of sparse files, and resuming files past 4 GB.
+#[inline]
+pub fn can_enable_sparse(peer_features: Option<&Features>) -> bool {
+    peer_features.is_some_and(|f| f.file_sparse)
+}
+
+/// Older peers only get the done or the error of an archive extraction.
+#[inline]
+pub fn can_report_extract_progress(peer_features: Option<&Features>) -> bool {
+    peer_features.is_some_and(|f| f.file_archive_progress)
+}
+
+/// Resumes a file at `offset`, or from the start if the peer can not resume it there.
+pub fn resume_offset(
+    offset: u64,
+    peer_features: Option<&Features>,
+) -> file_transfer_send_confirm_request::Union {
+    if offset <= u32::MAX as u64 {
+        file_transfer_send_confirm_request::Union::OffsetBlk(offset as u32)
+    } else if can_enable_sparse(peer_features) {
+        file_transfer_send_confirm_request::Union::Offset(offset)
+    } else {
+        file_transfer_send_confirm_request::Union::OffsetBlk(0)
+    }
+}
+
 #[repr(i32)]
 #[derive(Copy, Clone, Serialize, Debug, PartialEq)]
 pub enum JobType {
@@ -310,6 +519,24 @@ impl JobType {
 pub enum DataSource {
     FilePath(PathBuf),
     MemoryCursor(Cursor<Vec<u8>>),
+    /// A directory sent as one archive of the files listed, see [`archive::ChunkReader`].
+    Archive(PathBuf, file_transfer_send_request::Archive, Vec<FileEntry>),
+    /// Bytes read in a thread as they are sent, of the size given, like a `MemoryCursor`.
+    Reader(Option<BlockingReader>, u64),
+}
+
+pub struct BlockingReader(Box<dyn std::io::Read + Send>);
+
+impl BlockingReader {
+    pub fn new(r: impl std::io::Read + Send + 'static) -> Self {
+        Self(Box::new(r))
+    }
+}
+
+impl Debug for BlockingReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BlockingReader")
+    }
 }
 
 impl Default for DataSource {
@@ -324,8 +551,10 @@ impl serde::Serialize for DataSource {
         S: serde::Serializer,
     {
         match self {
-            DataSource::FilePath(p) => serializer.serialize_str(p.to_str().unwrap_or("")),
-            DataSource::MemoryCursor(_) => serializer.serialize_str(""),
+            DataSource::FilePath(p) | DataSource::Archive(p, ..) => {
+                serializer.serialize_str(p.to_str().unwrap_or(""))
+            }
+            DataSource::MemoryCursor(_) | DataSource::Reader(..) => serializer.serialize_str(""),
         }
     }
 }
@@ -334,16 +563,29 @@ impl Display for DataSource {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             DataSource::FilePath(p) => write!(f, "File: {}", p.to_string_lossy().to_string()),
-            DataSource::MemoryCursor(_) => write!(f, "Bytes"),
+            DataSource::MemoryCursor(_) | DataSource::Reader(..) => write!(f, "Bytes"),
+            DataSource::Archive(p, archive, _) => {
+                write!(f, "Archive {:?}: {}", archive, p.to_string_lossy())
+            }
         }
     }
 }
 
 impl DataSource {
+    /// The files at `path`, or one archive of the directory if `archive` is set.
+    pub fn from_path(path: &str, archive: file_transfer_send_request::Archive) -> Self {
+        match archive {
+            file_transfer_send_request::Archive::NoArchive => {
+                DataSource::FilePath(PathBuf::from(path))
+            }
+            _ => DataSource::Archive(PathBuf::from(path), archive, Vec::new()),
+        }
+    }
+
     fn to_meta(&self) -> String {
         match self {
-            DataSource::FilePath(p) => p.to_string_lossy().to_string(),
-            DataSource::MemoryCursor(_) => "".to_string(),
+            DataSource::FilePath(p) | DataSource::Archive(p, ..) => p.to_string_lossy().to_string(),
+            DataSource::MemoryCursor(_) | DataSource::Reader(..) => "".to_string(),
         }
     }
 }
@@ -351,6 +593,8 @@ impl DataSource {
 enum DataStream {
     FileStream(File),
     BufStream(TokioBufStream<Cursor<Vec<u8>>>),
+    // Of an archive or a reader, only read.
+    Chunks(archive::ChunkReader),
 }
 
 impl Debug for DataStream {
@@ -358,6 +602,7 @@ impl Debug for DataStream {
         match self {
             DataStream::FileStream(fs) => write!(f, "{:?}", fs),
             DataStream::BufStream(_) => write!(f, "BufStream"),
+            DataStream::Chunks(_) => write!(f, "Chunks"),
         }
     }
 }
@@ -367,6 +612,7 @@ impl DataStream {
         match self {
             DataStream::FileStream(fs) => fs.write_all(buf).await?,
             DataStream::BufStream(bs) => bs.write_all(buf).await?,
+            DataStream::Chunks(_) => bail!("Chunks are only read"),
         }
         Ok(())
     }
@@ -375,6 +621,7 @@ impl DataStream {
         match self {
             DataStream::FileStream(fs) => fs.read(buf).await,
             DataStream::BufStream(bs) => bs.read(buf).await,
+            DataStream::Chunks(c) => c.read(buf).await,
         }
     }
 }
@@ -383,6 +630,161 @@ impl DataStream {
 pub struct FileDigest {
     pub size: u64,
     pub modified: u64,
+    // Hex SHA-256, if the sender hashed the file before sending.
+    #[serde(default)]
+    pub hash: String,
+}
+
+// Files up to this size are hashed before their digest is sent, for the receiver to
+// recognize an identical copy whatever its modification time.
+const MAX_DIGEST_HASH_SIZE: u64 = 16 * 1024 * 1024;
+
+// The digests of up to this many files are sent ahead of the one being sent, when pipelining.
+const PIPELINE_FILES: i32 = 16;
+const MAX_BATCH_FILES: usize = 256;
+const DEFAULT_BLOCK_SIZE: usize = 128 * 1024;
+const MIN_BLOCK_SIZE: usize = 32 * 1024;
+const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+// Blocks take about this long to read and send at the measured throughput, when pipelining.
+const BLOCK_TIME: Duration = Duration::from_millis(20);
+const MIN_COMPRESS_LEVEL: i32 = 1;
+const MAX_COMPRESS_LEVEL: i32 = 9;
+// A dictionary is trained on the first small files batched, if there are many more of them.
+const DICTIONARY_SAMPLES: usize = 64;
+const DICTIONARY_SAMPLE_SIZE: usize = 16 * 1024;
+const DICTIONARY_SIZE: usize = 32 * 1024;
+// Written where a hole can not be punched.
+static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+#[inline]
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// SHA-256 of a file as it is sent, the holes of sparse files as their offset and length
+// rather than the zeros, merged if contiguous for the hash not to depend on the reads.
+#[derive(Debug)]
+struct FileHasher {
+    file_num: i32,
+    sha: Sha256,
+    hole: Option<(u64, u64)>,
+}
+
+impl FileHasher {
+    fn new(file_num: i32) -> Self {
+        Self {
+            file_num,
+            sha: Sha256::new(),
+            hole: None,
+        }
+    }
+
+    // The one of `file_num`, a new one if `hasher` is of another file.
+    fn of(hasher: &mut Option<FileHasher>, file_num: i32) -> &mut FileHasher {
+        if hasher.as_ref().map(|h| h.file_num) != Some(file_num) {
+            *hasher = None;
+        }
+        hasher.get_or_insert_with(|| FileHasher::new(file_num))
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.flush_hole();
+        self.sha.update(data);
+    }
+
+    fn update_hole(&mut self, offset: u64, len: u64) {
+        match self.hole.as_mut() {
+            Some((o, l)) if *o + *l == offset => *l += len,
+            _ => {
+                self.flush_hole();
+                self.hole = Some((offset, len));
+            }
+        }
+    }
+
+    fn flush_hole(&mut self) {
+        if let Some((offset, len)) = self.hole.take() {
+            self.sha.update(offset.to_le_bytes());
+            self.sha.update(len.to_le_bytes());
+        }
+    }
+
+    fn finalize(mut self) -> Vec<u8> {
+        self.flush_hole();
+        self.sha.finalize().to_vec()
+    }
+}
+
+// The current position of `file`, the hole at it, and the data before the next hole if none.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+async fn hole_at(file: &mut File) -> std::io::Result<(u64, u64, u64)> {
+    use std::{io::SeekFrom, os::unix::io::AsRawFd};
+    let pos = file.stream_position().await?;
+    let len = file.metadata().await?.len();
+    if pos >= len {
+        return Ok((pos, 0, u64::MAX));
+    }
+    // Moves the offset of the file, set again below.
+    let hole = unsafe { libc::lseek(file.as_raw_fd(), pos as _, libc::SEEK_HOLE) };
+    if hole < 0 {
+        file.seek(SeekFrom::Start(pos)).await?;
+        return Err(std::io::Error::last_os_error());
+    }
+    let hole = hole as u64;
+    if hole > pos {
+        file.seek(SeekFrom::Start(pos)).await?;
+        // The end of the file is a hole too.
+        let data = if hole < len { hole - pos } else { u64::MAX };
+        return Ok((pos, 0, data));
+    }
+    let data = unsafe { libc::lseek(file.as_raw_fd(), pos as _, libc::SEEK_DATA) };
+    // No data left in the file.
+    let data = if data < 0 { len } else { data as u64 };
+    file.seek(SeekFrom::Start(data)).await?;
+    Ok((pos, data - pos, 0))
+}
+
+// Hashes the first `len` bytes of `file`, read from the start, walking its holes if `holes`.
+// Returns the length hashed, less than `len` if the file is shorter.
+#[allow(unused_variables)]
+async fn hash_prefix(
+    file: &mut File,
+    len: u64,
+    holes: bool,
+    hasher: &mut FileHasher,
+) -> std::io::Result<u64> {
+    let mut buf = vec![0u8; 128 * 1024];
+    let mut pos = 0;
+    while pos < len {
+        #[allow(unused_mut)]
+        let mut n = (len - pos).min(buf.len() as u64);
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if holes {
+            let (_, hole, data) = hole_at(file).await?;
+            if hole > 0 {
+                let hole = hole.min(len - pos);
+                hasher.update_hole(pos, hole);
+                pos += hole;
+                file.seek(std::io::SeekFrom::Start(pos)).await?;
+                continue;
+            }
+            n = n.min(data);
+        }
+        let m = file.read(&mut buf[..n as usize]).await?;
+        if m == 0 {
+            break;
+        }
+        hasher.update(&buf[..m]);
+        pos += m as u64;
+    }
+    Ok(pos)
+}
+
+/// SHA-256 of the file at `path`.
+pub fn hash_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
 }
 
 #[derive(Default, Serialize, Debug)]
@@ -412,11 +814,91 @@ pub struct TransferJob {
     file_skipped: bool,
     file_is_waiting: bool,
     default_overwrite_strategy: Option<bool>,
+    // Receiver, the digests of the files not started yet.
+    #[serde(skip_serializing)]
+    digests: HashMap<i32, FileDigest>,
+    // The peer can send or receive deltas.
+    #[serde(skip_serializing)]
+    peer_delta: bool,
+    // Sender, the signatures of the receiver's copies which came with its digests.
+    #[serde(skip_serializing)]
+    peer_signatures: HashMap<i32, FileBlockSignatures>,
+    // Sender, the delta of the current file.
+    #[serde(skip_serializing)]
+    delta: Option<(i32, delta::Matcher)>,
+    // Receiver, the copy the signatures were sent for.
+    #[serde(skip_serializing)]
+    delta_basis: Option<DeltaBasis>,
+    // Of the current file so far.
+    #[serde(skip_serializing)]
+    hasher: Option<FileHasher>,
+    // Hex hash of the files done, by file number, see `FileHasher`.
+    #[serde(skip_serializing)]
+    file_hashes: BTreeMap<i32, String>,
+    #[serde(skip_serializing)]
+    error: Option<String>,
+    // Sender, the next files are confirmed while one is sent, and small ones are batched.
+    #[serde(skip_serializing)]
+    pipelining: bool,
+    // Sender, the digests of the files before this one are sent.
+    #[serde(skip_serializing)]
+    digests_sent: i32,
+    // Sender, the confirmations of the files not opened yet.
+    #[serde(skip_serializing)]
+    confirms: HashMap<i32, FileTransferSendConfirmRequest>,
+    // Sender, the size of the blocks read, adapted to the throughput when pipelining.
+    #[serde(skip_serializing)]
+    block_size: usize,
+    // Sender, whether the blocks of a file are compressed, decided on its first data.
+    #[serde(skip_serializing)]
+    compress_file: Option<(i32, bool)>,
+    // Sender, adapted to the time spent compressing.
+    #[serde(skip_serializing)]
+    compress_level: i32,
+    // Sender, not the shared one of `compress::compress`, the level and dictionary change.
+    #[serde(skip_serializing)]
+    compressor: BlockCompressor,
+    // Sender, the time spent compressing since the last adaptation.
+    #[serde(skip_serializing)]
+    compress_time: Duration,
+    // Sender, the small files sampled to train the dictionary, `None` when done.
+    #[serde(skip_serializing)]
+    samples: Option<Vec<Vec<u8>>>,
+    // Of the small files batched, trained by the sender.
+    #[serde(skip_serializing)]
+    dictionary: Option<Vec<u8>>,
+    #[serde(skip_serializing)]
+    dictionary_sent: bool,
+    // Receiver, whether the files include symlinks, not to be written through.
+    #[serde(skip_serializing)]
+    symlinks: bool,
+    // Receiver, whether the files get the sender's owner, see `apply_metadata`.
+    #[serde(skip_serializing)]
+    preserve_owner: bool,
+    // Sender, whether the holes of sparse files are sent as such.
     #[serde(skip_serializing)]
-    digest: FileDigest,
+    sparse: bool,
+    // Sender, nothing is sent while paused.
+    paused: bool,
+    #[serde(skip_serializing)]
+    limiter: limit::TokenBucket,
+    // Sender, only sends within the window.
+    #[serde(skip_serializing)]
+    window: Option<limit::TimeWindow>,
+    // The directory is sent as an archive, the one file of the job.
+    #[serde(skip_serializing)]
+    archive: file_transfer_send_request::Archive,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[derive(Debug)]
+struct DeltaBasis {
+    file_num: i32,
+    path: PathBuf,
+    len: u64,
+    block_size: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
 pub struct TransferJobMeta {
     #[serde(default)]
     pub id: i32,
@@ -430,6 +912,27 @@ pub struct TransferJobMeta {
     pub file_num: i32,
     #[serde(default)]
     pub is_remote: bool,
+    // The progress, to resume the job after the app or the machine restarts.
+    #[serde(default)]
+    pub total_size: u64,
+    #[serde(default)]
+    pub finished_size: u64,
+    // Hex SHA-256 of the files done, by file number.
+    #[serde(default)]
+    pub hashes: BTreeMap<i32, String>,
+    // Neither paused nor waiting to be resumed, it is resumed without asking.
+    #[serde(default)]
+    pub running: bool,
+}
+
+impl TransferJobMeta {
+    /// Whether this is the journal of `job`, which may have got another id, and resume
+    /// from another file if the journal was saved before it moved on.
+    pub fn is_of(&self, job: &TransferJob) -> bool {
+        self.remote == job.remote
+            && self.to == job.data_source.to_meta()
+            && self.is_remote == job.is_remote
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -472,6 +975,7 @@ impl TransferJob {
     ) -> Self {
         log::info!("new write {}", data_source);
         let total_size = files.iter().map(|x| x.size).sum();
+        let symlinks = has_symlinks(&files);
         Self {
             id,
             r#type,
@@ -483,29 +987,83 @@ impl TransferJob {
             files,
             total_size,
             enable_overwrite_detection,
+            symlinks,
             ..Default::default()
         }
     }
 
+    /// With `preserve_metadata`, the files carry their metadata and symlinks are sent as links.
+    #[allow(clippy::too_many_arguments)]
     pub fn new_read(
         id: i32,
         r#type: JobType,
         remote: String,
-        data_source: DataSource,
+        mut data_source: DataSource,
         file_num: i32,
         show_hidden: bool,
         is_remote: bool,
         enable_overwrite_detection: bool,
+        preserve_metadata: bool,
     ) -> ResultType<Self> {
         log::info!("new read {}", data_source);
-        let (files, total_size) = match &data_source {
+        let (files, total_size) = match &mut data_source {
             DataSource::FilePath(p) => {
                 let p = p.to_str().ok_or(anyhow!("Invalid path"))?;
-                let files = get_recursive_files(p, show_hidden)?;
+                let files = if preserve_metadata {
+                    get_recursive_files_with_metadata(p, show_hidden)?
+                } else {
+                    get_recursive_files(p, show_hidden)?
+                };
                 let total_size = files.iter().map(|x| x.size).sum();
                 (files, total_size)
             }
-            DataSource::MemoryCursor(c) => (Vec::new(), c.get_ref().len() as u64),
+            DataSource::MemoryCursor(_) | DataSource::Reader(..) => {
+                let size = match &data_source {
+                    DataSource::MemoryCursor(c) => c.get_ref().len() as u64,
+                    DataSource::Reader(_, size) => *size,
+                    _ => 0,
+                };
+                // Generic bytes are one file, written where the receiver asks.
+                let files = if r#type == JobType::Generic {
+                    vec![FileEntry {
+                        entry_type: FileType::File.into(),
+                        size,
+                        modified_time: SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map(|t| t.as_secs())
+                            .unwrap_or(0),
+                        ..Default::default()
+                    }]
+                } else {
+                    Vec::new()
+                };
+                (files, size)
+            }
+            DataSource::Archive(p, _, archived) => {
+                let s = p.to_str().ok_or(anyhow!("Invalid path"))?;
+                if !p.is_dir() {
+                    bail!("Only directories are archived");
+                }
+                *archived = get_recursive_files(s, show_hidden)?;
+                // Estimated, the archive is created as it is sent.
+                let total_size = archived.iter().map(|x| x.size).sum();
+                let entry = FileEntry {
+                    entry_type: FileType::File.into(),
+                    size: total_size,
+                    modified_time: std::fs::metadata(p)
+                        .and_then(|m| m.modified())
+                        .ok()
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .map(|t| t.as_secs())
+                        .unwrap_or(0),
+                    ..Default::default()
+                };
+                (vec![entry], total_size)
+            }
+        };
+        let archive = match &data_source {
+            DataSource::Archive(_, archive, _) => *archive,
+            _ => Default::default(),
         };
         Ok(Self {
             id,
@@ -517,7 +1075,12 @@ impl TransferJob {
             is_remote,
             files,
             total_size,
-            enable_overwrite_detection,
+            // Nothing to compare the archive with.
+            enable_overwrite_detection: enable_overwrite_detection
+                && archive == file_transfer_send_request::Archive::NoArchive,
+            block_size: DEFAULT_BLOCK_SIZE,
+            compress_level: COMPRESS_LEVEL,
+            archive,
             ..Default::default()
         })
     }
@@ -539,13 +1102,140 @@ impl TransferJob {
 
     #[inline]
     pub fn set_files(&mut self, files: Vec<FileEntry>) {
+        self.symlinks = has_symlinks(&files);
         self.files = files;
     }
 
     #[inline]
-    pub fn set_digest(&mut self, size: u64, modified: u64) {
-        self.digest.size = size;
-        self.digest.modified = modified;
+    pub fn set_digest(&mut self, file_num: i32, size: u64, modified: u64, hash: &[u8]) {
+        self.digests.insert(
+            file_num,
+            FileDigest {
+                size,
+                modified,
+                hash: to_hex(hash),
+            },
+        );
+    }
+
+    #[inline]
+    pub fn set_pipelining(&mut self, pipelining: bool) {
+        self.pipelining = pipelining;
+        self.samples = pipelining.then(Vec::new);
+    }
+
+    /// The files sent, those archived if the directory is sent as an archive.
+    #[inline]
+    pub fn file_count(&self) -> usize {
+        match &self.data_source {
+            DataSource::Archive(_, _, archived) => archived.len(),
+            _ => self.files.len(),
+        }
+    }
+
+    #[inline]
+    pub fn archive(&self) -> file_transfer_send_request::Archive {
+        self.archive
+    }
+
+    /// Receiver, the directory is received as an archive written to the path of the job.
+    #[inline]
+    pub fn set_archive(&mut self, archive: file_transfer_send_request::Archive) {
+        self.archive = archive;
+    }
+
+    #[inline]
+    pub fn set_sparse(&mut self, sparse: bool) {
+        self.sparse = sparse;
+    }
+
+    #[inline]
+    pub fn set_preserve_owner(&mut self, preserve_owner: bool) {
+        self.preserve_owner = preserve_owner;
+    }
+
+    #[inline]
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// In bytes per second, 0 for none, see also [`limit::set_global_rate_limit`].
+    #[inline]
+    pub fn set_rate_limit(&mut self, rate: u64) {
+        self.limiter.set_rate(rate);
+    }
+
+    /// The job is deferred to `window`, if any.
+    #[inline]
+    pub fn set_window(&mut self, window: Option<limit::TimeWindow>) {
+        self.window = window;
+    }
+
+    /// Whether the job may send now, given its pause, rate limit and time window.
+    pub fn can_send(&mut self) -> bool {
+        !self.paused && !matches!(self.window, Some(w) if !w.contains_now()) && self.limiter.ready()
+    }
+
+    /// Counts `n` bytes sent against the limits of the job and of the process.
+    pub fn consume(&mut self, n: u64) {
+        self.limiter.consume(n);
+        limit::global_consume(n);
+    }
+
+    /// Hex SHA-256 of a file done, verified by the receiver. The holes of sparse files are
+    /// hashed as their offset and length.
+    #[inline]
+    pub fn file_hash(&self, file_num: i32) -> Option<&str> {
+        self.file_hashes.get(&file_num).map(|x| x.as_str())
+    }
+
+    fn update_hash(&mut self, data: &[u8]) {
+        FileHasher::of(&mut self.hasher, self.file_num).update(data);
+    }
+
+    fn finish_hash(&mut self) -> Vec<u8> {
+        let hash = match self.hasher.take() {
+            Some(hasher) if hasher.file_num == self.file_num => hasher.finalize(),
+            // Nothing was written, an empty file.
+            _ => Sha256::digest([]).to_vec(),
+        };
+        self.file_hashes.insert(self.file_num, to_hex(&hash));
+        hash
+    }
+
+    /// SHA-256 of the current file if it is small enough, see [`MAX_DIGEST_HASH_SIZE`].
+    #[inline]
+    pub async fn current_file_hash(&self, size: u64) -> Vec<u8> {
+        self.digest_hash(self.file_num, size).await
+    }
+
+    async fn digest_hash(&self, file_num: i32, size: u64) -> Vec<u8> {
+        if size > MAX_DIGEST_HASH_SIZE {
+            return Vec::new();
+        }
+        let DataSource::FilePath(p) = &self.data_source else {
+            return Vec::new();
+        };
+        let Some(entry) = self.files.get(file_num as usize).filter(|e| !is_link(e)) else {
+            return Vec::new();
+        };
+        let path = Self::join(p, &entry.name);
+        match tokio::task::spawn_blocking(move || hash_file(&path)).await {
+            Ok(Ok(hash)) => hash,
+            Ok(Err(e)) => {
+                log::warn!("Failed to hash file: {}", e);
+                Vec::new()
+            }
+            Err(e) => {
+                log::error!("Failed to hash file: {}", e);
+                Vec::new()
+            }
+        }
     }
 
     #[inline]
@@ -585,12 +1275,17 @@ impl TransferJob {
                 let download_path = format!("{}.download", get_string(&path));
                 let digest_path = format!("{}.digest", get_string(&path));
                 std::fs::remove_file(digest_path).ok();
-                std::fs::rename(download_path, &path).ok();
-                filetime::set_file_mtime(
-                    &path,
-                    filetime::FileTime::from_unix_time(entry.modified_time as _, 0),
-                )
-                .ok();
+                // Nothing to rename if the file failed verification.
+                if std::fs::rename(download_path, &path).is_ok() {
+                    filetime::set_file_mtime(
+                        &path,
+                        filetime::FileTime::from_unix_time(entry.modified_time as _, 0),
+                    )
+                    .ok();
+                    if let Some(metadata) = entry.metadata.as_ref() {
+                        apply_metadata(&path, metadata, false, self.preserve_owner);
+                    }
+                }
             }
         }
     }
@@ -629,6 +1324,35 @@ impl TransferJob {
         if block.id != self.id {
             bail!("Wrong id");
         }
+        if !block.dictionary.is_empty() {
+            self.transferred += block.dictionary.len() as u64;
+            self.dictionary = Some(block.dictionary.to_vec());
+        }
+        if block.batch.is_empty() {
+            return self.write_block(block).await;
+        }
+        // Whole small files, each ended by its hash.
+        let mut res = Ok(());
+        for entry in block.batch {
+            let r = self
+                .write_block(FileTransferBlock {
+                    id: block.id,
+                    file_num: entry.file_num,
+                    data: entry.data,
+                    compressed: entry.compressed,
+                    hash: entry.hash,
+                    with_dictionary: entry.with_dictionary,
+                    ..Default::default()
+                })
+                .await;
+            if res.is_ok() {
+                res = r;
+            }
+        }
+        res
+    }
+
+    async fn write_block(&mut self, block: FileTransferBlock) -> ResultType<()> {
         match &self.data_source {
             DataSource::FilePath(p) => {
                 let file_num = block.file_num as usize;
@@ -646,9 +1370,17 @@ impl TransferJob {
                         (p.to_string_lossy().to_string(), None)
                     } else {
                         let path = Self::join(p, &entry.name);
+                        if self.symlinks {
+                            check_symlinks(p, &path)?;
+                        }
                         if let Some(pp) = path.parent() {
                             std::fs::create_dir_all(pp).ok();
                         }
+                        if is_link(entry) {
+                            self.data_stream = None;
+                            self.hasher = None;
+                            return self.create_link(file_num, &path);
+                        }
                         let file_path = get_string(&path);
                         (
                             format!("{}.download", &file_path),
@@ -656,13 +1388,19 @@ impl TransferJob {
                         )
                     };
                     if let Some(dp) = digest_path.as_ref() {
-                        if Path::new(dp).exists() {
+                        if self.symlinks {
+                            // Not to follow a symlink of the same name.
+                            std::fs::remove_file(&path).ok();
+                            std::fs::remove_file(dp).ok();
+                        } else if Path::new(dp).exists() {
                             std::fs::remove_file(dp)?;
                         }
                     }
                     self.data_stream = Some(DataStream::FileStream(File::create(&path).await?));
+                    self.hasher = None;
                     if let Some(dp) = digest_path.as_ref() {
-                        std::fs::write(dp, json!(self.digest).to_string()).ok();
+                        let digest = self.digests.remove(&block.file_num).unwrap_or_default();
+                        std::fs::write(dp, json!(digest).to_string()).ok();
                     }
                 }
             }
@@ -671,14 +1409,22 @@ impl TransferJob {
                     self.data_stream = Some(DataStream::BufStream(TokioBufStream::new(c.clone())));
                 }
             }
+            DataSource::Archive(..) | DataSource::Reader(..) => {
+                bail!("Archives and readers are only read")
+            }
         }
         if block.compressed {
-            let tmp = decompress(&block.data);
+            let tmp = if block.with_dictionary {
+                decompress_with(&block.data, self.dictionary.as_deref().unwrap_or_default())
+            } else {
+                decompress(&block.data)
+            };
             self.data_stream
                 .as_mut()
                 .ok_or(anyhow!("data stream is None"))?
                 .write_all(&tmp)
                 .await?;
+            self.update_hash(&tmp);
             self.finished_size += tmp.len() as u64;
         } else {
             self.data_stream
@@ -686,12 +1432,209 @@ impl TransferJob {
                 .ok_or(anyhow!("file is None"))?
                 .write_all(&block.data)
                 .await?;
+            self.update_hash(&block.data);
             self.finished_size += block.data.len() as u64;
         }
+        if block.hole > 0 {
+            self.write_hole(block.hole).await?;
+        }
+        if !block.copy_blks.is_empty() {
+            self.copy_blocks(&block.copy_blks).await?;
+        }
         self.transferred += block.data.len() as u64;
+        if !block.hash.is_empty() {
+            self.verify_hash(&block.hash).await?;
+        }
+        Ok(())
+    }
+
+    // Punched, or zeros where fallocate can not, if there is data of the file already.
+    async fn write_hole(&mut self, len: u64) -> ResultType<()> {
+        use std::io::SeekFrom;
+        self.finished_size += len;
+        let Some(DataStream::FileStream(file)) = self.data_stream.as_mut() else {
+            bail!("No file to write a hole in");
+        };
+        file.flush().await?;
+        let pos = file.stream_position().await?;
+        FileHasher::of(&mut self.hasher, self.file_num).update_hole(pos, len);
+        let end = pos + len;
+        let size = file.metadata().await?.len();
+        if size > pos {
+            let n = size.min(end) - pos;
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            let punched = {
+                use std::os::unix::io::AsRawFd;
+                let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+                unsafe { libc::fallocate(file.as_raw_fd(), mode, pos as _, n as _) == 0 }
+            };
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            let punched = false;
+            if !punched {
+                let mut left = n;
+                while left > 0 {
+                    let m = left.min(ZEROS.len() as u64);
+                    file.write_all(&ZEROS[..m as usize]).await?;
+                    left -= m;
+                }
+                file.flush().await?;
+            }
+        }
+        if size < end {
+            file.set_len(end).await?;
+        }
+        file.seek(SeekFrom::Start(end)).await?;
+        Ok(())
+    }
+
+    // In place of the file or link at `path`.
+    fn create_link(&self, file_num: usize, path: &Path) -> ResultType<()> {
+        let entry = &self.files[file_num];
+        let Some(metadata) = entry.metadata.as_ref() else {
+            return Ok(());
+        };
+        if std::fs::symlink_metadata(path).is_ok() {
+            std::fs::remove_file(path)?;
+        }
+        if metadata.hard_link > 0 {
+            let i = metadata.hard_link as usize - 1;
+            let (DataSource::FilePath(p), true) = (&self.data_source, i < file_num) else {
+                bail!("Wrong hard link of {}", entry.name);
+            };
+            let original = Self::join(p, &self.files[i].name);
+            check_symlinks(p, &original)?;
+            std::fs::hard_link(original, path)?;
+            return Ok(());
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&metadata.link_target, path)?;
+        #[cfg(windows)]
+        {
+            let target = path
+                .parent()
+                .map(|d| d.join(&metadata.link_target))
+                .unwrap_or_default();
+            if entry.entry_type == FileType::DirLink.into() || target.is_dir() {
+                std::os::windows::fs::symlink_dir(&metadata.link_target, path)?;
+            } else {
+                std::os::windows::fs::symlink_file(&metadata.link_target, path)?;
+            }
+        }
+        apply_metadata(path, metadata, true, self.preserve_owner);
         Ok(())
     }
 
+    // The file is only renamed into place if it has the sender's hash.
+    async fn verify_hash(&mut self, expected: &[u8]) -> ResultType<()> {
+        let hash = self.finish_hash();
+        if hash == expected {
+            // Written before it is renamed into place.
+            if let Some(DataStream::FileStream(file)) = self.data_stream.as_mut() {
+                file.flush().await?;
+            }
+            return Ok(());
+        }
+        self.file_hashes.remove(&self.file_num);
+        self.data_stream.take();
+        self.remove_download_file();
+        let name = self
+            .files
+            .get(self.file_num as usize)
+            .map(|f| f.name.clone())
+            .unwrap_or_default();
+        let err = format!(
+            "{} is corrupted, SHA-256 {} instead of {}",
+            name,
+            to_hex(&hash),
+            to_hex(expected)
+        );
+        self.error = Some(err.clone());
+        bail!(err);
+    }
+
+    // The old file is only replaced when the new one is complete, so it can be read meanwhile.
+    async fn copy_blocks(&mut self, blks: &[u32]) -> ResultType<()> {
+        let (path, len, block_size) = match &self.delta_basis {
+            Some(basis) if basis.file_num == self.file_num => {
+                (basis.path.clone(), basis.len, basis.block_size)
+            }
+            _ => bail!("No file to copy blocks from"),
+        };
+        let mut file = File::open(&path).await?;
+        let mut buf = vec![0u8; block_size as usize];
+        for &blk in blks {
+            let offset = blk as u64 * block_size;
+            if offset >= len {
+                bail!("Wrong block number");
+            }
+            let n = (len - offset).min(block_size) as usize;
+            file.seek(std::io::SeekFrom::Start(offset)).await?;
+            if file.read_exact(&mut buf[..n]).await.is_err() {
+                bail!("{} changed during the transfer", path.display());
+            }
+            self.data_stream
+                .as_mut()
+                .ok_or(anyhow!("data stream is None"))?
+                .write_all(&buf[..n])
+                .await?;
+            self.update_hash(&buf[..n]);
+            self.finished_size += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Signatures of the existing copy of the file for the sender to send only the changed
+    /// blocks, `None` if the sender can not or it is not worth it.
+    pub async fn delta_signatures(&mut self, file_num: i32) -> Option<FileBlockSignatures> {
+        if !self.peer_delta || self.r#type != JobType::Generic {
+            return None;
+        }
+        let DataSource::FilePath(p) = &self.data_source else {
+            return None;
+        };
+        let entry = self.files.get(file_num as usize).filter(|e| !is_link(e))?;
+        let path = Self::join(p, &entry.name);
+        let len = std::fs::metadata(&path).ok().filter(|m| m.is_file())?.len();
+        if len < delta::MIN_FILE_SIZE {
+            return None;
+        }
+        let path2 = path.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            delta::signatures(std::io::BufReader::new(std::fs::File::open(path2)?), len)
+        })
+        .await;
+        match res {
+            Ok(Ok(signatures)) => {
+                self.delta_basis = Some(DeltaBasis {
+                    file_num,
+                    path,
+                    len,
+                    block_size: signatures.block_size as u64,
+                });
+                Some(signatures)
+            }
+            Ok(Err(e)) => {
+                log::warn!("Failed to read {}: {}", path.display(), e);
+                None
+            }
+            Err(e) => {
+                log::error!("Failed to compute block signatures: {}", e);
+                None
+            }
+        }
+    }
+
+    #[inline]
+    pub fn set_peer_delta(&mut self, peer_delta: bool) {
+        self.peer_delta = peer_delta;
+    }
+
+    /// Keeps the signatures sent back with the digest of an upload, until the file is confirmed.
+    #[inline]
+    pub fn set_peer_signatures(&mut self, file_num: i32, signatures: FileBlockSignatures) {
+        self.peer_signatures.insert(file_num, signatures);
+    }
+
     #[inline]
     pub fn join(p: &PathBuf, name: &str) -> PathBuf {
         if name.is_empty() {
@@ -713,6 +1656,13 @@ impl TransferJob {
                     return Ok(true);
                 };
                 if self.data_stream.is_none() {
+                    if is_link(&self.files[file_num]) {
+                        // Nothing to read, but confirmed as a file if it replaces one, see `read`.
+                        if !self.enable_overwrite_detection {
+                            self.file_confirmed = true;
+                        }
+                        return Ok(false);
+                    }
                     match File::open(Self::join(p, &self.files[file_num].name)).await {
                         Ok(file) => {
                             self.data_stream = Some(DataStream::FileStream(file));
@@ -737,15 +1687,49 @@ impl TransferJob {
                     self.data_stream = Some(DataStream::BufStream(TokioBufStream::new(t)));
                 }
             }
+            DataSource::Archive(p, archive, archived) => {
+                if file_num >= self.files.len() {
+                    self.data_stream.take();
+                    return Ok(true);
+                }
+                if self.data_stream.is_none() {
+                    // Created once, it can not be resumed midway.
+                    let files = std::mem::take(archived);
+                    self.data_stream = Some(DataStream::Chunks(archive::ChunkReader::archive(
+                        p.clone(),
+                        files,
+                        *archive,
+                    )));
+                    self.file_confirmed = true;
+                    self.file_is_waiting = false;
+                }
+            }
+            DataSource::Reader(r, _) => {
+                if self.data_stream.is_none() {
+                    // Read once, an empty stream after.
+                    self.data_stream = Some(match r.take() {
+                        Some(r) => DataStream::Chunks(archive::ChunkReader::reader(r.0)),
+                        None => DataStream::BufStream(TokioBufStream::new(Default::default())),
+                    });
+                }
+            }
         }
         Ok(false)
     }
 
     /// Get current file's digest (last_modified, file_size) for overwrite detection.
     async fn get_current_digest(&self) -> ResultType<(u64, u64)> {
+        if let Some(entry) = self
+            .files
+            .get(self.file_num as usize)
+            .filter(|e| is_link(e))
+        {
+            return Ok((entry.modified_time, 0));
+        }
         let meta = match self.data_stream.as_ref().ok_or(anyhow!("file is None"))? {
             DataStream::FileStream(file) => file.metadata().await?,
             DataStream::BufStream(_) => bail!("No digest for buf stream"),
+            DataStream::Chunks(_) => bail!("No digest for chunk stream"),
         };
         let last_modified = meta
             .modified()?
@@ -754,7 +1738,7 @@ impl TransferJob {
         Ok((last_modified, meta.len()))
     }
 
-    async fn init_data_stream(&mut self, stream: &mut crate::Stream) -> ResultType<()> {
+    async fn init_data_stream(&mut self, stream: &mut impl Sink) -> ResultType<()> {
         if self.open_data_stream().await? {
             return Ok(());
         }
@@ -763,8 +1747,19 @@ impl TransferJob {
             && !self.file_confirmed()
             && !self.file_is_waiting()
         {
-            self.send_current_digest(stream).await?;
-            self.set_file_is_waiting(true);
+            if self.file_num < self.digests_sent {
+                // The digest was sent ahead, it may be confirmed already.
+                self.set_file_is_waiting(true);
+                if let Some(r) = self.confirms.remove(&self.file_num) {
+                    self.confirm(&r).await;
+                }
+            } else {
+                self.send_current_digest(stream).await?;
+                self.set_file_is_waiting(true);
+            }
+            if self.pipelining {
+                self.send_digests_ahead(stream).await?;
+            }
         }
         Ok(())
     }
@@ -798,24 +1793,75 @@ impl TransferJob {
         }
 
         let file_num = self.file_num as usize;
-        let name = match &self.data_source {
+        let compressible = match &self.data_source {
             DataSource::FilePath(p) => {
                 if file_num >= self.files.len() {
                     self.data_stream.take();
                     return Ok(None);
                 };
-                if self.files.len() == 1 && self.files[file_num].name.is_empty() {
+                let name = if self.files.len() == 1 && self.files[file_num].name.is_empty() {
                     p.file_name()
                         .map(|p| p.to_str().unwrap_or(""))
                         .unwrap_or("")
                 } else {
                     &self.files[file_num].name
+                };
+                !is_compressed_file(name)
+            }
+            DataSource::MemoryCursor(..) => false,
+            DataSource::Reader(..) => true,
+            DataSource::Archive(_, archive, _) => {
+                if file_num >= self.files.len() {
+                    self.data_stream.take();
+                    return Ok(None);
                 }
+                // Of stored files, tar.zst is compressed already.
+                *archive == file_transfer_send_request::Archive::Zip
             }
-            DataSource::MemoryCursor(..) => "",
         };
-        const BUF_SIZE: usize = 128 * 1024;
-        let mut buf: Vec<u8> = vec![0; BUF_SIZE];
+        if self.files.get(file_num).is_some_and(is_link) {
+            // Created by the receiver from the metadata.
+            self.file_num += 1;
+            self.file_confirmed = false;
+            self.file_is_waiting = false;
+            return Ok(Some(FileTransferBlock {
+                id: self.id,
+                file_num: file_num as _,
+                ..Default::default()
+            }));
+        }
+        if self
+            .delta
+            .as_ref()
+            .is_some_and(|(n, _)| *n == file_num as i32)
+        {
+            return self.read_delta(compressible).await;
+        }
+        // Nothing of the current file is sent yet.
+        let fresh = match self.hasher.as_ref() {
+            Some(h) => h.file_num != file_num as i32,
+            None => true,
+        };
+        #[allow(unused_mut)]
+        let mut buf_size = self.block_size;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.sparse {
+            match self.hole_at().await {
+                Ok((_, 0, data)) => buf_size = data.min(buf_size as u64) as usize,
+                Ok((pos, hole, _)) => {
+                    FileHasher::of(&mut self.hasher, self.file_num).update_hole(pos, hole);
+                    self.finished_size += hole;
+                    return Ok(Some(FileTransferBlock {
+                        id: self.id,
+                        file_num: file_num as _,
+                        hole,
+                        ..Default::default()
+                    }));
+                }
+                Err(e) => log::debug!("Failed to find the holes of the file: {}", e),
+            }
+        }
+        let mut buf: Vec<u8> = vec![0; buf_size];
         let mut compressed = false;
         let mut offset: usize = 0;
         loop {
@@ -835,31 +1881,39 @@ impl TransferJob {
                 }
                 Ok(n) => {
                     offset += n;
-                    if n == 0 || offset == BUF_SIZE {
+                    if n == 0 || offset == buf_size {
                         break;
                     }
                 }
             }
         }
         unsafe { buf.set_len(offset) };
+        if self.pipelining
+            && fresh
+            && offset < buf_size
+            && matches!(self.data_source, DataSource::FilePath(_))
+        {
+            // The whole file is read.
+            return Ok(Some(self.read_batch(buf, compressible).await));
+        }
+        let mut hash = Vec::new();
         if offset == 0 {
-            if matches!(self.data_source, DataSource::MemoryCursor(_)) {
+            if matches!(
+                self.data_source,
+                DataSource::MemoryCursor(_) | DataSource::Reader(..)
+            ) {
                 self.data_stream.take();
                 return Ok(None);
             }
+            hash = self.finish_hash();
             self.file_num += 1;
             self.data_stream = None;
             self.file_confirmed = false;
             self.file_is_waiting = false;
         } else {
+            self.update_hash(&buf);
             self.finished_size += offset as u64;
-            if matches!(self.data_source, DataSource::FilePath(_)) && !is_compressed_file(name) {
-                let tmp = compress(&buf);
-                if tmp.len() < buf.len() {
-                    buf = tmp;
-                    compressed = true;
-                }
-            }
+            (buf, compressed) = self.compress_block(buf, compressible, false);
             self.transferred += buf.len() as u64;
         }
         Ok(Some(FileTransferBlock {
@@ -867,13 +1921,267 @@ impl TransferJob {
             file_num: file_num as _,
             data: buf.into(),
             compressed,
+            hash: hash.into(),
+            ..Default::default()
+        }))
+    }
+
+    // The hole at the current position of the file, see `hole_at`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    async fn hole_at(&mut self) -> std::io::Result<(u64, u64, u64)> {
+        let Some(DataStream::FileStream(file)) = self.data_stream.as_mut() else {
+            return Ok((0, 0, u64::MAX));
+        };
+        hole_at(file).await
+    }
+
+    // The current file, read whole, and the next small files which are confirmed already.
+    async fn read_batch(&mut self, data: Vec<u8>, compressible: bool) -> FileTransferBlock {
+        let mut block = FileTransferBlock {
+            id: self.id,
+            file_num: self.file_num,
+            ..Default::default()
+        };
+        if let Some(dictionary) = self.dictionary.as_ref().filter(|_| !self.dictionary_sent) {
+            self.transferred += dictionary.len() as u64;
+            block.dictionary = dictionary.clone().into();
+            self.dictionary_sent = true;
+        }
+        let mut size = data.len() as u64;
+        let mut batch = vec![self.batch_entry(data, compressible)];
+        self.file_num += 1;
+        while batch.len() < MAX_BATCH_FILES {
+            let Some(entry) = self.files.get(self.file_num as usize) else {
+                break;
+            };
+            if size + entry.size > self.block_size as u64
+                || is_link(entry)
+                || self.peer_signatures.contains_key(&self.file_num)
+            {
+                break;
+            }
+            let skip = match self.confirms.get(&self.file_num) {
+                Some(r) if r.signatures.is_none() => match r.union {
+                    Some(file_transfer_send_confirm_request::Union::Skip(s)) => s,
+                    Some(file_transfer_send_confirm_request::Union::OffsetBlk(0))
+                    | Some(file_transfer_send_confirm_request::Union::Offset(0)) => false,
+                    _ => break,
+                },
+                _ => break,
+            };
+            if !skip {
+                let DataSource::FilePath(p) = &self.data_source else {
+                    break;
+                };
+                let compressible = !is_compressed_file(&entry.name);
+                // On error, the file is opened as usual to report it.
+                let Ok(data) = tokio::fs::read(Self::join(p, &entry.name)).await else {
+                    break;
+                };
+                size += data.len() as u64;
+                batch.push(self.batch_entry(data, compressible));
+            }
+            self.confirms.remove(&self.file_num);
+            self.file_num += 1;
+        }
+        self.data_stream = None;
+        self.file_confirmed = false;
+        self.file_is_waiting = false;
+        block.batch = batch;
+        self.update_dictionary().await;
+        block
+    }
+
+    fn batch_entry(&mut self, data: Vec<u8>, compressible: bool) -> FileBatchEntry {
+        let hash = Sha256::digest(&data).to_vec();
+        self.file_hashes.insert(self.file_num, to_hex(&hash));
+        self.finished_size += data.len() as u64;
+        if let Some(samples) = self.samples.as_mut() {
+            if compressible && !data.is_empty() {
+                samples.push(data[..data.len().min(DICTIONARY_SAMPLE_SIZE)].to_vec());
+            }
+        }
+        let (data, compressed) = self.compress_block(data, compressible, true);
+        self.transferred += data.len() as u64;
+        FileBatchEntry {
+            file_num: self.file_num,
+            data: data.into(),
+            compressed,
+            hash: hash.into(),
+            with_dictionary: compressed && self.dictionary.is_some(),
+            ..Default::default()
+        }
+    }
+
+    // Source trees and the like have many small files alike, which compress better with a
+    // dictionary trained on the first ones.
+    async fn update_dictionary(&mut self) {
+        match self.samples.as_ref() {
+            Some(samples) if samples.len() >= DICTIONARY_SAMPLES => {}
+            _ => return,
+        }
+        let samples = self.samples.take().unwrap_or_default();
+        let left = self
+            .files
+            .iter()
+            .skip(self.file_num as usize)
+            .filter(|f| f.size <= DICTIONARY_SAMPLE_SIZE as u64)
+            .count();
+        if left < DICTIONARY_SAMPLES {
+            return;
+        }
+        let res =
+            tokio::task::spawn_blocking(move || train_dictionary(&samples, DICTIONARY_SIZE)).await;
+        match res {
+            Ok(Ok(dictionary)) => {
+                log::info!(
+                    "id: {}, compressing small files with a dictionary of {} bytes",
+                    self.id,
+                    dictionary.len()
+                );
+                self.dictionary = Some(dictionary);
+            }
+            Ok(Err(e)) => log::debug!("Failed to train a dictionary: {}", e),
+            Err(e) => log::error!("Failed to train a dictionary: {}", e),
+        }
+    }
+
+    // Compresses the data of the current file if its first data looked compressible and it is
+    // smaller so, with the dictionary if `with_dictionary` and there is one.
+    fn compress_block(
+        &mut self,
+        data: Vec<u8>,
+        compressible: bool,
+        with_dictionary: bool,
+    ) -> (Vec<u8>, bool) {
+        if data.is_empty() {
+            return (data, false);
+        }
+        let compressible = match self.compress_file {
+            Some((file_num, c)) if file_num == self.file_num => c,
+            _ => {
+                let c = compressible && is_compressible(&data);
+                self.compress_file = Some((self.file_num, c));
+                c
+            }
+        };
+        if !compressible {
+            return (data, false);
+        }
+        let dictionary = match self.dictionary.as_ref() {
+            Some(dictionary) if with_dictionary => dictionary.as_slice(),
+            _ => &[],
+        };
+        let start = Instant::now();
+        let tmp = self
+            .compressor
+            .compress(&data, self.compress_level, dictionary);
+        self.compress_time += start.elapsed();
+        if !tmp.is_empty() && tmp.len() < data.len() {
+            (tmp, true)
+        } else {
+            (data, false)
+        }
+    }
+
+    /// Bytes of the files per byte sent or received, more than 1 with compression and deltas.
+    #[inline]
+    pub fn compression_ratio(&self) -> f64 {
+        if self.transferred == 0 {
+            1.0
+        } else {
+            self.finished_size as f64 / self.transferred as f64
+        }
+    }
+
+    // After a block is read and sent: a lower compression level when compressing takes most
+    // of the time, a higher one when sending does. Larger blocks as long as they go fast,
+    // smaller ones not to hold up the other messages when the link is slow.
+    fn adapt(&mut self, size: u64, elapsed: Duration) {
+        let compress_time = std::mem::take(&mut self.compress_time);
+        if !compress_time.is_zero() {
+            if compress_time > elapsed / 2 {
+                self.compress_level = (self.compress_level - 1).max(MIN_COMPRESS_LEVEL);
+            } else if compress_time < elapsed / 8 {
+                self.compress_level = (self.compress_level + 1).min(MAX_COMPRESS_LEVEL);
+            }
+        }
+        if !self.pipelining || size == 0 {
+            return;
+        }
+        let throughput = size as f64 / elapsed.as_secs_f64().max(1e-6);
+        let target = (throughput * BLOCK_TIME.as_secs_f64()) as usize;
+        self.block_size = target
+            .clamp(self.block_size / 2, self.block_size * 2)
+            .clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE);
+    }
+
+    async fn read_delta(&mut self, compressible: bool) -> ResultType<Option<FileTransferBlock>> {
+        let file_num = self.file_num;
+        let mut buf = vec![0u8; 128 * 1024];
+        loop {
+            let Some((_, matcher)) = self.delta.as_mut() else {
+                bail!("No delta");
+            };
+            if let Some(chunk) = matcher.next_chunk() {
+                let copied = chunk.copies.len() as u64 * matcher.block_size();
+                self.finished_size += chunk.literal.len() as u64 + copied;
+                let copy_blks = chunk.copies;
+                let (data, compressed) = self.compress_block(chunk.literal, compressible, false);
+                self.transferred += data.len() as u64;
+                return Ok(Some(FileTransferBlock {
+                    id: self.id,
+                    file_num,
+                    data: data.into(),
+                    compressed,
+                    copy_blks,
+                    ..Default::default()
+                }));
+            }
+            if matcher.is_done() {
+                break;
+            }
+            match self
+                .data_stream
+                .as_mut()
+                .ok_or(anyhow!("data stream is None"))?
+                .read(&mut buf)
+                .await
+            {
+                Err(err) => {
+                    self.file_num += 1;
+                    self.data_stream = None;
+                    self.delta = None;
+                    self.file_confirmed = false;
+                    self.file_is_waiting = false;
+                    return Err(err.into());
+                }
+                Ok(0) => matcher.finish(),
+                Ok(n) => {
+                    matcher.feed(&buf[..n]);
+                    self.update_hash(&buf[..n]);
+                }
+            }
+        }
+        // The empty block ends the file, as in `read`.
+        let hash = self.finish_hash();
+        self.file_num += 1;
+        self.data_stream = None;
+        self.delta = None;
+        self.file_confirmed = false;
+        self.file_is_waiting = false;
+        Ok(Some(FileTransferBlock {
+            id: self.id,
+            file_num,
+            hash: hash.into(),
             ..Default::default()
         }))
     }
 
     // Only for generic job and file stream
-    async fn send_current_digest(&mut self, stream: &mut Stream) -> ResultType<()> {
+    async fn send_current_digest(&mut self, stream: &mut impl Sink) -> ResultType<()> {
         let (last_modified, file_size) = self.get_current_digest().await?;
+        let hash = self.current_file_hash(file_size).await;
         let mut msg = Message::new();
         let mut resp = FileResponse::new();
         resp.set_digest(FileTransferDigest {
@@ -882,6 +2190,8 @@ impl TransferJob {
             last_modified,
             file_size,
             is_resume: self.is_resume,
+            delta: true,
+            hash: hash.into(),
             ..Default::default()
         });
         msg.set_file_response(resp);
@@ -892,6 +2202,36 @@ impl TransferJob {
             self.file_num,
             msg
         );
+        self.digests_sent = self.digests_sent.max(self.file_num + 1);
+        Ok(())
+    }
+
+    // The digests of the next files, for them to be confirmed while this one is sent.
+    async fn send_digests_ahead(&mut self, stream: &mut impl Sink) -> ResultType<()> {
+        let end = (self.file_num + PIPELINE_FILES).min(self.files.len() as i32);
+        let mut file_num = self.digests_sent.max(self.file_num + 1);
+        while file_num < end {
+            let entry = &self.files[file_num as usize];
+            let file_size = if is_link(entry) { 0 } else { entry.size };
+            let last_modified = entry.modified_time;
+            let hash = self.digest_hash(file_num, file_size).await;
+            let mut msg = Message::new();
+            let mut resp = FileResponse::new();
+            resp.set_digest(FileTransferDigest {
+                id: self.id,
+                file_num,
+                last_modified,
+                file_size,
+                is_resume: self.is_resume,
+                delta: true,
+                hash: hash.into(),
+                ..Default::default()
+            });
+            msg.set_file_response(resp);
+            stream.send(&msg).await?;
+            file_num += 1;
+            self.digests_sent = file_num;
+        }
         Ok(())
     }
 
@@ -950,6 +2290,9 @@ impl TransferJob {
 
     /// Get job error message, useful for getting status when job had finished
     pub fn job_error(&self) -> Option<String> {
+        if let Some(err) = self.error.as_ref() {
+            return Some(err.clone());
+        }
         if self.job_skipped() {
             return Some("skipped".to_string());
         }
@@ -974,10 +2317,13 @@ impl TransferJob {
             let download_path = format!("{}.download", &file_path);
             let digest_path = format!("{}.digest", &file_path);
 
-            let mut f = if Path::new(&download_path).exists() && Path::new(&digest_path).exists() {
+            let is_download =
+                Path::new(&download_path).exists() && Path::new(&digest_path).exists();
+            let mut f = if is_download {
                 // If both download and digest files exist, seek (writer) to the offset
                 match OpenOptions::new()
                     .create(true)
+                    .read(true)
                     .write(true)
                     .open(&download_path)
                     .await
@@ -1005,8 +2351,20 @@ impl TransferJob {
                 );
                 return;
             };
+            // The hash is of the whole file, the part sent before included. The holes are those
+            // sent as such, by a sparse sender, and those of the file received.
+            let mut hasher = FileHasher::new(file_num as i32);
+            let holes = self.sparse || is_download;
+            let hashed = hash_prefix(&mut f, offset, holes, &mut hasher)
+                .await
+                .unwrap_or_default();
+            if hashed < offset {
+                log::warn!("Failed to read {} up to offset {}", file_path, offset);
+                return;
+            }
             if f.seek(std::io::SeekFrom::Start(offset)).await.is_ok() {
                 self.data_stream = Some(DataStream::FileStream(f));
+                self.hasher = Some(hasher);
                 self.transferred += offset;
                 self.finished_size += offset;
             }
@@ -1014,7 +2372,15 @@ impl TransferJob {
     }
 
     pub async fn confirm(&mut self, r: &FileTransferSendConfirmRequest) -> bool {
-        if self.file_num() != r.file_num {
+        // A link has no stream, its confirmation is applied at once.
+        let opened = self.data_stream.is_some()
+            || self.files.get(self.file_num as usize).is_some_and(is_link);
+        if self.pipelining
+            && (r.file_num > self.file_num || (r.file_num == self.file_num && !opened))
+        {
+            // Applied when the file is opened, see `init_data_stream`.
+            self.confirms.insert(r.file_num, r.clone());
+        } else if self.file_num() != r.file_num {
             // This branch will always be hit if:
             // 1. `confirm()` is called in `ui_cm_interface.rs`
             // 2. Not resuming
@@ -1022,28 +2388,55 @@ impl TransferJob {
             // It is ok. Because `confirm()` in `ui_cm_interface.rs` is only used for resuming.
             log::info!("file num truncated, ignoring");
         } else {
-            match r.union {
+            let offset = match r.union {
                 Some(file_transfer_send_confirm_request::Union::Skip(s)) => {
                     if s {
                         self.set_file_skipped();
                     } else {
                         self.set_file_confirmed(true);
                     }
+                    None
                 }
                 Some(file_transfer_send_confirm_request::Union::OffsetBlk(offset)) => {
-                    self.set_file_confirmed(true);
-                    // If offset is greater than 0, we need to seek to the offset
-                    if offset > 0 {
-                        self.set_stream_offset(r.file_num as usize, offset as u64)
-                            .await;
-                    }
+                    Some(offset as u64)
+                }
+                Some(file_transfer_send_confirm_request::Union::Offset(offset)) => Some(offset),
+                _ => None,
+            };
+            if let Some(offset) = offset {
+                self.set_file_confirmed(true);
+                // If offset is greater than 0, we need to seek to the offset
+                if offset > 0 {
+                    self.set_stream_offset(r.file_num as usize, offset).await;
+                } else {
+                    self.start_delta(r);
                 }
-                _ => {}
             }
         }
         true
     }
 
+    // The file is sent as a delta if the receiver has sent the signatures of its copy.
+    fn start_delta(&mut self, r: &FileTransferSendConfirmRequest) {
+        let peer_signatures = self.peer_signatures.remove(&r.file_num);
+        let signatures = match (r.signatures.as_ref(), peer_signatures.as_ref()) {
+            (Some(signatures), _) | (None, Some(signatures)) => signatures,
+            (None, None) => return,
+        };
+        match delta::Matcher::new(signatures) {
+            Ok(matcher) => {
+                log::info!(
+                    "id: {}, file_num: {}, sending the delta, block size: {}",
+                    self.id,
+                    r.file_num,
+                    matcher.block_size()
+                );
+                self.delta = Some((r.file_num, matcher));
+            }
+            Err(e) => log::warn!("id: {}, file_num: {}, {}", self.id, r.file_num, e),
+        }
+    }
+
     #[inline]
     pub fn gen_meta(&self) -> TransferJobMeta {
         TransferJobMeta {
@@ -1053,8 +2446,39 @@ impl TransferJob {
             file_num: self.file_num,
             show_hidden: self.show_hidden,
             is_remote: self.is_remote,
+            total_size: self.total_size,
+            finished_size: self.finished_size,
+            hashes: self
+                .file_hashes
+                .range(..self.file_num)
+                .map(|(k, v)| (*k, v.clone()))
+                .collect(),
+            running: !self.paused && !self.is_last_job,
         }
     }
+
+    /// Restores the progress of the files done from the journal `meta` of a previous run.
+    pub fn restore(&mut self, meta: &TransferJobMeta) {
+        if !meta.is_of(self) {
+            return;
+        }
+        self.file_hashes.extend(
+            meta.hashes
+                .range(..self.file_num)
+                .map(|(k, v)| (*k, v.clone())),
+        );
+        self.finished_size = if self.files.is_empty() {
+            // Until the files are known, see `set_finished_size_on_resume`.
+            meta.finished_size
+        } else {
+            // The part of the current file is added as it is resumed, see `set_stream_offset`.
+            self.files
+                .iter()
+                .take(self.file_num as usize)
+                .map(|f| f.size)
+                .sum()
+        };
+    }
 }
 
 #[inline]
@@ -1132,6 +2556,8 @@ pub fn new_send(
     path: String,
     file_num: i32,
     include_hidden: bool,
+    preserve_metadata: bool,
+    archive: file_transfer_send_request::Archive,
 ) -> Message {
     log::info!("new send: {}, id: {}", path, id);
     let mut action = FileAction::new();
@@ -1142,6 +2568,8 @@ pub fn new_send(
         include_hidden,
         file_num,
         file_type: t.into(),
+        preserve_metadata,
+        archive: archive.into(),
         ..Default::default()
     });
     let mut msg_out = Message::new();
@@ -1179,7 +2607,7 @@ pub fn get_job_immutable(id: i32, jobs: &[TransferJob]) -> Option<&TransferJob>
     jobs.iter().find(|x| x.id() == id)
 }
 
-async fn init_jobs(jobs: &mut Vec<TransferJob>, stream: &mut crate::Stream) -> ResultType<()> {
+async fn init_jobs(jobs: &mut Vec<TransferJob>, stream: &mut impl Sink) -> ResultType<()> {
     for job in jobs.iter_mut() {
         if job.is_last_job {
             continue;
@@ -1195,16 +2623,21 @@ async fn init_jobs(jobs: &mut Vec<TransferJob>, stream: &mut crate::Stream) -> R
 
 pub async fn handle_read_jobs(
     jobs: &mut Vec<TransferJob>,
-    stream: &mut crate::Stream,
+    stream: &mut impl Sink,
 ) -> ResultType<String> {
     init_jobs(jobs, stream).await?;
 
     let mut job_log = Default::default();
+    if !limit::global_ready() {
+        return Ok(job_log);
+    }
     let mut finished = Vec::new();
     for job in jobs.iter_mut() {
-        if job.is_last_job {
+        if job.is_last_job || !job.can_send() {
             continue;
         }
+        let finished_size = job.finished_size();
+        let start = Instant::now();
         match job.read().await {
             Err(err) => {
                 stream
@@ -1212,7 +2645,13 @@ pub async fn handle_read_jobs(
                     .await?;
             }
             Ok(Some(block)) => {
-                stream.send(&new_block(block)).await?;
+                let msg = new_block(block);
+                job.consume(msg.compute_size());
+                stream.send(&msg).await?;
+                job.adapt(
+                    job.finished_size().saturating_sub(finished_size),
+                    start.elapsed(),
+                );
             }
             Ok(None) => {
                 if job.job_completed() {
@@ -1312,8 +2751,13 @@ pub fn is_write_need_confirmation(
         // We can use the digest file to check whether the file is the same.
         if let Ok(content) = std::fs::read_to_string(digest_file) {
             if let Ok(local_digest) = serde_json::from_str::<FileDigest>(&content) {
-                let is_identical = local_digest.modified == digest.last_modified
-                    && local_digest.size == digest.file_size;
+                // The hash tells a file changed in place, the modification time may not.
+                let is_identical = local_digest.size == digest.file_size
+                    && if local_digest.hash.is_empty() || digest.hash.is_empty() {
+                        local_digest.modified == digest.last_modified
+                    } else {
+                        local_digest.hash == to_hex(&digest.hash)
+                    };
                 if is_identical {
                     if let Ok(download_metadata) = std::fs::metadata(download_file) {
                         // Get the file size of the local file
@@ -1336,8 +2780,27 @@ pub fn is_write_need_confirmation(
         }
     }
 
+    // A link in place of the file is replaced, not written through, so never the same.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_symlink() {
+            let local_mt = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+            return Ok(DigestCheckResult::NeedConfirm(FileTransferDigest {
+                id: digest.id,
+                file_num: digest.file_num,
+                last_modified: local_mt.as_secs(),
+                file_size: metadata.len(),
+                ..Default::default()
+            }));
+        }
+    }
     if path.exists() && path.is_file() {
         let metadata = std::fs::metadata(path)?;
+        if !digest.hash.is_empty()
+            && digest.file_size == metadata.len()
+            && hash_file(path).is_ok_and(|hash| hash == digest.hash)
+        {
+            return Ok(DigestCheckResult::IsSame);
+        }
         let modified_time = metadata.modified()?;
         let remote_mt = Duration::from_secs(digest.last_modified);
         let local_mt = modified_time.duration_since(UNIX_EPOCH)?;
@@ -1365,7 +2828,8 @@ pub fn is_write_need_confirmation(
 pub fn serialize_transfer_jobs(jobs: &[TransferJob]) -> String {
     let mut v = vec![];
     for job in jobs {
-        let value = serde_json::to_value(job).unwrap_or_default();
+        let mut value = serde_json::to_value(job).unwrap_or_default();
+        value["compression_ratio"] = json!(job.compression_ratio());
         v.push(value);
     }
     serde_json::to_string(&v).unwrap_or_default()
@@ -1376,5 +2840,614 @@ pub fn serialize_transfer_job(job: &TransferJob, done: bool, cancel: bool, error
     value["done"] = json!(done);
     value["cancel"] = json!(cancel);
     value["error"] = json!(error);
+    // Hex SHA-256 by file number, of the files sent, or received and verified.
+    value["hashes"] = json!(job.file_hashes);
+    value["compression_ratio"] = json!(job.compression_ratio());
     serde_json::to_string(&value).unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    // A directory of the test, removed when dropped. Also used by the tests of the submodules.
+    pub(super) struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub(super) fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("hbb_common_{}_{}", name, std::process::id()));
+            std::fs::remove_dir_all(&dir).ok();
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    // How `transfer` sends the files.
+    #[derive(Default)]
+    struct Opts {
+        pipelining: bool,
+        metadata: bool,
+        sparse: bool,
+        // With overwrite detection, whether the files there are overwritten.
+        overwrite: Option<bool>,
+        // The files skipped, by name.
+        skip: Vec<&'static str>,
+        // Flips a bit of the first data.
+        corrupt: bool,
+    }
+
+    struct Transferred {
+        reader: TransferJob,
+        writer: TransferJob,
+        // The most files in a batch.
+        batched: usize,
+        // The files asked to be overwritten.
+        asked: usize,
+    }
+
+    // Sends `source` to `dst`, as a delta for the files there. With pipelining, the files
+    // are confirmed ahead, else as the digest of each is checked.
+    async fn transfer(source: DataSource, dst: &Path, opts: Opts) -> ResultType<Transferred> {
+        let is_path = matches!(source, DataSource::FilePath(_));
+        let mut reader = TransferJob::new_read(
+            1,
+            JobType::Generic,
+            "".into(),
+            source,
+            0,
+            false,
+            false,
+            opts.overwrite.is_some(),
+            opts.metadata,
+        )?;
+        reader.set_pipelining(opts.pipelining);
+        reader.set_sparse(opts.sparse);
+        let mut writer = TransferJob::new_write(
+            1,
+            JobType::Generic,
+            "".into(),
+            DataSource::FilePath(dst.to_path_buf()),
+            0,
+            false,
+            false,
+            reader.files().clone(),
+            opts.overwrite.is_some(),
+        );
+        writer.set_peer_delta(true);
+        let is_skipped = |job: &TransferJob, file_num: i32| {
+            let name = &job.files()[file_num as usize].name;
+            opts.skip
+                .iter()
+                .any(|x| *name == x.replace('/', std::path::MAIN_SEPARATOR_STR))
+        };
+        let new_confirm = |file_num, skip| FileTransferSendConfirmRequest {
+            id: 1,
+            file_num,
+            union: Some(if skip {
+                file_transfer_send_confirm_request::Union::Skip(true)
+            } else {
+                file_transfer_send_confirm_request::Union::OffsetBlk(0)
+            }),
+            ..Default::default()
+        };
+        if opts.pipelining {
+            for file_num in 0..reader.files().len() as i32 {
+                let req = new_confirm(file_num, is_skipped(&reader, file_num));
+                reader.confirm(&req).await;
+            }
+        }
+        let (mut batched, mut asked) = (0, 0);
+        let mut corrupt = opts.corrupt;
+        while !reader.open_data_stream().await? {
+            let file_num = reader.file_num;
+            if opts.pipelining {
+                if let Some(r) = reader.confirms.remove(&file_num) {
+                    reader.confirm(&r).await;
+                }
+            } else if is_path && !reader.file_confirmed() {
+                let mut skip = is_skipped(&reader, file_num);
+                if let Some(overwrite) = opts.overwrite {
+                    let (last_modified, file_size) = reader.get_current_digest().await?;
+                    let digest = FileTransferDigest {
+                        id: 1,
+                        file_num,
+                        last_modified,
+                        file_size,
+                        hash: reader.current_file_hash(file_size).await.into(),
+                        ..Default::default()
+                    };
+                    let name = &reader.files()[file_num as usize].name;
+                    let path = get_string(&TransferJob::join(&dst.to_path_buf(), name));
+                    skip |= match is_write_need_confirmation(false, &path, &digest)? {
+                        DigestCheckResult::IsSame => true,
+                        DigestCheckResult::NeedConfirm(_) => {
+                            asked += 1;
+                            !overwrite
+                        }
+                        DigestCheckResult::NoSuchFile => false,
+                    };
+                }
+                let mut req = new_confirm(file_num, skip);
+                if !skip {
+                    req.signatures = writer.delta_signatures(file_num).await.into();
+                }
+                reader.confirm(&req).await;
+            }
+            if reader.file_num != file_num {
+                // Skipped.
+                continue;
+            }
+            let Some(mut block) = reader.read().await? else {
+                break;
+            };
+            if corrupt && !block.data.is_empty() {
+                let mut data = block.data.to_vec();
+                data[0] ^= 1;
+                block.data = data.into();
+                corrupt = false;
+            }
+            batched = batched.max(block.batch.len());
+            writer.write(block).await?;
+        }
+        writer.modify_time();
+        Ok(Transferred {
+            reader,
+            writer,
+            batched,
+            asked,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_transfer_hash() {
+        let dir = TempDir::new("fs");
+        let (src, dst) = (dir.join("src.bin"), dir.join("dst.bin"));
+        let mut data: Vec<u8> = (0..2_000_000).map(|_| rand::random::<u8>()).collect();
+        std::fs::write(&src, &data).unwrap();
+        let source = || DataSource::FilePath(src.clone());
+        let t = transfer(source(), &dst, Opts::default()).await.unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        let hash = to_hex(&Sha256::digest(&data));
+        assert_eq!(t.writer.file_hash(0), Some(hash.as_str()));
+        assert_eq!(t.reader.file_hash(0), Some(hash.as_str()));
+        assert_eq!(to_hex(&hash_file(&dst).unwrap()), hash);
+
+        // The old copy is kept if the new one is corrupted.
+        data[1_000_000] ^= 1;
+        std::fs::write(&src, &data).unwrap();
+        let opts = Opts {
+            corrupt: true,
+            ..Default::default()
+        };
+        assert!(transfer(source(), &dst, opts).await.is_err());
+        assert_eq!(to_hex(&hash_file(&dst).unwrap()), hash);
+        assert!(!dir.join("dst.bin.download").exists());
+
+        // A delta against the old copy.
+        let t = transfer(source(), &dst, Opts::default()).await.unwrap();
+        assert!(t.writer.transferred < 100_000);
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_archive() {
+        let dir = TempDir::new("archive");
+        let src = dir.join("src");
+        std::fs::create_dir_all(src.join("b")).unwrap();
+        for i in 0..100 {
+            std::fs::write(src.join("b").join(format!("{}.txt", i)), i.to_string()).unwrap();
+        }
+        let big: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(src.join("big.bin"), &big).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::Permissions::from_mode(0o4755);
+            std::fs::set_permissions(src.join("b").join("0.txt"), mode).unwrap();
+        }
+        for (archive, name) in [
+            (file_transfer_send_request::Archive::TarZst, "src.tar.zst"),
+            (file_transfer_send_request::Archive::Zip, "src.zip"),
+        ] {
+            let source = DataSource::Archive(src.clone(), archive, Vec::new());
+            let dst = dir.join(name);
+            let t = transfer(source, &dst, Opts::default()).await.unwrap();
+            // The 101 files archived are extracted below.
+            assert_eq!(t.reader.files().len(), 1);
+            let out = dir.join(format!("out_{}", name));
+            let mut progress = Vec::new();
+            let cancel = AtomicBool::new(false);
+            assert_eq!(
+                archive::extract(&get_string(&dst), &get_string(&out), &cancel, |n, read| {
+                    progress.push((n, read))
+                })
+                .unwrap(),
+                101
+            );
+            assert!(progress.windows(2).all(|w| w[0] <= w[1]));
+            assert!(matches!(progress.last(), Some((101, read)) if *read >= 1_000_000));
+            assert_eq!(
+                std::fs::read_to_string(out.join("src").join("b").join("42.txt")).unwrap(),
+                "42"
+            );
+            assert_eq!(std::fs::read(out.join("src").join("big.bin")).unwrap(), big);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let meta = std::fs::metadata(out.join("src").join("b").join("0.txt")).unwrap();
+                assert_eq!(meta.permissions().mode() & 0o7777, 0o755);
+            }
+
+            // Cancelled once 100 KB are read.
+            let out = dir.join(format!("cancelled_{}", name));
+            let res = archive::extract(&get_string(&dst), &get_string(&out), &cancel, |_, read| {
+                if read > 100_000 {
+                    cancel.store(true, Ordering::SeqCst);
+                }
+            });
+            assert!(res.is_err());
+            assert!(std::fs::metadata(out.join("src").join("big.bin"))
+                .map_or(true, |m| m.len() < 1_000_000));
+        }
+        let cancel = AtomicBool::new(false);
+        assert!(
+            archive::extract(&get_string(&src.join("big.bin")), "", &cancel, |_, _| {}).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transfer_bytes() {
+        let dir = TempDir::new("bytes");
+        let data = b"line\n".repeat(100_000);
+        let cursor = || std::io::Cursor::new(data.clone());
+        let sources = vec![
+            DataSource::MemoryCursor(cursor()),
+            DataSource::Reader(Some(BlockingReader::new(cursor())), data.len() as _),
+        ];
+        for (i, source) in sources.into_iter().enumerate() {
+            let dst = dir.join(format!("history{}.txt", i));
+            let t = transfer(source, &dst, Opts::default()).await.unwrap();
+            assert_eq!(t.reader.files().len(), 1);
+            assert_eq!(std::fs::read(&dst).unwrap(), data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transfer_batch() {
+        let dir = TempDir::new("batch");
+        let (src, dst) = (dir.join("src"), dir.join("dst"));
+        std::fs::create_dir_all(src.join("b")).unwrap();
+        let big: Vec<u8> = (0..300_000).map(|_| rand::random::<u8>()).collect();
+        let files = [
+            ("a.txt", b"hello".to_vec()),
+            ("b/c.bin", vec![7u8; 1000]),
+            ("d.bin", big),
+            ("e.txt", Vec::new()),
+            ("f.txt", b"skipped".to_vec()),
+        ];
+        for (name, data) in files.iter() {
+            std::fs::write(src.join(name), data).unwrap();
+        }
+        let opts = Opts {
+            pipelining: true,
+            skip: vec!["f.txt"],
+            ..Default::default()
+        };
+        let t = transfer(DataSource::FilePath(src.clone()), &dst, opts)
+            .await
+            .unwrap();
+        assert!(t.batched > 1);
+        let names: Vec<String> = t.writer.files().iter().map(|f| f.name.clone()).collect();
+        for (name, data) in files.iter() {
+            let path = dst.join(name);
+            if *name == "f.txt" {
+                assert!(!path.exists());
+                continue;
+            }
+            assert_eq!(&std::fs::read(&path).unwrap(), data);
+            let name = name.replace('/', std::path::MAIN_SEPARATOR_STR);
+            let file_num = names.iter().position(|x| *x == name).unwrap() as i32;
+            let hash = to_hex(&Sha256::digest(data));
+            assert_eq!(t.writer.file_hash(file_num), Some(hash.as_str()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transfer_dictionary() {
+        let dir = TempDir::new("dict");
+        let (src, dst) = (dir.join("src"), dir.join("dst"));
+        std::fs::create_dir_all(&src).unwrap();
+        let n = 600;
+        for i in 0..n {
+            let data = format!(
+                "use std::io;\n\npub fn f{i}(x: u32) -> io::Result<u32> {{\n    Ok(x * {})\n}}\n",
+                i * 7
+            );
+            std::fs::write(src.join(format!("f{}.rs", i)), data).unwrap();
+        }
+        let opts = Opts {
+            pipelining: true,
+            ..Default::default()
+        };
+        let t = transfer(DataSource::FilePath(src.clone()), &dst, opts)
+            .await
+            .unwrap();
+        assert!(t.reader.dictionary_sent);
+        assert!(t.writer.dictionary.is_some());
+        assert!(t.writer.compression_ratio() > 1.0);
+        for i in 0..n {
+            let name = format!("f{}.rs", i);
+            assert_eq!(
+                std::fs::read(dst.join(&name)).unwrap(),
+                std::fs::read(src.join(&name)).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_adapt() {
+        let mut job = TransferJob {
+            pipelining: true,
+            block_size: DEFAULT_BLOCK_SIZE,
+            ..Default::default()
+        };
+        // 1 MB/s, 20 KB in 20 ms, at most halving.
+        job.adapt(1_000_000, Duration::from_secs(1));
+        assert_eq!(job.block_size, DEFAULT_BLOCK_SIZE / 2);
+        job.adapt(1_000_000, Duration::from_secs(1));
+        assert_eq!(job.block_size, MIN_BLOCK_SIZE);
+        for _ in 0..10 {
+            job.adapt(job.block_size as u64, Duration::from_micros(100));
+        }
+        assert_eq!(job.block_size, MAX_BLOCK_SIZE);
+
+        // Compressing takes most of the time.
+        job.compress_level = COMPRESS_LEVEL;
+        job.compress_time = Duration::from_millis(90);
+        job.adapt(1000, Duration::from_millis(100));
+        assert_eq!(job.compress_level, COMPRESS_LEVEL - 1);
+        job.compress_time = Duration::from_millis(1);
+        job.adapt(1000, Duration::from_millis(100));
+        assert_eq!(job.compress_level, COMPRESS_LEVEL);
+    }
+
+    #[test]
+    fn test_xattr_allowed() {
+        assert!(is_xattr_allowed("user.comment", false));
+        assert!(!is_xattr_allowed("security.selinux", false));
+        assert!(!is_xattr_allowed("trusted.overlay.opaque", false));
+        assert!(is_xattr_allowed("system.posix_acl_access", true));
+        assert!(!is_xattr_allowed("security.capability", false));
+        assert!(!is_xattr_allowed("security.capability", true));
+    }
+
+    #[test]
+    fn test_job_journal() {
+        let new_job = |id| TransferJob {
+            id,
+            remote: "/remote/dir".into(),
+            data_source: DataSource::FilePath(PathBuf::from("/local/dir")),
+            is_remote: true,
+            file_num: 2,
+            ..Default::default()
+        };
+        let mut job = new_job(1);
+        job.finished_size = 300;
+        for i in 0..3 {
+            job.file_hashes.insert(i, format!("{:064x}", i));
+        }
+        let meta = job.gen_meta();
+        assert!(meta.running);
+        assert_eq!(meta.hashes.len(), 2);
+        let meta: TransferJobMeta =
+            serde_json::from_str(&serde_json::to_string(&meta).unwrap()).unwrap();
+
+        // Restored with another id, the files are not known yet.
+        let mut restored = new_job(7);
+        restored.restore(&meta);
+        assert_eq!(restored.file_hashes, meta.hashes);
+        assert_eq!(restored.finished_size(), 300);
+        let mut other = new_job(1);
+        other.remote = "/remote/other".into();
+        other.restore(&meta);
+        assert!(other.file_hashes.is_empty() && other.finished_size() == 0);
+        // The files known, of the CM, only those done count.
+        let mut restored = new_job(8);
+        restored.files = (0..3)
+            .map(|_| FileEntry {
+                size: 100,
+                ..Default::default()
+            })
+            .collect();
+        restored.restore(&meta);
+        assert_eq!(restored.finished_size(), 200);
+
+        job.set_paused(true);
+        assert!(!job.gen_meta().running);
+        // Saved before the journal, never resumed without asking.
+        let old =
+            r#"{"id":1,"remote":"/r","to":"/l","show_hidden":false,"file_num":0,"is_remote":true}"#;
+        assert!(
+            !serde_json::from_str::<TransferJobMeta>(old)
+                .unwrap()
+                .running
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_transfer_metadata() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        let dir = TempDir::new("meta");
+        let (src, dst) = (dir.join("src"), dir.join("dst"));
+        std::fs::create_dir_all(src.join("b")).unwrap();
+        std::fs::write(src.join("a.sh"), b"#!/bin/sh").unwrap();
+        std::fs::set_permissions(src.join("a.sh"), std::fs::Permissions::from_mode(0o4750))
+            .unwrap();
+        std::fs::write(src.join("b/c.txt"), b"hello").unwrap();
+        std::fs::hard_link(src.join("a.sh"), src.join("d.sh")).unwrap();
+        std::os::unix::fs::symlink("b/c.txt", src.join("link")).unwrap();
+        std::os::unix::fs::symlink("b", src.join("dir")).unwrap();
+        let source = || DataSource::FilePath(src.clone());
+        let opts = |overwrite| Opts {
+            metadata: true,
+            overwrite,
+            ..Default::default()
+        };
+        let t = transfer(source(), &dst, opts(None)).await.unwrap();
+        assert_eq!(t.reader.files().len(), 5);
+        // Without the setuid bit.
+        let meta = std::fs::metadata(dst.join("a.sh")).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o750);
+        assert_eq!(
+            meta.ino(),
+            std::fs::metadata(dst.join("d.sh")).unwrap().ino()
+        );
+        assert_eq!(
+            std::fs::read_link(dst.join("link")).unwrap(),
+            Path::new("b/c.txt")
+        );
+        assert_eq!(std::fs::read(dst.join("link")).unwrap(), b"hello");
+        assert_eq!(std::fs::read_link(dst.join("dir")).unwrap(), Path::new("b"));
+
+        // Not written through a symlink of the job.
+        let mut files = t.writer.files().clone();
+        files.push(FileEntry {
+            name: "dir/x.txt".into(),
+            ..Default::default()
+        });
+        let mut writer = TransferJob::new_write(
+            2,
+            JobType::Generic,
+            "".into(),
+            DataSource::FilePath(dst.clone()),
+            0,
+            false,
+            false,
+            files,
+            false,
+        );
+        let block = FileTransferBlock {
+            id: 2,
+            file_num: 5,
+            data: b"evil".to_vec().into(),
+            ..Default::default()
+        };
+        assert!(writer.write(block).await.is_err());
+        assert!(!dst.join("b/x.txt.download").exists());
+
+        // Links replace what is there only if confirmed, as files do.
+        std::fs::remove_file(src.join("link")).unwrap();
+        std::os::unix::fs::symlink("a.sh", src.join("link")).unwrap();
+        let t = transfer(source(), &dst, opts(Some(false))).await.unwrap();
+        assert_eq!(t.asked, 3);
+        assert_eq!(
+            std::fs::read_link(dst.join("link")).unwrap(),
+            Path::new("b/c.txt")
+        );
+        transfer(source(), &dst, opts(Some(true))).await.unwrap();
+        assert_eq!(
+            std::fs::read_link(dst.join("link")).unwrap(),
+            Path::new("a.sh")
+        );
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    async fn transfer_sparse(size: u64) {
+        use std::{
+            io::{Read, Seek, SeekFrom, Write},
+            os::unix::fs::MetadataExt,
+        };
+        let dir = TempDir::new(&format!("sparse_{}", size));
+        let (src, dst) = (dir.join("src.img"), dir.join("dst.img"));
+        let data: Vec<u8> = (0..1024 * 1024).map(|_| rand::random::<u8>()).collect();
+        let mut f = std::fs::File::create(&src).unwrap();
+        f.write_all(b"head").unwrap();
+        f.seek(SeekFrom::Start(size / 2)).unwrap();
+        f.write_all(&data).unwrap();
+        // Ends with a hole.
+        f.set_len(size).unwrap();
+        drop(f);
+        let opts = Opts {
+            sparse: true,
+            ..Default::default()
+        };
+        let Transferred { reader, writer, .. } =
+            transfer(DataSource::FilePath(src.clone()), &dst, opts)
+                .await
+                .unwrap();
+        assert!(reader.transferred < 2 * data.len() as u64);
+        assert_eq!(writer.file_hash(0), reader.file_hash(0));
+        // The same when hashed again to resume.
+        let mut hasher = FileHasher::new(0);
+        let mut f = File::open(&src).await.unwrap();
+        assert_eq!(
+            hash_prefix(&mut f, size, true, &mut hasher).await.unwrap(),
+            size
+        );
+        assert_eq!(
+            Some(to_hex(&hasher.finalize())).as_deref(),
+            reader.file_hash(0)
+        );
+        let meta = std::fs::metadata(&dst).unwrap();
+        assert_eq!(meta.len(), size);
+        // The holes are not written.
+        assert!(meta.blocks() * 512 < 4 * data.len() as u64);
+        let mut f = std::fs::File::open(&dst).unwrap();
+        let mut buf = vec![0; data.len()];
+        f.read_exact(&mut buf[..4]).unwrap();
+        assert_eq!(&buf[..4], b"head");
+        f.seek(SeekFrom::Start(size / 2)).unwrap();
+        f.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+        f.seek(SeekFrom::Start(size - 4)).unwrap();
+        f.read_exact(&mut buf[..4]).unwrap();
+        assert_eq!(&buf[..4], &[0; 4]);
+    }
+
+    #[test]
+    fn test_resume_offset() {
+        use file_transfer_send_confirm_request::Union;
+        let large = u32::MAX as u64 + 1;
+        let sparse = Features {
+            file_sparse: true,
+            ..Default::default()
+        };
+        assert_eq!(resume_offset(1, None), Union::OffsetBlk(1));
+        // Peers without the feature restart the file.
+        assert_eq!(resume_offset(large, None), Union::OffsetBlk(0));
+        assert_eq!(
+            resume_offset(large, Some(&Features::default())),
+            Union::OffsetBlk(0)
+        );
+        assert_eq!(resume_offset(large, Some(&sparse)), Union::Offset(large));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn test_transfer_sparse() {
+        transfer_sparse(64 * 1024 * 1024).await;
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn test_transfer_sparse_10g() {
+        transfer_sparse(10 * 1024 * 1024 * 1024).await;
+    }
+}
@@ -0,0 +1,479 @@
+//! Archives of directories, created while they are sent, and extracted once received, and the
+//! reading in a thread of what is produced as it is sent.
+//!
+//! The zip files are of stored entries, the transfer compresses them, with the CRC and sizes
+//! after the data so that they are written in one pass.
+use super::{get_file_name, get_string};
+use crate::{
+    bail,
+    config::COMPRESS_LEVEL,
+    log,
+    message_proto::{file_transfer_send_request::Archive, FileEntry},
+    ResultType,
+};
+use chrono::{Datelike, TimeZone, Timelike};
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tokio::sync::mpsc;
+
+// The archive is passed to the reader in chunks of this size, at most this many ahead.
+const CHUNK_SIZE: usize = 128 * 1024;
+const CHUNKS_AHEAD: usize = 8;
+
+/// Data produced in a thread while it is read, an archive of files under a directory or what
+/// a blocking reader reads.
+pub struct ChunkReader {
+    rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChunkReader {
+    /// The `files` listed under `dir`, which are in the directory named as `dir` in the archive.
+    pub fn archive(dir: PathBuf, files: Vec<FileEntry>, format: Archive) -> Self {
+        Self::spawn(move |w| match format {
+            Archive::Zip => write_zip(&dir, &files, w),
+            _ => write_tar_zst(&dir, &files, w),
+        })
+    }
+
+    pub fn reader(mut r: impl Read + Send + 'static) -> Self {
+        Self::spawn(move |w| io::copy(&mut r, w).map(|_| ()))
+    }
+
+    fn spawn(f: impl FnOnce(&mut ChunkWriter) -> io::Result<()> + Send + 'static) -> Self {
+        let (tx, rx) = mpsc::channel(CHUNKS_AHEAD);
+        std::thread::spawn(move || {
+            let mut w = ChunkWriter {
+                tx: tx.clone(),
+                chunk: Vec::with_capacity(CHUNK_SIZE),
+            };
+            if let Err(err) = f(&mut w).and_then(|_| w.flush()) {
+                // Stopped by the reader if it is gone.
+                tx.blocking_send(Err(err)).ok();
+            }
+        });
+        Self {
+            rx,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.chunk.len() {
+            match self.rx.recv().await {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+struct ChunkWriter {
+    tx: mpsc::Sender<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(CHUNK_SIZE - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..n]);
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.chunk.is_empty() {
+            let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+            if self.tx.blocking_send(Ok(chunk)).is_err() {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "The archive is not read anymore",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The path of a file in the archive, under the name of `dir`, "/" separated.
+fn name_in_archive(dir: &Path, name: &str) -> String {
+    let base = get_file_name(dir);
+    let path = if name.is_empty() {
+        PathBuf::from(base)
+    } else {
+        Path::new(&base).join(name)
+    };
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn path_of(dir: &Path, name: &str) -> PathBuf {
+    if name.is_empty() {
+        dir.to_path_buf()
+    } else {
+        dir.join(name)
+    }
+}
+
+// The files which can not be opened are skipped.
+fn write_tar_zst(dir: &Path, files: &[FileEntry], w: impl Write) -> io::Result<()> {
+    let mut tar = tar::Builder::new(zstd::stream::write::Encoder::new(w, COMPRESS_LEVEL)?);
+    tar.follow_symlinks(false);
+    for entry in files {
+        let path = path_of(dir, &entry.name);
+        let name = name_in_archive(dir, &entry.name);
+        // Opened before anything of it is written.
+        if let Err(err) = File::open(&path) {
+            log::warn!("Skip {} in the archive: {}", get_string(&path), err);
+            continue;
+        }
+        tar.append_path_with_name(&path, &name)?;
+    }
+    tar.into_inner()?.finish()?;
+    Ok(())
+}
+
+struct ZipEntry {
+    name: String,
+    crc: u32,
+    size: u64,
+    offset: u64,
+    time: (u16, u16),
+    mode: u32,
+}
+
+const ZIP64_LIMIT: u64 = u32::MAX as u64;
+// Bit 3, the CRC and sizes follow the data, and bit 11, UTF-8 names.
+const ZIP_FLAGS: u16 = 0x0808;
+const ZIP_VERSION: u16 = 45;
+
+// Counts the bytes written.
+struct CountingWriter<W> {
+    w: W,
+    written: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn put(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.w.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    fn put_u16(&mut self, v: u16) -> io::Result<()> {
+        self.put(&v.to_le_bytes())
+    }
+
+    fn put_u32(&mut self, v: u32) -> io::Result<()> {
+        self.put(&v.to_le_bytes())
+    }
+
+    fn put_u64(&mut self, v: u64) -> io::Result<()> {
+        self.put(&v.to_le_bytes())
+    }
+}
+
+// The files which can not be opened are skipped.
+fn write_zip(dir: &Path, files: &[FileEntry], w: impl Write) -> io::Result<()> {
+    let mut w = CountingWriter { w, written: 0 };
+    let mut entries = Vec::new();
+    for entry in files {
+        let path = path_of(dir, &entry.name);
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) => {
+                log::warn!("Skip {} in the archive: {}", get_string(&path), err);
+                continue;
+            }
+        };
+        let meta = file.metadata()?;
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::PermissionsExt::mode(&meta.permissions());
+        #[cfg(not(unix))]
+        let mode = 0o100644;
+        let mut entry = ZipEntry {
+            name: name_in_archive(dir, &entry.name),
+            crc: 0,
+            size: 0,
+            offset: w.written,
+            time: dos_time(entry.modified_time),
+            mode,
+        };
+        // Decided before the size is known, the file may grow.
+        let zip64 = meta.len() >= ZIP64_LIMIT;
+        w.put_u32(0x04034b50)?;
+        w.put_u16(ZIP_VERSION)?;
+        w.put_u16(ZIP_FLAGS)?;
+        w.put_u16(0)?;
+        w.put_u16(entry.time.0)?;
+        w.put_u16(entry.time.1)?;
+        w.put_u32(0)?;
+        let sizes = if zip64 { u32::MAX } else { 0 };
+        w.put_u32(sizes)?;
+        w.put_u32(sizes)?;
+        w.put_u16(entry.name.len() as _)?;
+        w.put_u16(if zip64 { 20 } else { 0 })?;
+        w.put(entry.name.as_bytes())?;
+        if zip64 {
+            w.put_u16(1)?;
+            w.put_u16(16)?;
+            w.put_u64(0)?;
+            w.put_u64(0)?;
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            w.put(&buf[..n])?;
+            entry.size += n as u64;
+        }
+        if !zip64 && entry.size >= ZIP64_LIMIT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} grew while it was archived", get_string(&path)),
+            ));
+        }
+        entry.crc = hasher.finalize();
+        w.put_u32(0x08074b50)?;
+        w.put_u32(entry.crc)?;
+        if zip64 {
+            w.put_u64(entry.size)?;
+            w.put_u64(entry.size)?;
+        } else {
+            w.put_u32(entry.size as _)?;
+            w.put_u32(entry.size as _)?;
+        }
+        entries.push(entry);
+    }
+
+    let cd_offset = w.written;
+    for entry in entries.iter() {
+        let mut extra = Vec::new();
+        if entry.size >= ZIP64_LIMIT {
+            extra.extend_from_slice(&entry.size.to_le_bytes());
+            extra.extend_from_slice(&entry.size.to_le_bytes());
+        }
+        if entry.offset >= ZIP64_LIMIT {
+            extra.extend_from_slice(&entry.offset.to_le_bytes());
+        }
+        w.put_u32(0x02014b50)?;
+        // Made by Unix, for the mode.
+        w.put_u16((3 << 8) | ZIP_VERSION)?;
+        w.put_u16(ZIP_VERSION)?;
+        w.put_u16(ZIP_FLAGS)?;
+        w.put_u16(0)?;
+        w.put_u16(entry.time.0)?;
+        w.put_u16(entry.time.1)?;
+        w.put_u32(entry.crc)?;
+        w.put_u32(entry.size.min(ZIP64_LIMIT) as _)?;
+        w.put_u32(entry.size.min(ZIP64_LIMIT) as _)?;
+        w.put_u16(entry.name.len() as _)?;
+        // The header of the extra field, and its data.
+        let extra_len = if extra.is_empty() { 0 } else { extra.len() + 4 };
+        w.put_u16(extra_len as _)?;
+        w.put_u16(0)?;
+        w.put_u16(0)?;
+        w.put_u16(0)?;
+        w.put_u32(entry.mode << 16)?;
+        w.put_u32(entry.offset.min(ZIP64_LIMIT) as _)?;
+        w.put(entry.name.as_bytes())?;
+        if !extra.is_empty() {
+            w.put_u16(1)?;
+            w.put_u16(extra.len() as _)?;
+            w.put(&extra)?;
+        }
+    }
+    let cd_size = w.written - cd_offset;
+    let count = entries.len() as u64;
+    if count >= u16::MAX as u64 || cd_offset >= ZIP64_LIMIT || cd_size >= ZIP64_LIMIT {
+        let eocd64_offset = w.written;
+        w.put_u32(0x06064b50)?;
+        w.put_u64(44)?;
+        w.put_u16(ZIP_VERSION)?;
+        w.put_u16(ZIP_VERSION)?;
+        w.put_u32(0)?;
+        w.put_u32(0)?;
+        w.put_u64(count)?;
+        w.put_u64(count)?;
+        w.put_u64(cd_size)?;
+        w.put_u64(cd_offset)?;
+        w.put_u32(0x07064b50)?;
+        w.put_u32(0)?;
+        w.put_u64(eocd64_offset)?;
+        w.put_u32(1)?;
+    }
+    w.put_u32(0x06054b50)?;
+    w.put_u16(0)?;
+    w.put_u16(0)?;
+    w.put_u16(count.min(u16::MAX as u64) as _)?;
+    w.put_u16(count.min(u16::MAX as u64) as _)?;
+    w.put_u32(cd_size.min(ZIP64_LIMIT) as _)?;
+    w.put_u32(cd_offset.min(ZIP64_LIMIT) as _)?;
+    w.put_u16(0)?;
+    w.w.flush()
+}
+
+// The MS-DOS time and date of seconds since the epoch, in local time.
+fn dos_time(secs: u64) -> (u16, u16) {
+    match chrono::Local.timestamp_opt(secs as i64, 0).single() {
+        Some(t) if t.year() >= 1980 => (
+            ((t.hour() << 11) | (t.minute() << 5) | (t.second() / 2)) as u16,
+            (((t.year() as u32 - 1980) << 9) | (t.month() << 5) | t.day()) as u16,
+        ),
+        _ => (0, (1 << 5) | 1),
+    }
+}
+
+/// Extracts the archive at `path`, .tar.zst, .tzst, .tar or .zip, into `to`, or the directory
+/// of the archive if empty. Returns the number of entries extracted.
+///
+/// `progress` is called with the entries extracted and the bytes of them read, as they are read.
+/// It stops with an error once `cancel` is set, what is extracted so far is left in place.
+///
+/// The setuid, setgid and sticky bits of the entries are not set.
+pub fn extract(
+    path: &str,
+    to: &str,
+    cancel: &AtomicBool,
+    progress: impl FnMut(usize, u64),
+) -> ResultType<usize> {
+    let path = Path::new(path);
+    let to = if to.is_empty() {
+        path.parent().unwrap_or(Path::new("")).to_path_buf()
+    } else {
+        PathBuf::from(to)
+    };
+    std::fs::create_dir_all(&to)?;
+    let name = get_file_name(path).to_lowercase();
+    let file = File::open(path)?;
+    let progress = RefCell::new(Progress {
+        entries: 0,
+        read: 0,
+        f: progress,
+        cancel,
+    });
+    if name.ends_with(".zip") {
+        extract_zip(file, &to, &progress)
+    } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+        extract_tar(zstd::stream::read::Decoder::new(file)?, &to, &progress)
+    } else if name.ends_with(".tar") {
+        extract_tar(file, &to, &progress)
+    } else {
+        bail!("Unsupported archive: {}", get_string(path));
+    }
+}
+
+struct Progress<'a, F> {
+    entries: usize,
+    read: u64,
+    f: F,
+    cancel: &'a AtomicBool,
+}
+
+impl<F: FnMut(usize, u64)> Progress<'_, F> {
+    fn check(&self) -> io::Result<()> {
+        if self.cancel.load(Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::Other, "Cancelled"));
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, n: usize) {
+        self.read += n as u64;
+        (self.f)(self.entries, self.read);
+    }
+
+    fn entry(&mut self) {
+        self.entries += 1;
+        (self.f)(self.entries, self.read);
+    }
+}
+
+// Reports the bytes read of the entries, the tar headers included.
+struct ProgressReader<'a, 'b, R, F> {
+    r: R,
+    progress: &'a RefCell<Progress<'b, F>>,
+}
+
+impl<R: Read, F: FnMut(usize, u64)> Read for ProgressReader<'_, '_, R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.progress.borrow().check()?;
+        let n = self.r.read(buf)?;
+        self.progress.borrow_mut().read(n);
+        Ok(n)
+    }
+}
+
+// Entries escaping `to` are skipped.
+fn extract_tar<F: FnMut(usize, u64)>(
+    r: impl Read,
+    to: &Path,
+    progress: &RefCell<Progress<F>>,
+) -> ResultType<usize> {
+    let mut archive = tar::Archive::new(ProgressReader { r, progress });
+    archive.set_preserve_permissions(false);
+    for entry in archive.entries()? {
+        progress.borrow().check()?;
+        if entry?.unpack_in(to)? {
+            progress.borrow_mut().entry();
+        }
+    }
+    Ok(progress.borrow().entries)
+}
+
+// Entries escaping `to` are skipped.
+fn extract_zip<F: FnMut(usize, u64)>(
+    file: File,
+    to: &Path,
+    progress: &RefCell<Progress<F>>,
+) -> ResultType<usize> {
+    let mut archive = zip::ZipArchive::new(file)?;
+    for i in 0..archive.len() {
+        progress.borrow().check()?;
+        let mut entry = archive.by_index(i)?;
+        let Some(path) = entry.enclosed_name().map(|p| to.join(p)) else {
+            continue;
+        };
+        if entry.is_dir() {
+            std::fs::create_dir_all(&path)?;
+        } else {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut r = ProgressReader {
+                r: &mut entry,
+                progress,
+            };
+            io::copy(&mut r, &mut File::create(&path)?)?;
+            #[cfg(unix)]
+            if let Some(mode) = entry.unix_mode() {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode & 0o777)).ok();
+            }
+        }
+        progress.borrow_mut().entry();
+    }
+    Ok(progress.borrow().entries)
+}
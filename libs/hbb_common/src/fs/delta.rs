@@ -0,0 +1,356 @@
+//! Delta transfer of files the receiver already has an older copy of, as in rsync.
+//!
+//! The receiver sends the signatures of its copy, a weak rolling checksum and a strong hash of
+//! each block. The sender looks for these blocks at every offset of its file and sends the
+//! blocks found as indexes into the receiver's copy, everything else as data.
+use crate::{bail, message_proto::FileBlockSignatures, ResultType};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, io::Read};
+
+/// Smaller files are sent whole, the signatures would save little.
+pub const MIN_FILE_SIZE: u64 = 1024 * 1024;
+const MIN_BLOCK_SIZE: u64 = 4 * 1024;
+const MAX_BLOCK_SIZE: u32 = 16 * 1024 * 1024;
+// Keeps the signatures of a large file within a few megabytes.
+const MAX_BLOCKS: u64 = 64 * 1024;
+const STRONG_LEN: usize = 16;
+// A chunk is at most this much data, like a block of a whole file.
+const MAX_LITERAL: usize = 128 * 1024;
+const MAX_COPIES: usize = 4096;
+
+/// The square root of the length, as rsync, rounded up to 1 KiB.
+fn block_size(len: u64) -> u32 {
+    let size = ((len as f64).sqrt() as u64)
+        .max(len.div_ceil(MAX_BLOCKS))
+        .max(MIN_BLOCK_SIZE);
+    (size.div_ceil(1024) * 1024).min(MAX_BLOCK_SIZE as u64) as u32
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let len = data.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, &x) in data.iter().enumerate() {
+            a = a.wrapping_add(x as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(x as u32));
+        }
+        Self { a, b, len }
+    }
+
+    // Moves the window one byte on.
+    fn roll(&mut self, out: u8, r#in: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(r#in as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(data: &[u8]) -> [u8; STRONG_LEN] {
+    let mut res = [0u8; STRONG_LEN];
+    res.copy_from_slice(&Sha256::digest(data)[..STRONG_LEN]);
+    res
+}
+
+// Like `read_exact`, but a short read at the end is fine.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Signatures of the `len` bytes of `reader`.
+pub fn signatures(reader: impl Read, len: u64) -> std::io::Result<FileBlockSignatures> {
+    signatures_with_block_size(reader, block_size(len))
+}
+
+fn signatures_with_block_size(
+    mut reader: impl Read,
+    block_size: u32,
+) -> std::io::Result<FileBlockSignatures> {
+    let mut weak = Vec::new();
+    let mut strongs = Vec::new();
+    let mut buf = vec![0u8; block_size as usize];
+    loop {
+        let n = read_full(&mut reader, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        weak.push(Rolling::new(&buf[..n]).digest());
+        strongs.extend_from_slice(&strong(&buf[..n]));
+        if n < buf.len() {
+            break;
+        }
+    }
+    Ok(FileBlockSignatures {
+        block_size,
+        weak,
+        strong: strongs.into(),
+        ..Default::default()
+    })
+}
+
+/// A part of the new file, `literal` data followed by blocks of the receiver's copy.
+#[derive(Debug, Default, PartialEq)]
+pub struct Chunk {
+    pub literal: Vec<u8>,
+    pub copies: Vec<u32>,
+}
+
+/// Finds the blocks of the receiver's copy in the file fed in.
+pub struct Matcher {
+    block_size: usize,
+    // Block indexes by weak checksum.
+    blocks: HashMap<u32, Vec<u32>>,
+    strong: Vec<u8>,
+    // Input not sent yet, from `pos`.
+    buf: Vec<u8>,
+    pos: usize,
+    // Of the block at `pos`.
+    rolling: Option<Rolling>,
+    eof: bool,
+    literal: Vec<u8>,
+    copies: Vec<u32>,
+}
+
+impl std::fmt::Debug for Matcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Matcher")
+            .field("block_size", &self.block_size)
+            .field("blocks", &self.blocks.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Matcher {
+    pub fn new(signatures: &FileBlockSignatures) -> ResultType<Self> {
+        if signatures.block_size == 0
+            || signatures.block_size > MAX_BLOCK_SIZE
+            || signatures.strong.len() != signatures.weak.len() * STRONG_LEN
+        {
+            bail!("Invalid block signatures");
+        }
+        let mut blocks: HashMap<u32, Vec<u32>> = HashMap::new();
+        // The last block may be shorter, its strong hash never matches a whole window.
+        for (i, weak) in signatures.weak.iter().enumerate() {
+            blocks.entry(*weak).or_default().push(i as u32);
+        }
+        Ok(Self {
+            block_size: signatures.block_size as usize,
+            blocks,
+            strong: signatures.strong.to_vec(),
+            buf: Vec::new(),
+            pos: 0,
+            rolling: None,
+            eof: false,
+            literal: Vec::new(),
+            copies: Vec::new(),
+        })
+    }
+
+    #[inline]
+    pub fn block_size(&self) -> u64 {
+        self.block_size as u64
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        self.buf.extend_from_slice(data);
+    }
+
+    /// No more input, the rest is sent as data.
+    pub fn finish(&mut self) {
+        self.eof = true;
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.eof && self.pos == self.buf.len() && self.literal.is_empty() && self.copies.is_empty()
+    }
+
+    fn find(&self, weak: u32, window: &[u8]) -> Option<u32> {
+        let indexes = self.blocks.get(&weak)?;
+        let hash = strong(window);
+        indexes.iter().copied().find(|&i| {
+            let start = i as usize * STRONG_LEN;
+            self.strong[start..start + STRONG_LEN] == hash
+        })
+    }
+
+    fn take_chunk(&mut self) -> Option<Chunk> {
+        if self.literal.is_empty() && self.copies.is_empty() {
+            return None;
+        }
+        Some(Chunk {
+            literal: std::mem::take(&mut self.literal),
+            copies: std::mem::take(&mut self.copies),
+        })
+    }
+
+    /// The next chunk, `None` if more input is needed, or when done.
+    pub fn next_chunk(&mut self) -> Option<Chunk> {
+        let bs = self.block_size;
+        loop {
+            if self.buf.len() - self.pos < bs {
+                if !self.eof {
+                    return None;
+                }
+                // Data can not follow the copies of the same chunk.
+                if self.copies.is_empty() {
+                    self.literal.extend_from_slice(&self.buf[self.pos..]);
+                    self.pos = self.buf.len();
+                }
+                return self.take_chunk();
+            }
+            let window = &self.buf[self.pos..self.pos + bs];
+            let rolling = *self.rolling.get_or_insert_with(|| Rolling::new(window));
+            if let Some(index) = self.find(rolling.digest(), window) {
+                self.copies.push(index);
+                self.pos += bs;
+                self.rolling = None;
+                if self.copies.len() >= MAX_COPIES {
+                    return self.take_chunk();
+                }
+                continue;
+            }
+            if !self.copies.is_empty() {
+                return self.take_chunk();
+            }
+            if self.pos + bs == self.buf.len() {
+                if !self.eof {
+                    return None;
+                }
+                self.literal.extend_from_slice(&self.buf[self.pos..]);
+                self.pos = self.buf.len();
+                self.rolling = None;
+                continue;
+            }
+            let (out, r#in) = (self.buf[self.pos], self.buf[self.pos + bs]);
+            let mut rolling = rolling;
+            rolling.roll(out, r#in);
+            self.rolling = Some(rolling);
+            self.literal.push(out);
+            self.pos += 1;
+            if self.literal.len() >= MAX_LITERAL {
+                return self.take_chunk();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    fn delta(basis: &[u8], target: &[u8], block_size: u32, feed_size: usize) -> Vec<Chunk> {
+        let sigs = signatures_with_block_size(basis, block_size).unwrap();
+        let mut matcher = Matcher::new(&sigs).unwrap();
+        let mut chunks = vec![];
+        let mut input = target.chunks(feed_size);
+        loop {
+            if let Some(chunk) = matcher.next_chunk() {
+                chunks.push(chunk);
+                continue;
+            }
+            if matcher.is_done() {
+                break;
+            }
+            match input.next() {
+                Some(data) => matcher.feed(data),
+                None => matcher.finish(),
+            }
+        }
+        chunks
+    }
+
+    fn apply(basis: &[u8], chunks: &[Chunk], block_size: usize) -> Vec<u8> {
+        let mut res = vec![];
+        for chunk in chunks {
+            res.extend_from_slice(&chunk.literal);
+            for &i in &chunk.copies {
+                let start = i as usize * block_size;
+                res.extend_from_slice(&basis[start..(start + block_size).min(basis.len())]);
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn test_rolling() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let mut rolling = Rolling::new(&data[..100]);
+        for i in 0..900 {
+            rolling.roll(data[i], data[i + 100]);
+            assert_eq!(
+                rolling.digest(),
+                Rolling::new(&data[i + 1..i + 101]).digest()
+            );
+        }
+    }
+
+    #[test]
+    fn test_delta() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let basis: Vec<u8> = (0..300_000).map(|_| rng.gen()).collect();
+        let mut target = basis[..50_000].to_vec();
+        target.extend_from_slice(b"inserted");
+        target.extend_from_slice(&basis[50_000..120_000]);
+        target.extend_from_slice(&basis[130_000..200_000]);
+        target.extend((0..5000).map(|_| rng.gen::<u8>()));
+        target.extend_from_slice(&basis[200_000..]);
+        target.extend_from_slice(b"tail");
+        for feed_size in [1000, 7000, 1 << 20] {
+            let chunks = delta(&basis, &target, 4096, feed_size);
+            assert_eq!(apply(&basis, &chunks, 4096), target);
+            let literal: usize = chunks.iter().map(|c| c.literal.len()).sum();
+            assert!(literal < 30_000, "{}", literal);
+        }
+        // Nothing in common, or no input.
+        let other: Vec<u8> = (0..10_000).map(|_| rng.gen()).collect();
+        let chunks = delta(&basis, &other, 4096, 3000);
+        assert!(chunks.iter().all(|c| c.copies.is_empty()));
+        assert_eq!(apply(&basis, &chunks, 4096), other);
+        assert!(delta(&basis, &[], 4096, 3000).is_empty());
+        // The short last block of the basis.
+        let chunks = delta(&basis, &basis, 1 << 16, 1 << 16);
+        assert_eq!(apply(&basis, &chunks, 1 << 16), basis);
+    }
+
+    #[test]
+    fn test_signatures() {
+        assert_eq!(block_size(0), 4096);
+        assert_eq!(block_size(100 << 20), 10240);
+        assert_eq!(block_size(1 << 40), 16 << 20);
+        let sigs = signatures_with_block_size(&[1u8; 10_000][..], 4096).unwrap();
+        assert_eq!(sigs.weak.len(), 3);
+        assert_eq!(sigs.strong.len(), 3 * STRONG_LEN);
+        assert!(Matcher::new(&sigs).is_ok());
+        let mut invalid = sigs.clone();
+        invalid.weak.pop();
+        assert!(Matcher::new(&invalid).is_err());
+        invalid = sigs;
+        invalid.block_size = 0;
+        assert!(Matcher::new(&invalid).is_err());
+    }
+}
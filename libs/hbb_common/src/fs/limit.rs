@@ -0,0 +1,182 @@
+//! Bandwidth limits and time windows of file transfers.
+//!
+//! The send paths do not wait for the limits, they skip a job until it may send again. A block
+//! is sent whole even if it is larger than what is left, the bucket is then in debt.
+use chrono::Timelike;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// At most a second of sending at the full rate after being idle.
+const BURST: Duration = Duration::from_secs(1);
+
+lazy_static::lazy_static! {
+    static ref GLOBAL: Mutex<TokenBucket> = Default::default();
+}
+
+/// A token bucket of `rate` bytes per second, unlimited if 0.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: 0.,
+            last: Instant::now(),
+        }
+    }
+
+    #[inline]
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: u64) {
+        if rate != self.rate {
+            *self = Self::new(rate);
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let burst = self.rate as f64 * BURST.as_secs_f64();
+        self.tokens = (self.tokens
+            + now.duration_since(self.last).as_secs_f64() * self.rate as f64)
+            .min(burst);
+        self.last = now;
+    }
+
+    /// Whether anything may be sent now.
+    pub fn ready(&mut self) -> bool {
+        self.delay().is_zero()
+    }
+
+    /// Until anything may be sent.
+    pub fn delay(&mut self) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        self.refill();
+        if self.tokens >= 0. {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+
+    pub fn consume(&mut self, n: u64) {
+        if self.rate > 0 {
+            self.refill();
+            self.tokens -= n as f64;
+        }
+    }
+}
+
+/// Of all the jobs of the process together, in bytes per second, 0 for none.
+pub fn set_global_rate_limit(rate: u64) {
+    GLOBAL.lock().unwrap().set_rate(rate);
+}
+
+#[inline]
+pub fn global_ready() -> bool {
+    GLOBAL.lock().unwrap().ready()
+}
+
+#[inline]
+pub fn global_consume(n: u64) {
+    GLOBAL.lock().unwrap().consume(n);
+}
+
+/// The rate limit option, in KB/s, as bytes per second.
+pub fn parse_rate_limit(option: &str) -> u64 {
+    option
+        .trim()
+        .parse::<u64>()
+        .unwrap_or(0)
+        .saturating_mul(1024)
+}
+
+/// A daily time window, in local time, "22:00-06:00" wraps midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    // Minutes since midnight.
+    start: u32,
+    end: u32,
+}
+
+impl TimeWindow {
+    /// `None` if empty, invalid or starting where it ends, meaning no window.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (start, end) = s.trim().split_once('-')?;
+        let minutes = |t: &str| -> Option<u32> {
+            let (h, m) = t.trim().split_once(':')?;
+            let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+            (h < 24 && m < 60).then_some(h * 60 + m)
+        };
+        let (start, end) = (minutes(start)?, minutes(end)?);
+        (start != end).then_some(Self { start, end })
+    }
+
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    pub fn contains_now(&self) -> bool {
+        let now = chrono::Local::now();
+        self.contains(now.hour() * 60 + now.minute())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(0);
+        bucket.consume(1 << 30);
+        assert!(bucket.ready());
+        let mut bucket = TokenBucket::new(1000);
+        assert!(bucket.ready());
+        bucket.consume(500);
+        let delay = bucket.delay();
+        assert!(delay > Duration::from_millis(400) && delay <= Duration::from_millis(500));
+        std::thread::sleep(delay);
+        assert!(bucket.ready());
+    }
+
+    #[test]
+    fn test_time_window() {
+        let w = TimeWindow::parse("22:00-06:30").unwrap();
+        assert!(w.contains(23 * 60) && w.contains(0) && w.contains(6 * 60 + 29));
+        assert!(!w.contains(6 * 60 + 30) && !w.contains(12 * 60));
+        let w = TimeWindow::parse(" 09:00 - 17:00 ").unwrap();
+        assert!(w.contains(9 * 60) && !w.contains(17 * 60) && !w.contains(8 * 60));
+        assert_eq!(TimeWindow::parse(""), None);
+        assert_eq!(TimeWindow::parse("25:00-01:00"), None);
+        assert_eq!(TimeWindow::parse("09:00-09:00"), None);
+    }
+
+    #[test]
+    fn test_parse_rate_limit() {
+        assert_eq!(parse_rate_limit(" 100 "), 102400);
+        assert_eq!(parse_rate_limit("abc"), 0);
+        assert_eq!(parse_rate_limit(&u64::MAX.to_string()), u64::MAX);
+    }
+}
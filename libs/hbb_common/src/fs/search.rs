@@ -0,0 +1,267 @@
+//! Search of the files under a directory, by name, size, modification time and content.
+use super::{get_file_name, get_path, visit_dir_recursive};
+use crate::{
+    config::Config,
+    message_proto::{FileEntry, FileSearch},
+    ResultType,
+};
+use regex::{bytes, Regex, RegexBuilder};
+use std::{
+    io::Read,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+// If the request does not say.
+const DEFAULT_MAX_RESULTS: usize = 1000;
+const DEFAULT_MAX_CONTENT_SIZE: u64 = 1 << 20;
+// Files are read whole to be searched for content.
+const MAX_CONTENT_SIZE: u64 = 64 << 20;
+// The files found are passed on at least this often, and at most this many at a time.
+const BATCH_INTERVAL: Duration = Duration::from_millis(500);
+const BATCH_SIZE: usize = 100;
+
+/// The filters of a [`FileSearch`], all of which a file matches.
+pub struct Filter {
+    names: Vec<Regex>,
+    min_size: u64,
+    max_size: u64,
+    modified_after: u64,
+    modified_before: u64,
+    content: Option<bytes::Regex>,
+    max_content_size: u64,
+}
+
+impl Filter {
+    pub fn new(search: &FileSearch) -> ResultType<Self> {
+        let case_insensitive = !search.case_sensitive;
+        let mut names = Vec::new();
+        for pattern in [glob_to_regex(&search.glob), search.regex.clone()] {
+            if !pattern.is_empty() {
+                names.push(
+                    RegexBuilder::new(&pattern)
+                        .case_insensitive(case_insensitive)
+                        .build()?,
+                );
+            }
+        }
+        let content = if search.content.is_empty() {
+            None
+        } else {
+            Some(
+                bytes::RegexBuilder::new(&regex::escape(&search.content))
+                    .case_insensitive(case_insensitive)
+                    .build()?,
+            )
+        };
+        Ok(Self {
+            names,
+            min_size: search.min_size,
+            max_size: search.max_size,
+            modified_after: search.modified_after,
+            modified_before: search.modified_before,
+            content,
+            max_content_size: match search.max_content_size {
+                0 => DEFAULT_MAX_CONTENT_SIZE,
+                n => n.min(MAX_CONTENT_SIZE),
+            },
+        })
+    }
+
+    /// Whether `entry`, listed under `root`, matches.
+    pub fn matches(&self, root: &Path, entry: &FileEntry) -> bool {
+        let name = match Path::new(&entry.name).file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            // `root` is the file.
+            None => get_file_name(root),
+        };
+        if !self.names.iter().all(|re| re.is_match(&name))
+            || entry.size < self.min_size
+            || (self.max_size > 0 && entry.size > self.max_size)
+            || entry.modified_time < self.modified_after
+            || (self.modified_before > 0 && entry.modified_time > self.modified_before)
+        {
+            return false;
+        }
+        match &self.content {
+            Some(content) if entry.size <= self.max_content_size => {
+                let path = if entry.name.is_empty() {
+                    root.to_path_buf()
+                } else {
+                    root.join(&entry.name)
+                };
+                // The file may have grown since it was listed.
+                let mut data = Vec::new();
+                std::fs::File::open(path)
+                    .and_then(|f| f.take(self.max_content_size).read_to_end(&mut data))
+                    .is_ok()
+                    && content.is_match(&data)
+            }
+            Some(_) => false,
+            None => true,
+        }
+    }
+}
+
+// "*" and "?" as in a shell, "[...]" and "[!...]" as classes, on the whole name.
+fn glob_to_regex(glob: &str) -> String {
+    if glob.is_empty() {
+        return String::new();
+    }
+    let mut re = String::from("^");
+    let mut in_class = false;
+    for c in glob.chars() {
+        match c {
+            '*' if !in_class => re.push_str(".*"),
+            '?' if !in_class => re.push('.'),
+            '[' if !in_class => {
+                in_class = true;
+                re.push('[');
+            }
+            ']' if in_class => {
+                in_class = false;
+                re.push(']');
+            }
+            '!' if in_class && re.ends_with('[') => re.push('^'),
+            '\\' | '[' | '&' | '~' if in_class => {
+                re.push('\\');
+                re.push(c);
+            }
+            c if in_class => re.push(c),
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/// Searches for the files matching `search`, passing them to `f` in batches as they are found,
+/// the last batch with `done`. Stops without `done` once `cancel` is set.
+pub fn search(
+    search: &FileSearch,
+    cancel: &AtomicBool,
+    mut f: impl FnMut(Vec<FileEntry>, bool),
+) -> ResultType<()> {
+    let filter = Filter::new(search)?;
+    let max_results = match search.max_results {
+        0 => DEFAULT_MAX_RESULTS,
+        n => n as usize,
+    };
+    let root = if search.path.is_empty() {
+        Config::get_home()
+    } else {
+        get_path(&search.path)
+    };
+    let mut batch = Vec::new();
+    let mut found = 0;
+    let mut last = Instant::now();
+    visit_dir_recursive(
+        &root,
+        &get_path(""),
+        search.include_hidden,
+        false,
+        &mut |entry| {
+            if cancel.load(Ordering::SeqCst) {
+                return false;
+            }
+            if filter.matches(&root, &entry) {
+                batch.push(entry);
+                found += 1;
+            }
+            if batch.len() >= BATCH_SIZE || (!batch.is_empty() && last.elapsed() >= BATCH_INTERVAL)
+            {
+                f(std::mem::take(&mut batch), false);
+                last = Instant::now();
+            }
+            found < max_results
+        },
+    )?;
+    if !cancel.load(Ordering::SeqCst) {
+        f(batch, true);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{get_string, tests::TempDir};
+
+    #[test]
+    fn test_glob() {
+        let matches =
+            |glob: &str, name: &str| Regex::new(&glob_to_regex(glob)).unwrap().is_match(name);
+        assert!(matches("*.log", "a.log") && !matches("*.log", "a.log.1"));
+        assert!(matches("a?c.txt", "abc.txt") && !matches("a?c.txt", "ac.txt"));
+        assert!(matches("[a-c]x", "bx") && !matches("[!a-c]x", "bx") && matches("[!a-c]x", "dx"));
+        assert!(matches("a+b(1).txt", "a+b(1).txt") && !matches("a.b", "axb"));
+    }
+
+    #[test]
+    fn test_search() {
+        let dir = TempDir::new("search");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.log"), "error: disk full").unwrap();
+        std::fs::write(dir.join("b.LOG"), "all good").unwrap();
+        std::fs::write(dir.join("sub").join("c.log"), "ERROR: again").unwrap();
+        std::fs::write(dir.join("sub").join("d.txt"), "error").unwrap();
+        let run = |search: FileSearch| {
+            let mut names = Vec::new();
+            let mut done = false;
+            super::search(&search, &AtomicBool::new(false), |entries, d| {
+                names.extend(entries.into_iter().map(|e| e.name));
+                done = d;
+            })
+            .unwrap();
+            assert!(done);
+            names.sort();
+            names
+        };
+        let search = FileSearch {
+            path: get_string(&dir),
+            glob: "*.log".into(),
+            ..Default::default()
+        };
+        let c = get_string(&Path::new("sub").join("c.log"));
+        assert_eq!(
+            run(search.clone()),
+            vec!["a.log".to_owned(), "b.LOG".into(), c.clone()]
+        );
+        assert_eq!(
+            run(FileSearch {
+                content: "error".into(),
+                ..search.clone()
+            }),
+            vec!["a.log".to_owned(), c]
+        );
+        assert_eq!(
+            run(FileSearch {
+                content: "error".into(),
+                case_sensitive: true,
+                ..search.clone()
+            }),
+            vec!["a.log".to_owned()]
+        );
+        assert_eq!(
+            run(FileSearch {
+                regex: "^[ab]\\.".into(),
+                min_size: 10,
+                ..search.clone()
+            }),
+            vec!["a.log".to_owned()]
+        );
+        assert!(super::search(
+            &FileSearch {
+                regex: "(".into(),
+                ..search.clone()
+            },
+            &AtomicBool::new(false),
+            |_, _| {}
+        )
+        .is_err());
+        let mut called = false;
+        super::search(&search, &AtomicBool::new(true), |_, _| called = true).unwrap();
+        assert!(!called);
+    }
+}
@@ -19,11 +19,13 @@ pub use tokio;
 pub use tokio_util;
 pub mod proxy;
 pub mod socket_client;
+pub mod nat64;
 pub mod tcp;
 pub mod udp;
 pub use env_logger;
 pub use log;
 pub mod bytes_codec;
+pub mod channel;
 pub use anyhow::{self, bail};
 pub use futures_util;
 pub mod config;
@@ -51,20 +53,25 @@ pub use machine_uid;
 pub use serde_derive;
 pub use serde_json;
 pub use sha2;
+pub use socket2;
 pub use sysinfo;
 pub use thiserror;
 pub use toml;
 pub use uuid;
 pub mod fingerprint;
 pub use flexi_logger;
+pub mod resume;
 pub mod stream;
 pub mod websocket;
 #[cfg(feature = "webrtc")]
 pub mod webrtc;
+#[cfg(feature = "quic")]
+pub mod quic;
 #[cfg(any(target_os = "android", target_os = "ios"))]
 pub use rustls_platform_verifier;
 pub use stream::Stream;
 pub use whoami;
+pub mod client_cert;
 pub mod tls;
 pub mod verifier;
 pub use async_recursion;
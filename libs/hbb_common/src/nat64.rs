@@ -0,0 +1,278 @@
+//! NAT64 for IPv6-only hosts.
+//!
+//! Without an IPv4 route, IPv4 servers are reached through a NAT64 gateway at an address made
+//! of the gateway's prefix and the IPv4 address (RFC 6052). Host names get such addresses from
+//! DNS64, IPv4 literals, e.g. a relay server configured by IP, are synthesized here with the
+//! prefix discovered as described in RFC 7050.
+use crate::{config::Config, ResultType};
+use anyhow::Context;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::Mutex,
+    time::Instant,
+};
+
+// Only has the A records below, DNS64 adds the AAAA records.
+const IPV4ONLY_ARPA: &str = "ipv4only.arpa:0";
+const IPV4ONLY_ADDRS: [Ipv4Addr; 2] =
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+// The prefix lengths allowed by RFC 6052, the most used first.
+const PREFIX_LENS: [u8; 6] = [96, 64, 56, 48, 40, 32];
+const REFRESH_INTERVAL_SECS: u64 = 60;
+
+lazy_static::lazy_static! {
+    static ref PREFIX: Mutex<(Option<Prefix>, Option<Instant>)> = Default::default();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prefix {
+    addr: Ipv6Addr,
+    len: u8,
+}
+
+impl Prefix {
+    pub fn new(addr: Ipv6Addr, len: u8) -> Option<Self> {
+        if !PREFIX_LENS.contains(&len) {
+            return None;
+        }
+        let mut octets = addr.octets();
+        octets[len as usize / 8..].fill(0);
+        Some(Self {
+            addr: Ipv6Addr::from(octets),
+            len,
+        })
+    }
+
+    pub fn well_known() -> Self {
+        Self {
+            addr: Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0),
+            len: 96,
+        }
+    }
+
+    pub fn synthesize(&self, ipv4: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.addr.octets();
+        for (i, b) in ipv4_positions(self.len).zip(ipv4.octets()) {
+            octets[i] = b;
+        }
+        Ipv6Addr::from(octets)
+    }
+
+    pub fn extract(&self, ipv6: Ipv6Addr) -> Option<Ipv4Addr> {
+        let n = self.len as usize / 8;
+        let octets = ipv6.octets();
+        // Bits 64 to 71 are reserved and must be zero.
+        if octets[..n] != self.addr.octets()[..n] || (n <= 8 && octets[8] != 0) {
+            return None;
+        }
+        let mut ipv4 = [0u8; 4];
+        for (b, i) in ipv4.iter_mut().zip(ipv4_positions(self.len)) {
+            *b = octets[i];
+        }
+        Some(Ipv4Addr::from(ipv4))
+    }
+}
+
+impl std::fmt::Display for Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+// The 4 bytes after the prefix, skipping the reserved byte 8.
+fn ipv4_positions(len: u8) -> impl Iterator<Item = usize> {
+    (len as usize / 8..16).filter(|&i| i != 8).take(4)
+}
+
+/// Finds the prefix from the AAAA records of ipv4only.arpa.
+fn find_prefix(addrs: impl IntoIterator<Item = Ipv6Addr>) -> Option<Prefix> {
+    for addr in addrs {
+        for len in PREFIX_LENS {
+            if let Some(prefix) = Prefix::new(addr, len) {
+                if prefix
+                    .extract(addr)
+                    .is_some_and(|ipv4| IPV4ONLY_ADDRS.contains(&ipv4))
+                {
+                    return Some(prefix);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// The address to connect to. Without an IPv4 route, IPv6 addresses are preferred and
+/// an IPv4 one is translated with `prefix`.
+pub fn select_addr(
+    addrs: &[SocketAddr],
+    ipv4_route: bool,
+    prefix: Option<Prefix>,
+) -> Option<SocketAddr> {
+    if ipv4_route {
+        return addrs.first().copied();
+    }
+    if let Some(addr) = addrs.iter().find(|addr| addr.is_ipv6()) {
+        return Some(*addr);
+    }
+    let addr = addrs.first()?;
+    match (addr, prefix) {
+        (SocketAddr::V4(v4), Some(prefix)) => Some(SocketAddr::new(
+            prefix.synthesize(*v4.ip()).into(),
+            v4.port(),
+        )),
+        _ => Some(*addr),
+    }
+}
+
+fn has_route(target: SocketAddr) -> bool {
+    // No packet is sent, connect only looks up the route.
+    UdpSocket::bind(Config::get_any_listen_addr(target.is_ipv4()))
+        .and_then(|socket| socket.connect(target))
+        .is_ok()
+}
+
+#[inline]
+pub fn has_ipv4_route() -> bool {
+    has_route(SocketAddr::from(([8, 8, 8, 8], 53)))
+}
+
+/// No IPv4 route but an IPv6 one.
+pub fn is_ipv6_only() -> bool {
+    !has_ipv4_route()
+        && has_route(SocketAddr::from((
+            Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888),
+            53,
+        )))
+}
+
+/// The NAT64 prefix of the network, looked up again after a minute.
+pub async fn get_prefix() -> Option<Prefix> {
+    {
+        let lock = PREFIX.lock().unwrap();
+        if lock
+            .1
+            .is_some_and(|x| x.elapsed().as_secs() < REFRESH_INTERVAL_SECS)
+        {
+            return lock.0;
+        }
+    }
+    let prefix = match tokio::net::lookup_host(IPV4ONLY_ARPA).await {
+        Ok(addrs) => find_prefix(addrs.filter_map(|addr| match addr.ip() {
+            IpAddr::V6(ip) => Some(ip),
+            IpAddr::V4(_) => None,
+        })),
+        Err(err) => {
+            log::debug!("Failed to look up {}: {}", IPV4ONLY_ARPA, err);
+            None
+        }
+    };
+    if let Some(prefix) = prefix {
+        log::info!("NAT64 prefix: {}", prefix);
+    }
+    *PREFIX.lock().unwrap() = (prefix, Some(Instant::now()));
+    prefix
+}
+
+/// The prefix found by the last [`get_prefix`], for callers which can not wait.
+#[inline]
+pub fn get_cached_prefix() -> Option<Prefix> {
+    PREFIX.lock().unwrap().0
+}
+
+/// The IPv6 address of an IPv4 one, if the network has a NAT64 prefix.
+pub async fn synthesize_addr(addr: SocketAddr) -> Option<SocketAddr> {
+    match addr {
+        SocketAddr::V4(v4) => {
+            let prefix = get_prefix().await?;
+            Some(SocketAddr::new(
+                prefix.synthesize(*v4.ip()).into(),
+                v4.port(),
+            ))
+        }
+        SocketAddr::V6(_) => Some(addr),
+    }
+}
+
+/// Resolves `target` to the address to connect to, see [`select_addr`].
+pub async fn resolve(target: &str) -> ResultType<SocketAddr> {
+    let addrs: Vec<_> = tokio::net::lookup_host(target).await?.collect();
+    let ipv4_route = has_ipv4_route();
+    let prefix = if ipv4_route || addrs.iter().any(|x| x.is_ipv6()) {
+        None
+    } else {
+        get_prefix().await
+    };
+    select_addr(&addrs, ipv4_route, prefix)
+        .with_context(|| format!("Failed to look up host for {target}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_synthesize() {
+        // RFC 6052 2.4
+        let ipv4 = Ipv4Addr::new(192, 0, 2, 33);
+        for (prefix, len, ipv6) in [
+            ("2001:db8::", 32, "2001:db8:c000:221::"),
+            ("2001:db8:100::", 40, "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::", 48, "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::", 56, "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::", 64, "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::", 96, "2001:db8:122:344::192.0.2.33"),
+            ("64:ff9b::", 96, "64:ff9b::192.0.2.33"),
+        ] {
+            let prefix = Prefix::new(prefix.parse().unwrap(), len).unwrap();
+            let ipv6: Ipv6Addr = ipv6.parse().unwrap();
+            assert_eq!(prefix.synthesize(ipv4), ipv6);
+            assert_eq!(prefix.extract(ipv6), Some(ipv4));
+        }
+        assert_eq!(
+            Prefix::new("64:ff9b::1".parse().unwrap(), 96),
+            Some(Prefix::well_known())
+        );
+        assert!(Prefix::new(Ipv6Addr::UNSPECIFIED, 80).is_none());
+        assert!(Prefix::well_known()
+            .extract("2001:db8::c000:221".parse().unwrap())
+            .is_none());
+        let prefix = Prefix::new("2001:db8::".parse().unwrap(), 32).unwrap();
+        assert!(prefix
+            .extract("2001:db8:c000:221:100::".parse().unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn test_find_prefix() {
+        let addrs = |s: &[&str]| s.iter().map(|x| x.parse().unwrap()).collect::<Vec<_>>();
+        assert_eq!(
+            find_prefix(addrs(&["64:ff9b::c000:aa", "64:ff9b::c000:ab"])),
+            Some(Prefix::well_known())
+        );
+        assert_eq!(
+            find_prefix(addrs(&["2001:db8:122:344:c0:0:aa00:0"])),
+            Prefix::new("2001:db8:122:344::".parse().unwrap(), 64)
+        );
+        assert_eq!(
+            find_prefix(addrs(&["2001:db8:c000:ab::"])),
+            Prefix::new("2001:db8::".parse().unwrap(), 32)
+        );
+        assert_eq!(find_prefix(addrs(&["2001:db8::1"])), None);
+        assert_eq!(find_prefix(addrs(&[])), None);
+    }
+
+    #[test]
+    fn test_select_addr() {
+        let v4: SocketAddr = "192.0.2.33:21116".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:21116".parse().unwrap();
+        let prefix = Some(Prefix::well_known());
+        assert_eq!(select_addr(&[v4, v6], true, prefix), Some(v4));
+        assert_eq!(select_addr(&[v4, v6], false, prefix), Some(v6));
+        assert_eq!(
+            select_addr(&[v4], false, prefix),
+            Some("[64:ff9b::c000:221]:21116".parse().unwrap())
+        );
+        assert_eq!(select_addr(&[v4], false, None), Some(v4));
+        assert_eq!(select_addr(&[], false, prefix), None);
+    }
+}
@@ -0,0 +1,459 @@
+//! Proxy auto-detection, for when no proxy is set manually.
+//!
+//! `OPTION_PROXY_PAC_URL` points at a PAC file. With `OPTION_ALLOW_PROXY_AUTO_DETECT` we
+//! also look for one at `http://wpad.<domain>/wpad.dat` (DNS WPAD, not DHCP). Without a PAC
+//! file, or before it is fetched and evaluated, `HTTPS_PROXY`, `HTTP_PROXY`, `ALL_PROXY` and
+//! `NO_PROXY` are used like curl does.
+//!
+//! Rendezvous and relay connections are looked up as `https://host:port/`, the way a
+//! browser tunnels TLS.
+use super::pac;
+use crate::{
+    config::{self, keys, Config, Socks5Server},
+    ResultType,
+};
+use anyhow::{anyhow, bail, Context};
+use httparse::{Response, EMPTY_HEADER};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use url::{Position, Url};
+
+// How long a PAC file and the decisions are kept.
+const CACHE_TTL: Duration = Duration::from_secs(300);
+const FETCH_TIMEOUT: u64 = 3_000;
+const MAX_PAC_SIZE: u64 = 1024 * 1024;
+
+lazy_static::lazy_static! {
+    // Keyed by the settings and the origin, `None` for DIRECT.
+    static ref DECISIONS: Mutex<HashMap<String, (Instant, Option<String>)>> = Default::default();
+    // The PAC url, empty for WPAD, when it was fetched and the script.
+    static ref SCRIPT: Mutex<Option<(String, Instant, Option<Arc<String>>)>> = Default::default();
+    // Keys of `DECISIONS` being looked up in the background, see `prefetch`.
+    static ref PENDING: Mutex<HashSet<String>> = Default::default();
+}
+
+#[inline]
+pub fn is_auto_detect_enabled() -> bool {
+    let option = keys::OPTION_ALLOW_PROXY_AUTO_DETECT;
+    config::option2bool(option, &Config::get_option(option))
+}
+
+/// Whether a PAC file or auto-detection decides the proxy when none is set manually.
+#[inline]
+pub fn is_enabled() -> bool {
+    !Config::get_option(keys::OPTION_PROXY_PAC_URL).is_empty() || is_auto_detect_enabled()
+}
+
+/// The proxy for `url`, the manual setting wins over the detected one.
+pub async fn get_socks_for(url: &str) -> Option<Socks5Server> {
+    if let Some(conf) = Config::get_socks() {
+        return Some(conf);
+    }
+    let pac_url = Config::get_option(keys::OPTION_PROXY_PAC_URL);
+    let auto = is_auto_detect_enabled();
+    if pac_url.is_empty() && !auto {
+        return None;
+    }
+    let key = cache_key(&pac_url, auto, url)?;
+    if let Some(proxy) = get_cached(&key) {
+        return to_conf(proxy);
+    }
+    to_conf(lookup(&pac_url, auto, url, key).await)
+}
+
+async fn lookup(pac_url: &str, auto: bool, url: &str, key: String) -> Option<String> {
+    let script = get_script(pac_url, auto).await;
+    let url = url.to_owned();
+    // PAC scripts run long and may resolve names, keep them off the runtime.
+    let proxy =
+        tokio::task::spawn_blocking(move || decide(&url, script.as_deref().map(|x| x.as_str())))
+            .await
+            .ok()?;
+    DECISIONS
+        .lock()
+        .unwrap()
+        .insert(key, (Instant::now(), proxy.clone()));
+    proxy
+}
+
+/// See [`get_socks_for`], for a rendezvous or relay server, e.g. "rs.example.com:21116".
+pub async fn get_socks_for_target(target: &str) -> Option<Socks5Server> {
+    get_socks_for(&format!("https://{}/", target)).await
+}
+
+/// The detected proxy url for `url`, without waiting for the PAC file.
+///
+/// For callbacks which can't be async, e.g. `reqwest::Proxy::custom`. The PAC file is never
+/// evaluated here, a miss uses the environment and looks `url` up in the background for the
+/// next calls.
+pub fn get_detected_proxy_cached(url: &str) -> Option<String> {
+    let pac_url = Config::get_option(keys::OPTION_PROXY_PAC_URL);
+    let auto = is_auto_detect_enabled();
+    if pac_url.is_empty() && !auto {
+        return None;
+    }
+    let key = cache_key(&pac_url, auto, url)?;
+    if let Some(proxy) = get_cached(&key) {
+        return proxy;
+    }
+    if cfg!(feature = "pac") {
+        prefetch(pac_url, auto, url.to_owned(), key);
+    }
+    decide(url, None)
+}
+
+fn cache_key(pac_url: &str, auto: bool, url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    Some(format!(
+        "{}|{}|{}",
+        auto,
+        pac_url,
+        &url[Position::BeforeScheme..Position::AfterPort]
+    ))
+}
+
+fn get_cached(key: &str) -> Option<Option<String>> {
+    let mut decisions = DECISIONS.lock().unwrap();
+    decisions.retain(|_, (at, _)| at.elapsed() < CACHE_TTL);
+    decisions.get(key).map(|(_, proxy)| proxy.clone())
+}
+
+fn to_conf(proxy: Option<String>) -> Option<Socks5Server> {
+    Some(Socks5Server {
+        proxy: proxy?,
+        ..Default::default()
+    })
+}
+
+// The PAC result if there is one, otherwise the environment decides.
+fn decide(url: &str, script: Option<&str>) -> Option<String> {
+    let parsed = Url::parse(url).ok()?;
+    let host = parsed
+        .host_str()?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    if let Some(script) = script {
+        match pac::find_proxy_for_url(script, url, host) {
+            Ok(res) => {
+                log::debug!("PAC result for {}: {}", host, res);
+                return pac::parse_result(&res);
+            }
+            Err(err) => log::warn!("Failed to evaluate the PAC file: {}", err),
+        }
+    }
+    from_env(&parsed, host)
+}
+
+async fn get_script(pac_url: &str, auto: bool) -> Option<Arc<String>> {
+    if !cfg!(feature = "pac") {
+        return None;
+    }
+    if let Some((k, at, script)) = SCRIPT.lock().unwrap().as_ref() {
+        if k == pac_url && at.elapsed() < CACHE_TTL {
+            return script.clone();
+        }
+    }
+    let script = if !pac_url.is_empty() {
+        match fetch(pac_url).await {
+            Ok(script) => Some(Arc::new(script)),
+            Err(err) => {
+                log::warn!("Failed to fetch the PAC file {}: {}", pac_url, err);
+                None
+            }
+        }
+    } else if auto {
+        discover_wpad().await
+    } else {
+        None
+    };
+    *SCRIPT.lock().unwrap() = Some((pac_url.to_owned(), Instant::now(), script.clone()));
+    DECISIONS.lock().unwrap().clear();
+    script
+}
+
+// For the next calls of `get_detected_proxy_cached`.
+fn prefetch(pac_url: String, auto: bool, url: String, key: String) {
+    if !PENDING.lock().unwrap().insert(key.clone()) {
+        return;
+    }
+    std::thread::spawn(move || {
+        prefetch_(&pac_url, auto, &url, key.clone());
+        PENDING.lock().unwrap().remove(&key);
+    });
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn prefetch_(pac_url: &str, auto: bool, url: &str, key: String) {
+    lookup(pac_url, auto, url, key).await;
+}
+
+async fn discover_wpad() -> Option<Arc<String>> {
+    for domain in wpad_domains() {
+        let url = format!("http://wpad.{}/wpad.dat", domain);
+        match fetch(&url).await {
+            Ok(script) => {
+                log::info!("Found the PAC file at {}", url);
+                return Some(Arc::new(script));
+            }
+            Err(err) => log::debug!("No PAC file at {}: {}", url, err),
+        }
+    }
+    None
+}
+
+// The domain of this machine and its parents, e.g. "a.corp.com" and "corp.com".
+fn wpad_domains() -> Vec<String> {
+    let mut domains = Vec::new();
+    let mut add = |domain: &str| {
+        let mut domain = domain.trim().trim_end_matches('.').to_lowercase();
+        // Never the top level domain, wpad.com is not ours.
+        while domain.contains('.') {
+            if !domains.contains(&domain) {
+                domains.push(domain.clone());
+            }
+            domain = domain
+                .split_once('.')
+                .map(|x| x.1.to_owned())
+                .unwrap_or_default();
+        }
+    };
+    if let Ok(domain) = std::env::var("USERDNSDOMAIN") {
+        add(&domain);
+    }
+    #[cfg(unix)]
+    if let Ok(conf) = std::fs::read_to_string("/etc/resolv.conf") {
+        for line in conf.lines() {
+            let mut it = line.split_whitespace();
+            if matches!(it.next(), Some("search") | Some("domain")) {
+                it.for_each(&mut add);
+            }
+        }
+    }
+    if let Ok(hostname) = whoami::fallible::hostname() {
+        if let Some((_, domain)) = hostname.split_once('.') {
+            add(domain);
+        }
+    }
+    domains
+}
+
+async fn fetch(url: &str) -> ResultType<String> {
+    let parsed = Url::parse(url)?;
+    let https = match parsed.scheme() {
+        "file" => {
+            let path = parsed
+                .to_file_path()
+                .map_err(|_| anyhow!("Invalid file url"))?;
+            return Ok(tokio::fs::read_to_string(path).await?);
+        }
+        "http" => false,
+        "https" => true,
+        scheme => bail!("Unsupported scheme: {}", scheme),
+    };
+    let host = parsed.host_str().context("No host")?;
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/x-ns-proxy-autoconfig, */*\r\nConnection: close\r\n\r\n",
+        &parsed[Position::BeforePath..Position::AfterQuery],
+        &parsed[Position::BeforeHost..Position::AfterPort]
+    );
+    let stream = crate::timeout(
+        FETCH_TIMEOUT,
+        TcpStream::connect((host.trim_start_matches('[').trim_end_matches(']'), port)),
+    )
+    .await??;
+    let response = if https {
+        use std::convert::TryFrom;
+
+        let domain = rustls_pki_types::ServerName::try_from(host.to_owned())?;
+        let client_config = crate::verifier::client_config(false)?;
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(client_config))
+            .connect(domain, stream)
+            .await?;
+        crate::timeout(FETCH_TIMEOUT, http_get(stream, &request)).await??
+    } else {
+        crate::timeout(FETCH_TIMEOUT, http_get(stream, &request)).await??
+    };
+    parse_response(&response)
+}
+
+async fn http_get<IO>(mut io: IO, request: &str) -> ResultType<Vec<u8>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    io.write_all(request.as_bytes()).await?;
+    io.flush().await?;
+    let mut response = Vec::new();
+    io.take(MAX_PAC_SIZE).read_to_end(&mut response).await?;
+    Ok(response)
+}
+
+fn parse_response(response: &[u8]) -> ResultType<String> {
+    let mut headers = [EMPTY_HEADER; 32];
+    let mut res = Response::new(&mut headers);
+    let httparse::Status::Complete(len) = res.parse(response)? else {
+        bail!("Incomplete HTTP response");
+    };
+    if res.code != Some(200) {
+        bail!("HTTP status {:?}", res.code);
+    }
+    Ok(String::from_utf8_lossy(&response[len..]).into_owned())
+}
+
+fn from_env(url: &Url, host: &str) -> Option<String> {
+    let no_proxy = get_env(&["NO_PROXY", "no_proxy"]);
+    if no_proxy_matches(&no_proxy, host, url.port_or_known_default()) {
+        return None;
+    }
+    let mut proxy = match url.scheme() {
+        "http" | "ws" => get_env(&["HTTP_PROXY", "http_proxy"]),
+        _ => get_env(&["HTTPS_PROXY", "https_proxy"]),
+    };
+    if proxy.is_empty() {
+        proxy = get_env(&["ALL_PROXY", "all_proxy"]);
+    }
+    normalize_env_proxy(&proxy)
+}
+
+fn get_env(names: &[&str]) -> String {
+    names
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|x| !x.trim().is_empty()))
+        .unwrap_or_default()
+}
+
+// "proxy:3128" is an HTTP proxy here, unlike in `OPTION_PROXY_URL`.
+fn normalize_env_proxy(proxy: &str) -> Option<String> {
+    let proxy = proxy.trim();
+    if proxy.is_empty() {
+        return None;
+    }
+    let Some((scheme, rest)) = proxy.split_once("://") else {
+        return Some(format!("http://{}", proxy));
+    };
+    match scheme.to_lowercase().as_str() {
+        scheme @ ("http" | "https" | "socks5") => Some(format!("{}://{}", scheme, rest)),
+        "socks5h" => Some(format!("socks5://{}", rest)),
+        _ => {
+            log::warn!("Unsupported proxy in the environment: {}", proxy);
+            None
+        }
+    }
+}
+
+/// `NO_PROXY` entries are domains (subdomains match too), IP addresses or CIDR blocks,
+/// optionally with a port, or "*".
+fn no_proxy_matches(no_proxy: &str, host: &str, port: Option<u16>) -> bool {
+    let host = host.to_lowercase();
+    let ip = host.parse::<IpAddr>().ok();
+    no_proxy
+        .split(',')
+        .map(|x| x.trim().to_lowercase())
+        .filter(|x| !x.is_empty())
+        .any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+            let (pattern, entry_port) = split_entry_port(&entry);
+            if entry_port.is_some() && entry_port != port {
+                return false;
+            }
+            if let Some(ip) = ip {
+                if let Some((net, bits)) = pattern.split_once('/') {
+                    return in_cidr(ip, net, bits);
+                }
+                return pattern.parse::<IpAddr>().ok() == Some(ip);
+            }
+            let domain = pattern.trim_start_matches('*').trim_start_matches('.');
+            host == domain || host.ends_with(&format!(".{}", domain))
+        })
+}
+
+fn split_entry_port(entry: &str) -> (&str, Option<u16>) {
+    if let Some(rest) = entry.strip_prefix('[') {
+        return match rest.split_once(']') {
+            Some((ip, port)) => (ip, port.strip_prefix(':').and_then(|x| x.parse().ok())),
+            None => (rest, None),
+        };
+    }
+    match entry.split_once(':') {
+        // More colons is an IPv6 address.
+        Some((host, port)) if !port.contains(':') => (host, port.parse().ok()),
+        _ => (entry, None),
+    }
+}
+
+fn in_cidr(ip: IpAddr, net: &str, bits: &str) -> bool {
+    let (Ok(net), Ok(bits)) = (net.parse::<IpAddr>(), bits.parse::<u32>()) else {
+        return false;
+    };
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) if bits <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) if bits <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_proxy() {
+        let no_proxy = "localhost, .corp.com,example.org:8080, 10.0.0.0/8, ::1, [fd00::1]:443";
+        let matches = |host, port| no_proxy_matches(no_proxy, host, Some(port));
+        assert!(matches("localhost", 80));
+        assert!(matches("corp.com", 443));
+        assert!(matches("rs.corp.com", 21116));
+        assert!(!matches("notcorp.com", 443));
+        assert!(matches("example.org", 8080));
+        assert!(!matches("example.org", 443));
+        assert!(matches("10.1.2.3", 21116));
+        assert!(!matches("11.1.2.3", 21116));
+        assert!(matches("::1", 21116));
+        assert!(matches("fd00::1", 443));
+        assert!(!matches("fd00::1", 80));
+        assert!(no_proxy_matches("*", "a.b", None));
+        assert!(!no_proxy_matches("", "a.b", None));
+    }
+
+    #[test]
+    fn test_normalize_env_proxy() {
+        assert_eq!(
+            normalize_env_proxy("proxy:3128"),
+            Some("http://proxy:3128".to_owned())
+        );
+        assert_eq!(
+            normalize_env_proxy("HTTP://u:p@proxy:3128/"),
+            Some("http://u:p@proxy:3128/".to_owned())
+        );
+        assert_eq!(
+            normalize_env_proxy("socks5h://10.0.0.1"),
+            Some("socks5://10.0.0.1".to_owned())
+        );
+        assert_eq!(normalize_env_proxy("socks4://10.0.0.1"), None);
+        assert_eq!(normalize_env_proxy(" "), None);
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let script = parse_response(b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ns-proxy-autoconfig\r\n\r\nfunction FindProxyForURL(u, h) { return \"DIRECT\"; }").unwrap();
+        assert!(script.starts_with("function"));
+        assert!(parse_response(b"HTTP/1.1 404 Not Found\r\n\r\n").is_err());
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+    }
+}
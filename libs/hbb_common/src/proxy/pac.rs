@@ -0,0 +1,224 @@
+//! Proxy auto-config files.
+//!
+//! https://developer.mozilla.org/en-US/docs/Web/HTTP/Proxy_servers_and_tunneling/Proxy_Auto-Configuration_PAC_file
+//!
+//! `dateRange` and the Microsoft IPv6 extensions are not supported, a script calling them
+//! fails and the connection goes without proxy.
+use crate::ResultType;
+
+/// Turns the result of `FindProxyForURL`, e.g. "PROXY a:8080; DIRECT", into a proxy url.
+///
+/// Only the first usable entry counts, `None` means DIRECT.
+pub(super) fn parse_result(res: &str) -> Option<String> {
+    for entry in res.split(';') {
+        let mut it = entry.split_whitespace();
+        let Some(kind) = it.next() else {
+            continue;
+        };
+        let scheme = match kind.to_uppercase().as_str() {
+            "DIRECT" => return None,
+            "PROXY" | "HTTP" => "http",
+            "HTTPS" => "https",
+            "SOCKS" | "SOCKS5" => "socks5",
+            _ => {
+                log::debug!("Unsupported PAC entry: {}", entry.trim());
+                continue;
+            }
+        };
+        if let Some(host) = it.next() {
+            return Some(format!("{}://{}", scheme, host));
+        }
+    }
+    None
+}
+
+#[cfg(feature = "pac")]
+pub(super) fn find_proxy_for_url(script: &str, url: &str, host: &str) -> ResultType<String> {
+    use boa_engine::{js_string, Context, JsResult, JsString, JsValue, NativeFunction, Source};
+
+    fn dns_resolve(_: &JsValue, args: &[JsValue], ctx: &mut Context) -> JsResult<JsValue> {
+        let host = match args.first() {
+            Some(host) => host.to_string(ctx)?.to_std_string_escaped(),
+            None => return Ok(JsValue::null()),
+        };
+        Ok(resolve_ipv4(&host)
+            .map(|ip| JsValue::from(JsString::from(ip.as_str())))
+            .unwrap_or(JsValue::null()))
+    }
+
+    fn my_ip_address(_: &JsValue, _: &[JsValue], _: &mut Context) -> JsResult<JsValue> {
+        Ok(JsValue::from(JsString::from(local_ipv4().as_str())))
+    }
+
+    let err = |e: boa_engine::JsError| anyhow::anyhow!("PAC error: {}", e);
+    let mut ctx = Context::default();
+    ctx.runtime_limits_mut()
+        .set_loop_iteration_limit(MAX_LOOP_ITERATIONS);
+    ctx.register_global_callable(
+        js_string!("dnsResolve"),
+        1,
+        NativeFunction::from_fn_ptr(dns_resolve),
+    )
+    .map_err(err)?;
+    ctx.register_global_callable(
+        js_string!("myIpAddress"),
+        0,
+        NativeFunction::from_fn_ptr(my_ip_address),
+    )
+    .map_err(err)?;
+    ctx.eval(Source::from_bytes(PAC_UTILS)).map_err(err)?;
+    ctx.eval(Source::from_bytes(script)).map_err(err)?;
+    let call = format!(
+        "FindProxyForURL({}, {})",
+        serde_json::to_string(url)?,
+        serde_json::to_string(host)?
+    );
+    let res = ctx.eval(Source::from_bytes(&call)).map_err(err)?;
+    Ok(res
+        .to_string(&mut ctx)
+        .map_err(err)?
+        .to_std_string_escaped())
+}
+
+#[cfg(not(feature = "pac"))]
+pub(super) fn find_proxy_for_url(_script: &str, _url: &str, _host: &str) -> ResultType<String> {
+    crate::bail!("Built without PAC support");
+}
+
+// A script looping forever must not hang the connection.
+#[cfg(feature = "pac")]
+const MAX_LOOP_ITERATIONS: u64 = 1_000_000;
+
+#[cfg(feature = "pac")]
+fn resolve_ipv4(host: &str) -> Option<String> {
+    use std::net::ToSocketAddrs;
+
+    (host, 0)
+        .to_socket_addrs()
+        .ok()?
+        .find(|addr| addr.is_ipv4())
+        .map(|addr| addr.ip().to_string())
+}
+
+#[cfg(feature = "pac")]
+fn local_ipv4() -> String {
+    // No packet is sent, connect only picks the interface of the default route.
+    std::net::UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| {
+            socket.connect("8.8.8.8:80")?;
+            socket.local_addr()
+        })
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|_| "127.0.0.1".to_owned())
+}
+
+// The predefined functions, `dnsResolve` and `myIpAddress` are native.
+#[cfg(feature = "pac")]
+const PAC_UTILS: &str = r#"
+function isPlainHostName(host) {
+    return host.indexOf('.') < 0;
+}
+function dnsDomainIs(host, domain) {
+    host = host.toLowerCase();
+    domain = domain.toLowerCase();
+    return host.length >= domain.length &&
+        host.substring(host.length - domain.length) == domain;
+}
+function localHostOrDomainIs(host, hostdom) {
+    return host == hostdom || hostdom.lastIndexOf(host + '.', 0) == 0;
+}
+function isResolvable(host) {
+    return dnsResolve(host) != null;
+}
+function convert_addr(ip) {
+    var b = ip.split('.');
+    return ((b[0] << 24) | (b[1] << 16) | (b[2] << 8) | b[3]) >>> 0;
+}
+function isInNet(host, pattern, mask) {
+    var ip = /^\d+\.\d+\.\d+\.\d+$/.test(host) ? host : dnsResolve(host);
+    if (ip == null) return false;
+    var m = convert_addr(mask);
+    return ((convert_addr(ip) & m) >>> 0) == ((convert_addr(pattern) & m) >>> 0);
+}
+function dnsDomainLevels(host) {
+    return host.split('.').length - 1;
+}
+function shExpMatch(str, shexp) {
+    var re = shexp.replace(/[.+^${}()|[\]\\]/g, '\\$&').replace(/\*/g, '.*').replace(/\?/g, '.');
+    return new RegExp('^' + re + '$').test(str);
+}
+var __pac_days = ['SUN', 'MON', 'TUE', 'WED', 'THU', 'FRI', 'SAT'];
+function weekdayRange(wd1, wd2, gmt) {
+    if (wd2 == 'GMT') {
+        gmt = wd2;
+        wd2 = undefined;
+    }
+    var now = new Date();
+    var d = gmt == 'GMT' ? now.getUTCDay() : now.getDay();
+    var d1 = __pac_days.indexOf(wd1);
+    var d2 = wd2 === undefined ? d1 : __pac_days.indexOf(wd2);
+    return d1 <= d2 ? d1 <= d && d <= d2 : d >= d1 || d <= d2;
+}
+function timeRange() {
+    var args = Array.prototype.slice.call(arguments);
+    var gmt = args[args.length - 1] == 'GMT';
+    if (gmt) args.pop();
+    var now = new Date();
+    var h = gmt ? now.getUTCHours() : now.getHours();
+    if (args.length == 1) return h == args[0];
+    if (args.length == 2) {
+        return args[0] <= args[1] ? args[0] <= h && h <= args[1] : h >= args[0] || h <= args[1];
+    }
+    return false;
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_result() {
+        assert_eq!(parse_result("DIRECT"), None);
+        assert_eq!(
+            parse_result("PROXY proxy.corp:8080; DIRECT"),
+            Some("http://proxy.corp:8080".to_owned())
+        );
+        assert_eq!(
+            parse_result(" QUIC a:1; socks 10.0.0.1:1080"),
+            Some("socks5://10.0.0.1:1080".to_owned())
+        );
+        assert_eq!(
+            parse_result("HTTPS p:443"),
+            Some("https://p:443".to_owned())
+        );
+        assert_eq!(parse_result(""), None);
+    }
+
+    #[cfg(feature = "pac")]
+    #[test]
+    fn test_find_proxy_for_url() {
+        let script = r#"
+            function FindProxyForURL(url, host) {
+                if (isPlainHostName(host) || shExpMatch(host, "*.local")) return "DIRECT";
+                if (isInNet(host, "10.0.0.0", "255.0.0.0")) return "SOCKS5 10.0.0.1:1080";
+                if (dnsDomainIs(host, ".corp.com") && url.substring(0, 6) == "https:")
+                    return "PROXY proxy.corp.com:8080";
+                return "DIRECT";
+            }
+        "#;
+        let find = |url, host| find_proxy_for_url(script, url, host).unwrap();
+        assert_eq!(find("https://rs:21116/", "rs"), "DIRECT");
+        assert_eq!(find("https://a.local/", "a.local"), "DIRECT");
+        assert_eq!(
+            find("https://10.2.3.4:21117/", "10.2.3.4"),
+            "SOCKS5 10.0.0.1:1080"
+        );
+        assert_eq!(
+            find("https://api.corp.com/", "api.corp.com"),
+            "PROXY proxy.corp.com:8080"
+        );
+        assert_eq!(find("http://api.corp.com/", "api.corp.com"), "DIRECT");
+        assert!(find_proxy_for_url("while (true) {}", "https://a/", "a").is_err());
+    }
+}
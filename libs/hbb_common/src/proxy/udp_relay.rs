@@ -0,0 +1,221 @@
+//! Hole punching, KCP and QUIC through a SOCKS5 UDP association.
+//!
+//! They all work on a plain `UdpSocket` connected to the peer. [`UdpRelay::bind`] hands out
+//! such a socket on the loopback interface, and every remote address gets a loopback socket
+//! of its own that stands in for it, see [`UdpRelay::peer_addr`]. Datagrams between the two
+//! are relayed through the association.
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::{net::UdpSocket, sync::mpsc};
+use tokio_socks::TargetAddr;
+
+use super::Socks5UdpFramed;
+use crate::ResultType;
+
+// The association is closed after this long without a datagram in either direction.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+lazy_static::lazy_static! {
+    // Keyed by the address of the socket handed out by `UdpRelay::bind`.
+    static ref RELAYS: Mutex<HashMap<SocketAddr, Arc<UdpRelay>>> = Default::default();
+}
+
+pub struct UdpRelay {
+    local: SocketAddr,
+    tx: mpsc::UnboundedSender<(Bytes, SocketAddr)>,
+    // The loopback socket standing in for each remote address.
+    peers: Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>,
+}
+
+impl UdpRelay {
+    /// Returns a loopback socket whose datagrams go through `framed`.
+    pub async fn bind(framed: Socks5UdpFramed) -> ResultType<Arc<UdpSocket>> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let local = socket.local_addr()?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let relay = Arc::new(Self {
+            local,
+            tx,
+            peers: Default::default(),
+        });
+        RELAYS.lock().unwrap().insert(local, relay.clone());
+        tokio::spawn(async move {
+            relay.run(framed, rx).await;
+            RELAYS.lock().unwrap().remove(&local);
+        });
+        Ok(Arc::new(socket))
+    }
+
+    /// The address `socket` has to send to for reaching `remote`.
+    ///
+    /// That is `remote` itself unless `socket` was handed out by [`Self::bind`].
+    pub async fn peer_addr(socket: &UdpSocket, remote: SocketAddr) -> ResultType<SocketAddr> {
+        let relay = socket
+            .local_addr()
+            .ok()
+            .and_then(|addr| RELAYS.lock().unwrap().get(&addr).cloned());
+        match relay {
+            Some(relay) => relay.map(remote).await,
+            None => Ok(remote),
+        }
+    }
+
+    async fn map(&self, remote: SocketAddr) -> ResultType<SocketAddr> {
+        // Binding a UDP socket does not block, so the peer is made under the lock and
+        // concurrent lookups of the same remote can't end up with two sockets.
+        let peer = match self.peers.lock().unwrap().entry(remote) {
+            Entry::Occupied(e) => return Ok(e.get().local_addr()?),
+            Entry::Vacant(e) => {
+                let peer = std::net::UdpSocket::bind("127.0.0.1:0")?;
+                peer.connect(self.local)?;
+                peer.set_nonblocking(true)?;
+                e.insert(Arc::new(UdpSocket::from_std(peer)?)).clone()
+            }
+        };
+        let addr = peer.local_addr()?;
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            loop {
+                tokio::select! {
+                    res = peer.recv(&mut buf) => match res {
+                        Ok(n) => {
+                            if tx.send((Bytes::copy_from_slice(&buf[..n]), remote)).is_err() {
+                                break;
+                            }
+                        }
+                        // The local socket is gone.
+                        Err(_) => break,
+                    },
+                    _ = tx.closed() => break,
+                }
+            }
+        });
+        Ok(addr)
+    }
+
+    async fn run(
+        &self,
+        mut framed: Socks5UdpFramed,
+        mut rx: mpsc::UnboundedReceiver<(Bytes, SocketAddr)>,
+    ) {
+        loop {
+            tokio::select! {
+                res = framed.next() => match res {
+                    Some(Ok((data, TargetAddr::Ip(addr)))) => {
+                        let peer = self.peers.lock().unwrap().get(&addr).cloned();
+                        if let Some(peer) = peer {
+                            peer.send(&data).await.ok();
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => {
+                        log::debug!("SOCKS5 UDP relay of {} failed: {}", self.local, err);
+                        break;
+                    }
+                    None => break,
+                },
+                Some((data, addr)) = rx.recv() => {
+                    if let Err(err) = framed.send(data, &TargetAddr::Ip(addr)).await {
+                        log::debug!("SOCKS5 UDP relay of {} failed: {}", self.local, err);
+                        break;
+                    }
+                }
+                _ = tokio::time::sleep(IDLE_TIMEOUT) => {
+                    log::debug!("SOCKS5 UDP relay of {} is idle, close it", self.local);
+                    break;
+                }
+            }
+        }
+        // Dropping `rx` stops the tasks of the peers, see `map`.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp::DynTcpStream;
+    use bytes::BytesMut;
+    use tokio_util::{codec::BytesCodec, udp::UdpFramed};
+
+    #[test]
+    fn test_udp_relay() {
+        test_udp_relay_async();
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn test_udp_relay_async() {
+        // A SOCKS5 UDP relay, see RFC 1928 section 7.
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut client = None;
+            let mut buf = [0u8; 1500];
+            loop {
+                let (n, from) = server.recv_from(&mut buf).await.unwrap();
+                if client.is_none() || client == Some(from) {
+                    client = Some(from);
+                    let (TargetAddr::Ip(to), len) =
+                        super::super::parse_udp_header(&buf[..n]).unwrap()
+                    else {
+                        unreachable!();
+                    };
+                    server.send_to(&buf[len..n], to).await.unwrap();
+                } else if let Some(client) = client {
+                    let mut data = BytesMut::new();
+                    super::super::put_udp_header(&mut data, &TargetAddr::Ip(from)).unwrap();
+                    data.extend_from_slice(&buf[..n]);
+                    server.send_to(&data, client).await.unwrap();
+                }
+            }
+        });
+        let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        let framed = Socks5UdpFramed {
+            framed: UdpFramed::new(
+                UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                BytesCodec::new(),
+            ),
+            relay: server_addr,
+            _control: DynTcpStream(Box::new(tokio::io::duplex(1).0)),
+        };
+
+        let socket = UdpRelay::bind(framed).await.unwrap();
+        let peer_addr = UdpRelay::peer_addr(&socket, remote_addr).await.unwrap();
+        assert_ne!(peer_addr, remote_addr);
+        assert_eq!(
+            UdpRelay::peer_addr(&socket, remote_addr).await.unwrap(),
+            peer_addr
+        );
+        // Concurrent lookups of a new remote share one socket.
+        let other = "127.0.0.1:9".parse().unwrap();
+        let (a, b) = tokio::join!(
+            UdpRelay::peer_addr(&socket, other),
+            UdpRelay::peer_addr(&socket, other)
+        );
+        assert_eq!(a.unwrap(), b.unwrap());
+        socket.connect(peer_addr).await.unwrap();
+        socket.send(b"ping").await.unwrap();
+        let mut buf = [0u8; 16];
+        let (n, from) = remote.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(from, server_addr);
+        remote.send_to(b"pong", from).await.unwrap();
+        let n = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"pong");
+
+        // Plain sockets are not mapped.
+        let plain = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert_eq!(
+            UdpRelay::peer_addr(&plain, remote_addr).await.unwrap(),
+            remote_addr
+        );
+    }
+}
@@ -0,0 +1,450 @@
+use crate::{bail, bytes_codec::BytesCodec, channel::Class, message_proto::*, ResultType};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use sodiumoxide::crypto::secretbox::{self, Key, Nonce};
+use std::{
+    convert::TryFrom,
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+// Must be a valid DNS name, the certificate is self-signed and not verified,
+// the peer is authenticated by the signed id/pk exchanged in secure_connection.
+const SERVER_NAME: &str = "rustdesk";
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Independent QUIC streams of one connection, so that a big file transfer
+/// or terminal output burst does not block video frames and input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    Control = 0,
+    Video = 1,
+    File = 2,
+    Terminal = 3,
+}
+
+const LANES: [Lane; 4] = [Lane::Control, Lane::Video, Lane::File, Lane::Terminal];
+
+impl Lane {
+    fn from_u8(v: u8) -> Option<Self> {
+        LANES.get(v as usize).copied()
+    }
+
+    // Messages that must keep their relative order (eg. FileAction and the following
+    // FileResponse blocks of an upload) are in the same class, so on the same lane.
+    pub fn of(msg: &Message) -> Self {
+        match Class::of(msg) {
+            Class::Video => Lane::Video,
+            Class::Bulk => Lane::File,
+            Class::Terminal => Lane::Terminal,
+            Class::Input | Class::Audio => Lane::Control,
+        }
+    }
+}
+
+// Same layout as FramedStream::get_nonce, with the lane in the 9th byte,
+// so that the sequence numbers of different lanes never produce the same nonce.
+fn get_nonce(lane: usize, seqnum: u64) -> Nonce {
+    let mut nonce = Nonce([0u8; secretbox::NONCEBYTES]);
+    nonce.0[..std::mem::size_of_val(&seqnum)].copy_from_slice(&seqnum.to_le_bytes());
+    nonce.0[std::mem::size_of_val(&seqnum)] = lane as u8;
+    nonce
+}
+
+struct LaneStream {
+    writer: FramedWrite<quinn::SendStream, BytesCodec>,
+    reader: FramedRead<quinn::RecvStream, BytesCodec>,
+    // seqnum of sent and received messages, like Encrypt
+    seq_out: u64,
+    seq_in: u64,
+}
+
+pub struct QuicStream {
+    _endpoint: quinn::Endpoint,
+    conn: quinn::Connection,
+    lanes: Vec<LaneStream>,
+    key: Option<Key>,
+    raw: bool,
+    local_addr: SocketAddr,
+    send_timeout: u64,
+}
+
+impl QuicStream {
+    /// Connect to the peer the punched `socket` is connected to.
+    pub async fn connect(socket: Arc<UdpSocket>, ms_timeout: u64) -> ResultType<Self> {
+        let peer_addr = socket.peer_addr()?;
+        let mut endpoint = quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            None,
+            to_std_socket(&socket)?,
+            runtime()?,
+        )?;
+        endpoint.set_default_client_config(client_config()?);
+        let conn = crate::timeout(ms_timeout, endpoint.connect(peer_addr, SERVER_NAME)?).await??;
+        let mut lanes = Vec::with_capacity(LANES.len());
+        for lane in LANES {
+            let (send, recv) = conn.open_bi().await?;
+            let mut lane_stream = LaneStream::new(send, recv);
+            // the stream is not visible to the peer until something is sent on it
+            lane_stream
+                .writer
+                .send(Bytes::from(vec![lane as u8]))
+                .await?;
+            lanes.push(lane_stream);
+        }
+        log::debug!("QUIC connection established with {}", peer_addr);
+        Ok(Self::new(endpoint, conn, lanes))
+    }
+
+    /// Accept the connection from the peer the punched `socket` is connected to.
+    pub async fn accept(socket: Arc<UdpSocket>, ms_timeout: u64) -> ResultType<Self> {
+        let peer_addr = socket.peer_addr()?;
+        let endpoint = quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            Some(server_config()?),
+            to_std_socket(&socket)?,
+            runtime()?,
+        )?;
+        let fut = async {
+            // The socket is not connected any more, so anybody could knock.
+            let incoming = loop {
+                let Some(incoming) = endpoint.accept().await else {
+                    bail!("QUIC endpoint closed");
+                };
+                if incoming.remote_address() == peer_addr {
+                    break incoming;
+                }
+                log::debug!(
+                    "Ignore QUIC connection from {}, expecting {}",
+                    incoming.remote_address(),
+                    peer_addr
+                );
+                incoming.ignore();
+            };
+            let conn = incoming.await?;
+            let mut lanes: Vec<Option<LaneStream>> = LANES.iter().map(|_| None).collect();
+            for _ in LANES {
+                let (send, recv) = conn.accept_bi().await?;
+                let mut lane_stream = LaneStream::new(send, recv);
+                let lane = match lane_stream.reader.next().await {
+                    Some(Ok(bytes)) if bytes.len() == 1 => Lane::from_u8(bytes[0]),
+                    _ => None,
+                };
+                let Some(lane) = lane else {
+                    bail!("Invalid QUIC lane header");
+                };
+                lanes[lane as usize] = Some(lane_stream);
+            }
+            Ok((conn, lanes.into_iter().flatten().collect::<Vec<_>>()))
+        };
+        let (conn, lanes) = crate::timeout(ms_timeout, fut).await??;
+        if lanes.len() != LANES.len() {
+            bail!("Duplicated QUIC lane");
+        }
+        log::debug!("QUIC connection accepted from {}", conn.remote_address());
+        Ok(Self::new(endpoint, conn, lanes))
+    }
+
+    fn new(endpoint: quinn::Endpoint, conn: quinn::Connection, lanes: Vec<LaneStream>) -> Self {
+        let local_addr = endpoint
+            .local_addr()
+            .unwrap_or(crate::config::Config::get_any_listen_addr(true));
+        Self {
+            _endpoint: endpoint,
+            conn,
+            lanes,
+            key: None,
+            raw: false,
+            local_addr,
+            send_timeout: 0,
+        }
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    #[inline]
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.conn.remote_address()
+    }
+
+    /// Smoothed round trip time measured by the QUIC congestion controller.
+    #[inline]
+    pub fn rtt(&self) -> Duration {
+        self.conn.rtt()
+    }
+
+    #[inline]
+    pub fn set_send_timeout(&mut self, ms: u64) {
+        self.send_timeout = ms;
+    }
+
+    /// Raw mode is used by port forwarding, where the byte order matters,
+    /// so everything goes through the control lane afterwards.
+    pub fn set_raw(&mut self) {
+        self.raw = true;
+        self.key = None;
+        let lane = &mut self.lanes[Lane::Control as usize];
+        lane.writer.encoder_mut().set_raw();
+        lane.reader.decoder_mut().set_raw();
+    }
+
+    #[inline]
+    pub fn set_key(&mut self, key: Key) {
+        self.key = Some(key);
+    }
+
+    #[inline]
+    pub fn is_secured(&self) -> bool {
+        self.key.is_some()
+    }
+
+    #[inline]
+    pub async fn send(&mut self, msg: &impl protobuf::Message) -> ResultType<()> {
+        let lane = match (msg as &dyn std::any::Any).downcast_ref::<Message>() {
+            Some(msg) => Lane::of(msg),
+            None => Lane::Control,
+        };
+        self.send_on(lane, msg.write_to_bytes()?).await
+    }
+
+    #[inline]
+    pub async fn send_raw(&mut self, msg: Vec<u8>) -> ResultType<()> {
+        self.send_on(Lane::Control, msg).await
+    }
+
+    #[inline]
+    pub async fn send_bytes(&mut self, bytes: Bytes) -> ResultType<()> {
+        self.send_on(Lane::Control, bytes.to_vec()).await
+    }
+
+    async fn send_on(&mut self, lane: Lane, msg: Vec<u8>) -> ResultType<()> {
+        let lane = if self.raw { Lane::Control } else { lane };
+        let lane = lane as usize;
+        let lane_stream = &mut self.lanes[lane];
+        let mut msg = msg;
+        if let Some(key) = self.key.as_ref() {
+            lane_stream.seq_out += 1;
+            msg = secretbox::seal(&msg, &get_nonce(lane, lane_stream.seq_out), key);
+        }
+        let bytes = Bytes::from(msg);
+        if self.send_timeout > 0 {
+            crate::timeout(self.send_timeout, lane_stream.writer.send(bytes)).await??;
+        } else {
+            lane_stream.writer.send(bytes).await?;
+        }
+        Ok(())
+    }
+
+    /// Receive the next message of any lane, the control lane is polled first.
+    pub async fn next(&mut self) -> Option<Result<BytesMut, Error>> {
+        let (lane, res) = {
+            let futs = self
+                .lanes
+                .iter_mut()
+                .enumerate()
+                .map(|(i, lane)| Box::pin(async move { (i, lane.reader.next().await) }));
+            futures::future::select_all(futs).await.0
+        };
+        let mut res = res;
+        if let Some(Ok(bytes)) = res.as_mut() {
+            if let Some(key) = self.key.as_ref() {
+                if bytes.len() > 1 {
+                    let lane_stream = &mut self.lanes[lane];
+                    lane_stream.seq_in += 1;
+                    match secretbox::open(bytes, &get_nonce(lane, lane_stream.seq_in), key) {
+                        Ok(dec) => {
+                            bytes.clear();
+                            bytes.put_slice(&dec);
+                        }
+                        Err(()) => {
+                            return Some(Err(Error::new(ErrorKind::Other, "decryption error")));
+                        }
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    #[inline]
+    pub async fn next_timeout(&mut self, ms: u64) -> Option<Result<BytesMut, Error>> {
+        if let Ok(res) = crate::timeout(ms, self.next()).await {
+            res
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for QuicStream {
+    fn drop(&mut self) {
+        self.conn.close(0u32.into(), b"");
+    }
+}
+
+impl LaneStream {
+    fn new(send: quinn::SendStream, recv: quinn::RecvStream) -> Self {
+        Self {
+            writer: FramedWrite::new(send, BytesCodec::new()),
+            reader: FramedRead::new(recv, BytesCodec::new()),
+            seq_out: 0,
+            seq_in: 0,
+        }
+    }
+}
+
+fn runtime() -> ResultType<Arc<dyn quinn::Runtime>> {
+    quinn::default_runtime().ok_or_else(|| anyhow::anyhow!("No async runtime found for QUIC"))
+}
+
+fn transport_config() -> Arc<quinn::TransportConfig> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    transport.max_idle_timeout(quinn::IdleTimeout::try_from(IDLE_TIMEOUT).ok());
+    Arc::new(transport)
+}
+
+fn client_config() -> ResultType<quinn::ClientConfig> {
+    let crypto = crate::verifier::client_config_danger()?;
+    let mut config = quinn::ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(crypto)?,
+    ));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+fn server_config() -> ResultType<quinn::ServerConfig> {
+    let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_owned()])?;
+    let key = rustls_pki_types::PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+    let mut config =
+        quinn::ServerConfig::with_single_cert(vec![cert.cert.der().clone()], key.into())?;
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+// quinn needs to own its socket, while the punched socket is shared
+// with the punching tasks, so we hand over a duplicated handle.
+// The tasks must not read it any more, what they get quinn misses.
+fn to_std_socket(socket: &UdpSocket) -> ResultType<std::net::UdpSocket> {
+    #[cfg(unix)]
+    let res = {
+        use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+        let s = unsafe { std::net::UdpSocket::from_raw_fd(socket.as_raw_fd()) };
+        let res = s.try_clone();
+        let _ = s.into_raw_fd();
+        res
+    };
+    #[cfg(windows)]
+    let res = {
+        use std::os::windows::io::{AsRawSocket, FromRawSocket, IntoRawSocket};
+        let s = unsafe { std::net::UdpSocket::from_raw_socket(socket.as_raw_socket()) };
+        let res = s.try_clone();
+        let _ = s.into_raw_socket();
+        res
+    };
+    let socket = res?;
+    disconnect(&socket)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+// quinn passes the destination address of every datagram, which a connected
+// socket rejects with EISCONN on macOS, so the association is dissolved there.
+// Not on Linux, which also releases an automatically bound port on disconnect.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn disconnect(socket: &std::net::UdpSocket) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let mut addr: libc::sockaddr = unsafe { std::mem::zeroed() };
+    addr.sa_family = libc::AF_UNSPEC as _;
+    let ret = unsafe {
+        libc::connect(
+            socket.as_raw_fd(),
+            &addr,
+            std::mem::size_of::<libc::sockaddr>() as _,
+        )
+    };
+    if ret != 0 {
+        let err = Error::last_os_error();
+        // macOS dissolves the association but still reports EAFNOSUPPORT.
+        if err.raw_os_error() != Some(libc::EAFNOSUPPORT) {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+fn disconnect(_socket: &std::net::UdpSocket) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::Message as _;
+
+    #[test]
+    fn test_lane_of() {
+        let mut msg = Message::new();
+        msg.set_video_frame(VideoFrame::new());
+        assert_eq!(Lane::of(&msg), Lane::Video);
+        let mut misc = Misc::new();
+        misc.set_switch_display(SwitchDisplay::new());
+        msg.set_misc(misc);
+        assert_eq!(Lane::of(&msg), Lane::Video);
+        msg.set_file_response(FileResponse::new());
+        assert_eq!(Lane::of(&msg), Lane::File);
+        msg.set_cliprdr(Cliprdr::new());
+        assert_eq!(Lane::of(&msg), Lane::File);
+        msg.set_terminal_action(TerminalAction::new());
+        assert_eq!(Lane::of(&msg), Lane::Terminal);
+        msg.set_mouse_event(MouseEvent::new());
+        assert_eq!(Lane::of(&msg), Lane::Control);
+        assert_eq!(Lane::of(&Message::new()), Lane::Control);
+    }
+
+    #[test]
+    fn test_lane_nonce() {
+        assert_ne!(get_nonce(0, 1).0, get_nonce(1, 1).0);
+        assert_ne!(get_nonce(0, 1).0, get_nonce(0, 2).0);
+    }
+
+    #[tokio::test]
+    async fn test_quic_lanes() {
+        let a = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let b = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+        let server = tokio::spawn(QuicStream::accept(b, 3_000));
+        let mut client = QuicStream::connect(a, 3_000).await.unwrap();
+        let mut server = server.await.unwrap().unwrap();
+        let key = secretbox::gen_key();
+        client.set_key(key.clone());
+        server.set_key(key);
+        let mut video = Message::new();
+        video.set_video_frame(VideoFrame::new());
+        let mut input = Message::new();
+        input.set_mouse_event(MouseEvent {
+            x: 1,
+            ..Default::default()
+        });
+        client.send(&video).await.unwrap();
+        client.send(&input).await.unwrap();
+        client.send(&video).await.unwrap();
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            let bytes = server.next_timeout(3_000).await.unwrap().unwrap();
+            received.push(Message::parse_from_bytes(&bytes).unwrap());
+        }
+        assert_eq!(received.iter().filter(|m| **m == video).count(), 2);
+        assert!(received.contains(&input));
+    }
+}
@@ -0,0 +1,546 @@
+//! Resumable sessions.
+//!
+//! After login both sides number the messages they send and keep them until the peer
+//! acknowledges them. If the transport dies, e.g. because the client switched from Wi-Fi to
+//! Ethernet, the client connects again and presents the session token it got in
+//! `LoginResponse`. The controlled side hands the new transport over to the existing
+//! connection with [`hand_over`], then both sides replay what the other one has missed.
+//!
+//! Both sides send a [`SessionResume`] right after enabling resumption, messages are only
+//! counted after the peer's one, so messages in flight during login are never counted.
+//! Video frames are neither counted nor replayed, they are stale by the time the session
+//! is resumed and would only flood the replay queue.
+//!
+//! The count of messages received only tells which ones were received if they arrive in the
+//! order they were sent. QUIC sends them on independent lanes, so sessions over QUIC are not
+//! resumable, nor resumed over QUIC.
+use crate::{
+    bail,
+    message_proto::{Message, SessionResume},
+    protobuf::Message as _,
+    sodiumoxide::randombytes::randombytes,
+    tokio::{
+        sync::mpsc,
+        time::{sleep_until, Instant},
+    },
+    ResultType, Stream,
+};
+use bytes::{Bytes, BytesMut};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    sync::Mutex,
+    time::Duration,
+};
+
+/// How long the controlled side keeps a session without transport.
+pub const GRACE: Duration = Duration::from_secs(20);
+// Older messages are dropped, the session can't be resumed if the peer missed them.
+const MAX_UNACKED_SIZE: usize = 8 * 1024 * 1024;
+const MAX_UNACKED_COUNT: usize = 4096;
+// The peer acknowledges at least every `ACK_INTERVAL`, so messages older than this were
+// either received or the session is gone already.
+const MAX_UNACKED_AGE: Duration = Duration::from_secs(GRACE.as_secs() + 10);
+const ACK_EVERY: u64 = 32;
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+// Encoded tag of `Message.session_resume`, field 33 with wire type 2.
+const SESSION_RESUME_TAG: [u8; 2] = [0x8A, 0x02];
+// Encoded tag of `Message.video_frame`, field 6 with wire type 2.
+const VIDEO_FRAME_TAG: u8 = 0x32;
+
+lazy_static::lazy_static! {
+    static ref SESSIONS: Mutex<HashMap<Vec<u8>, mpsc::UnboundedSender<Handover>>> = Default::default();
+}
+
+pub struct Handover {
+    pub stream: Stream,
+    /// Messages of the session the client received before the transport died.
+    pub received: u64,
+}
+
+pub fn new_token() -> Vec<u8> {
+    randombytes(32)
+}
+
+#[inline]
+pub fn exists(token: &[u8]) -> bool {
+    SESSIONS.lock().unwrap().contains_key(token)
+}
+
+/// Hands the transport of a reconnected client over to the connection owning `token`.
+pub fn hand_over(token: &[u8], stream: Stream, received: u64) -> ResultType<()> {
+    let Some(tx) = SESSIONS.lock().unwrap().get(token).cloned() else {
+        bail!("Session not found");
+    };
+    if tx.send(Handover { stream, received }).is_err() {
+        bail!("Session closed");
+    }
+    Ok(())
+}
+
+#[inline]
+fn is_video_frame(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&VIDEO_FRAME_TAG)
+}
+
+/// Messages sent and not acknowledged by the peer yet.
+#[derive(Default)]
+struct Unacked {
+    // Sequence number of the first message in `queue`, minus one.
+    first: u64,
+    queue: VecDeque<(Instant, Bytes)>,
+    size: usize,
+}
+
+impl Unacked {
+    #[inline]
+    fn sent(&self) -> u64 {
+        self.first + self.queue.len() as u64
+    }
+
+    fn push(&mut self, bytes: Bytes) {
+        let now = Instant::now();
+        self.size += bytes.len();
+        self.queue.push_back((now, bytes));
+        while self.size > MAX_UNACKED_SIZE
+            || self.queue.len() > MAX_UNACKED_COUNT
+            || self
+                .queue
+                .front()
+                .map(|(t, _)| now.duration_since(*t) > MAX_UNACKED_AGE)
+                .unwrap_or(false)
+        {
+            self.pop();
+        }
+    }
+
+    fn pop(&mut self) {
+        if let Some((_, bytes)) = self.queue.pop_front() {
+            self.size -= bytes.len();
+            self.first += 1;
+        }
+    }
+
+    fn ack(&mut self, received: u64) {
+        while self.first < received && !self.queue.is_empty() {
+            self.pop();
+        }
+    }
+
+    /// The messages after the first `received` ones, `None` if some were dropped already.
+    fn since(&self, received: u64) -> Option<impl Iterator<Item = &Bytes>> {
+        if received < self.first || received > self.sent() {
+            return None;
+        }
+        Some(
+            self.queue
+                .iter()
+                .skip((received - self.first) as usize)
+                .map(|(_, bytes)| bytes),
+        )
+    }
+}
+
+fn session_resume(token: Vec<u8>, received: u64) -> Message {
+    let mut msg = Message::new();
+    msg.set_session_resume(SessionResume {
+        token: token.into(),
+        received,
+        ..Default::default()
+    });
+    msg
+}
+
+pub struct ResumableStream {
+    inner: Option<Stream>,
+    // Empty until the session is resumable.
+    token: Vec<u8>,
+    unacked: Unacked,
+    // Whether the peer enabled resumption too, we only count messages from then on.
+    counting: bool,
+    received: u64,
+    acked: u64,
+    last_ack: Instant,
+    // Whether the session is registered for `hand_over`, only on the controlled side.
+    registered: bool,
+    // When the controlled side gives up waiting for the client.
+    deadline: Option<Instant>,
+    local_addr: SocketAddr,
+    send_timeout: u64,
+}
+
+impl ResumableStream {
+    pub fn wrap(stream: Stream) -> Stream {
+        Stream::Resumable(Box::new(Self {
+            local_addr: stream.local_addr(),
+            inner: Some(stream),
+            token: Vec::new(),
+            unacked: Default::default(),
+            counting: false,
+            received: 0,
+            acked: 0,
+            last_ack: Instant::now(),
+            registered: false,
+            deadline: None,
+            send_timeout: 0,
+        }))
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        !self.token.is_empty()
+    }
+
+    /// Starts numbering the messages, `register` on the controlled side so a reconnected
+    /// client can find the session.
+    ///
+    /// Returns the receiver of the transports handed over with [`hand_over`] if `register`.
+    /// The owner polls it next to [`Self::next`] and passes them on to [`Self::attach`].
+    pub async fn enable(
+        &mut self,
+        token: Vec<u8>,
+        register: bool,
+    ) -> Option<mpsc::UnboundedReceiver<Handover>> {
+        if !self.is_ordered() {
+            log::info!("Session not resumable, messages are not ordered");
+            return None;
+        }
+        let mut rx = None;
+        if register {
+            let (tx, rx_) = mpsc::unbounded_channel();
+            SESSIONS.lock().unwrap().insert(token.clone(), tx);
+            self.registered = true;
+            rx = Some(rx_);
+        }
+        self.token = token;
+        if let Some(inner) = self.inner.as_mut() {
+            if let Err(err) = inner.send(&session_resume(vec![], 0)).await {
+                self.on_broken(&err.to_string());
+            }
+        }
+        rx
+    }
+
+    /// Takes the transport out, so it can be handed over to another session.
+    #[inline]
+    pub fn detach(&mut self) -> Option<Stream> {
+        self.inner.take()
+    }
+
+    #[inline]
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    #[inline]
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    /// Continues the session on `stream`, replaying the messages the peer missed.
+    pub async fn attach(&mut self, mut stream: Stream, peer_received: u64) -> ResultType<()> {
+        if !stream.is_ordered() {
+            bail!("Sessions are not resumed over QUIC");
+        }
+        let Some(missed) = self.unacked.since(peer_received) else {
+            bail!(
+                "Messages were lost, the peer received {} of {}",
+                peer_received,
+                self.unacked.sent()
+            );
+        };
+        if self.send_timeout > 0 {
+            stream.set_send_timeout(self.send_timeout);
+        }
+        stream.send(&session_resume(vec![], self.received)).await?;
+        for bytes in missed {
+            stream.send_raw(bytes.to_vec()).await?;
+        }
+        self.unacked.ack(peer_received);
+        self.acked = self.received;
+        self.last_ack = Instant::now();
+        self.inner = Some(stream);
+        self.deadline = None;
+        Ok(())
+    }
+
+    fn on_broken(&mut self, err: &str) {
+        log::info!("Session transport lost: {}", err);
+        self.inner = None;
+        if self.registered {
+            self.deadline = Some(Instant::now() + GRACE);
+        }
+    }
+
+    #[inline]
+    pub fn set_send_timeout(&mut self, ms: u64) {
+        self.send_timeout = ms;
+        if let Some(inner) = self.inner.as_mut() {
+            inner.set_send_timeout(ms);
+        }
+    }
+
+    #[inline]
+    pub fn set_raw(&mut self) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.set_raw();
+        }
+    }
+
+    #[inline]
+    pub fn set_key(&mut self, key: sodiumoxide::crypto::secretbox::Key) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.set_key(key);
+        }
+    }
+
+    #[inline]
+    pub fn is_secured(&self) -> bool {
+        self.inner.as_ref().map(|s| s.is_secured()).unwrap_or(false)
+    }
+
+    #[inline]
+    pub fn is_ordered(&self) -> bool {
+        self.inner.as_ref().map(|s| s.is_ordered()).unwrap_or(true)
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    #[inline]
+    pub async fn send(&mut self, msg: &impl protobuf::Message) -> ResultType<()> {
+        if !self.is_enabled() {
+            return match self.inner.as_mut() {
+                Some(inner) => inner.send(msg).await,
+                None => bail!("Stream detached"),
+            };
+        }
+        self.send_raw(msg.write_to_bytes()?).await
+    }
+
+    pub async fn send_raw(&mut self, bytes: Vec<u8>) -> ResultType<()> {
+        let enabled = self.is_enabled();
+        let video = is_video_frame(&bytes);
+        let Some(inner) = self.inner.as_mut() else {
+            if enabled {
+                if !video {
+                    self.unacked.push(bytes.into());
+                }
+                return Ok(());
+            }
+            bail!("Stream detached");
+        };
+        if !enabled {
+            return inner.send_raw(bytes).await;
+        }
+        let mut res = Ok(());
+        if self.counting
+            && self.received > self.acked
+            && (self.received >= self.acked + ACK_EVERY || self.last_ack.elapsed() >= ACK_INTERVAL)
+        {
+            res = inner.send(&session_resume(vec![], self.received)).await;
+            self.acked = self.received;
+            self.last_ack = Instant::now();
+        }
+        if res.is_ok() {
+            if video {
+                res = inner.send_raw(bytes).await;
+            } else {
+                let bytes = Bytes::from(bytes);
+                self.unacked.push(bytes.clone());
+                res = inner.send_raw(bytes.to_vec()).await;
+            }
+        }
+        if let Err(err) = res {
+            // Kept in `unacked`, will be replayed once the session is resumed.
+            self.on_broken(&err.to_string());
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub async fn send_bytes(&mut self, bytes: Bytes) -> ResultType<()> {
+        match self.inner.as_mut() {
+            Some(inner) => inner.send_bytes(bytes).await,
+            None => bail!("Stream detached"),
+        }
+    }
+
+    pub async fn next(&mut self) -> Option<Result<BytesMut, Error>> {
+        loop {
+            let Some(inner) = self.inner.as_mut() else {
+                let deadline = self.deadline?;
+                sleep_until(deadline).await;
+                self.deadline = None;
+                return Some(Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Session was not resumed in time",
+                )));
+            };
+            let res = inner.next().await;
+            if !self.is_enabled() {
+                return res;
+            }
+            match res {
+                Some(Ok(bytes)) => {
+                    if bytes.starts_with(&SESSION_RESUME_TAG) {
+                        if let Ok(msg) = Message::parse_from_bytes(&bytes) {
+                            if msg.has_session_resume() {
+                                self.counting = true;
+                                self.unacked.ack(msg.session_resume().received);
+                                continue;
+                            }
+                        }
+                    }
+                    if self.counting && !is_video_frame(&bytes) {
+                        self.received += 1;
+                    }
+                    return Some(Ok(bytes));
+                }
+                Some(Err(err)) => {
+                    self.on_broken(&err.to_string());
+                    if !self.registered {
+                        return Some(Err(err));
+                    }
+                }
+                None => {
+                    self.on_broken("Reset by the peer");
+                    // Only the controlled side waits for the client to come back.
+                    if !self.registered {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+
+    #[inline]
+    pub async fn next_timeout(&mut self, ms: u64) -> Option<Result<BytesMut, Error>> {
+        crate::timeout(ms, self.next()).await.ok().flatten()
+    }
+}
+
+impl Drop for ResumableStream {
+    fn drop(&mut self) {
+        if self.registered {
+            SESSIONS.lock().unwrap().remove(&self.token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_resume_tag() {
+        let bytes = session_resume(vec![1; 32], 12345).write_to_bytes().unwrap();
+        assert!(bytes.starts_with(&SESSION_RESUME_TAG));
+    }
+
+    #[test]
+    fn test_video_frame_tag() {
+        let mut msg = Message::new();
+        msg.set_video_frame(Default::default());
+        assert!(is_video_frame(&msg.write_to_bytes().unwrap()));
+        let bytes = session_resume(vec![], 0).write_to_bytes().unwrap();
+        assert!(!is_video_frame(&bytes));
+    }
+
+    #[test]
+    fn test_unacked() {
+        let mut unacked = Unacked::default();
+        for i in 0..10u8 {
+            unacked.push(Bytes::from(vec![i]));
+        }
+        assert_eq!(unacked.sent(), 10);
+        unacked.ack(4);
+        let missed: Vec<_> = unacked.since(6).unwrap().map(|b| b[0]).collect();
+        assert_eq!(missed, vec![6, 7, 8, 9]);
+        assert_eq!(unacked.since(10).unwrap().count(), 0);
+        // Acknowledged messages are gone, as are the ones never sent.
+        assert!(unacked.since(3).is_none());
+        assert!(unacked.since(11).is_none());
+
+        let big = Bytes::from(vec![0; MAX_UNACKED_SIZE / 2 + 1]);
+        unacked.push(big.clone());
+        unacked.push(big);
+        assert_eq!(unacked.sent(), 12);
+        assert!(unacked.since(10).is_none());
+        assert_eq!(unacked.since(11).unwrap().count(), 1);
+
+        let mut unacked = Unacked::default();
+        for _ in 0..MAX_UNACKED_COUNT + 1 {
+            unacked.push(Bytes::from_static(b"x"));
+        }
+        assert!(unacked.since(0).is_none());
+        assert_eq!(unacked.since(1).unwrap().count(), MAX_UNACKED_COUNT);
+    }
+
+    #[cfg(feature = "quic")]
+    async fn tcp_pair() -> (Stream, Stream) {
+        use crate::tokio::net::{TcpListener, TcpStream};
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (a, b) = crate::tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (b, b_addr) = b.unwrap();
+        (Stream::from(a.unwrap(), addr), Stream::from(b, b_addr))
+    }
+
+    #[cfg(feature = "quic")]
+    #[tokio::test]
+    async fn test_quic_not_resumed() {
+        use crate::{
+            message_proto::{FileResponse, FileTransferBlock},
+            quic::QuicStream,
+            tokio::net::UdpSocket,
+        };
+        use std::sync::Arc;
+        let a = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let b = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+        let server = crate::tokio::spawn(QuicStream::accept(b, 3_000));
+        let client = QuicStream::connect(a, 3_000).await.unwrap();
+        let server = server.await.unwrap().unwrap();
+
+        let mut stream = ResumableStream::wrap(Stream::Quic(client));
+        let s = stream.as_resumable().unwrap();
+        assert!(s.enable(new_token(), true).await.is_none());
+        assert!(!s.is_enabled());
+
+        // A transfer over TCP, broken after the peer received the first block.
+        let (a, _b) = tcp_pair().await;
+        let mut stream = ResumableStream::wrap(a);
+        let s = stream.as_resumable().unwrap();
+        s.enable(new_token(), false).await;
+        let mut blocks = Vec::new();
+        for id in 0..3 {
+            let mut response = FileResponse::new();
+            response.set_block(FileTransferBlock {
+                id,
+                ..Default::default()
+            });
+            let mut msg = Message::new();
+            msg.set_file_response(response);
+            s.send(&msg).await.unwrap();
+            blocks.push(msg);
+        }
+        assert!(s.detach().is_some());
+        // Not continued over QUIC, whose lanes may deliver the replay out of order.
+        assert!(s.attach(Stream::Quic(server), 1).await.is_err());
+        let (a, mut b) = tcp_pair().await;
+        s.attach(a, 1).await.unwrap();
+        let bytes = b.next_timeout(3_000).await.unwrap().unwrap();
+        assert!(Message::parse_from_bytes(&bytes)
+            .unwrap()
+            .has_session_resume());
+        for msg in &blocks[1..] {
+            let bytes = b.next_timeout(3_000).await.unwrap().unwrap();
+            assert_eq!(&Message::parse_from_bytes(&bytes).unwrap(), msg);
+        }
+    }
+}
@@ -1,7 +1,9 @@
 #[cfg(feature = "webrtc")]
 use crate::webrtc::{self, is_webrtc_endpoint};
 use crate::{
-    config::{Config, NetworkType},
+    config::{Config, NetworkType, Socks5Server},
+    nat64,
+    proxy::{auto, Proxy, UdpRelay},
     tcp::FramedStream,
     udp::FramedSocket,
     websocket::{self, check_ws, is_ws_endpoint},
@@ -155,7 +157,7 @@ pub async fn connect_tcp_local<
     local: Option<SocketAddr>,
     ms_timeout: u64,
 ) -> ResultType<Stream> {
-    if let Some(conf) = Config::get_socks() {
+    if let Some(conf) = auto::get_socks_for_target(&target.to_string()).await {
         return Ok(Stream::Tcp(
             FramedStream::connect(target, local, &conf, ms_timeout).await?,
         ));
@@ -164,7 +166,10 @@ pub async fn connect_tcp_local<
     if let Some(target_addr) = target.resolve() {
         if let Some(local_addr) = local {
             if local_addr.is_ipv6() && target_addr.is_ipv4() {
-                let resolved_target = query_nip_io(target_addr).await?;
+                let resolved_target = match nat64::synthesize_addr(*target_addr).await {
+                    Some(addr) => addr,
+                    None => query_nip_io(target_addr).await?,
+                };
                 return Ok(Stream::Tcp(
                     FramedStream::new(resolved_target, Some(local_addr), ms_timeout).await?,
                 ));
@@ -172,6 +177,14 @@ pub async fn connect_tcp_local<
         }
     }
 
+    // IPv4 literals are only reachable through NAT64 on an IPv6-only host.
+    if !local.is_some_and(|x| x.is_ipv4()) && nat64::is_ipv6_only() {
+        let resolved_target = nat64::resolve(&target.to_string()).await?;
+        return Ok(Stream::Tcp(
+            FramedStream::new(resolved_target, local, ms_timeout).await?,
+        ));
+    }
+
     Ok(Stream::Tcp(
         FramedStream::new(target, local, ms_timeout).await?,
     ))
@@ -197,6 +210,14 @@ pub async fn query_nip_io(addr: &SocketAddr) -> ResultType<SocketAddr> {
 pub fn ipv4_to_ipv6(addr: String, ipv4: bool) -> String {
     if !ipv4 && crate::is_ipv4_str(&addr) {
         if let Some(ip) = addr.split(':').next() {
+            if let (Some(prefix), Ok(v4)) = (nat64::get_cached_prefix(), ip.parse()) {
+                let v6 = prefix.synthesize(v4);
+                return match addr.split_once(':') {
+                    Some((_, port)) => format!("[{v6}]:{port}"),
+                    None => v6.to_string(),
+                };
+            }
+            // Left to the DNS64 of the network.
             return addr.replace(ip, &format!("{ip}.nip.io"));
         }
     }
@@ -209,10 +230,7 @@ async fn test_target(target: &str) -> ResultType<SocketAddr> {
             return Ok(addr);
         }
     }
-    tokio::net::lookup_host(target)
-        .await?
-        .next()
-        .context(format!("Failed to look up host for {target}"))
+    nat64::resolve(target).await
 }
 
 #[inline]
@@ -223,44 +241,69 @@ pub async fn new_direct_udp_for(target: &str) -> ResultType<(Arc<UdpSocket>, Soc
     Ok((Arc::new(socket), peer_addr))
 }
 
+/// Like [`new_direct_udp_for`], but through the proxy configured for `target` if UDP can go
+/// through it, see [`UdpRelay`]. Use [`udp_peer_addr`] for the other peers of the socket.
+pub async fn new_punch_udp_for(
+    target: &str,
+    ms_timeout: u64,
+) -> ResultType<(Arc<UdpSocket>, SocketAddr)> {
+    let Some(conf) = auto::get_socks_for_target(target)
+        .await
+        .filter(|conf| crate::proxy::is_udp_capable(&conf.proxy))
+    else {
+        return new_direct_udp_for(target).await;
+    };
+    let peer_addr = tokio::net::lookup_host(target)
+        .await?
+        .find(|addr| addr.is_ipv4())
+        .context("Failed to resolve the target address")?;
+    let framed = Proxy::from_conf(&conf, Some(ms_timeout))?
+        .udp_associate()
+        .await?;
+    let socket = UdpRelay::bind(framed).await?;
+    let peer_addr = UdpRelay::peer_addr(&socket, peer_addr).await?;
+    Ok((socket, peer_addr))
+}
+
+/// The address `socket` has to send to or connect to for reaching `peer_addr`.
+#[inline]
+pub async fn udp_peer_addr(socket: &UdpSocket, peer_addr: SocketAddr) -> ResultType<SocketAddr> {
+    UdpRelay::peer_addr(socket, peer_addr).await
+}
+
 #[inline]
 pub async fn new_udp_for(
     target: &str,
     ms_timeout: u64,
 ) -> ResultType<(FramedSocket, TargetAddr<'static>)> {
-    let (ipv4, target) = if NetworkType::Direct == Config::get_network_type() {
+    let conf = auto::get_socks_for_target(target).await;
+    let (ipv4, target) = if conf.is_none() {
         let addr = test_target(target).await?;
         (addr.is_ipv4(), addr.into_target_addr()?)
     } else {
         (true, target.into_target_addr()?)
     };
     Ok((
-        new_udp(Config::get_any_listen_addr(ipv4), ms_timeout).await?,
+        new_udp(Config::get_any_listen_addr(ipv4), conf, ms_timeout).await?,
         target.to_owned(),
     ))
 }
 
-async fn new_udp<T: ToSocketAddrs>(local: T, ms_timeout: u64) -> ResultType<FramedSocket> {
-    match Config::get_socks() {
+async fn new_udp<T: ToSocketAddrs>(
+    local: T,
+    conf: Option<Socks5Server>,
+    ms_timeout: u64,
+) -> ResultType<FramedSocket> {
+    match conf {
         None => Ok(FramedSocket::new(local).await?),
-        Some(conf) => {
-            let socket = FramedSocket::new_proxy(
-                conf.proxy.as_str(),
-                local,
-                conf.username.as_str(),
-                conf.password.as_str(),
-                ms_timeout,
-            )
-            .await?;
-            Ok(socket)
-        }
+        Some(conf) => Ok(FramedSocket::new_proxy(&conf, ms_timeout).await?),
     }
 }
 
 pub async fn rebind_udp_for(
     target: &str,
 ) -> ResultType<Option<(FramedSocket, TargetAddr<'static>)>> {
-    if Config::get_network_type() != NetworkType::Direct {
+    if auto::get_socks_for_target(target).await.is_some() {
         return Ok(None);
     }
     let addr = test_target(target).await?;
@@ -1,16 +1,21 @@
-use crate::{config, tcp, websocket, ResultType};
+use crate::{config, resume, tcp, websocket, ResultType};
+#[cfg(feature = "quic")]
+use crate::quic;
 #[cfg(feature = "webrtc")]
 use crate::webrtc;
 use sodiumoxide::crypto::secretbox::Key;
 use std::net::SocketAddr;
 use tokio::net::TcpStream;
 
-// support Websocket and tcp.
+// support Websocket, tcp and quic.
 pub enum Stream {
     #[cfg(feature = "webrtc")]
     WebRTC(webrtc::WebRTCStream),
+    #[cfg(feature = "quic")]
+    Quic(quic::QuicStream),
     WebSocket(websocket::WsFramedStream),
     Tcp(tcp::FramedStream),
+    Resumable(Box<resume::ResumableStream>),
 }
 
 impl Stream {
@@ -19,7 +24,10 @@ impl Stream {
         match self {
             #[cfg(feature = "webrtc")]
             Stream::WebRTC(s) => s.set_send_timeout(ms),
+            #[cfg(feature = "quic")]
+            Stream::Quic(s) => s.set_send_timeout(ms),
             Stream::WebSocket(s) => s.set_send_timeout(ms),
+            Stream::Resumable(s) => s.set_send_timeout(ms),
             Stream::Tcp(s) => s.set_send_timeout(ms),
         }
     }
@@ -29,7 +37,10 @@ impl Stream {
         match self {
             #[cfg(feature = "webrtc")]
             Stream::WebRTC(s) => s.set_raw(),
+            #[cfg(feature = "quic")]
+            Stream::Quic(s) => s.set_raw(),
             Stream::WebSocket(s) => s.set_raw(),
+            Stream::Resumable(s) => s.set_raw(),
             Stream::Tcp(s) => s.set_raw(),
         }
     }
@@ -39,7 +50,10 @@ impl Stream {
         match self {
             #[cfg(feature = "webrtc")]
             Stream::WebRTC(s) => s.send_bytes(bytes).await,
+            #[cfg(feature = "quic")]
+            Stream::Quic(s) => s.send_bytes(bytes).await,
             Stream::WebSocket(s) => s.send_bytes(bytes).await,
+            Stream::Resumable(s) => Box::pin(s.send_bytes(bytes)).await,
             Stream::Tcp(s) => s.send_bytes(bytes).await,
         }
     }
@@ -49,7 +63,10 @@ impl Stream {
         match self {
             #[cfg(feature = "webrtc")]
             Stream::WebRTC(s) => s.send_raw(bytes).await,
+            #[cfg(feature = "quic")]
+            Stream::Quic(s) => s.send_raw(bytes).await,
             Stream::WebSocket(s) => s.send_raw(bytes).await,
+            Stream::Resumable(s) => Box::pin(s.send_raw(bytes)).await,
             Stream::Tcp(s) => s.send_raw(bytes).await,
         }
     }
@@ -59,7 +76,10 @@ impl Stream {
         match self {
             #[cfg(feature = "webrtc")]
             Stream::WebRTC(s) => s.set_key(key),
+            #[cfg(feature = "quic")]
+            Stream::Quic(s) => s.set_key(key),
             Stream::WebSocket(s) => s.set_key(key),
+            Stream::Resumable(s) => s.set_key(key),
             Stream::Tcp(s) => s.set_key(key),
         }
     }
@@ -69,7 +89,10 @@ impl Stream {
         match self {
             #[cfg(feature = "webrtc")]
             Stream::WebRTC(s) => s.is_secured(),
+            #[cfg(feature = "quic")]
+            Stream::Quic(s) => s.is_secured(),
             Stream::WebSocket(s) => s.is_secured(),
+            Stream::Resumable(s) => s.is_secured(),
             Stream::Tcp(s) => s.is_secured(),
         }
     }
@@ -82,7 +105,10 @@ impl Stream {
         match self {
             #[cfg(feature = "webrtc")]
             Stream::WebRTC(s) => s.next_timeout(timeout).await,
+            #[cfg(feature = "quic")]
+            Stream::Quic(s) => s.next_timeout(timeout).await,
             Stream::WebSocket(s) => s.next_timeout(timeout).await,
+            Stream::Resumable(s) => Box::pin(s.next_timeout(timeout)).await,
             Stream::Tcp(s) => s.next_timeout(timeout).await,
         }
     }
@@ -107,7 +133,10 @@ impl Stream {
         match self {
             #[cfg(feature = "webrtc")]
             Self::WebRTC(s) => s.send(msg).await,
+            #[cfg(feature = "quic")]
+            Self::Quic(s) => s.send(msg).await,
             Self::WebSocket(ws) => ws.send(msg).await,
+            Self::Resumable(s) => Box::pin(s.send(msg)).await,
             Self::Tcp(tcp) => tcp.send(msg).await,
         }
     }
@@ -118,7 +147,10 @@ impl Stream {
         match self {
             #[cfg(feature = "webrtc")]
             Self::WebRTC(s) => s.next().await,
+            #[cfg(feature = "quic")]
+            Self::Quic(s) => s.next().await,
             Self::WebSocket(ws) => ws.next().await,
+            Self::Resumable(s) => Box::pin(s.next()).await,
             Self::Tcp(tcp) => tcp.next().await,
         }
     }
@@ -128,7 +160,10 @@ impl Stream {
         match self {
             #[cfg(feature = "webrtc")]
             Self::WebRTC(s) => s.local_addr(),
+            #[cfg(feature = "quic")]
+            Self::Quic(s) => s.local_addr(),
             Self::WebSocket(ws) => ws.local_addr(),
+            Self::Resumable(s) => s.local_addr(),
             Self::Tcp(tcp) => tcp.local_addr(),
         }
     }
@@ -138,6 +173,25 @@ impl Stream {
         Self::Tcp(tcp::FramedStream::from(stream, stream_addr))
     }
 
+    /// Whether messages arrive in the order they were sent, not so on the lanes of QUIC.
+    #[inline]
+    pub fn is_ordered(&self) -> bool {
+        match self {
+            #[cfg(feature = "quic")]
+            Self::Quic(_) => false,
+            Self::Resumable(s) => s.is_ordered(),
+            _ => true,
+        }
+    }
+
+    #[inline]
+    pub fn as_resumable(&mut self) -> Option<&mut resume::ResumableStream> {
+        match self {
+            Self::Resumable(s) => Some(s),
+            _ => None,
+        }
+    }
+
     #[inline]
     #[cfg(feature = "webrtc")]
     pub fn get_webrtc_stream(&self) -> Option<webrtc::WebRTCStream> {
@@ -1,6 +1,11 @@
-use std::{collections::HashMap, sync::RwLock};
+use std::{collections::HashMap, convert::TryFrom, sync::RwLock};
 
-use crate::config::allow_insecure_tls_fallback;
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE},
+    Engine as _,
+};
+
+use crate::config::{allow_insecure_tls_fallback, keys, Config, EXE_TLS_PINS};
 
 #[derive(Debug, Clone, Copy)]
 pub enum TlsType {
@@ -12,8 +17,12 @@ pub enum TlsType {
 lazy_static::lazy_static! {
     static ref URL_TLS_TYPE: RwLock<HashMap<String, TlsType>> = RwLock::new(HashMap::new());
     static ref URL_TLS_DANGER_ACCEPT_INVALID_CERTS: RwLock<HashMap<String, bool>> = RwLock::new(HashMap::new());
+    // The raw option and what it parses to, to avoid decoding the pins on every handshake.
+    static ref TLS_PINS: RwLock<(String, TlsPins)> = Default::default();
 }
 
+pub type TlsPins = HashMap<String, Vec<[u8; 32]>>;
+
 #[inline]
 pub fn is_plain(url: &str) -> bool {
     url.starts_with("ws://") || url.starts_with("http://")
@@ -71,11 +80,110 @@ pub fn reset_tls_cache() {
     }
 }
 
+#[inline]
+fn strip_port(domain_port: &str) -> &str {
+    if let Some(rest) = domain_port.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match domain_port.rfind(':') {
+        // A bare IPv6 address has more than one colon.
+        Some(pos) if domain_port.find(':') == Some(pos) => &domain_port[..pos],
+        _ => domain_port,
+    }
+}
+
+/// Parses "host=pin,pin;host2=pin", a pin being the base64 SHA-256 of the DER encoded
+/// SubjectPublicKeyInfo, optionally prefixed by "sha256/" or "sha256//" as curl does.
+///
+/// A host whose pins are all invalid is kept with no pin, so it fails instead of
+/// silently going unpinned.
+pub fn parse_tls_pins(s: &str) -> TlsPins {
+    let mut res = TlsPins::new();
+    for entry in s.split(&[';', '\n'][..]) {
+        let Some((host, pins)) = entry.split_once('=') else {
+            if !entry.trim().is_empty() {
+                log::error!("Invalid TLS pin entry: {}", entry.trim());
+            }
+            continue;
+        };
+        let host = strip_port(host.trim()).to_lowercase();
+        if host.is_empty() {
+            continue;
+        }
+        let pins_of_host = res.entry(host).or_default();
+        for pin in pins.split(',') {
+            let pin = pin.trim();
+            if pin.is_empty() {
+                continue;
+            }
+            let b64 = pin.trim_start_matches("sha256/").trim_start_matches('/');
+            match STANDARD
+                .decode(b64)
+                .or_else(|_| URL_SAFE.decode(b64))
+                .ok()
+                .and_then(|v| <[u8; 32]>::try_from(v).ok())
+            {
+                Some(pin) => pins_of_host.push(pin),
+                None => log::error!("Invalid TLS pin: {}", pin),
+            }
+        }
+    }
+    res
+}
+
+// Pins bundled in a custom client take precedence over the option.
+#[inline]
+fn get_tls_pins_option() -> String {
+    let raw = EXE_TLS_PINS.read().unwrap().clone();
+    if raw.is_empty() {
+        Config::get_option(keys::OPTION_TLS_PINS)
+    } else {
+        raw
+    }
+}
+
+#[inline]
+pub fn has_tls_pins() -> bool {
+    !get_tls_pins_option().is_empty()
+}
+
+/// The SPKI pins of `host`, `None` if it is not pinned.
+pub fn get_tls_pins(host: &str) -> Option<Vec<[u8; 32]>> {
+    let raw = get_tls_pins_option();
+    if raw.is_empty() {
+        return None;
+    }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let host = host.strip_suffix('.').unwrap_or(host).to_lowercase();
+    {
+        let pins = TLS_PINS.read().unwrap();
+        if pins.0 == raw {
+            return pins.1.get(&host).cloned();
+        }
+    }
+    let parsed = parse_tls_pins(&raw);
+    let res = parsed.get(&host).cloned();
+    *TLS_PINS.write().unwrap() = (raw, parsed);
+    res
+}
+
+#[inline]
+pub fn is_tls_pinned(url: &str) -> bool {
+    if is_plain(url) {
+        return false;
+    }
+    get_tls_pins(strip_port(get_domain_and_port_from_url(url))).is_some()
+}
+
 #[inline]
 pub fn get_cached_tls_type(url: &str) -> Option<TlsType> {
     if is_plain(url) {
         return Some(TlsType::Plain);
     }
+    // Native TLS can't check pins or use the client certificate, never fall back to it.
+    if is_tls_pinned(url) || crate::client_cert::is_configured() {
+        return Some(TlsType::Rustls);
+    }
     let domain_port = get_domain_and_port_from_url(url);
     URL_TLS_TYPE.read().unwrap().get(domain_port).cloned()
 }
@@ -86,7 +194,7 @@ pub fn get_cached_tls_accept_invalid_cert(url: &str) -> Option<bool> {
         return Some(false);
     }
 
-    if is_plain(url) {
+    if is_plain(url) || is_tls_pinned(url) {
         return Some(false);
     }
     let domain_port = get_domain_and_port_from_url(url);
@@ -118,4 +226,23 @@ mod tests {
             assert_eq!(domain_port, expected_domain_port);
         }
     }
+
+    #[test]
+    fn test_parse_tls_pins() {
+        let pin = "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+        let pin_url_safe = "47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU=";
+        let pins = parse_tls_pins(&format!(
+            "Api.Example.com={pin}, sha256//{pin_url_safe};[::1]:21118=bad\nrs:443=sha256/{pin}"
+        ));
+        let expected = STANDARD.decode(pin).unwrap();
+        assert_eq!(pins.len(), 3);
+        assert_eq!(pins["api.example.com"].len(), 2);
+        assert!(pins["api.example.com"]
+            .iter()
+            .all(|p| p[..] == expected[..]));
+        assert!(pins["::1"].is_empty());
+        assert_eq!(pins["rs"].len(), 1);
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("::1"), "::1");
+    }
 }
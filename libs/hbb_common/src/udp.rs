@@ -1,4 +1,8 @@
-use crate::ResultType;
+use crate::{
+    config::Socks5Server,
+    proxy::{Proxy, Socks5UdpFramed},
+    ResultType,
+};
 use anyhow::{anyhow, Context};
 use bytes::{Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
@@ -6,7 +10,7 @@ use protobuf::Message;
 use socket2::{Domain, Socket, Type};
 use std::net::SocketAddr;
 use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
-use tokio_socks::{udp::Socks5UdpFramed, IntoTargetAddr, TargetAddr, ToProxyAddrs};
+use tokio_socks::{IntoTargetAddr, TargetAddr};
 use tokio_util::{codec::BytesCodec, udp::UdpFramed};
 
 pub enum FramedSocket {
@@ -65,26 +69,14 @@ impl FramedSocket {
         )))
     }
 
-    pub async fn new_proxy<'a, 't, P: ToProxyAddrs, T: ToSocketAddrs>(
-        proxy: P,
-        local: T,
-        username: &'a str,
-        password: &'a str,
-        ms_timeout: u64,
-    ) -> ResultType<Self> {
-        let framed = if username.trim().is_empty() {
-            super::timeout(ms_timeout, Socks5UdpFramed::connect(proxy, Some(local))).await??
-        } else {
-            super::timeout(
-                ms_timeout,
-                Socks5UdpFramed::connect_with_password(proxy, Some(local), username, password),
-            )
-            .await??
-        };
+    pub async fn new_proxy(conf: &Socks5Server, ms_timeout: u64) -> ResultType<Self> {
+        let framed = Proxy::from_conf(conf, Some(ms_timeout))?
+            .udp_associate()
+            .await?;
         log::trace!(
-            "Socks5 udp connected, local addr: {:?}, target addr: {}",
+            "Socks5 udp associated, local addr: {:?}, relay addr: {}",
             framed.local_addr(),
-            framed.socks_addr()
+            framed.relay_addr()
         );
         Ok(Self::ProxySocks(framed))
     }
@@ -103,7 +95,7 @@ impl FramedSocket {
                     f.send((send_data, addr)).await?
                 }
             }
-            Self::ProxySocks(f) => f.send((send_data, addr)).await?,
+            Self::ProxySocks(f) => f.send(send_data, &addr).await?,
         };
         Ok(())
     }
@@ -123,7 +115,7 @@ impl FramedSocket {
                     f.send((Bytes::from(msg), addr)).await?
                 }
             }
-            Self::ProxySocks(f) => f.send((Bytes::from(msg), addr)).await?,
+            Self::ProxySocks(f) => f.send(Bytes::from(msg), &addr).await?,
         };
         Ok(())
     }
@@ -138,11 +130,7 @@ impl FramedSocket {
                 Some(Err(e)) => Some(Err(anyhow!(e))),
                 None => None,
             },
-            Self::ProxySocks(f) => match f.next().await {
-                Some(Ok((data, _))) => Some(Ok((data.data, data.dst_addr))),
-                Some(Err(e)) => Some(Err(anyhow!(e))),
-                None => None,
-            },
+            Self::ProxySocks(f) => f.next().await,
         }
     }
 
@@ -1,7 +1,10 @@
-use crate::ResultType;
-use rustls_pki_types::{ServerName, UnixTime};
-use std::sync::Arc;
-use tokio_rustls::rustls::{self, client::WebPkiServerVerifier, ClientConfig};
+use crate::{client_cert::ClientCertResolver, ResultType};
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+use sha2::{Digest, Sha256};
+use std::{convert::TryFrom, sync::Arc};
+use tokio_rustls::rustls::{
+    self, client::WebPkiServerVerifier, crypto::CryptoProvider, ClientConfig, RootCertStore,
+};
 use tokio_rustls::rustls::{
     client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
     DigitallySignedStruct, Error as TLSError, SignatureScheme,
@@ -60,6 +63,233 @@ impl ServerCertVerifier for NoVerifier {
     }
 }
 
+/// Checks the SPKI pins of pinned servers, see `tls::get_tls_pins`, other servers go to `inner`.
+///
+/// A pin of the server's own key is enough, so that a self-signed server can be pinned.
+/// A pin of a CA key also needs `chain` to verify, otherwise anyone could send the CA
+/// certificate along with their own. The CA may be an intermediate or the trust anchor
+/// of any chain from the certificate to `roots`.
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    chain: Arc<dyn ServerCertVerifier>,
+    roots: Arc<RootCertStore>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedVerifier {
+    fn new(
+        inner: Arc<dyn ServerCertVerifier>,
+        chain: Arc<dyn ServerCertVerifier>,
+        roots: Arc<RootCertStore>,
+        provider: Arc<CryptoProvider>,
+    ) -> Self {
+        Self {
+            inner,
+            chain,
+            roots,
+            provider,
+        }
+    }
+
+    // Whether a chain from `end_entity` to one of `roots` has a pinned key.
+    fn has_pinned_chain(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+        pins: &[[u8; 32]],
+    ) -> bool {
+        let Ok(cert) = webpki::EndEntityCert::try_from(end_entity) else {
+            return false;
+        };
+        let is_pinned = |hash: Option<[u8; 32]>| hash.map(|h| pins.contains(&h)).unwrap_or(false);
+        // Called for every chain that is built, an error makes webpki try the next one.
+        let check = |path: &webpki::VerifiedPath<'_>| {
+            let anchor = der_wrap(SEQUENCE, &path.anchor().subject_public_key_info);
+            if is_pinned(Some(Sha256::digest(&anchor).into()))
+                || path
+                    .intermediate_certificates()
+                    .any(|cert| is_pinned(spki_sha256(&cert.der())))
+            {
+                Ok(())
+            } else {
+                Err(webpki::Error::UnknownIssuer)
+            }
+        };
+        cert.verify_for_usage(
+            self.provider.signature_verification_algorithms.all,
+            &self.roots.roots,
+            intermediates,
+            now,
+            webpki::KeyUsage::server_auth(),
+            None,
+            Some(&check),
+        )
+        .is_ok()
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, TLSError> {
+        let host = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_owned(),
+            ServerName::IpAddress(ip) => std::net::IpAddr::from(*ip).to_string(),
+            _ => String::new(),
+        };
+        let Some(pins) = crate::tls::get_tls_pins(&host) else {
+            return self.inner.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            );
+        };
+        let is_pinned = |cert: &CertificateDer<'_>| {
+            spki_sha256(cert)
+                .map(|hash| pins.contains(&hash))
+                .unwrap_or(false)
+        };
+        if is_pinned(end_entity) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        let verified = self.chain.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        if self.has_pinned_chain(end_entity, intermediates, now, &pins) {
+            return Ok(verified);
+        }
+        log::error!("The certificate of {} matches none of its pins", host);
+        Err(TLSError::General(format!(
+            "certificate pin mismatch for {}",
+            host
+        )))
+    }
+
+    // The handshake signature is always checked, a pinned certificate is public.
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TLSError> {
+        self.chain.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TLSError> {
+        self.chain.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.chain.supported_verify_schemes()
+    }
+}
+
+const SEQUENCE: u8 = 0x30;
+
+struct Der<'a> {
+    tag: u8,
+    element: &'a [u8],
+    content: &'a [u8],
+    rest: &'a [u8],
+}
+
+// The first DER element of `data`.
+fn der_next(data: &[u8]) -> Option<Der<'_>> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = data
+            .get(2..2 + n)?
+            .iter()
+            .fold(0usize, |len, b| (len << 8) | *b as usize);
+        (len, 2 + n)
+    };
+    let element = data.get(..header.checked_add(len)?)?;
+    Some(Der {
+        tag,
+        element,
+        content: &element[header..],
+        rest: &data[element.len()..],
+    })
+}
+
+// The DER element of `tag` with `content`.
+fn der_wrap(tag: u8, content: &[u8]) -> Vec<u8> {
+    let len = content.len().to_be_bytes();
+    let len = &len[len.iter().position(|b| *b != 0).unwrap_or(len.len() - 1)..];
+    let mut element = vec![tag];
+    if content.len() < 0x80 {
+        element.push(content.len() as u8);
+    } else {
+        element.push(0x80 | len.len() as u8);
+        element.extend_from_slice(len);
+    }
+    element.extend_from_slice(content);
+    element
+}
+
+/// SHA-256 of the DER encoded SubjectPublicKeyInfo of an X.509 certificate.
+pub fn spki_sha256(cert: &[u8]) -> Option<[u8; 32]> {
+    const VERSION: u8 = 0xa0;
+    let Der {
+        tag: SEQUENCE,
+        content: cert,
+        ..
+    } = der_next(cert)?
+    else {
+        return None;
+    };
+    let Der {
+        tag: SEQUENCE,
+        content: tbs,
+        ..
+    } = der_next(cert)?
+    else {
+        return None;
+    };
+    let mut rest = tbs;
+    if rest.first() == Some(&VERSION) {
+        rest = der_next(rest)?.rest;
+    }
+    // serialNumber, signature, issuer, validity and subject
+    for _ in 0..5 {
+        rest = der_next(rest)?.rest;
+    }
+    let Der {
+        tag: SEQUENCE,
+        element: spki,
+        ..
+    } = der_next(rest)?
+    else {
+        return None;
+    };
+    Some(Sha256::digest(spki).into())
+}
+
 /// A certificate verifier that tries a primary verifier first,
 /// and falls back to a platform verifier if the primary fails.
 #[cfg(any(target_os = "android", target_os = "ios"))]
@@ -162,9 +392,7 @@ impl ServerCertVerifier for FallbackPlatformVerifier {
     }
 }
 
-fn webpki_server_verifier(
-    provider: Arc<rustls::crypto::CryptoProvider>,
-) -> ResultType<Arc<WebPkiServerVerifier>> {
+fn root_cert_store() -> Arc<RootCertStore> {
     // Load root certificates from both bundled webpki_roots and system-native certificate stores.
     // This approach is consistent with how reqwest and tokio-tungstenite handle root certificates.
     // https://github.com/snapview/tokio-tungstenite/blob/35d110c24c9d030d1608ec964d70c789dfb27452/src/tls.rs#L95
@@ -177,7 +405,13 @@ fn webpki_server_verifier(
         log::warn!("native root CA certificate loading errors: {errors:?}");
     }
     root_cert_store.add_parsable_certificates(certs);
+    Arc::new(root_cert_store)
+}
 
+fn webpki_server_verifier(
+    roots: Arc<RootCertStore>,
+    provider: Arc<CryptoProvider>,
+) -> ResultType<Arc<WebPkiServerVerifier>> {
     // Build verifier using with_root_certificates behavior (WebPkiServerVerifier without CRLs).
     // Both reqwest and tokio-tungstenite use this approach.
     // https://github.com/seanmonstar/reqwest/blob/b126ca49da7897e5d676639cdbf67a0f6838b586/src/async_impl/client.rs#L749
@@ -190,13 +424,10 @@ fn webpki_server_verifier(
     // to match the behavior of with_root_certificates, which allows unknown revocation status by default.
     // https://github.com/rustls/rustls/blob/1ee126adb3352a2dcd72420dcd6040351a6ddc1e/rustls/src/webpki/server_verifier.rs#L37
     // Note: build() only returns an error if the root certificate store is empty, which won't happen here.
-    let verifier = rustls::client::WebPkiServerVerifier::builder_with_provider(
-        Arc::new(root_cert_store),
-        provider.clone(),
-    )
-    .allow_unknown_revocation_status()
-    .build()
-    .map_err(|e| anyhow::anyhow!(e))?;
+    let verifier = rustls::client::WebPkiServerVerifier::builder_with_provider(roots, provider)
+        .allow_unknown_revocation_status()
+        .build()
+        .map_err(|e| anyhow::anyhow!(e))?;
     Ok(verifier)
 }
 
@@ -216,15 +447,30 @@ pub fn client_config_safe() -> ResultType<ClientConfig> {
     // https://github.com/snapview/tokio-tungstenite/blob/35d110c24c9d030d1608ec964d70c789dfb27452/src/tls.rs#L126
     let config_builder = rustls::ClientConfig::builder();
     let provider = config_builder.crypto_provider().clone();
-    let webpki_verifier = webpki_server_verifier(provider.clone())?;
+    let roots = root_cert_store();
+    let webpki_verifier = webpki_server_verifier(roots.clone(), provider.clone())?;
     #[cfg(any(target_os = "android", target_os = "ios"))]
     {
-        match FallbackPlatformVerifier::with_platform_fallback(webpki_verifier.clone(), provider) {
+        match FallbackPlatformVerifier::with_platform_fallback(
+            webpki_verifier.clone(),
+            provider.clone(),
+        ) {
             Ok(fallback_verifier) => {
+                let fallback_verifier: Arc<dyn ServerCertVerifier> = Arc::new(fallback_verifier);
+                let verifier: Arc<dyn ServerCertVerifier> = if crate::tls::has_tls_pins() {
+                    Arc::new(PinnedVerifier::new(
+                        fallback_verifier.clone(),
+                        fallback_verifier,
+                        roots,
+                        provider,
+                    ))
+                } else {
+                    fallback_verifier
+                };
                 let config = config_builder
                     .dangerous()
-                    .with_custom_certificate_verifier(Arc::new(fallback_verifier))
-                    .with_no_client_auth();
+                    .with_custom_certificate_verifier(verifier)
+                    .with_client_cert_resolver(Arc::new(ClientCertResolver));
                 Ok(config)
             }
             Err(e) => {
@@ -232,26 +478,158 @@ pub fn client_config_safe() -> ResultType<ClientConfig> {
                     "Failed to create fallback verifier: {:?}, use webpki verifier instead",
                     e
                 );
-                let config = config_builder
-                    .with_webpki_verifier(webpki_verifier)
-                    .with_no_client_auth();
+                let config = if crate::tls::has_tls_pins() {
+                    config_builder
+                        .dangerous()
+                        .with_custom_certificate_verifier(Arc::new(PinnedVerifier::new(
+                            webpki_verifier.clone(),
+                            webpki_verifier,
+                            roots,
+                            provider,
+                        )))
+                        .with_client_cert_resolver(Arc::new(ClientCertResolver))
+                } else {
+                    config_builder
+                        .with_webpki_verifier(webpki_verifier)
+                        .with_client_cert_resolver(Arc::new(ClientCertResolver))
+                };
                 Ok(config)
             }
         }
     }
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     {
-        let config = config_builder
-            .with_webpki_verifier(webpki_verifier)
-            .with_no_client_auth();
+        let config = if crate::tls::has_tls_pins() {
+            config_builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier::new(
+                    webpki_verifier.clone(),
+                    webpki_verifier,
+                    roots,
+                    provider,
+                )))
+                .with_client_cert_resolver(Arc::new(ClientCertResolver))
+        } else {
+            config_builder
+                .with_webpki_verifier(webpki_verifier)
+                .with_client_cert_resolver(Arc::new(ClientCertResolver))
+        };
         Ok(config)
     }
 }
 
+/// Fails every handshake, for when the pins of a server can't be checked.
+pub fn client_config_reject() -> ClientConfig {
+    ClientConfig::builder()
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth()
+}
+
+/// Accepts any certificate, except from pinned servers which must match their pins.
 pub fn client_config_danger() -> ResultType<ClientConfig> {
-    let config = ClientConfig::builder()
+    let config_builder = ClientConfig::builder();
+    let verifier: Arc<dyn ServerCertVerifier> = if crate::tls::has_tls_pins() {
+        let provider = config_builder.crypto_provider().clone();
+        let roots = root_cert_store();
+        Arc::new(PinnedVerifier::new(
+            Arc::new(NoVerifier),
+            webpki_server_verifier(roots.clone(), provider.clone())?,
+            roots,
+            provider,
+        ))
+    } else {
+        Arc::new(NoVerifier)
+    };
+    let config = config_builder
         .dangerous()
-        .with_custom_certificate_verifier(Arc::new(NoVerifier))
-        .with_no_client_auth();
+        .with_custom_certificate_verifier(verifier)
+        .with_client_cert_resolver(Arc::new(ClientCertResolver));
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_spki_sha256() {
+        // openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -subj "/CN=pin.test"
+        let cert = STANDARD
+            .decode(
+                "MIIBfTCCASOgAwIBAgIUdwt9e365QpbpwOSpZYvQBL9C3AowCgYIKoZIzj0EAwIwEzERMA8GA1UEAwwIcGlu\
+                 LnRlc3QwIBcNMjYxMDE4MDYxNjE5WhgPMjEyNjA5MjQwNjE2MTlaMBMxETAPBgNVBAMMCHBpbi50ZXN0MFkw\
+                 EwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEcKUDrPsGLMkRoT3PiOZos/MJfxIq0wwVsxQixaC1iH7TNR7MhfV7\
+                 rUXxairaaGzA9spqDSZx8eb2nmi7SwTqa6NTMFEwHQYDVR0OBBYEFPbUApAjx5587zWG8vaqbtH2OkWDMB8G\
+                 A1UdIwQYMBaAFPbUApAjx5587zWG8vaqbtH2OkWDMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSAAw\
+                 RQIhANieuWzgV9VYgheJfKakyRlPQBWR+CpPohPxmWKY/jVTAiBR1tLVfykqso/GTR3N1R95wt7SeawDYibu\
+                 tgPBJGJhIw==",
+            )
+            .unwrap();
+        // openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary
+        let pin = STANDARD
+            .decode("DAprDyytOwtSKjuiyT1svySnqmGMeehAYkmyy7/3+Hk=")
+            .unwrap();
+        assert_eq!(spki_sha256(&cert).unwrap()[..], pin[..]);
+        assert_eq!(spki_sha256(&cert[..cert.len() / 2]), None);
+        assert_eq!(spki_sha256(&[]), None);
+    }
+
+    #[test]
+    fn test_pinned_anchor() {
+        // A CA, and a certificate of pin.test issued by it.
+        let ca = STANDARD
+            .decode(
+                "MIIBfDCCASOgAwIBAgIUMMULHgBsmgxTYSFtqIjL3tKp46swCgYIKoZIzj0EAwIwEzERMA8GA1UEAwwIcGlu\
+                 IHJvb3QwIBcNMjYxMDE4MDkyMjQzWhgPMjEyNjA5MjQwOTIyNDNaMBMxETAPBgNVBAMMCHBpbiByb290MFkw\
+                 EwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE5L1g4YUzH40xi2iqH0IBpYNElgLmPScxqEfQqfO1V9Pqz9rDI9H6\
+                 3PqXot1OHVU/ZD7ziR+mwxGhrtf8GxgWJ6NTMFEwHQYDVR0OBBYEFLOYgKEPHLydkVhHC11ryi00M/RiMB8G\
+                 A1UdIwQYMBaAFLOYgKEPHLydkVhHC11ryi00M/RiMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDRwAw\
+                 RAIgBmfEOFWuw0zUKTqElW7uQjYdPfJqUmAT8eMmqVwly2cCIDL3fPiAh6qXbJp6Om3uis4zLWmvis8lS4VV\
+                 0uNmoVD4",
+            )
+            .unwrap();
+        let leaf = STANDARD
+            .decode(
+                "MIIBojCCAUegAwIBAgIUZUbM4QYIwvUtjZ3e+qQJ4SyxPlcwCgYIKoZIzj0EAwIwEzERMA8GA1UEAwwIcGlu\
+                 IHJvb3QwIBcNMjYxMDE4MDkyMjQzWhgPMjEyNjA5MjQwOTIyNDNaMBMxETAPBgNVBAMMCHBpbi50ZXN0MFkw\
+                 EwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE94l/NPo3OYI0aNU/a94yGx2FbQObTNwhlTr6NDp4aGBaSL2qrZFK\
+                 +AtLk59vV2FWUl+qX9wfyLq3Cm/yT/Yo2aN3MHUwEwYDVR0RBAwwCoIIcGluLnRlc3QwEwYDVR0lBAwwCgYI\
+                 KwYBBQUHAwEwCQYDVR0TBAIwADAdBgNVHQ4EFgQUlAMyT7WP/EHSiTRDxgBNQHgVuc8wHwYDVR0jBBgwFoAU\
+                 s5iAoQ8cvJ2RWEcLXWvKLTQz9GIwCgYIKoZIzj0EAwIDSQAwRgIhAIYkslDAQaWU/EluoenhoILL03PizeDK\
+                 5OUOrKfB8GoRAiEAwooYWFpBTZHpKOmUhMDxpr5FH9dS0xedkHO6zR5nh7U=",
+            )
+            .unwrap();
+        let ca_pin: [u8; 32] = STANDARD
+            .decode("4el3s2es8JxDIkMIXKzK3l3CgQTADMS+Z/bk+5L3K9I=")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(spki_sha256(&ca), Some(ca_pin));
+        let leaf_pin = spki_sha256(&leaf).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from(ca)).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = PinnedVerifier::new(
+            Arc::new(NoVerifier),
+            webpki_server_verifier(Arc::new(roots.clone()), provider.clone()).unwrap(),
+            Arc::new(roots),
+            provider,
+        );
+        let leaf = CertificateDer::from(leaf);
+        let now = UnixTime::since_unix_epoch(std::time::Duration::from_secs(1_800_000_000));
+        // The CA is the trust anchor, it is never sent by the server.
+        assert!(verifier.has_pinned_chain(&leaf, &[], now, &[ca_pin]));
+        // The server's own key is not on the chain above it.
+        assert!(!verifier.has_pinned_chain(&leaf, &[], now, &[leaf_pin]));
+        assert!(!verifier.has_pinned_chain(&leaf, &[], now, &[[0; 32]]));
+        // No chain to the roots at all.
+        let verifier = PinnedVerifier {
+            roots: Arc::new(RootCertStore::empty()),
+            ..verifier
+        };
+        assert!(!verifier.has_pinned_chain(&leaf, &[], now, &[ca_pin]));
+    }
+}
@@ -3,13 +3,14 @@ use crate::{
         keys::OPTION_RELAY_SERVER, use_ws, Config, Socks5Server, RELAY_PORT, RENDEZVOUS_PORT,
     },
     protobuf::Message,
+    proxy::auto,
     socket_client::split_host_port,
     sodiumoxide::crypto::secretbox::Key,
-    tcp::Encrypt,
+    tcp::{DynTcpStream, Encrypt, FramedStream},
     tls::{get_cached_tls_accept_invalid_cert, get_cached_tls_type, upsert_tls_cache, TlsType},
     ResultType,
 };
-use anyhow::bail;
+use anyhow::{bail, Context};
 use async_recursion::async_recursion;
 use bytes::{Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
@@ -22,14 +23,14 @@ use std::{
 use tokio::{net::TcpStream, time::timeout};
 use tokio_native_tls::native_tls::TlsConnector;
 use tokio_tungstenite::{
-    connect_async_tls_with_config, tungstenite::protocol::Message as WsMessage, Connector,
+    client_async_tls_with_config, tungstenite::protocol::Message as WsMessage, Connector,
     MaybeTlsStream, WebSocketStream,
 };
 use tungstenite::client::IntoClientRequest;
 use tungstenite::protocol::Role;
 
 pub struct WsFramedStream {
-    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    stream: WebSocketStream<MaybeTlsStream<DynTcpStream>>,
     addr: SocketAddr,
     encrypt: Option<Encrypt>,
     send_timeout: u64,
@@ -52,6 +53,8 @@ impl WsFramedStream {
             TlsType::Rustls => {
                 let connector = match crate::verifier::client_config(danger_accept_invalid_certs) {
                     Ok(client_config) => Some(Connector::Rustls(Arc::new(client_config))),
+                    // The default connector can't check pins.
+                    Err(e) if crate::tls::has_tls_pins() => return Err(e),
                     Err(e) => {
                         log::warn!(
                             "Failed to get client config: {:?}, fallback to default connector",
@@ -65,18 +68,47 @@ impl WsFramedStream {
         }
     }
 
-    async fn connect(
+    // The TCP connection to the host of `url`, through the proxy if there is one.
+    async fn connect_tcp(
         url: &str,
+        proxy_conf: Option<&Socks5Server>,
         ms_timeout: u64,
-    ) -> ResultType<WebSocketStream<MaybeTlsStream<TcpStream>>> {
-        // to-do: websocket proxy.
+    ) -> ResultType<(DynTcpStream, SocketAddr)> {
+        let parsed = url::Url::parse(url)?;
+        let host = parsed
+            .host_str()
+            .context("No host in the websocket url")?
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = parsed
+            .port_or_known_default()
+            .context("No port in the websocket url")?;
+        if let Some(conf) = proxy_conf {
+            let stream = FramedStream::connect((host, port), None, conf, ms_timeout).await?;
+            let addr = stream.local_addr();
+            return Ok((stream.0.into_inner(), addr));
+        }
+        let stream = timeout(
+            Duration::from_millis(ms_timeout),
+            TcpStream::connect((host, port)),
+        )
+        .await??;
+        let addr = stream.peer_addr()?;
+        Ok((DynTcpStream(Box::new(stream)), addr))
+    }
 
+    async fn connect(
+        url: &str,
+        proxy_conf: Option<&Socks5Server>,
+        ms_timeout: u64,
+    ) -> ResultType<(WebSocketStream<MaybeTlsStream<DynTcpStream>>, SocketAddr)> {
         let tls_type = get_cached_tls_type(url);
         let is_tls_type_cached = tls_type.is_some();
         let tls_type = tls_type.unwrap_or(TlsType::Rustls);
         let danger_accept_invalid_cert = get_cached_tls_accept_invalid_cert(&url);
         Self::try_connect(
             url,
+            proxy_conf,
             ms_timeout,
             tls_type,
             is_tls_type_cached,
@@ -89,28 +121,35 @@ impl WsFramedStream {
     #[async_recursion]
     async fn try_connect(
         url: &str,
+        proxy_conf: Option<&Socks5Server>,
         ms_timeout: u64,
         tls_type: TlsType,
         is_tls_type_cached: bool,
         danger_accept_invalid_cert: Option<bool>,
         original_danger_accept_invalid_certs: Option<bool>,
-    ) -> ResultType<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    ) -> ResultType<(WebSocketStream<MaybeTlsStream<DynTcpStream>>, SocketAddr)> {
         let ws_config = None;
-        let disable_nagle = false;
         let request = url
             .into_client_request()
             .map_err(|e| Error::new(ErrorKind::Other, e))?;
         let connector =
             Self::get_connector(&tls_type, danger_accept_invalid_cert.unwrap_or(false))?;
+        let (stream, addr) = Self::connect_tcp(url, proxy_conf, ms_timeout).await?;
         match timeout(
             Duration::from_millis(ms_timeout),
-            connect_async_tls_with_config(request, ws_config, disable_nagle, connector),
+            client_async_tls_with_config(request, stream, ws_config, connector),
         )
         .await?
         {
             Ok((ws_stream, _)) => {
                 upsert_tls_cache(url, tls_type, danger_accept_invalid_cert.unwrap_or(false));
-                Ok(ws_stream)
+                Ok((ws_stream, addr))
+            }
+            // No other TLS setting can help then.
+            Err(e) if crate::client_cert::describe_rejection(&e).is_some() => {
+                let msg = crate::client_cert::describe_rejection(&e).unwrap_or_default();
+                log::error!("WebSocket connection failed: {}, {}, {:?}", url, msg, e);
+                bail!(msg)
             }
             Err(e) => match (tls_type, is_tls_type_cached, danger_accept_invalid_cert) {
                 (TlsType::Rustls, _, None) => {
@@ -121,6 +160,7 @@ impl WsFramedStream {
                         );
                     Self::try_connect(
                         url,
+                        proxy_conf,
                         ms_timeout,
                         tls_type,
                         is_tls_type_cached,
@@ -137,6 +177,7 @@ impl WsFramedStream {
                     );
                     Self::try_connect(
                         url,
+                        proxy_conf,
                         ms_timeout,
                         TlsType::NativeTls,
                         is_tls_type_cached,
@@ -153,6 +194,7 @@ impl WsFramedStream {
                         );
                     Self::try_connect(
                         url,
+                        proxy_conf,
                         ms_timeout,
                         tls_type,
                         is_tls_type_cached,
@@ -174,19 +216,19 @@ impl WsFramedStream {
         }
     }
 
+    /// Without `proxy_conf`, the proxy set manually or detected for `url` is used.
     pub async fn new<T: AsRef<str>>(
         url: T,
         _local_addr: Option<SocketAddr>,
-        _proxy_conf: Option<&Socks5Server>,
+        proxy_conf: Option<&Socks5Server>,
         ms_timeout: u64,
     ) -> ResultType<Self> {
-        let stream = Self::connect(url.as_ref(), ms_timeout).await?;
-        let addr = match stream.get_ref() {
-            MaybeTlsStream::Plain(tcp) => tcp.peer_addr()?,
-            MaybeTlsStream::NativeTls(tls) => tls.get_ref().get_ref().get_ref().peer_addr()?,
-            MaybeTlsStream::Rustls(tls) => tls.get_ref().0.peer_addr()?,
-            _ => return Err(Error::new(ErrorKind::Other, "Unsupported stream type").into()),
+        let url = url.as_ref();
+        let proxy_conf = match proxy_conf {
+            Some(conf) => Some(conf.clone()),
+            None => auto::get_socks_for(url).await,
         };
+        let (stream, addr) = Self::connect(url, proxy_conf.as_ref(), ms_timeout).await?;
 
         let ws = Self {
             stream,
@@ -205,9 +247,12 @@ impl WsFramedStream {
 
     #[inline]
     pub async fn from_tcp_stream(stream: TcpStream, addr: SocketAddr) -> ResultType<Self> {
-        let ws_stream =
-            WebSocketStream::from_raw_socket(MaybeTlsStream::Plain(stream), Role::Client, None)
-                .await;
+        let ws_stream = WebSocketStream::from_raw_socket(
+            MaybeTlsStream::Plain(DynTcpStream(Box::new(stream))),
+            Role::Client,
+            None,
+        )
+        .await;
 
         Ok(Self {
             stream: ws_stream,
@@ -22,7 +22,7 @@ pub struct Session {
 }
 
 impl Session {
-    pub fn new(id: &str, sender: mpsc::UnboundedSender<Data>) -> Self {
+    pub fn new(id: &str, conn_type: ConnType, sender: mpsc::UnboundedSender<Data>) -> Self {
         let mut password = "".to_owned();
         if PeerConfig::load(id).password.is_empty() {
             password = rpassword::prompt_password("Enter password: ").unwrap();
@@ -33,14 +33,11 @@ impl Session {
             password,
             lc: Default::default(),
         };
-        session.lc.write().unwrap().initialize(
-            id.to_owned(),
-            ConnType::PORT_FORWARD,
-            None,
-            false,
-            None,
-            None,
-        );
+        session
+            .lc
+            .write()
+            .unwrap()
+            .initialize(id.to_owned(), conn_type, None, false, None, None);
         session
     }
 }
@@ -126,7 +123,7 @@ impl Interface for Session {
 #[tokio::main(flavor = "current_thread")]
 pub async fn connect_test(id: &str, key: String, token: String) {
     let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
-    let handler = Session::new(&id, sender);
+    let handler = Session::new(&id, ConnType::PORT_FORWARD, sender);
     match crate::client::Client::start(id, &key, &token, ConnType::PORT_FORWARD, handler).await {
         Err(err) => {
             log::error!("Failed to connect {}: {}", &id, err);
@@ -172,7 +169,7 @@ pub async fn start_one_port_forward(
     crate::common::test_rendezvous_server();
     crate::common::test_nat_type();
     let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
-    let handler = Session::new(&id, sender);
+    let handler = Session::new(&id, ConnType::PORT_FORWARD, sender);
     if let Err(err) = crate::port_forward::listen(
         handler.id.clone(),
         handler.password.clone(),
@@ -191,3 +188,26 @@ pub async fn start_one_port_forward(
     }
     log::info!("port forward (:{}) exit", port);
 }
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn start_one_ssh_bridge(id: String, port: i32, key: String, token: String) {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = Session::new(&id, ConnType::TERMINAL, sender);
+    if let Err(err) = crate::ssh_bridge::listen(
+        handler.id.clone(),
+        handler.password.clone(),
+        port,
+        handler.clone(),
+        receiver,
+        &key,
+        &token,
+        handler.lc.clone(),
+    )
+    .await
+    {
+        log::error!("Failed to listen on {}: {}", port, err);
+    }
+    log::info!("ssh bridge (:{}) exit", port);
+}
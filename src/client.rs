@@ -53,12 +53,12 @@ use hbb_common::{
     fs::JobType,
     futures::future::{select_ok, FutureExt},
     get_version_number, log,
-    message_proto::{option_message::BoolOption, *},
+    message_proto::{file_transfer_send_request::Archive, option_message::BoolOption, *},
     protobuf::{Message as _, MessageField},
     rand,
     rendezvous_proto::*,
     sha2::{Digest, Sha256},
-    socket_client::{connect_tcp, connect_tcp_local, ipv4_to_ipv6, new_direct_udp_for},
+    socket_client::{self, connect_tcp, connect_tcp_local, ipv4_to_ipv6, new_punch_udp_for},
     sodiumoxide::{base64, crypto::sign},
     timeout,
     tokio::{
@@ -229,6 +229,58 @@ impl Client {
         }
     }
 
+    /// Connect again after the transport of a session was lost, and continue the session
+    /// identified by `session_token` instead of logging in.
+    ///
+    /// Returns the new stream, the direct flag, the KCP stream to keep alive and the number
+    /// of messages the peer received, see [`hbb_common::resume`].
+    pub async fn resume(
+        peer: &str,
+        key: &str,
+        token: &str,
+        conn_type: ConnType,
+        interface: impl Interface,
+        session_token: &[u8],
+        received: u64,
+    ) -> ResultType<(Stream, bool, Option<KcpStream>, u64)> {
+        let ((mut stream, direct, _pk, kcp, _stream_type), _) =
+            Self::start(peer, key, token, conn_type, interface).await?;
+        if !stream.is_ordered() {
+            bail!("Sessions are not resumed over QUIC");
+        }
+        loop {
+            let Some(res) = stream.next_timeout(READ_TIMEOUT).await else {
+                bail!("Timeout");
+            };
+            let msg_in = Message::parse_from_bytes(&res?)?;
+            match msg_in.union {
+                Some(message::Union::Hash(_)) => {
+                    let mut msg_out = Message::new();
+                    msg_out.set_login_request(LoginRequest {
+                        my_id: Config::get_id(),
+                        resume: Some(SessionResume {
+                            token: session_token.to_vec().into(),
+                            received,
+                            ..Default::default()
+                        })
+                        .into(),
+                        ..Default::default()
+                    });
+                    stream.send(&msg_out).await?;
+                }
+                Some(message::Union::LoginResponse(lr)) => {
+                    if let Some(login_response::Union::Error(err)) = lr.union {
+                        bail!(err);
+                    }
+                }
+                Some(message::Union::SessionResume(r)) => {
+                    return Ok((stream, direct, kcp, r.received));
+                }
+                _ => {}
+            }
+        }
+    }
+
     /// Start a new connection.
     async fn _start(
         peer: &str,
@@ -250,6 +302,8 @@ impl Client {
         if config::is_incoming_only() {
             bail!("Incoming only mode");
         }
+        #[cfg(feature = "webrtc")]
+        allow_err!(crate::hbbs_http::turn::refresh_credentials().await);
         // to-do: remember the port for each peer, so that we can retry easier
         if hbb_common::is_ip_str(peer) {
             return Ok((
@@ -308,18 +362,21 @@ impl Client {
         }
 
         let (stop_udp_tx, stop_udp_rx) = oneshot::channel::<()>();
+        let mut udp_test = None;
         let udp =
         // no need to care about multiple rendezvous servers case, since it is acutally not used any more.
         // Shared state for UDP NAT test result
         if crate::get_udp_punch_enabled() && !interface.is_force_relay() {
-            if let Ok((socket, addr)) = new_direct_udp_for(&rendezvous_server).await {
+            if let Ok((socket, addr)) =
+                new_punch_udp_for(&rendezvous_server, CONNECT_TIMEOUT).await
+            {
                 let udp_port = Arc::new(Mutex::new(0));
                 let up_cloned = udp_port.clone();
                 let socket_cloned = socket.clone();
                 let func = async move {
                     allow_err!(test_udp_uat(socket_cloned, addr, up_cloned, stop_udp_rx).await);
                 };
-                tokio::spawn(func);
+                udp_test = Some(tokio::spawn(func));
                 (Some(socket), Some(udp_port))
             } else {
                 (None, None)
@@ -334,7 +391,7 @@ impl Client {
             conn_type,
             interface.clone(),
             udp.clone(),
-            Some(stop_udp_tx),
+            udp_test.map(|task| (stop_udp_tx, task)),
             rendezvous_server.clone(),
             servers.clone(),
             contained,
@@ -370,7 +427,7 @@ impl Client {
         conn_type: ConnType,
         interface: impl Interface,
         mut udp: (Option<Arc<UdpSocket>>, Option<Arc<Mutex<u16>>>),
-        stop_udp_tx: Option<oneshot::Sender<()>>,
+        stop_udp: Option<(oneshot::Sender<()>, tokio::task::JoinHandle<()>)>,
         mut rendezvous_server: String,
         servers: Vec<String>,
         contained: bool,
@@ -414,6 +471,8 @@ impl Client {
         let my_nat_type = crate::get_nat_type(100).await;
         let mut is_local = false;
         let mut feedback = 0;
+        let mut quic = false;
+        let mut kcp_fec = false;
         use hbb_common::protobuf::Enum;
         let nat_type = if interface.is_force_relay() {
             NatType::SYMMETRIC
@@ -440,8 +499,12 @@ impl Client {
                 hbb_common::sleep(0.001).await;
             }
         }
-        // Stop UDP NAT test task if still running
-        stop_udp_tx.map(|tx| tx.send(()));
+        // Stop UDP NAT test task if still running, and wait for it. It reads the socket QUIC
+        // takes over, which must get all that comes in.
+        if let Some((tx, task)) = stop_udp {
+            tx.send(()).ok();
+            task.await.ok();
+        }
         let mut msg_out = RendezvousMessage::new();
         let mut ipv6 = if crate::get_ipv6_punch_enabled() {
             if let Some((socket, addr)) = crate::get_ipv6_socket().await {
@@ -464,6 +527,8 @@ impl Client {
             udp_port: udp_nat_port as _,
             force_relay: interface.is_force_relay(),
             socket_addr_v6: ipv6.1.unwrap_or_default(),
+            quic: crate::get_quic_enabled(),
+            kcp_fec: crate::kcp_stream::is_fec_enabled(),
             ..Default::default()
         });
         for i in 1..=3 {
@@ -507,10 +572,15 @@ impl Client {
                             relay_server = ph.relay_server;
                             peer_addr = AddrMangle::decode(&ph.socket_addr);
                             feedback = ph.feedback;
+                            quic = ph.quic;
+                            kcp_fec = ph.kcp_fec;
                             let s = udp.0.take();
                             if ph.is_udp && s.is_some() {
                                 if let Some(s) = s {
-                                    allow_err!(s.connect(peer_addr).await);
+                                    match socket_client::udp_peer_addr(&s, peer_addr).await {
+                                        Ok(addr) => allow_err!(s.connect(addr).await),
+                                        Err(err) => log::warn!("{}", err),
+                                    }
                                     udp.0 = Some(s);
                                 }
                             }
@@ -540,8 +610,16 @@ impl Client {
                             let addr = AddrMangle::decode(&rr.socket_addr_v6);
                             if addr.port() > 0 {
                                 if s.connect(addr).await.is_ok() {
-                                    connect_futures
-                                        .push(udp_nat_connect(s, "IPv6", CONNECT_TIMEOUT).boxed());
+                                    connect_futures.push(
+                                        udp_nat_connect(
+                                            s,
+                                            "IPv6",
+                                            CONNECT_TIMEOUT,
+                                            rr.quic,
+                                            rr.kcp_fec,
+                                        )
+                                        .boxed(),
+                                    );
                                 }
                             }
                         }
@@ -573,7 +651,7 @@ impl Client {
                         let pk =
                             Self::secure_connection(&peer, signed_id_pk, &key, &mut conn).await?;
                         return Ok((
-                            (conn, typ == "IPv6", pk, kcp, typ),
+                            (conn, typ.starts_with("IPv6"), pk, kcp, typ),
                             (feedback, rendezvous_server),
                             false,
                         ));
@@ -619,6 +697,8 @@ impl Client {
                 udp.0,
                 ipv6.0,
                 punch_type,
+                quic,
+                kcp_fec,
             )
             .await?,
             (feedback, rendezvous_server),
@@ -645,6 +725,8 @@ impl Client {
         udp_socket_nat: Option<Arc<UdpSocket>>,
         udp_socket_v6: Option<Arc<UdpSocket>>,
         punch_type: &str,
+        quic: bool,
+        kcp_fec: bool,
     ) -> ResultType<(
         Stream,
         bool,
@@ -697,10 +779,14 @@ impl Client {
             .boxed(),
         );
         if let Some(udp_socket_nat) = udp_socket_nat {
-            connect_futures.push(udp_nat_connect(udp_socket_nat, "UDP", connect_timeout).boxed());
+            connect_futures.push(
+                udp_nat_connect(udp_socket_nat, "UDP", connect_timeout, quic, kcp_fec).boxed(),
+            );
         }
         if let Some(udp_socket_v6) = udp_socket_v6 {
-            connect_futures.push(udp_nat_connect(udp_socket_v6, "IPv6", connect_timeout).boxed());
+            connect_futures.push(
+                udp_nat_connect(udp_socket_v6, "IPv6", connect_timeout, quic, kcp_fec).boxed(),
+            );
         }
         // Run all connection attempts concurrently, return the first successful one
         let (mut conn, kcp, mut typ) = match select_ok(connect_futures).await {
@@ -1848,7 +1934,7 @@ impl LoginConfigHandler {
             config::option2bool("force-always-relay", &self.get_option("force-always-relay"))
                 || force_relay
                 || use_ws()
-                || Config::is_proxy();
+                || (Config::is_proxy() && !socket_client::is_proxy_udp_capable());
         if let Some((real_id, server, key)) = &self.other_server {
             let other_server_key = self.get_option("other-server-key");
             if !other_server_key.is_empty() && key.is_empty() {
@@ -2681,6 +2767,16 @@ impl LoginConfigHandler {
             })
             .into(),
             hwid,
+            features: Some(Features {
+                channels: true,
+                resume: true,
+                file_pipelining: true,
+                file_metadata: true,
+                file_sparse: true,
+                file_archive_progress: true,
+                ..Default::default()
+            })
+            .into(),
             ..Default::default()
         };
         match self.conn_type {
@@ -2756,6 +2852,12 @@ impl LoginConfigHandler {
             "terminal-service-id"
         }
     }
+
+    /// Logs in to a new terminal service on the next login, not the one kept for the peer.
+    pub fn forget_terminal_service_id(&mut self) {
+        let key = self.get_key_terminal_service_id();
+        self.config.options.remove(key);
+    }
 }
 
 /// Media data.
@@ -3669,6 +3771,10 @@ pub enum Data {
     Login((String, String, String, bool)),
     Message(Message),
     SendFiles((i32, JobType, String, String, i32, bool, bool)),
+    // The remote directory is received as one archive, written to the local path.
+    ReceiveArchive((i32, String, String, Archive, bool)),
+    // The scrollback of the remote terminal is received as a text file, written to the local path.
+    ExportTerminal((i32, i32, String)),
     RemoveDirAll((i32, String, bool, bool)),
     ConfirmDeleteFiles((i32, i32)),
     SetNoConfirm(i32),
@@ -3676,6 +3782,7 @@ pub enum Data {
     RemoveFile((i32, String, i32, bool)),
     CreateDir((i32, String, bool)),
     CancelJob(i32),
+    LimitJob((i32, bool, u64)),
     RemovePortForward(i32),
     AddPortForward((i32, String, i32)),
     #[cfg(all(target_os = "windows", not(feature = "flutter")))]
@@ -4160,6 +4267,8 @@ async fn udp_nat_connect(
     socket: Arc<UdpSocket>,
     typ: &'static str,
     ms_timeout: u64,
+    quic: bool,
+    kcp_fec: bool,
 ) -> ResultType<(Stream, Option<KcpStream>, &'static str)> {
     crate::punch_udp(socket.clone(), false)
         .await
@@ -4167,7 +4276,20 @@ async fn udp_nat_connect(
             log::debug!("{err}");
             anyhow!(err)
         })?;
-    let res = KcpStream::connect(socket, Duration::from_millis(ms_timeout))
+    #[cfg(feature = "quic")]
+    if quic {
+        let stream = hbb_common::quic::QuicStream::connect(socket, ms_timeout)
+            .await
+            .map_err(|err| {
+                log::debug!("Failed to connect QUIC stream: {}", err);
+                anyhow!(err)
+            })?;
+        let typ = if typ == "IPv6" { "IPv6 QUIC" } else { "QUIC" };
+        return Ok((Stream::Quic(stream), None, typ));
+    }
+    #[cfg(not(feature = "quic"))]
+    let _ = quic;
+    let res = KcpStream::connect(socket, Duration::from_millis(ms_timeout), kcp_fec)
         .await
         .map_err(|err| {
             log::debug!("Failed to connect KCP stream: {}", err);
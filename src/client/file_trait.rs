@@ -55,6 +55,11 @@ pub trait FileManager: Interface {
         self.send(Data::CancelJob(id));
     }
 
+    /// Pauses or limits to `rate_limit` bytes per second, if not 0, a job of either side.
+    fn limit_job(&self, id: i32, paused: bool, rate_limit: u64) {
+        self.send(Data::LimitJob((id, paused, rate_limit)));
+    }
+
     fn read_empty_dirs(&self, path: String, include_hidden: bool) {
         let mut msg_out = Message::new();
         let mut file_action = FileAction::new();
@@ -67,6 +72,15 @@ pub trait FileManager: Interface {
         self.send(Data::Message(msg_out));
     }
 
+    /// Streams the files matching `search` back until done or cancelled with [`Self::cancel_job`].
+    fn search_remote_files(&self, search: FileSearch) {
+        let mut msg_out = Message::new();
+        let mut file_action = FileAction::new();
+        file_action.set_search(search);
+        msg_out.set_file_action(file_action);
+        self.send(Data::Message(msg_out));
+    }
+
     fn read_remote_dir(&self, path: String, include_hidden: bool) {
         let mut msg_out = Message::new();
         let mut file_action = FileAction::new();
@@ -161,6 +175,39 @@ pub trait FileManager: Interface {
         )));
     }
 
+    /// Receives the remote directory `path` as one archive, created as it is sent, written to `to`.
+    fn receive_archive(
+        &self,
+        id: i32,
+        path: String,
+        to: String,
+        archive: file_transfer_send_request::Archive,
+        include_hidden: bool,
+    ) {
+        self.send(Data::ReceiveArchive((
+            id,
+            path,
+            to,
+            archive,
+            include_hidden,
+        )));
+    }
+
+    /// Extracts the remote archive `path` into `to`, or where it is if empty, reported as the
+    /// progress of a job of `id` until it is done, fails or is cancelled by `cancel_job`.
+    fn extract_remote_archive(&self, id: i32, path: String, to: String) {
+        let mut msg_out = Message::new();
+        let mut file_action = FileAction::new();
+        file_action.set_extract(FileArchiveExtract {
+            id,
+            path,
+            to,
+            ..Default::default()
+        });
+        msg_out.set_file_action(file_action);
+        self.send(Data::Message(msg_out));
+    }
+
     fn resume_job(&self, id: i32, is_remote: bool) {
         self.send(Data::ResumeJob((id, is_remote)));
     }
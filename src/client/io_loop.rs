@@ -20,27 +20,30 @@ use clipboard::ContextSend;
 use crossbeam_queue::ArrayQueue;
 #[cfg(not(target_os = "ios"))]
 use hbb_common::tokio::sync::mpsc::error::TryRecvError;
+#[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
+use hbb_common::tokio::sync::Mutex as TokioMutex;
 use hbb_common::{
     allow_err,
+    channel::{self, Class},
     config::{self, LocalConfig, PeerConfig, TransferSerde},
     fs::{
-        self, can_enable_overwrite_detection, get_job, get_string, new_send_confirm,
-        DigestCheckResult, RemoveJobMeta,
+        self, can_enable_overwrite_detection, can_enable_pipelining, can_enable_sparse,
+        can_preserve_metadata, get_job, get_string, new_send_confirm, DigestCheckResult,
+        RemoveJobMeta,
     },
     get_time, log,
     message_proto::{permission_info::Permission, *},
     protobuf::Message as _,
     rendezvous_proto::ConnType,
+    resume::{self, ResumableStream},
     timeout,
     tokio::{
         self,
         sync::mpsc,
         time::{self, Duration, Instant},
     },
-    Stream,
+    ResultType, Stream,
 };
-#[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
-use hbb_common::{tokio::sync::Mutex as TokioMutex, ResultType};
 use scrap::CodecFormat;
 use std::{
     collections::HashMap,
@@ -53,6 +56,8 @@ use std::{
     },
 };
 
+const SAVE_JOBS_INTERVAL: Duration = Duration::from_secs(5);
+
 pub struct Remote<T: InvokeUiSession> {
     handler: Session<T>,
     audio_sender: MediaSender,
@@ -66,6 +71,7 @@ pub struct Remote<T: InvokeUiSession> {
     remove_jobs: HashMap<i32, RemoveJob>,
     timer: crate::RustDeskInterval,
     last_update_jobs_status: (Instant, HashMap<i32, u64>),
+    last_save_jobs: Instant,
     is_connected: bool,
     first_frame: bool,
     #[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
@@ -78,6 +84,9 @@ pub struct Remote<T: InvokeUiSession> {
     chroma: Arc<RwLock<Option<Chroma>>>,
     last_record_state: bool,
     sent_close_reason: bool,
+    outgoing: Outgoing,
+    // Packets of the peer received over KCP, for the loss feedback in `TestDelay`.
+    kcp_stats: Option<Arc<crate::kcp_stream::KcpStats>>,
 }
 
 #[derive(Default)]
@@ -97,6 +106,34 @@ impl ParsedPeerInfo {
     }
 }
 
+/// Messages to the peer.
+///
+/// Old peers get them right away. Peers supporting `Features::channels` get them through
+/// a weighted fair scheduler drained by `io_loop`, like the controlled side does, so file
+/// blocks queued for upload don't hold up input.
+#[derive(Default)]
+struct Outgoing {
+    scheduler: channel::Scheduler<Message>,
+    enabled: bool,
+}
+
+impl Outgoing {
+    async fn send(&mut self, peer: &mut Stream, msg: &Message) -> ResultType<()> {
+        if self.enabled {
+            self.scheduler.push_message(msg.clone());
+            return Ok(());
+        }
+        peer.send(msg).await
+    }
+
+    async fn flush(&mut self, peer: &mut Stream) -> ResultType<()> {
+        while let Some(msg) = self.scheduler.pop() {
+            peer.send(&msg).await?;
+        }
+        Ok(())
+    }
+}
+
 impl<T: InvokeUiSession> Remote<T> {
     pub fn new(
         handler: Session<T>,
@@ -113,6 +150,7 @@ impl<T: InvokeUiSession> Remote<T> {
             remove_jobs: Default::default(),
             timer: crate::rustdesk_interval(time::interval(SEC30)),
             last_update_jobs_status: (Instant::now(), Default::default()),
+            last_save_jobs: Instant::now(),
             is_connected: false,
             first_frame: false,
             #[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
@@ -127,6 +165,8 @@ impl<T: InvokeUiSession> Remote<T> {
             chroma: Default::default(),
             last_record_state: false,
             sent_close_reason: false,
+            outgoing: Default::default(),
+            kcp_stats: None,
         }
     }
 
@@ -174,7 +214,9 @@ impl<T: InvokeUiSession> Remote<T> {
         )
         .await
         {
-            Ok(((mut peer, direct, pk, kcp, stream_type), (feedback, rendezvous_server))) => {
+            Ok(((peer, mut direct, pk, mut kcp, stream_type), (feedback, rendezvous_server))) => {
+                let mut peer = ResumableStream::wrap(peer);
+                self.kcp_stats = kcp.as_ref().map(|k| k.stats());
                 self.handler
                     .connection_round_state
                     .lock()
@@ -226,6 +268,10 @@ impl<T: InvokeUiSession> Remote<T> {
                             if let Some(res) = res {
                                 match res {
                                     Err(err) => {
+                                        if self.resume_session(&mut peer, &mut kcp, &mut direct, key, token, conn_type).await {
+                                            last_recv_time = Instant::now();
+                                            continue;
+                                        }
                                         self.handler.on_establish_connection_error(err.to_string());
                                         break;
                                     }
@@ -245,6 +291,9 @@ impl<T: InvokeUiSession> Remote<T> {
                                 if self.handler.is_restarting_remote_device() {
                                     log::info!("Restart remote device");
                                     self.handler.msgbox("restarting", "Restarting remote device", "remote_restarting_tip", "");
+                                } else if self.resume_session(&mut peer, &mut kcp, &mut direct, key, token, conn_type).await {
+                                    last_recv_time = Instant::now();
+                                    continue;
                                 } else {
                                     log::info!("Reset by the peer");
                                     self.handler.msgbox("error", "Connection Error", "Reset by the peer", "");
@@ -259,6 +308,15 @@ impl<T: InvokeUiSession> Remote<T> {
                                 }
                             }
                         }
+                        _ = async {}, if !self.outgoing.scheduler.is_empty() => {
+                            if let Some(msg) = self.outgoing.scheduler.pop() {
+                                // Where the blocks of the read jobs fail to be sent.
+                                if let Err(err) = peer.send(&msg).await {
+                                    self.handler.msgbox("error", "Connection Error", &err.to_string(), "");
+                                    break;
+                                }
+                            }
+                        }
                         _msg = rx_clip_client.recv() => {
                             #[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
                             self.handle_local_clipboard_msg(&mut peer, _msg).await;
@@ -269,7 +327,14 @@ impl<T: InvokeUiSession> Remote<T> {
                                 break;
                             }
                             if !self.read_jobs.is_empty() {
-                                if let Err(err) = fs::handle_read_jobs(&mut self.read_jobs, &mut peer).await {
+                                let res = if !self.outgoing.enabled {
+                                    fs::handle_read_jobs(&mut self.read_jobs, &mut peer).await
+                                } else if self.outgoing.scheduler.pending(Class::Bulk) == 0 {
+                                    fs::handle_read_jobs(&mut self.read_jobs, &mut self.outgoing.scheduler).await
+                                } else {
+                                    Ok(Default::default())
+                                };
+                                if let Err(err) = res {
                                     self.handler.msgbox("error", "Connection Error", &err.to_string(), "");
                                     break;
                                 }
@@ -356,7 +421,7 @@ impl<T: InvokeUiSession> Remote<T> {
 
     #[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
     async fn handle_local_clipboard_msg(
-        &self,
+        &mut self,
         peer: &mut Stream,
         msg: Option<clipboard::ClipboardFile>,
     ) {
@@ -397,7 +462,7 @@ impl<T: InvokeUiSession> Remote<T> {
                         };
                         log::debug!("Send system clipboard message to remote");
                         let msg = crate::clipboard_file::clip_2_msg(clip);
-                        allow_err!(peer.send(&msg).await);
+                        allow_err!(self.outgoing.send(peer, &msg).await);
                     }
                 }
             },
@@ -407,6 +472,25 @@ impl<T: InvokeUiSession> Remote<T> {
         }
     }
 
+    // The limits of the options, for the files sent.
+    fn limit_read_job(&self, job: &mut fs::TransferJob) {
+        fs::limit::set_global_rate_limit(fs::limit::parse_rate_limit(&LocalConfig::get_option(
+            config::keys::OPTION_FILE_TRANSFER_RATE_LIMIT,
+        )));
+        job.set_window(fs::limit::TimeWindow::parse(&LocalConfig::get_option(
+            config::keys::OPTION_FILE_TRANSFER_WINDOW,
+        )));
+    }
+
+    fn preserve_metadata(&self) -> bool {
+        can_preserve_metadata(self.handler.lc.read().unwrap().features.as_ref())
+            && LocalConfig::get_bool_option(config::keys::OPTION_FILE_TRANSFER_PRESERVE_METADATA)
+    }
+
+    fn preserve_owner() -> bool {
+        LocalConfig::get_bool_option(config::keys::OPTION_FILE_TRANSFER_PRESERVE_OWNER)
+    }
+
     fn handle_job_status(&mut self, id: i32, file_num: i32, err: Option<String>) {
         if let Some(job) = self.remove_jobs.get_mut(&id) {
             if job.no_confirm {
@@ -521,10 +605,73 @@ impl<T: InvokeUiSession> Remote<T> {
         }
     }
 
+    // Connects again and continues the session after the transport was lost, e.g. because
+    // the network changed. Returns false if the session is not resumable or resuming failed.
+    async fn resume_session(
+        &mut self,
+        peer: &mut Stream,
+        kcp: &mut Option<crate::kcp_stream::KcpStream>,
+        direct: &mut bool,
+        key: &str,
+        token: &str,
+        conn_type: ConnType,
+    ) -> bool {
+        let Some(s) = peer.as_resumable().filter(|s| s.is_enabled()) else {
+            return false;
+        };
+        let session_token = s.token().to_vec();
+        let received = s.received();
+        let deadline = Instant::now() + resume::GRACE;
+        while Instant::now() < deadline {
+            log::info!("Resuming session of id={}", self.handler.get_id());
+            let ms = deadline
+                .saturating_duration_since(Instant::now())
+                .as_millis() as u64;
+            let res = timeout(
+                ms,
+                Client::resume(
+                    &self.handler.get_id(),
+                    key,
+                    token,
+                    conn_type,
+                    self.handler.clone(),
+                    &session_token,
+                    received,
+                ),
+            )
+            .await;
+            match res {
+                Ok(Ok((stream, d, k, peer_received))) => {
+                    let Some(s) = peer.as_resumable() else {
+                        return false;
+                    };
+                    if let Err(err) = s.attach(stream, peer_received).await {
+                        log::error!("Failed to resume session: {}", err);
+                        return false;
+                    }
+                    *kcp = k;
+                    self.kcp_stats = kcp.as_ref().map(|k| k.stats());
+                    *direct = d;
+                    self.handler.update_direct(Some(d));
+                    self.handler.update_received(true);
+                    log::info!("Session resumed");
+                    return true;
+                }
+                Ok(Err(err)) => {
+                    log::warn!("Failed to resume session: {}", err);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                Err(_) => break,
+            }
+        }
+        false
+    }
+
     async fn send_close_reason(&mut self, peer: &mut Stream, reason: &str) {
         if self.sent_close_reason {
             return;
         }
+        allow_err!(self.outgoing.flush(peer).await);
         let mut misc = Misc::new();
         misc.set_close_reason(reason.to_owned());
         let mut msg = Message::new();
@@ -565,7 +712,7 @@ impl<T: InvokeUiSession> Remote<T> {
                     },
                     _ => {}
                 }
-                allow_err!(peer.send(&msg).await);
+                allow_err!(self.outgoing.send(peer, &msg).await);
             }
             Data::SendFiles((id, r#type, path, to, file_num, include_hidden, is_remote)) => {
                 log::info!("send files, is remote {}", is_remote);
@@ -578,7 +725,7 @@ impl<T: InvokeUiSession> Remote<T> {
                             fs::DataSource::MemoryCursor(std::io::Cursor::new(Vec::new()))
                         }
                     };
-                    self.write_jobs.push(fs::TransferJob::new_write(
+                    let mut job = fs::TransferJob::new_write(
                         id,
                         r#type,
                         path.clone(),
@@ -588,9 +735,23 @@ impl<T: InvokeUiSession> Remote<T> {
                         is_remote,
                         Vec::new(),
                         od,
-                    ));
+                    );
+                    job.set_preserve_owner(Self::preserve_owner());
+                    self.write_jobs.push(job);
                     allow_err!(
-                        peer.send(&fs::new_send(id, r#type, path, file_num, include_hidden))
+                        self.outgoing
+                            .send(
+                                peer,
+                                &fs::new_send(
+                                    id,
+                                    r#type,
+                                    path,
+                                    file_num,
+                                    include_hidden,
+                                    self.preserve_metadata(),
+                                    file_transfer_send_request::Archive::NoArchive,
+                                )
+                            )
                             .await
                     );
                 } else {
@@ -603,11 +764,12 @@ impl<T: InvokeUiSession> Remote<T> {
                         include_hidden,
                         is_remote,
                         od,
+                        self.preserve_metadata(),
                     ) {
                         Err(err) => {
                             self.handle_job_status(id, -1, Some(err.to_string()));
                         }
-                        Ok(job) => {
+                        Ok(mut job) => {
                             log::debug!(
                                 "New job {}, read {} to remote {}, {} files",
                                 id,
@@ -615,6 +777,10 @@ impl<T: InvokeUiSession> Remote<T> {
                                 to,
                                 job.files().len()
                             );
+                            let features = self.handler.lc.read().unwrap().features.clone();
+                            job.set_pipelining(can_enable_pipelining(features.as_ref()));
+                            job.set_sparse(can_enable_sparse(features.as_ref()));
+                            self.limit_read_job(&mut job);
                             self.handler.update_folder_files(
                                 job.id(),
                                 job.files(),
@@ -635,7 +801,11 @@ impl<T: InvokeUiSession> Remote<T> {
                             self.read_jobs.push(job);
                             self.timer = crate::rustdesk_interval(time::interval(MILLI1));
                             allow_err!(
-                                peer.send(&fs::new_receive(id, to, file_num, files, total_size))
+                                self.outgoing
+                                    .send(
+                                        peer,
+                                        &fs::new_receive(id, to, file_num, files, total_size)
+                                    )
                                     .await
                             );
                         }
@@ -662,7 +832,9 @@ impl<T: InvokeUiSession> Remote<T> {
                         Vec::new(),
                         od,
                     );
+                    self.restore_job(&mut job);
                     job.is_last_job = true;
+                    job.set_preserve_owner(Self::preserve_owner());
                     self.write_jobs.push(job);
                 } else {
                     match fs::TransferJob::new_read(
@@ -674,6 +846,7 @@ impl<T: InvokeUiSession> Remote<T> {
                         include_hidden,
                         is_remote,
                         od,
+                        self.preserve_metadata(),
                     ) {
                         Err(err) => {
                             self.handle_job_status(id, -1, Some(err.to_string()));
@@ -686,6 +859,11 @@ impl<T: InvokeUiSession> Remote<T> {
                                 to,
                                 job.files().len()
                             );
+                            let features = self.handler.lc.read().unwrap().features.clone();
+                            job.set_pipelining(can_enable_pipelining(features.as_ref()));
+                            job.set_sparse(can_enable_sparse(features.as_ref()));
+                            self.limit_read_job(&mut job);
+                            self.restore_job(&mut job);
                             self.handler.update_folder_files(
                                 job.id(),
                                 job.files(),
@@ -700,20 +878,87 @@ impl<T: InvokeUiSession> Remote<T> {
                     }
                 }
             }
+            Data::ReceiveArchive((id, path, to, archive, include_hidden)) => {
+                log::debug!("New job {}, write archive {} of remote {}", id, to, path);
+                let mut job = fs::TransferJob::new_write(
+                    id,
+                    fs::JobType::Generic,
+                    path.clone(),
+                    fs::DataSource::FilePath(PathBuf::from(&to)),
+                    0,
+                    include_hidden,
+                    true,
+                    Vec::new(),
+                    false,
+                );
+                job.set_archive(archive);
+                self.write_jobs.push(job);
+                allow_err!(
+                    self.outgoing
+                        .send(
+                            peer,
+                            &fs::new_send(
+                                id,
+                                fs::JobType::Generic,
+                                path,
+                                0,
+                                include_hidden,
+                                false,
+                                archive,
+                            )
+                        )
+                        .await
+                );
+            }
+            Data::ExportTerminal((id, terminal_id, to)) => {
+                log::debug!(
+                    "New job {}, write terminal {} history to {}",
+                    id,
+                    terminal_id,
+                    to
+                );
+                self.write_jobs.push(fs::TransferJob::new_write(
+                    id,
+                    fs::JobType::Generic,
+                    format!("terminal{}.txt", terminal_id),
+                    fs::DataSource::FilePath(PathBuf::from(&to)),
+                    0,
+                    false,
+                    true,
+                    Vec::new(),
+                    false,
+                ));
+                let mut action = TerminalAction::new();
+                action.set_export(TerminalExport {
+                    terminal_id,
+                    id,
+                    ..Default::default()
+                });
+                let mut msg_out = Message::new();
+                msg_out.set_terminal_action(action);
+                allow_err!(self.outgoing.send(peer, &msg_out).await);
+            }
             Data::ResumeJob((id, is_remote)) => {
                 if is_remote {
+                    let preserve_metadata = self.preserve_metadata();
                     if let Some(job) = get_job(id, &mut self.write_jobs) {
                         job.is_last_job = false;
                         job.is_resume = true;
                         allow_err!(
-                            peer.send(&fs::new_send(
-                                id,
-                                fs::JobType::Generic,
-                                job.remote.clone(),
-                                job.file_num,
-                                job.show_hidden
-                            ))
-                            .await
+                            self.outgoing
+                                .send(
+                                    peer,
+                                    &fs::new_send(
+                                        id,
+                                        fs::JobType::Generic,
+                                        job.remote.clone(),
+                                        job.file_num,
+                                        job.show_hidden,
+                                        preserve_metadata,
+                                        job.archive(),
+                                    )
+                                )
+                                .await
                         );
                     }
                 } else {
@@ -733,14 +978,18 @@ impl<T: InvokeUiSession> Remote<T> {
                                     fs::transform_windows_path(&mut files);
                                 }
                                 allow_err!(
-                                    peer.send(&fs::new_receive(
-                                        id,
-                                        job.remote.clone(),
-                                        job.file_num,
-                                        files,
-                                        job.total_size(),
-                                    ))
-                                    .await
+                                    self.outgoing
+                                        .send(
+                                            peer,
+                                            &fs::new_receive(
+                                                id,
+                                                job.remote.clone(),
+                                                job.file_num,
+                                                files,
+                                                job.total_size(),
+                                            )
+                                        )
+                                        .await
                                 );
                             }
                             fs::DataSource::MemoryCursor(_) => {
@@ -793,7 +1042,7 @@ impl<T: InvokeUiSession> Remote<T> {
                         }
                         let mut msg = Message::new();
                         let mut file_action = FileAction::new();
-                        let req = FileTransferSendConfirmRequest {
+                        let mut req = FileTransferSendConfirmRequest {
                             id,
                             file_num,
                             union: if need_override {
@@ -804,9 +1053,12 @@ impl<T: InvokeUiSession> Remote<T> {
                             ..Default::default()
                         };
                         job.confirm(&req).await;
+                        if need_override {
+                            req.signatures = job.delta_signatures(file_num).await.into();
+                        }
                         file_action.set_send_confirm(req);
                         msg.set_file_action(file_action);
-                        allow_err!(peer.send(&msg).await);
+                        allow_err!(self.outgoing.send(peer, &msg).await);
                     }
                 }
             }
@@ -822,7 +1074,7 @@ impl<T: InvokeUiSession> Remote<T> {
                         ..Default::default()
                     });
                     msg_out.set_file_action(file_action);
-                    allow_err!(peer.send(&msg_out).await);
+                    allow_err!(self.outgoing.send(peer, &msg_out).await);
                     self.remove_jobs
                         .insert(id, RemoveJob::new(Vec::new(), path, sep, is_remote));
                 } else {
@@ -852,13 +1104,33 @@ impl<T: InvokeUiSession> Remote<T> {
                     ..Default::default()
                 });
                 msg_out.set_file_action(file_action);
-                allow_err!(peer.send(&msg_out).await);
+                allow_err!(self.outgoing.send(peer, &msg_out).await);
                 if let Some(job) = fs::remove_job(id, &mut self.write_jobs) {
                     job.remove_download_file();
                 }
                 let _ = fs::remove_job(id, &mut self.read_jobs);
                 self.remove_jobs.remove(&id);
             }
+            Data::LimitJob((id, paused, rate_limit)) => {
+                if let Some(job) = get_job(id, &mut self.read_jobs) {
+                    job.set_paused(paused);
+                    job.set_rate_limit(rate_limit);
+                } else {
+                    if let Some(job) = get_job(id, &mut self.write_jobs) {
+                        job.set_paused(paused);
+                    }
+                    let mut msg_out = Message::new();
+                    let mut file_action = FileAction::new();
+                    file_action.set_limit(FileTransferLimit {
+                        id,
+                        paused,
+                        rate_limit,
+                        ..Default::default()
+                    });
+                    msg_out.set_file_action(file_action);
+                    allow_err!(self.outgoing.send(peer, &msg_out).await);
+                }
+            }
             Data::RemoveDir((id, path)) => {
                 let mut msg_out = Message::new();
                 let mut file_action = FileAction::new();
@@ -869,7 +1141,7 @@ impl<T: InvokeUiSession> Remote<T> {
                     ..Default::default()
                 });
                 msg_out.set_file_action(file_action);
-                allow_err!(peer.send(&msg_out).await);
+                allow_err!(self.outgoing.send(peer, &msg_out).await);
             }
             Data::RemoveFile((id, path, file_num, is_remote)) => {
                 if is_remote {
@@ -882,7 +1154,7 @@ impl<T: InvokeUiSession> Remote<T> {
                         ..Default::default()
                     });
                     msg_out.set_file_action(file_action);
-                    allow_err!(peer.send(&msg_out).await);
+                    allow_err!(self.outgoing.send(peer, &msg_out).await);
                 } else {
                     match fs::remove_file(&path) {
                         Err(err) => {
@@ -904,7 +1176,7 @@ impl<T: InvokeUiSession> Remote<T> {
                         ..Default::default()
                     });
                     msg_out.set_file_action(file_action);
-                    allow_err!(peer.send(&msg_out).await);
+                    allow_err!(self.outgoing.send(peer, &msg_out).await);
                 } else {
                     match fs::create_dir(&path) {
                         Err(err) => {
@@ -927,7 +1199,7 @@ impl<T: InvokeUiSession> Remote<T> {
                         ..Default::default()
                     });
                     msg_out.set_file_action(file_action);
-                    allow_err!(peer.send(&msg_out).await);
+                    allow_err!(self.outgoing.send(peer, &msg_out).await);
                 } else {
                     let err = fs::rename_file(&path, &new_name)
                         .err()
@@ -946,7 +1218,7 @@ impl<T: InvokeUiSession> Remote<T> {
                 misc.set_elevation_request(request);
                 let mut msg = Message::new();
                 msg.set_misc(misc);
-                allow_err!(peer.send(&msg).await);
+                allow_err!(self.outgoing.send(peer, &msg).await);
                 self.elevation_requested = true;
             }
             Data::ElevateWithLogon(username, password) => {
@@ -960,7 +1232,7 @@ impl<T: InvokeUiSession> Remote<T> {
                 misc.set_elevation_request(request);
                 let mut msg = Message::new();
                 msg.set_misc(misc);
-                allow_err!(peer.send(&msg).await);
+                allow_err!(self.outgoing.send(peer, &msg).await);
                 self.elevation_requested = true;
             }
             Data::NewVoiceCall => {
@@ -970,7 +1242,7 @@ impl<T: InvokeUiSession> Remote<T> {
                     NonZeroI64::new(msg.voice_call_request().req_timestamp)
                         .unwrap_or(NonZeroI64::new(get_time()).unwrap()),
                 );
-                allow_err!(peer.send(&msg).await);
+                allow_err!(self.outgoing.send(peer, &msg).await);
                 self.handler.on_voice_call_waiting();
             }
             Data::CloseVoiceCall => {
@@ -978,7 +1250,7 @@ impl<T: InvokeUiSession> Remote<T> {
                 let msg = new_voice_call_request(false);
                 self.handler
                     .on_voice_call_closed("Closed manually by the peer");
-                allow_err!(peer.send(&msg).await);
+                allow_err!(self.outgoing.send(peer, &msg).await);
             }
             Data::ResetDecoder(display) => match display {
                 Some(display) => {
@@ -999,7 +1271,7 @@ impl<T: InvokeUiSession> Remote<T> {
                     sid,
                     ..Default::default()
                 });
-                allow_err!(peer.send(&msg).await);
+                allow_err!(self.outgoing.send(peer, &msg).await);
             }
             _ => {}
         }
@@ -1051,31 +1323,66 @@ impl<T: InvokeUiSession> Remote<T> {
             }
             self.last_update_jobs_status.0 = Instant::now();
         }
+        if self.last_save_jobs.elapsed() >= SAVE_JOBS_INTERVAL {
+            self.save_jobs();
+        }
     }
 
     pub async fn sync_jobs_status_to_local(&mut self) -> bool {
         if !self.is_connected {
             return false;
         }
+        self.save_jobs();
+        true
+    }
+
+    // The journal of the jobs, to resume them if the app or the machine restarts.
+    fn save_jobs(&mut self) {
+        self.last_save_jobs = Instant::now();
+        // Terminal histories are exported from memory, there is nothing to resume.
+        if self.handler.is_terminal() {
+            return;
+        }
         let mut config: PeerConfig = self.handler.load_config();
         let mut transfer_metas = TransferSerde::default();
         for job in self.read_jobs.iter() {
             let json_str = serde_json::to_string(&job.gen_meta()).unwrap_or_default();
             transfer_metas.read_jobs.push(json_str);
         }
-        for job in self.write_jobs.iter() {
+        // Archives are created as they are sent, they can only be sent again from the start.
+        for job in self
+            .write_jobs
+            .iter()
+            .filter(|job| job.archive() == file_transfer_send_request::Archive::NoArchive)
+        {
             let json_str = serde_json::to_string(&job.gen_meta()).unwrap_or_default();
             transfer_metas.write_jobs.push(json_str);
         }
-        log::info!("meta: {:?}", transfer_metas);
+        log::debug!("meta: {:?}", transfer_metas);
         if config.transfer != transfer_metas {
             config.transfer = transfer_metas;
             self.handler.save_config(config);
         }
-        true
     }
 
-    async fn send_toggle_virtual_display_msg(&self, peer: &mut Stream) {
+    // Restores the progress of a job of a previous run from the journal.
+    fn restore_job(&self, job: &mut fs::TransferJob) {
+        let config = self.handler.load_config();
+        let jobs = if job.is_remote {
+            &config.transfer.write_jobs
+        } else {
+            &config.transfer.read_jobs
+        };
+        if let Some(meta) = jobs
+            .iter()
+            .filter_map(|s| serde_json::from_str::<fs::TransferJobMeta>(s).ok())
+            .find(|meta| meta.is_of(job))
+        {
+            job.restore(&meta);
+        }
+    }
+
+    async fn send_toggle_virtual_display_msg(&mut self, peer: &mut Stream) {
         if !self.peer_info.is_support_virtual_display() {
             return;
         }
@@ -1091,12 +1398,12 @@ impl<T: InvokeUiSession> Remote<T> {
                 });
                 let mut msg_out = Message::new();
                 msg_out.set_misc(misc);
-                allow_err!(peer.send(&msg_out).await);
+                allow_err!(self.outgoing.send(peer, &msg_out).await);
             }
         }
     }
 
-    async fn send_toggle_privacy_mode_msg(&self, peer: &mut Stream) {
+    async fn send_toggle_privacy_mode_msg(&mut self, peer: &mut Stream) {
         let lc = self.handler.lc.read().unwrap();
         if lc.version >= hbb_common::get_version_number("1.2.4")
             && lc.get_toggle_option("privacy-mode")
@@ -1115,7 +1422,7 @@ impl<T: InvokeUiSession> Remote<T> {
             });
             let mut msg_out = Message::new();
             msg_out.set_misc(misc);
-            allow_err!(peer.send(&msg_out).await);
+            allow_err!(self.outgoing.send(peer, &msg_out).await);
         }
     }
 
@@ -1331,6 +1638,11 @@ impl<T: InvokeUiSession> Remote<T> {
                         }
                     }
                     Some(login_response::Union::PeerInfo(pi)) => {
+                        if !lr.session_token.is_empty() {
+                            if let Some(s) = peer.as_resumable() {
+                                s.enable(lr.session_token.to_vec(), false).await;
+                            }
+                        }
                         let peer_version = pi.version.clone();
                         let peer_platform = pi.platform.clone();
                         self.set_peer_info(&pi);
@@ -1459,6 +1771,17 @@ impl<T: InvokeUiSession> Remote<T> {
                         Some(file_response::Union::EmptyDirs(res)) => {
                             self.handler.update_empty_dirs(res);
                         }
+                        Some(file_response::Union::Search(res)) => {
+                            self.handler.update_file_search(res);
+                        }
+                        Some(file_response::Union::Extract(p)) => {
+                            self.handler.job_progress(
+                                p.id,
+                                p.entries,
+                                p.speed as f64,
+                                p.extracted_size as f64,
+                            );
+                        }
                         Some(file_response::Union::Dir(fd)) => {
                             #[cfg(windows)]
                             let entries = fd.entries.to_vec();
@@ -1481,8 +1804,15 @@ impl<T: InvokeUiSession> Remote<T> {
                             }
                         }
                         Some(file_response::Union::Digest(digest)) => {
+                            let peer_features = self.handler.lc.read().unwrap().features.clone();
                             if digest.is_upload {
                                 if let Some(job) = fs::get_job(digest.id, &mut self.read_jobs) {
+                                    if let Some(signatures) = digest.signatures.as_ref() {
+                                        job.set_peer_signatures(
+                                            digest.file_num,
+                                            signatures.clone(),
+                                        );
+                                    }
                                     if let Some(file) = job.files().get(digest.file_num as usize) {
                                         if let fs::DataSource::FilePath(p) = &job.data_source {
                                             let read_path =
@@ -1493,7 +1823,7 @@ impl<T: InvokeUiSession> Remote<T> {
                                             if digest.is_identical && job.is_resume {
                                                 if digest.transferred_size > 0 {
                                                     overwrite_strategy = Some(true);
-                                                    offset = digest.transferred_size as _;
+                                                    offset = digest.transferred_size;
                                                 }
                                             }
                                             if let Some(overwrite) = overwrite_strategy {
@@ -1501,7 +1831,10 @@ impl<T: InvokeUiSession> Remote<T> {
                                                     id: digest.id,
                                                     file_num: digest.file_num,
                                                     union: Some(if overwrite {
-                                                        file_transfer_send_confirm_request::Union::OffsetBlk(offset)
+                                                        fs::resume_offset(
+                                                            offset,
+                                                            peer_features.as_ref(),
+                                                        )
                                                     } else {
                                                         file_transfer_send_confirm_request::Union::Skip(
                                                             true,
@@ -1511,7 +1844,7 @@ impl<T: InvokeUiSession> Remote<T> {
                                                 };
                                                 job.confirm(&req).await;
                                                 let msg = new_send_confirm(req);
-                                                allow_err!(peer.send(&msg).await);
+                                                allow_err!(self.outgoing.send(peer, &msg).await);
                                             } else {
                                                 self.handler.override_file_confirm(
                                                     digest.id,
@@ -1530,7 +1863,13 @@ impl<T: InvokeUiSession> Remote<T> {
                                         if let fs::DataSource::FilePath(p) = &job.data_source {
                                             let write_path =
                                                 get_string(&fs::TransferJob::join(p, &file.name));
-                                            job.set_digest(digest.file_size, digest.last_modified);
+                                            job.set_digest(
+                                                digest.file_num,
+                                                digest.file_size,
+                                                digest.last_modified,
+                                                &digest.hash,
+                                            );
+                                            job.set_peer_delta(digest.delta);
                                             let peer_ver = self.handler.lc.read().unwrap().version;
                                             let is_support_resume =
                                                 crate::is_support_file_transfer_resume_num(
@@ -1551,7 +1890,9 @@ impl<T: InvokeUiSession> Remote<T> {
                                                         };
                                                         job.confirm(&req).await;
                                                         let msg = new_send_confirm(req);
-                                                        allow_err!(peer.send(&msg).await);
+                                                        allow_err!(
+                                                            self.outgoing.send(peer, &msg).await
+                                                        );
                                                     }
                                                     DigestCheckResult::NeedConfirm(digest) => {
                                                         let mut overwrite_strategy =
@@ -1562,24 +1903,39 @@ impl<T: InvokeUiSession> Remote<T> {
                                                             && digest.transferred_size > 0
                                                         {
                                                             overwrite_strategy = Some(true);
-                                                            offset = digest.transferred_size as _;
+                                                            offset = digest.transferred_size;
                                                         }
                                                         if let Some(overwrite) = overwrite_strategy
                                                         {
-                                                            let req =
+                                                            let mut req =
                                                                 FileTransferSendConfirmRequest {
                                                                     id: digest.id,
                                                                     file_num: digest.file_num,
                                                                     union: Some(if overwrite {
-                                                                        file_transfer_send_confirm_request::Union::OffsetBlk(offset)
+                                                                        fs::resume_offset(
+                                                                            offset,
+                                                                            peer_features.as_ref(),
+                                                                        )
                                                                     } else {
                                                                         file_transfer_send_confirm_request::Union::Skip(true)
                                                                     }),
                                                                     ..Default::default()
                                                                 };
                                                             job.confirm(&req).await;
+                                                            if overwrite && offset == 0 {
+                                                                req.signatures = job
+                                                                    .delta_signatures(
+                                                                        digest.file_num,
+                                                                    )
+                                                                    .await
+                                                                    .into();
+                                                            }
                                                             let msg = new_send_confirm(req);
-                                                            allow_err!(peer.send(&msg).await);
+                                                            allow_err!(
+                                                                self.outgoing
+                                                                    .send(peer, &msg)
+                                                                    .await
+                                                            );
                                                         } else {
                                                             self.handler.override_file_confirm(
                                                                 digest.id,
@@ -1599,7 +1955,9 @@ impl<T: InvokeUiSession> Remote<T> {
                                                     };
                                                         job.confirm(&req).await;
                                                         let msg = new_send_confirm(req);
-                                                        allow_err!(peer.send(&msg).await);
+                                                        allow_err!(
+                                                            self.outgoing.send(peer, &msg).await
+                                                        );
                                                     }
                                                 },
                                                 Err(err) => {
@@ -1613,8 +1971,9 @@ impl<T: InvokeUiSession> Remote<T> {
                         }
                         Some(file_response::Union::Block(block)) => {
                             if let Some(job) = fs::get_job(block.id, &mut self.write_jobs) {
-                                if let Err(_err) = job.write(block).await {
+                                if let Err(err) = job.write(block).await {
                                     // to-do: add "skip" for writing job
+                                    log::error!("Failed to write file block: {}", err);
                                 }
                                 if job.r#type == fs::JobType::Generic {
                                     self.update_jobs_status();
@@ -1905,7 +2264,13 @@ impl<T: InvokeUiSession> Remote<T> {
                     }
                     _ => {}
                 },
-                Some(message::Union::TestDelay(t)) => {
+                Some(message::Union::TestDelay(mut t)) => {
+                    if !t.from_client {
+                        if let Some(stats) = self.kcp_stats.as_ref() {
+                            (t.loss, t.retransmits) = stats.take_feedback();
+                            t.kcp_rtt = stats.rtt();
+                        }
+                    }
                     self.handler.handle_test_delay(t, peer).await;
                 }
                 Some(message::Union::AudioFrame(frame)) => {
@@ -2029,6 +2394,7 @@ impl<T: InvokeUiSession> Remote<T> {
         // Check features field for terminal support
         if let Some(features) = pi.features.as_ref() {
             self.peer_info.support_terminal = features.terminal;
+            self.outgoing.enabled = features.channels;
         }
 
         if let Ok(platform_additions) =
@@ -2308,7 +2674,7 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
 
                 for msg in out_msgs.into_iter() {
-                    allow_err!(_peer.send(&msg).await);
+                    allow_err!(self.outgoing.send(_peer, &msg).await);
                 }
             }
         }
@@ -1108,10 +1108,34 @@ pub fn get_ipv6_punch_enabled() -> bool {
     )
 }
 
+// QUIC replaces KCP on the punched UDP sockets if both sides support it.
+pub fn get_quic_enabled() -> bool {
+    cfg!(feature = "quic")
+        && config::option2bool(
+            keys::OPTION_ENABLE_QUIC,
+            &get_local_option(keys::OPTION_ENABLE_QUIC),
+        )
+}
+
+/// Whether the controlled side listens with QUIC, `relayed` being what hbbs forwarded of
+/// `PunchHoleRequest.quic`. The client follows the answer forwarded back, so both stay on KCP
+/// with an hbbs not forwarding these fields.
+#[inline]
+pub fn accept_quic(relayed: bool) -> bool {
+    relayed && get_quic_enabled()
+}
+
 pub fn get_local_option(key: &str) -> String {
     let v = LocalConfig::get_option(key);
-    if key == keys::OPTION_ENABLE_UDP_PUNCH || key == keys::OPTION_ENABLE_IPV6_PUNCH {
+    if key == keys::OPTION_ENABLE_UDP_PUNCH
+        || key == keys::OPTION_ENABLE_IPV6_PUNCH
+        || key == keys::OPTION_ENABLE_QUIC
+    {
         if v.is_empty() {
+            // IPv6 is the only way to a direct connection on an IPv6-only host.
+            if key == keys::OPTION_ENABLE_IPV6_PUNCH && hbb_common::nat64::is_ipv6_only() {
+                return v;
+            }
             if !is_public(&Config::get_rendezvous_server()) {
                 return "N".to_owned();
             }
@@ -1128,6 +1152,14 @@ pub fn get_audit_server(api: String, custom: String, typ: String) -> String {
     format!("{}/api/audit/{}", url, typ)
 }
 
+// Says so if the server rejected the client certificate, the reqwest error does not.
+fn http_error(e: reqwest::Error) -> hbb_common::anyhow::Error {
+    match hbb_common::client_cert::describe_rejection(&e) {
+        Some(msg) => anyhow!(msg),
+        None => anyhow!("{:?}", e),
+    }
+}
+
 pub async fn post_request(url: String, body: String, header: &str) -> ResultType<String> {
     let proxy_conf = Config::get_socks();
     let tls_url = get_url_for_tls(&url, &proxy_conf);
@@ -1181,7 +1213,7 @@ async fn post_request_(
                 );
                 Ok(resp)
             }
-            Err(e) => Err(anyhow!("{:?}", e)),
+            Err(e) => Err(http_error(e)),
         }
     } else {
         match req.body(body.clone()).timeout(to).send().await {
@@ -1224,7 +1256,7 @@ async fn post_request_(
                         .await
                     }
                 } else {
-                    Err(anyhow!("{:?}", e))
+                    Err(http_error(e))
                 }
             }
         }
@@ -1285,7 +1317,7 @@ async fn get_http_response_async(
                 );
                 Ok(resp)
             }
-            Err(e) => Err(anyhow!("{:?}", e)),
+            Err(e) => Err(http_error(e)),
         }
     } else {
         if let Some(b) = body.clone() {
@@ -1338,7 +1370,7 @@ async fn get_http_response_async(
                         .await
                     }
                 } else {
-                    Err(anyhow!("{:?}", e))
+                    Err(http_error(e))
                 }
             }
         }
@@ -2521,4 +2553,14 @@ mod tests {
         assert_eq!(combined_mask & MOUSE_TYPE_MASK, MOUSE_TYPE_DOWN);
         assert_eq!(combined_mask >> 3, MOUSE_BUTTON_LEFT | MOUSE_BUTTON_RIGHT);
     }
+
+    #[test]
+    fn test_quic_without_hbbs_support() {
+        // What an hbbs not forwarding the `quic` fields relays, whatever the options.
+        assert!(!accept_quic(PunchHole::default().quic));
+        assert!(!accept_quic(FetchLocalAddr::default().quic));
+        // Nor does the answer the client follows, both sides use KCP.
+        assert!(!PunchHoleResponse::default().quic);
+        assert!(!RelayResponse::default().quic);
+    }
 }
@@ -504,6 +504,10 @@ pub fn core_main() -> Option<Vec<String>> {
                             );
                             crate::ui_interface::set_option("api-server".into(), lic.api);
                             crate::ui_interface::set_option("relay-server".into(), lic.relay);
+                            crate::ui_interface::set_option(
+                                config::keys::OPTION_TLS_PINS.into(),
+                                lic.pins,
+                            );
                         }
                     }
                 } else {
@@ -16,6 +16,15 @@ pub struct CustomServer {
     pub api: String,
     #[serde(default)]
     pub relay: String,
+    // SPKI pins, in the format of the `tls-pins` option.
+    #[serde(default)]
+    pub pins: String,
+}
+
+// "https://api.example.com:21114/" -> "api.example.com:21114"
+fn get_host_of(s: &str) -> &str {
+    let s = s.split_once("://").map(|(_, s)| s).unwrap_or(s);
+    s.split('/').next().unwrap_or(s)
 }
 
 fn get_custom_server_from_config_string(s: &str) -> ResultType<CustomServer> {
@@ -63,6 +72,7 @@ pub fn get_custom_server_from_string(s: &str) -> ResultType<CustomServer> {
         let mut key = String::default();
         let mut api = String::default();
         let mut relay = String::default();
+        let mut pins = Vec::<String>::new();
         let strs_iter = strs.iter();
         for el in strs_iter {
             let el_lower = el.to_lowercase();
@@ -78,12 +88,29 @@ pub fn get_custom_server_from_string(s: &str) -> ResultType<CustomServer> {
             if el_lower.starts_with("relay=") {
                 relay = el.chars().skip(6).collect();
             }
+            // URL-safe base64, "/" is not allowed in file names.
+            if el_lower.starts_with("pin=") {
+                pins.push(el.chars().skip(4).collect());
+            }
         }
+        // The pins of a file name apply to all the servers.
+        let pins = if pins.is_empty() {
+            String::default()
+        } else {
+            [&host, &api, &relay]
+                .iter()
+                .map(|s| get_host_of(s))
+                .filter(|s| !s.is_empty())
+                .map(|s| format!("{}={}", s, pins.join(",")))
+                .collect::<Vec<_>>()
+                .join(";")
+        };
         return Ok(CustomServer {
             host,
             key,
             api,
             relay,
+            pins,
         });
     } else {
         let s = s
@@ -122,6 +149,7 @@ mod test {
                 key: "".to_owned(),
                 api: "".to_owned(),
                 relay: "".to_owned(),
+                pins: "".to_owned(),
             }
         );
         assert_eq!(
@@ -131,6 +159,7 @@ mod test {
                 key: "".to_owned(),
                 api: "".to_owned(),
                 relay: "".to_owned(),
+                pins: "".to_owned(),
             }
         );
         // key in these tests is "foobar.,2" base64 encoded
@@ -144,6 +173,7 @@ mod test {
                 key: "Zm9vYmFyLiwyCg==".to_owned(),
                 api: "abc".to_owned(),
                 relay: "".to_owned(),
+                pins: "".to_owned(),
             }
         );
         assert_eq!(
@@ -156,6 +186,7 @@ mod test {
                 key: "Zm9vYmFyLiwyCg==".to_owned(),
                 api: "".to_owned(),
                 relay: "".to_owned(),
+                pins: "".to_owned(),
             }
         );
         assert_eq!(
@@ -168,6 +199,7 @@ mod test {
                 key: "Zm9vYmFyLiwyCg==".to_owned(),
                 api: "".to_owned(),
                 relay: "server.example.net".to_owned(),
+                pins: "".to_owned(),
             }
         );
         assert_eq!(
@@ -180,6 +212,7 @@ mod test {
                 key: "Zm9vYmFyLiwyCg==".to_owned(),
                 api: "".to_owned(),
                 relay: "server.example.net".to_owned(),
+                pins: "".to_owned(),
             }
         );
         let lic = CustomServer {
@@ -187,6 +220,7 @@ mod test {
             key: "5Qbwsde3unUcJBtrx9ZkvUmwFNoExHzpryHuPUdqlWM=".to_owned(),
             api: "".to_owned(),
             relay: "".to_owned(),
+            pins: "".to_owned(),
         };
         assert_eq!(
             get_custom_server_from_string("rustdesk-licensed-0nI900VsFHZVBVdIlncwpHS4V0bOZ0dtVldrpVO4JHdCp0YV5WdzUGZzdnYRVjI6ISeltmIsISMuEjLx4SMiojI0N3boJye.exe")
@@ -215,5 +249,14 @@ mod test {
         assert_eq!(
             get_custom_server_from_string("rustdesk-licensed--0nI900VsFHZVBVdIlncwpHS4V0bOZ0dtVldrpVO4JHdCp0YV5WdzUGZzdnYRVjI6ISeltmIsISMuEjLx4SMiojI0N3boJye--.exe")
                 .unwrap(), lic);
+        assert_eq!(
+            get_custom_server_from_string(
+                "rustdesk-host=rs.example.net,api=https://api.example.net:21114,pin=DAprDyytOwtSKjuiyT1svySnqmGMeehAYkmyy7_3-Hk=,pin=47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU=.exe"
+            )
+            .unwrap()
+            .pins,
+            "rs.example.net=DAprDyytOwtSKjuiyT1svySnqmGMeehAYkmyy7_3-Hk=,47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU=;\
+             api.example.net:21114=DAprDyytOwtSKjuiyT1svySnqmGMeehAYkmyy7_3-Hk=,47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
     }
 }
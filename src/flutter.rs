@@ -816,6 +816,16 @@ impl InvokeUiSession for FlutterHandler {
         );
     }
 
+    fn update_file_search(&self, res: FileSearchResult) {
+        let mut value = crate::common::_make_fd_to_json(res.id, res.path, &res.entries);
+        value.insert("done".into(), res.done.into());
+        self.push_event(
+            "file_search",
+            &[("value", &serde_json::to_string(&value).unwrap_or_default())],
+            &[],
+        );
+    }
+
     // unused in flutter
     fn update_transfer_list(&self) {}
 
@@ -902,6 +912,7 @@ impl InvokeUiSession for FlutterHandler {
         }
         let features = serde_json::ser::to_string(&features).unwrap_or("".to_owned());
         let resolutions = serialize_resolutions(&pi.resolutions.resolutions);
+        let terminal_shells = serde_json::ser::to_string(&pi.terminal_shells).unwrap_or_default();
         *self.peer_info.write().unwrap() = pi.clone();
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
         let is_support_multi_ui_session = crate::common::is_support_multi_ui_session(&pi.version);
@@ -927,6 +938,7 @@ impl InvokeUiSession for FlutterHandler {
                 ("current_display", &pi.current_display.to_string()),
                 ("resolutions", &resolutions),
                 ("platform_additions", &pi.platform_additions),
+                ("terminal_shells", &terminal_shells),
             ],
             &[],
         );
@@ -1135,6 +1147,7 @@ impl InvokeUiSession for FlutterHandler {
                     ("message", json!(&opened.message)),
                     ("pid", json!(opened.pid)),
                     ("service_id", json!(&opened.service_id)),
+                    ("read_only", json!(opened.read_only)),
                 ];
                 if !opened.persistent_sessions.is_empty() {
                     event_data.push(("persistent_sessions", json!(opened.persistent_sessions)));
@@ -1165,6 +1178,55 @@ impl InvokeUiSession for FlutterHandler {
                 ];
                 self.push_event_("terminal_response", &event_data, &[], &[]);
             }
+            Some(Union::Role(role)) => {
+                let event_data: Vec<(&str, serde_json::Value)> = vec![
+                    ("type", json!("role")),
+                    ("terminal_id", json!(role.terminal_id)),
+                    ("read_only", json!(role.read_only)),
+                    ("viewers", json!(role.viewers)),
+                ];
+                self.push_event_("terminal_response", &event_data, &[], &[]);
+            }
+            Some(Union::SearchResult(result)) => {
+                let matches: Vec<serde_json::Value> = result
+                    .matches
+                    .iter()
+                    .map(|m| {
+                        json!({
+                            "line": m.line,
+                            "start": m.start,
+                            "end": m.end,
+                            "text": m.text,
+                        })
+                    })
+                    .collect();
+                let event_data: Vec<(&str, serde_json::Value)> = vec![
+                    ("type", json!("search_result")),
+                    ("terminal_id", json!(result.terminal_id)),
+                    ("matches", json!(matches)),
+                    ("first_line", json!(result.first_line)),
+                    ("lines", json!(result.lines)),
+                    ("truncated", json!(result.truncated)),
+                ];
+                self.push_event_("terminal_response", &event_data, &[], &[]);
+            }
+            Some(Union::Services(services)) => {
+                let services: Vec<serde_json::Value> = services
+                    .services
+                    .iter()
+                    .map(|s| {
+                        json!({
+                            "service_id": s.service_id,
+                            "terminal_ids": s.terminal_ids,
+                            "attached": s.attached,
+                            "persistent": s.persistent,
+                        })
+                    })
+                    .collect();
+                let event_data: Vec<(&str, serde_json::Value)> =
+                    vec![("type", json!("services")), ("services", json!(services))];
+                self.push_event_("terminal_response", &event_data, &[], &[]);
+            }
             Some(Union::Error(error)) => {
                 let event_data: Vec<(&str, serde_json::Value)> = vec![
                     ("type", json!("error")),
@@ -658,7 +658,12 @@ pub fn session_send_chat(session_id: SessionID, text: String) {
 // Terminal functions
 pub fn session_open_terminal(session_id: SessionID, terminal_id: i32, rows: u32, cols: u32) {
     if let Some(session) = sessions::get_session_by_session_id(&session_id) {
-        session.open_terminal(terminal_id, rows, cols);
+        session.open_terminal(hbb_common::message_proto::OpenTerminal {
+            terminal_id,
+            rows,
+            cols,
+            ..Default::default()
+        });
     } else {
         log::error!(
             "[flutter_ffi] Session not found for session_id: {}",
@@ -667,6 +672,81 @@ pub fn session_open_terminal(session_id: SessionID, terminal_id: i32, rows: u32,
     }
 }
 
+pub fn session_watch_terminal(session_id: SessionID, terminal_id: i32, rows: u32, cols: u32) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.open_terminal(hbb_common::message_proto::OpenTerminal {
+            terminal_id,
+            rows,
+            cols,
+            read_only: true,
+            ..Default::default()
+        });
+    }
+}
+
+/// Opens a terminal running `shell`, one of the `terminal_shells` of the peer info, or the
+/// default one if empty. `env` is a JSON object of the variables to set.
+pub fn session_open_terminal_with_shell(
+    session_id: SessionID,
+    terminal_id: i32,
+    rows: u32,
+    cols: u32,
+    shell: String,
+    cwd: String,
+    env: String,
+    term: String,
+) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.open_terminal(hbb_common::message_proto::OpenTerminal {
+            terminal_id,
+            rows,
+            cols,
+            shell,
+            cwd,
+            env: serde_json::from_str(&env).unwrap_or_default(),
+            term,
+            ..Default::default()
+        });
+    }
+}
+
+pub fn session_search_terminal(
+    session_id: SessionID,
+    terminal_id: i32,
+    query: String,
+    regex: bool,
+    case_sensitive: bool,
+    max_matches: u32,
+) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.search_terminal(terminal_id, query, regex, case_sensitive, max_matches);
+    }
+}
+
+pub fn session_export_terminal(session_id: SessionID, act_id: i32, terminal_id: i32, to: String) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.export_terminal(act_id, terminal_id, to);
+    }
+}
+
+pub fn session_handoff_terminal(session_id: SessionID, terminal_id: i32, take: bool) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.handoff_terminal(terminal_id, take);
+    }
+}
+
+pub fn session_list_terminal_services(session_id: SessionID) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.list_terminal_services();
+    }
+}
+
+pub fn session_join_terminal_service(session_id: SessionID, service_id: String) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.join_terminal_service(service_id);
+    }
+}
+
 pub fn session_send_terminal_input(session_id: SessionID, terminal_id: i32, data: String) {
     if let Some(session) = sessions::get_session_by_session_id(&session_id) {
         session.send_terminal_input(terminal_id, data);
@@ -734,6 +814,36 @@ pub fn session_send_files(
     }
 }
 
+/// `format` is "zip" for a zip, a tar.zst otherwise.
+pub fn session_receive_archive(
+    session_id: SessionID,
+    act_id: i32,
+    path: String,
+    to: String,
+    format: String,
+    include_hidden: bool,
+) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        let archive = if format == "zip" {
+            hbb_common::message_proto::file_transfer_send_request::Archive::Zip
+        } else {
+            hbb_common::message_proto::file_transfer_send_request::Archive::TarZst
+        };
+        session.receive_archive(act_id, path, to, archive, include_hidden);
+    }
+}
+
+pub fn session_extract_remote_archive(
+    session_id: SessionID,
+    act_id: i32,
+    path: String,
+    to: String,
+) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.extract_remote_archive(act_id, path, to);
+    }
+}
+
 pub fn session_set_confirm_override_file(
     session_id: SessionID,
     act_id: i32,
@@ -788,6 +898,45 @@ pub fn session_cancel_job(session_id: SessionID, act_id: i32) {
     }
 }
 
+pub fn session_search_remote_files(
+    session_id: SessionID,
+    act_id: i32,
+    path: String,
+    include_hidden: bool,
+    glob: String,
+    regex: String,
+    case_sensitive: bool,
+    min_size: i64,
+    max_size: i64,
+    modified_after: i64,
+    modified_before: i64,
+    content: String,
+) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.search_remote_files(hbb_common::message_proto::FileSearch {
+            id: act_id,
+            path,
+            include_hidden,
+            glob,
+            regex,
+            case_sensitive,
+            min_size: min_size.max(0) as u64,
+            max_size: max_size.max(0) as u64,
+            modified_after: modified_after.max(0) as u64,
+            modified_before: modified_before.max(0) as u64,
+            content,
+            ..Default::default()
+        });
+    }
+}
+
+// `rate_limit` in KB/s, 0 for none.
+pub fn session_limit_job(session_id: SessionID, act_id: i32, paused: bool, rate_limit: i32) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.limit_job(act_id, paused, rate_limit.max(0) as u64 * 1024);
+    }
+}
+
 pub fn session_create_dir(session_id: SessionID, act_id: i32, path: String, is_remote: bool) {
     if let Some(session) = sessions::get_session_by_session_id(&session_id) {
         session.create_dir(act_id, path, is_remote);
@@ -981,7 +1130,9 @@ pub fn main_set_option(key: String, value: String) {
     // If `is_allow_tls_fallback` and https proxy is used, we need to restart rendezvous mediator.
     // No need to check if https proxy is used, because this option does not change frequently
     // and restarting mediator is safe even https proxy is not used.
-    let is_allow_tls_fallback = key.eq(config::keys::OPTION_ALLOW_INSECURE_TLS_FALLBACK);
+    let is_allow_tls_fallback = key.eq(config::keys::OPTION_ALLOW_INSECURE_TLS_FALLBACK)
+        || key.eq(config::keys::OPTION_TLS_PINS)
+        || key.eq(config::keys::OPTION_TLS_CLIENT_CERT);
     if is_allow_tls_fallback
         || key.eq("custom-rendezvous-server")
         || key.eq(config::keys::OPTION_ALLOW_WEBSOCKET)
@@ -8,6 +8,8 @@ pub mod downloader;
 mod http_client;
 pub mod record_upload;
 pub mod sync;
+#[cfg(feature = "webrtc")]
+pub mod turn;
 pub use http_client::{
     create_http_client_async, create_http_client_async_with_url, create_http_client_with_url,
     get_url_for_tls,
@@ -32,10 +32,31 @@ macro_rules! configure_http_client {
                     }
                     Err(e) => {
                         hbb_common::log::error!("Failed to get client config: {}", e);
+                        if hbb_common::tls::has_tls_pins() {
+                            builder =
+                                builder.use_preconfigured_tls(
+                                    hbb_common::verifier::client_config_reject(),
+                                );
+                        }
                     }
                 }
                 #[cfg(not(any(target_os = "android", target_os = "ios")))]
-                {
+                if hbb_common::tls::has_tls_pins() || hbb_common::client_cert::is_configured() {
+                    // The built-in config of reqwest can't check pins or use PKCS#11 keys.
+                    match hbb_common::verifier::client_config($danger_accept_invalid_cert) {
+                        Ok(client_config) => {
+                            builder = builder.use_preconfigured_tls(client_config);
+                        }
+                        Err(e) => {
+                            hbb_common::log::error!("Failed to get client config: {}", e);
+                            if hbb_common::tls::has_tls_pins() {
+                                builder = builder.use_preconfigured_tls(
+                                    hbb_common::verifier::client_config_reject(),
+                                );
+                            }
+                        }
+                    }
+                } else {
                     builder = builder.use_rustls_tls();
                     if $danger_accept_invalid_cert {
                         builder = builder.danger_accept_invalid_certs(true);
@@ -44,11 +65,31 @@ macro_rules! configure_http_client {
             }
         }
 
+        // The default client can't check pins.
+        let new_client = || {
+            if hbb_common::tls::has_tls_pins() {
+                <$Client>::builder()
+                    .no_proxy()
+                    .use_preconfigured_tls(hbb_common::verifier::client_config_reject())
+                    .build()
+                    .unwrap_or_else(|_| <$Client>::new())
+            } else {
+                <$Client>::new()
+            }
+        };
+
         let client = if let Some(conf) = Config::get_socks() {
             let proxy_result = Proxy::from_conf(&conf, None);
 
             match proxy_result {
                 Ok(proxy) => {
+                    // reqwest can't chain proxies, only the first one is used.
+                    if !proxy.chain.is_empty() {
+                        log::error!(
+                            "HTTP requests can't go through a proxy chain, only the first of the {} proxies is used",
+                            proxy.chain.len() + 1
+                        );
+                    }
                     let proxy_setup = match &proxy.intercept {
                         ProxyScheme::Http { host, .. } => {
                             reqwest::Proxy::all(format!("http://{}", host))
@@ -71,24 +112,31 @@ macro_rules! configure_http_client {
                             builder = builder.proxy(p);
                             builder.build().unwrap_or_else(|e| {
                                 info!("Failed to create a proxied client: {}", e);
-                                <$Client>::new()
+                                new_client()
                             })
                         }
                         Err(e) => {
                             info!("Failed to set up proxy: {}", e);
-                            <$Client>::new()
+                            new_client()
                         }
                     }
                 }
                 Err(e) => {
                     info!("Failed to configure proxy: {}", e);
-                    <$Client>::new()
+                    new_client()
                 }
             }
         } else {
+            if hbb_common::proxy::auto::is_enabled() {
+                // Decided per request from the PAC file, or the environment until it is
+                // evaluated for that origin.
+                builder = builder.proxy(reqwest::Proxy::custom(|url| {
+                    hbb_common::proxy::auto::get_detected_proxy_cached(url.as_str())
+                }));
+            }
             builder.build().unwrap_or_else(|e| {
                 info!("Failed to create a client: {}", e);
-                <$Client>::new()
+                new_client()
             })
         };
 
@@ -233,6 +233,8 @@ async fn start_hbbs_sync_async() {
                     continue;
                 }
                 last_sent = Some(Instant::now());
+                #[cfg(feature = "webrtc")]
+                hbb_common::allow_err!(super::turn::refresh_credentials().await);
                 let mut v = Value::default();
                 v["id"] = json!(id);
                 v["uuid"] = json!(crate::encode64(hbb_common::get_uuid()));
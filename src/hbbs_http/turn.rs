@@ -0,0 +1,80 @@
+use hbb_common::{config::Config, lazy_static, webrtc, ResultType};
+use serde::Deserialize;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Fetch new credentials before the cached ones get too close to expiring.
+const MIN_TTL: Duration = Duration::from_secs(300);
+// Don't ask again too soon if the server failed or doesn't support TURN credentials.
+const RETRY_INTERVAL: Duration = Duration::from_secs(600);
+// Retry sooner if the cached credentials are about to expire.
+const RETRY_INTERVAL_EXPIRING: Duration = Duration::from_secs(30);
+
+lazy_static::lazy_static! {
+    // The api server last asked, and when.
+    static ref LAST_FETCH: Mutex<Option<(String, Instant)>> = Default::default();
+}
+
+#[derive(Debug, Deserialize)]
+struct TurnCredentials {
+    username: String,
+    #[serde(alias = "password")]
+    credential: String,
+    // in seconds
+    ttl: u64,
+    uris: Vec<String>,
+}
+
+/// Fetches time-limited TURN credentials from the API server.
+///
+/// The API server is only asked again when the cached credentials expire soon,
+/// or when the API server has changed. Used by both the controlled side and
+/// the controlling side before a WebRTC connection is made.
+pub async fn refresh_credentials() -> ResultType<()> {
+    let api = crate::common::get_api_server(
+        Config::get_option("api-server"),
+        Config::get_option("custom-rendezvous-server"),
+    );
+    if api.is_empty() || crate::common::is_public(&api) {
+        return Ok(());
+    }
+    {
+        let mut last = LAST_FETCH.lock().unwrap();
+        if let Some((last_api, at)) = last.as_ref() {
+            if *last_api == api {
+                let ttl = webrtc::turn_credentials_ttl();
+                let retry = if ttl.is_zero() {
+                    RETRY_INTERVAL
+                } else {
+                    RETRY_INTERVAL_EXPIRING
+                };
+                if ttl > MIN_TTL || at.elapsed() < retry {
+                    return Ok(());
+                }
+            } else {
+                // Credentials from another server are of no use.
+                webrtc::set_turn_credentials(vec![], "".to_owned(), "".to_owned(), Duration::ZERO);
+            }
+        }
+        *last = Some((api.clone(), Instant::now()));
+    }
+    let body = serde_json::json!({
+        "id": Config::get_id(),
+        "uuid": crate::encode64(hbb_common::get_uuid()),
+    });
+    let resp = crate::post_request(
+        format!("{}/api/turn-credentials", api),
+        body.to_string(),
+        "",
+    )
+    .await?;
+    // Not supported by this server.
+    if resp.is_empty() {
+        return Ok(());
+    }
+    let c: TurnCredentials = serde_json::from_str(&resp)?;
+    webrtc::set_turn_credentials(c.uris, c.username, c.credential, Duration::from_secs(c.ttl));
+    Ok(())
+}
@@ -75,6 +75,8 @@ pub enum FS {
         id: i32,
         file_num: i32,
         files: Vec<(String, u64)>,
+        // The encoded `FileMetadata` of each file, empty if not preserved.
+        metadata: Vec<Vec<u8>>,
         overwrite_detection: bool,
         total_size: u64,
         conn_id: i32,
@@ -87,6 +89,11 @@ pub enum FS {
         file_num: i32,
         data: Bytes,
         compressed: bool,
+        copy_blks: Vec<u32>,
+        hash: Vec<u8>,
+        dictionary: Vec<u8>,
+        with_dictionary: bool,
+        hole: u64,
     },
     WriteDone {
         id: i32,
@@ -109,6 +116,8 @@ pub enum FS {
         last_modified: u64,
         is_upload: bool,
         is_resume: bool,
+        delta: bool,
+        hash: Vec<u8>,
     },
     SendConfirm(Vec<u8>),
     Rename {
@@ -125,16 +134,25 @@ pub enum FS {
         include_hidden: bool,
         conn_id: i32,
         overwrite_detection: bool,
+        preserve_metadata: bool,
+        // FileTransferSendRequest.Archive, the directory is sent as one archive
+        archive: i32,
     },
     CancelRead {
         id: i32,
         conn_id: i32,
     },
+    LimitRead {
+        id: i32,
+        paused: bool,
+        rate_limit: u64,
+        conn_id: i32,
+    },
     SendConfirmForRead {
         id: i32,
         file_num: i32,
         skip: bool,
-        offset_blk: u32,
+        offset: u64,
         conn_id: i32,
     },
     ReadAllFiles {
@@ -143,6 +161,20 @@ pub enum FS {
         include_hidden: bool,
         conn_id: i32,
     },
+    // Serialized FileSearch, cancelled by CancelRead with its id
+    SearchFiles {
+        search: Vec<u8>,
+        conn_id: i32,
+    },
+    // Cancelled by CancelRead with its id
+    ExtractArchive {
+        id: i32,
+        path: String,
+        to: String,
+        conn_id: i32,
+        // Whether FileArchiveExtractProgress is sent
+        progress: bool,
+    },
 }
 
 #[cfg(target_os = "windows")]
@@ -333,6 +365,7 @@ pub enum Data {
         #[serde(skip)]
         data: bytes::Bytes,
         compressed: bool,
+        hash: Vec<u8>,
         conn_id: i32,
     },
     /// File read completed successfully
@@ -355,6 +388,7 @@ pub enum Data {
         last_modified: u64,
         file_size: u64,
         is_resume: bool,
+        hash: Vec<u8>,
         conn_id: i32,
     },
     /// Response to ReadAllFiles: recursive directory listing
@@ -465,6 +499,8 @@ pub struct CheckIfRestart {
     ws: String,
     disable_udp: String,
     allow_insecure_tls_fallback: String,
+    tls_pins: String,
+    tls_client_cert: String,
     api_server: String,
 }
 
@@ -480,6 +516,8 @@ impl CheckIfRestart {
             allow_insecure_tls_fallback: Config::get_option(
                 config::keys::OPTION_ALLOW_INSECURE_TLS_FALLBACK,
             ),
+            tls_pins: Config::get_option(config::keys::OPTION_TLS_PINS),
+            tls_client_cert: Config::get_option(config::keys::OPTION_TLS_CLIENT_CERT),
             api_server: Config::get_option("api-server"),
         }
     }
@@ -490,7 +528,9 @@ impl Drop for CheckIfRestart {
         // No need to check if https proxy is used, because this option does not change frequently
         // and restarting mediator is safe even https proxy is not used.
         let allow_insecure_tls_fallback_changed = self.allow_insecure_tls_fallback
-            != Config::get_option(config::keys::OPTION_ALLOW_INSECURE_TLS_FALLBACK);
+            != Config::get_option(config::keys::OPTION_ALLOW_INSECURE_TLS_FALLBACK)
+            || self.tls_pins != Config::get_option(config::keys::OPTION_TLS_PINS)
+            || self.tls_client_cert != Config::get_option(config::keys::OPTION_TLS_CLIENT_CERT);
         if allow_insecure_tls_fallback_changed
             || self.stop_service != Config::get_option("stop-service")
             || self.rendezvous_servers != Config::get_rendezvous_servers()
@@ -2,21 +2,115 @@ use hbb_common::{
     anyhow,
     bytes::{Bytes, BytesMut},
     bytes_codec::BytesCodec,
-    config, log,
+    config::{self, keys, Config},
+    log,
     tcp::{DynTcpStream, FramedStream},
-    tokio::{self, net::UdpSocket, sync::mpsc, sync::oneshot},
+    tokio::{self, net::UdpSocket, sync::mpsc, sync::oneshot, time},
     tokio_util, ResultType, Stream,
 };
 use kcp_sys::{
     endpoint::KcpEndpoint,
+    ffi_safe::KcpConfig,
     packet_def::{KcpPacket, KcpPacketHeader},
     stream,
 };
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+mod fec;
+mod stats;
+use fec::{FecConfig, HEADER_LEN as FEC_HEADER_LEN};
+pub use stats::{KcpStats, LOSS_FEEDBACK_SCALE};
+
+// Flush a partial FEC group if no more packets come in this time.
+const FEC_FLUSH_INTERVAL: Duration = Duration::from_millis(20);
+
+/// KCP tuning set by `OPTION_KCP_PROFILE`.
+///
+/// The option is "normal" (kcp-sys defaults), "fast", "turbo",
+/// or "nodelay,interval,resend,nc,snd_wnd,rcv_wnd", e.g. "1,10,2,1,256,256".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KcpProfile {
+    pub nodelay: bool,
+    pub interval: i32,
+    pub resend: i32,
+    pub nc: bool,
+    pub snd_wnd: u32,
+    pub rcv_wnd: u32,
+}
+
+impl KcpProfile {
+    const FAST: KcpProfile = KcpProfile {
+        nodelay: true,
+        interval: 20,
+        resend: 2,
+        nc: true,
+        snd_wnd: 128,
+        rcv_wnd: 128,
+    };
+    const TURBO: KcpProfile = KcpProfile {
+        nodelay: true,
+        interval: 10,
+        resend: 2,
+        nc: true,
+        snd_wnd: 512,
+        rcv_wnd: 512,
+    };
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "fast" => return Some(Self::FAST),
+            "turbo" => return Some(Self::TURBO),
+            _ => {}
+        }
+        let v: Vec<&str> = s.split(',').map(|x| x.trim()).collect();
+        if v.len() != 6 {
+            return None;
+        }
+        let profile = KcpProfile {
+            nodelay: v[0] == "1",
+            interval: v[1].parse().ok()?,
+            resend: v[2].parse().ok()?,
+            nc: v[3] == "1",
+            snd_wnd: v[4].parse().ok()?,
+            rcv_wnd: v[5].parse().ok()?,
+        };
+        if !(10..=5000).contains(&profile.interval)
+            || profile.resend < 0
+            || profile.snd_wnd == 0
+            || profile.rcv_wnd == 0
+        {
+            return None;
+        }
+        Some(profile)
+    }
+
+    fn get() -> Option<Self> {
+        Self::parse(&Config::get_option(keys::OPTION_KCP_PROFILE))
+    }
+
+    fn apply(self, endpoint: &mut KcpEndpoint) {
+        endpoint.set_kcp_config_factory(Box::new(move |conv| {
+            let mut config = KcpConfig::new(conv);
+            config.nodelay = Some(self.nodelay);
+            config.interval = Some(self.interval);
+            config.resend = Some(self.resend);
+            config.nc = Some(self.nc);
+            config.snd_wnd = Some(self.snd_wnd);
+            config.rcv_wnd = Some(self.rcv_wnd);
+            config
+        }));
+    }
+}
+
+/// Whether to ask the peer for FEC framing on the KCP packets, see `OPTION_KCP_FEC`.
+pub fn is_fec_enabled() -> bool {
+    FecConfig::parse(&Config::get_option(keys::OPTION_KCP_FEC)).is_some()
+}
 
 pub struct KcpStream {
     _endpoint: KcpEndpoint,
     stop_sender: Option<oneshot::Sender<()>>,
+    stats: Arc<KcpStats>,
 }
 
 impl KcpStream {
@@ -29,12 +123,23 @@ impl KcpStream {
         ))
     }
 
+    fn new_endpoint() -> KcpEndpoint {
+        let mut endpoint = KcpEndpoint::new();
+        if let Some(profile) = KcpProfile::get() {
+            log::debug!("KCP profile: {:?}", profile);
+            profile.apply(&mut endpoint);
+        }
+        endpoint
+    }
+
+    // `fec`: the peers agreed on FEC framing during the punch hole
     pub async fn accept(
         udp_socket: Arc<UdpSocket>,
         timeout: std::time::Duration,
         init_packet: Option<BytesMut>,
+        fec: bool,
     ) -> ResultType<(Self, Stream)> {
-        let mut endpoint = KcpEndpoint::new();
+        let mut endpoint = Self::new_endpoint();
         endpoint.run().await;
 
         let (input, output) = (
@@ -44,12 +149,29 @@ impl KcpStream {
                 .ok_or_else(|| anyhow::anyhow!("Failed to get output receiver"))?,
         );
         let (stop_sender, stop_receiver) = oneshot::channel();
+        let stats = Arc::new(KcpStats::default());
+        let mut decoder = fec.then(fec::Decoder::default);
         if let Some(packet) = init_packet {
-            if packet.len() >= std::mem::size_of::<KcpPacketHeader>() {
-                input.send(packet.into()).await?;
+            let packets = match decoder.as_mut() {
+                Some(decoder) => decoder.decode(&packet, &stats).unwrap_or_default(),
+                None => vec![packet.to_vec()],
+            };
+            for packet in packets {
+                if packet.len() >= std::mem::size_of::<KcpPacketHeader>() {
+                    input.send(BytesMut::from(&packet[..]).into()).await?;
+                }
             }
         }
-        Self::kcp_io(udp_socket.clone(), input, output, stop_receiver).await;
+        Self::kcp_io(
+            udp_socket.clone(),
+            input,
+            output,
+            stop_receiver,
+            fec,
+            decoder,
+            stats.clone(),
+        )
+        .await;
 
         let conn_id = tokio::time::timeout(timeout, endpoint.accept()).await??;
         if let Some(stream) = stream::KcpStream::new(&endpoint, conn_id) {
@@ -57,6 +179,7 @@ impl KcpStream {
                 Self {
                     _endpoint: endpoint,
                     stop_sender: Some(stop_sender),
+                    stats,
                 },
                 Self::create_framed(stream, udp_socket.local_addr().ok()),
             ))
@@ -68,8 +191,9 @@ impl KcpStream {
     pub async fn connect(
         udp_socket: Arc<UdpSocket>,
         timeout: std::time::Duration,
+        fec: bool,
     ) -> ResultType<(Self, Stream)> {
-        let mut endpoint = KcpEndpoint::new();
+        let mut endpoint = Self::new_endpoint();
         endpoint.run().await;
 
         let (input, output) = (
@@ -79,7 +203,17 @@ impl KcpStream {
                 .ok_or_else(|| anyhow::anyhow!("Failed to get output receiver"))?,
         );
         let (stop_sender, stop_receiver) = oneshot::channel();
-        Self::kcp_io(udp_socket.clone(), input, output, stop_receiver).await;
+        let stats = Arc::new(KcpStats::default());
+        Self::kcp_io(
+            udp_socket.clone(),
+            input,
+            output,
+            stop_receiver,
+            fec,
+            fec.then(fec::Decoder::default),
+            stats.clone(),
+        )
+        .await;
 
         let conn_id = endpoint.connect(timeout, 0, 0, Bytes::new()).await?;
         if let Some(stream) = stream::KcpStream::new(&endpoint, conn_id) {
@@ -87,6 +221,7 @@ impl KcpStream {
                 Self {
                     _endpoint: endpoint,
                     stop_sender: Some(stop_sender),
+                    stats,
                 },
                 Self::create_framed(stream, udp_socket.local_addr().ok()),
             ))
@@ -95,15 +230,39 @@ impl KcpStream {
         }
     }
 
+    #[inline]
+    pub fn stats(&self) -> Arc<KcpStats> {
+        self.stats.clone()
+    }
+
+    async fn send_frames(udp: &UdpSocket, frames: Vec<Vec<u8>>) -> std::io::Result<()> {
+        for frame in frames {
+            udp.send(&frame).await?;
+        }
+        Ok(())
+    }
+
     async fn kcp_io(
         udp_socket: Arc<UdpSocket>,
         input: mpsc::Sender<KcpPacket>,
         mut output: mpsc::Receiver<KcpPacket>,
         mut stop_receiver: oneshot::Receiver<()>,
+        fec: bool,
+        mut decoder: Option<fec::Decoder>,
+        stats: Arc<KcpStats>,
     ) {
         let udp = udp_socket.clone();
+        // Once negotiated, every packet is sent with the FEC header, the parity ratio is our own.
+        let mut encoder = if fec {
+            let config = FecConfig::parse(&Config::get_option(keys::OPTION_KCP_FEC));
+            Some(fec::Encoder::new(config.unwrap_or(FecConfig::DEFAULT)))
+        } else {
+            None
+        };
         tokio::spawn(async move {
             let mut buf = vec![0; 1500];
+            let mut flush_timer = time::interval(FEC_FLUSH_INTERVAL);
+            let mut tracker = stats::Tracker::default();
             loop {
                 tokio::select! {
                     _ = &mut stop_receiver => {
@@ -111,17 +270,50 @@ impl KcpStream {
                         break;
                     }
                     Some(data) = output.recv() => {
-                        if let Err(e) = udp.send(&data.inner()).await {
+                        tracker.on_send(&data.inner());
+                        let frames = match encoder.as_mut() {
+                            Some(encoder) => match encoder.encode(&data.inner()) {
+                                Ok(frames) => frames,
+                                Err(e) => {
+                                    log::debug!("KCP FEC encode error: {:?}", e);
+                                    break;
+                                }
+                            },
+                            None => vec![data.inner().to_vec()],
+                        };
+                        if let Err(e) = Self::send_frames(&udp, frames).await {
                             log::debug!("KCP send error: {:?}", e);
                             break;
                         }
                     }
+                    _ = flush_timer.tick(), if encoder.as_ref().map(|e| e.is_pending()).unwrap_or(false) => {
+                        if let Some(encoder) = encoder.as_mut() {
+                            let frames = encoder.flush().unwrap_or_default();
+                            if let Err(e) = Self::send_frames(&udp, frames).await {
+                                log::debug!("KCP send error: {:?}", e);
+                                break;
+                            }
+                        }
+                    }
                     result = udp.recv_from(&mut buf) => {
                         match result {
                             Ok((size, _)) => {
+                                if let Some(decoder) = decoder.as_mut() {
+                                    if size < FEC_HEADER_LEN {
+                                        continue;
+                                    }
+                                    for packet in decoder.decode(&buf[..size], &stats).unwrap_or_default() {
+                                        if packet.len() >= std::mem::size_of::<KcpPacketHeader>() {
+                                            tracker.on_recv(&packet, &stats);
+                                            input.send(BytesMut::from(&packet[..]).into()).await.ok();
+                                        }
+                                    }
+                                    continue;
+                                }
                                 if size < std::mem::size_of::<KcpPacketHeader>() {
                                     continue;
                                 }
+                                tracker.on_recv(&buf[..size], &stats);
                                 input
                                     .send(BytesMut::from(&buf[..size]).into())
                                     .await.ok();
@@ -0,0 +1,318 @@
+// Reed-Solomon forward error correction on the UDP datagrams carrying KCP.
+//
+// Every KCP packet is sent at once as a data frame, and after `data_shards` packets (or when
+// flushed) `parity_shards` parity frames follow, so the receiver can rebuild lost packets of the
+// group without waiting for a KCP retransmission.
+//
+// Frame layout: | group: u32 | index: u8 | data_shards: u8 | parity_shards: u8 | payload |
+// Shard counts are only filled in parity frames, data frames carry zeros.
+// A shard is the KCP packet prefixed with its u16 length and zero padded to the longest packet
+// of the group.
+use super::KcpStats;
+use hbb_common::{bail, ResultType};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::{collections::HashMap, sync::atomic::Ordering};
+
+pub const HEADER_LEN: usize = 7;
+// Groups older than this are dropped by the decoder.
+const MAX_GROUPS: u32 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FecConfig {
+    pub data_shards: u8,
+    pub parity_shards: u8,
+}
+
+impl FecConfig {
+    pub const DEFAULT: FecConfig = FecConfig {
+        data_shards: 10,
+        parity_shards: 3,
+    };
+
+    // "Y" for the default ratio or "data:parity", e.g. "8:4". Anything else disables FEC.
+    pub fn parse(s: &str) -> Option<Self> {
+        if s == "Y" {
+            return Some(Self::DEFAULT);
+        }
+        let (d, p) = s.split_once(':')?;
+        let config = FecConfig {
+            data_shards: d.trim().parse().ok()?,
+            parity_shards: p.trim().parse().ok()?,
+        };
+        if config.data_shards == 0
+            || config.parity_shards == 0
+            || config.data_shards as usize + config.parity_shards as usize > 255
+        {
+            return None;
+        }
+        Some(config)
+    }
+}
+
+fn frame(group: u32, index: u8, data_shards: u8, parity_shards: u8, payload: &[u8]) -> Vec<u8> {
+    let mut v = Vec::with_capacity(HEADER_LEN + payload.len());
+    v.extend_from_slice(&group.to_be_bytes());
+    v.push(index);
+    v.push(data_shards);
+    v.push(parity_shards);
+    v.extend_from_slice(payload);
+    v
+}
+
+pub struct Encoder {
+    config: FecConfig,
+    group: u32,
+    shards: Vec<Vec<u8>>,
+}
+
+impl Encoder {
+    pub fn new(config: FecConfig) -> Self {
+        Self {
+            config,
+            group: 0,
+            shards: Vec::with_capacity(config.data_shards as _),
+        }
+    }
+
+    /// Returns the frames to send for `packet`, including the parity frames once the group is full.
+    pub fn encode(&mut self, packet: &[u8]) -> ResultType<Vec<Vec<u8>>> {
+        let mut out = vec![frame(self.group, self.shards.len() as _, 0, 0, packet)];
+        let mut shard = Vec::with_capacity(packet.len() + 2);
+        shard.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+        shard.extend_from_slice(packet);
+        self.shards.push(shard);
+        if self.shards.len() >= self.config.data_shards as usize {
+            out.append(&mut self.flush()?);
+        }
+        Ok(out)
+    }
+
+    /// Closes the current group, even if it is not full.
+    pub fn flush(&mut self) -> ResultType<Vec<Vec<u8>>> {
+        if self.shards.is_empty() {
+            return Ok(vec![]);
+        }
+        let data_shards = self.shards.len();
+        let parity_shards = self.config.parity_shards as usize;
+        let len = self
+            .shards
+            .iter()
+            .map(|s| s.len())
+            .max()
+            .unwrap_or_default();
+        let mut shards = std::mem::take(&mut self.shards);
+        shards.iter_mut().for_each(|s| s.resize(len, 0));
+        shards.resize(data_shards + parity_shards, vec![0; len]);
+        ReedSolomon::new(data_shards, parity_shards)?.encode(&mut shards)?;
+        let out = shards[data_shards..]
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                frame(
+                    self.group,
+                    (data_shards + i) as _,
+                    data_shards as _,
+                    parity_shards as _,
+                    s,
+                )
+            })
+            .collect();
+        self.group = self.group.wrapping_add(1);
+        Ok(out)
+    }
+
+    #[inline]
+    pub fn is_pending(&self) -> bool {
+        !self.shards.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct Group {
+    shards: Vec<Option<Vec<u8>>>,
+    // (data_shards, parity_shards), known after the first parity frame
+    counts: Option<(usize, usize)>,
+    delivered: Vec<bool>,
+    done: bool,
+}
+
+#[derive(Default)]
+pub struct Decoder {
+    groups: HashMap<u32, Group>,
+    latest: u32,
+}
+
+impl Decoder {
+    /// Returns the KCP packets carried or rebuilt from `frame`.
+    pub fn decode(&mut self, frame: &[u8], stats: &KcpStats) -> ResultType<Vec<Vec<u8>>> {
+        if frame.len() < HEADER_LEN {
+            bail!("FEC frame too short");
+        }
+        let group = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        let index = frame[4] as usize;
+        let counts = (frame[5] as usize, frame[6] as usize);
+        let payload = &frame[HEADER_LEN..];
+        let age = self.latest.wrapping_sub(group);
+        if age > u32::MAX / 2 {
+            self.latest = group;
+            self.evict(stats);
+        } else if age >= MAX_GROUPS {
+            // too old, already accounted for
+            return Ok(vec![]);
+        }
+        let g = self.groups.entry(group).or_default();
+        if g.shards.len() <= index {
+            g.shards.resize(index + 1, None);
+            g.delivered.resize(index + 1, false);
+        }
+        let mut out = vec![];
+        if counts.0 == 0 {
+            if g.delivered[index] {
+                return Ok(out);
+            }
+            stats.received.fetch_add(1, Ordering::Relaxed);
+            g.delivered[index] = true;
+            out.push(payload.to_vec());
+            let mut shard = Vec::with_capacity(payload.len() + 2);
+            shard.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            shard.extend_from_slice(payload);
+            g.shards[index] = Some(shard);
+        } else {
+            if g.counts.is_none() {
+                let (d, p) = counts;
+                g.shards.resize((d + p).max(g.shards.len()), None);
+                g.delivered.resize(g.shards.len(), false);
+                g.counts = Some(counts);
+            }
+            g.shards[index] = Some(payload.to_vec());
+        }
+        if let Some((d, p)) = g.counts {
+            if !g.done
+                && g.shards.len() == d + p
+                && g.delivered[..d].iter().any(|x| !x)
+                && g.shards.iter().filter(|s| s.is_some()).count() >= d
+            {
+                g.done = true;
+                // Data shards are shorter than the parity ones before padding.
+                let len = g.shards[d..].iter().flatten().map(|s| s.len()).max();
+                let len = len.unwrap_or_default();
+                g.shards.iter_mut().flatten().for_each(|s| s.resize(len, 0));
+                ReedSolomon::new(d, p)?.reconstruct_data(&mut g.shards)?;
+                for i in 0..d {
+                    if g.delivered[i] {
+                        continue;
+                    }
+                    if let Some(shard) = &g.shards[i] {
+                        let n = u16::from_be_bytes([shard[0], shard[1]]) as usize;
+                        if n + 2 <= shard.len() {
+                            out.push(shard[2..n + 2].to_vec());
+                            g.delivered[i] = true;
+                            stats.lost.fetch_add(1, Ordering::Relaxed);
+                            stats.recovered.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    fn evict(&mut self, stats: &KcpStats) {
+        let latest = self.latest;
+        self.groups.retain(|id, g| {
+            if latest.wrapping_sub(*id) < MAX_GROUPS {
+                return true;
+            }
+            // The number of data shards is only known if a parity frame made it,
+            // else at least the ones before the last data frame received are missing.
+            let d = g.counts.map(|(d, _)| d).unwrap_or(g.delivered.len());
+            let missing = g.delivered[..d].iter().filter(|x| !**x).count();
+            stats.lost.fetch_add(missing as _, Ordering::Relaxed);
+            false
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fec_config() {
+        assert_eq!(FecConfig::parse("Y"), Some(FecConfig::DEFAULT));
+        assert_eq!(
+            FecConfig::parse("8:4"),
+            Some(FecConfig {
+                data_shards: 8,
+                parity_shards: 4
+            })
+        );
+        assert_eq!(FecConfig::parse(""), None);
+        assert_eq!(FecConfig::parse("N"), None);
+        assert_eq!(FecConfig::parse("0:3"), None);
+        assert_eq!(FecConfig::parse("200:100"), None);
+    }
+
+    #[test]
+    fn test_fec_recover() {
+        let mut encoder = Encoder::new(FecConfig {
+            data_shards: 4,
+            parity_shards: 2,
+        });
+        let packets: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 20 + i as usize * 7]).collect();
+        let mut frames = vec![];
+        for p in packets.iter() {
+            frames.append(&mut encoder.encode(p).unwrap());
+        }
+        assert!(encoder.is_pending());
+        frames.append(&mut encoder.flush().unwrap());
+        assert!(!encoder.is_pending());
+        // 10 data frames, 2 full groups and a partial one with 2 parity frames each
+        assert_eq!(frames.len(), 16);
+
+        let stats = KcpStats::default();
+        let mut decoder = Decoder::default();
+        let mut received = vec![];
+        for (i, f) in frames.iter().enumerate() {
+            // drop 2 data frames of the first group and 1 of the last
+            if i == 0 || i == 2 || i == 13 {
+                continue;
+            }
+            received.append(&mut decoder.decode(f, &stats).unwrap());
+        }
+        received.sort();
+        let mut expected = packets.clone();
+        expected.sort();
+        assert_eq!(received, expected);
+        assert_eq!(stats.received.load(Ordering::Relaxed), 7);
+        assert_eq!(stats.recovered.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.residual_loss(), 0.);
+
+        // Duplicated frames are not delivered twice.
+        assert!(decoder.decode(&frames[1], &stats).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fec_lost_without_parity() {
+        let mut encoder = Encoder::new(FecConfig {
+            data_shards: 4,
+            parity_shards: 2,
+        });
+        let mut frames = vec![];
+        for i in 0..4 * (MAX_GROUPS + 1) {
+            frames.append(&mut encoder.encode(&[i as u8; 10]).unwrap());
+        }
+        let stats = KcpStats::default();
+        let mut decoder = Decoder::default();
+        for (i, f) in frames.iter().enumerate() {
+            // the first group loses its second data frame and both parity frames
+            if i == 1 || i == 4 || i == 5 {
+                continue;
+            }
+            decoder.decode(f, &stats).unwrap();
+        }
+        assert_eq!(stats.lost.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.recovered.load(Ordering::Relaxed), 0);
+        assert!(stats.residual_loss() > 0.);
+    }
+}
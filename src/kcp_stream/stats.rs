@@ -0,0 +1,224 @@
+// Per session counters of a KCP stream, reported to the host in `TestDelay`.
+//
+// kcp_sys hands the ikcp output over as is, so the segments are read from the datagrams:
+// | conv: u32 | cmd: u8 | frg: u8 | wnd: u16 | ts: u32 | sn: u32 | una: u32 | len: u32 | data |
+// all little endian, several segments may share one datagram.
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Instant,
+};
+
+/// `TestDelay.loss` and `TestDelay.retransmits` are ratios times this.
+pub const LOSS_FEEDBACK_SCALE: f32 = 10_000.;
+const SEGMENT_HEADER_LEN: usize = 24;
+const CMD_PUSH: u8 = 81;
+const CMD_ACK: u8 = 82;
+// Bounds the send times kept if the peer stops acknowledging.
+const MAX_PENDING: usize = 4096;
+
+/// Counters of one KCP session.
+#[derive(Debug, Default)]
+pub struct KcpStats {
+    pub received: AtomicU64,
+    // including the recovered ones
+    pub lost: AtomicU64,
+    pub recovered: AtomicU64,
+    // data segments of the peer, and how many of them were sent again
+    pub segments: AtomicU64,
+    pub retransmits: AtomicU64,
+    // smoothed round trip of our data segments in ms, 0 until measured
+    pub rtt: AtomicU32,
+}
+
+impl KcpStats {
+    // Ratio of the packets that were lost and not rebuilt by FEC, so KCP had to retransmit them.
+    pub fn residual_loss(&self) -> f32 {
+        let received = self.received.load(Ordering::Relaxed);
+        let lost = self.lost.load(Ordering::Relaxed);
+        let recovered = self.recovered.load(Ordering::Relaxed);
+        let total = received + lost;
+        if total == 0 {
+            return 0.;
+        }
+        lost.saturating_sub(recovered) as f32 / total as f32
+    }
+
+    // Ratio of the peer's data segments that arrived as retransmissions,
+    // whether FEC is on or not.
+    pub fn retransmit_ratio(&self) -> f32 {
+        let segments = self.segments.load(Ordering::Relaxed);
+        if segments == 0 {
+            return 0.;
+        }
+        self.retransmits.load(Ordering::Relaxed) as f32 / segments as f32
+    }
+
+    pub fn reset(&self) {
+        self.received.store(0, Ordering::Relaxed);
+        self.lost.store(0, Ordering::Relaxed);
+        self.recovered.store(0, Ordering::Relaxed);
+        self.segments.store(0, Ordering::Relaxed);
+        self.retransmits.store(0, Ordering::Relaxed);
+    }
+
+    /// The residual loss and the retransmit ratio since the last call,
+    /// for `TestDelay.loss` and `TestDelay.retransmits`.
+    pub fn take_feedback(&self) -> (u32, u32) {
+        let loss = (self.residual_loss() * LOSS_FEEDBACK_SCALE) as u32;
+        let retransmits = (self.retransmit_ratio() * LOSS_FEEDBACK_SCALE) as u32;
+        self.reset();
+        (loss, retransmits)
+    }
+
+    #[inline]
+    pub fn rtt(&self) -> u32 {
+        self.rtt.load(Ordering::Relaxed)
+    }
+}
+
+struct Segment {
+    cmd: u8,
+    sn: u32,
+    una: u32,
+}
+
+fn segments(mut packet: &[u8]) -> impl Iterator<Item = Segment> + '_ {
+    std::iter::from_fn(move || {
+        if packet.len() < SEGMENT_HEADER_LEN {
+            return None;
+        }
+        let u32_at =
+            |i: usize| u32::from_le_bytes([packet[i], packet[i + 1], packet[i + 2], packet[i + 3]]);
+        let segment = Segment {
+            cmd: packet[4],
+            sn: u32_at(12),
+            una: u32_at(16),
+        };
+        let len = SEGMENT_HEADER_LEN.saturating_add(u32_at(20) as usize);
+        packet = packet.get(len..).unwrap_or_default();
+        Some(segment)
+    })
+}
+
+// a comes before b, with the wrapping of the sequence numbers
+#[inline]
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Reads the KCP segments going through `kcp_io` into `KcpStats`.
+#[derive(Default)]
+pub struct Tracker {
+    // first send of our unacknowledged data segments, `None` once sent again (Karn's rule)
+    pending: HashMap<u32, Option<Instant>>,
+    una: u32,
+    // next data segment of the peer, if any was received
+    next_sn: Option<u32>,
+}
+
+impl Tracker {
+    pub fn on_send(&mut self, packet: &[u8]) {
+        for s in segments(packet) {
+            if s.cmd != CMD_PUSH || before(s.sn, self.una) {
+                continue;
+            }
+            if let Some(sent) = self.pending.get_mut(&s.sn) {
+                *sent = None;
+            } else if self.pending.len() < MAX_PENDING {
+                self.pending.insert(s.sn, Some(Instant::now()));
+            }
+        }
+    }
+
+    pub fn on_recv(&mut self, packet: &[u8], stats: &KcpStats) {
+        for s in segments(packet) {
+            match s.cmd {
+                CMD_PUSH => {
+                    stats.segments.fetch_add(1, Ordering::Relaxed);
+                    match self.next_sn {
+                        Some(next) if before(s.sn, next) => {
+                            stats.retransmits.fetch_add(1, Ordering::Relaxed);
+                        }
+                        _ => self.next_sn = Some(s.sn.wrapping_add(1)),
+                    }
+                }
+                CMD_ACK => {
+                    if let Some(Some(sent)) = self.pending.remove(&s.sn) {
+                        let sample = sent.elapsed().as_millis() as u32;
+                        let rtt = stats.rtt();
+                        let rtt = if rtt == 0 {
+                            sample.max(1)
+                        } else {
+                            (rtt * 7 + sample) / 8
+                        };
+                        stats.rtt.store(rtt, Ordering::Relaxed);
+                    }
+                }
+                _ => {}
+            }
+            if before(self.una, s.una) {
+                self.una = s.una;
+                let una = s.una;
+                self.pending.retain(|sn, _| !before(*sn, una));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(cmd: u8, sn: u32, una: u32, data: &[u8]) -> Vec<u8> {
+        let mut v = vec![];
+        v.extend_from_slice(&1u32.to_le_bytes());
+        v.extend_from_slice(&[cmd, 0, 128, 0]);
+        v.extend_from_slice(&0u32.to_le_bytes());
+        v.extend_from_slice(&sn.to_le_bytes());
+        v.extend_from_slice(&una.to_le_bytes());
+        v.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        v.extend_from_slice(data);
+        v
+    }
+
+    #[test]
+    fn test_retransmits() {
+        let stats = KcpStats::default();
+        let mut tracker = Tracker::default();
+        // 0, 1 and 3 in one datagram, 2 sent again after 3, then 3 once more
+        let mut packet = segment(CMD_PUSH, 0, 0, b"a");
+        packet.extend(segment(CMD_PUSH, 1, 0, b"bb"));
+        packet.extend(segment(CMD_PUSH, 3, 0, b"dddd"));
+        tracker.on_recv(&packet, &stats);
+        tracker.on_recv(&segment(CMD_PUSH, 2, 0, b"ccc"), &stats);
+        tracker.on_recv(&segment(CMD_PUSH, 3, 0, b"dddd"), &stats);
+        tracker.on_recv(&segment(CMD_PUSH, 4, 0, b""), &stats);
+        assert_eq!(stats.segments.load(Ordering::Relaxed), 6);
+        assert_eq!(stats.retransmits.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.take_feedback(), (0, 3333));
+        assert_eq!(stats.take_feedback(), (0, 0));
+        // a truncated segment is not read
+        tracker.on_recv(&segment(CMD_PUSH, 5, 0, b"e")[..10], &stats);
+        assert_eq!(stats.segments.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_rtt() {
+        let stats = KcpStats::default();
+        let mut tracker = Tracker::default();
+        tracker.on_send(&segment(CMD_PUSH, 0, 0, b"a"));
+        tracker.on_send(&segment(CMD_PUSH, 1, 0, b"b"));
+        // 1 is sent again, its ack does not tell which send it answers
+        tracker.on_send(&segment(CMD_PUSH, 1, 0, b"b"));
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        tracker.on_recv(&segment(CMD_ACK, 1, 0, b""), &stats);
+        assert_eq!(stats.rtt(), 0);
+        tracker.on_recv(&segment(CMD_ACK, 0, 2, b""), &stats);
+        assert!(stats.rtt() >= 20);
+        assert!(tracker.pending.is_empty());
+        // acknowledged by una, it is not timed again
+        tracker.on_send(&segment(CMD_PUSH, 0, 0, b"a"));
+        assert!(tracker.pending.is_empty());
+    }
+}
@@ -17,18 +17,73 @@ use hbb_common::{
 
 use std::{
     collections::{HashMap, HashSet},
-    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket},
     time::Instant,
 };
 
 type Message = RendezvousMessage;
 
+// Link-local, IPv6 has no broadcast. "RD" in the group id.
+const MULTICAST_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x5244);
+
 #[cfg(not(target_os = "ios"))]
 pub(super) fn start_listening() -> ResultType<()> {
+    let socket_v6 = match listen_multicast_v6() {
+        Ok(socket) => Some(socket),
+        Err(err) => {
+            log::warn!("Failed to listen for lan discovery on IPv6: {}", err);
+            None
+        }
+    };
     let addr = SocketAddr::from(([0, 0, 0, 0], get_broadcast_port()));
-    let socket = std::net::UdpSocket::bind(addr)?;
-    socket.set_read_timeout(Some(std::time::Duration::from_millis(1000)))?;
+    let socket = match std::net::UdpSocket::bind(addr) {
+        Ok(socket) => socket,
+        Err(err) => {
+            let Some(socket_v6) = socket_v6 else {
+                return Err(err.into());
+            };
+            log::warn!("Failed to listen for lan discovery on IPv4: {}", err);
+            return listen(socket_v6);
+        }
+    };
+    if let Some(socket_v6) = socket_v6 {
+        std::thread::spawn(move || allow_err!(listen(socket_v6)));
+    }
     log::info!("lan discovery listener started");
+    listen(socket)
+}
+
+// The group is joined on the interfaces up now, those coming up later are not listened on.
+#[cfg(not(target_os = "ios"))]
+fn listen_multicast_v6() -> ResultType<UdpSocket> {
+    use hbb_common::socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::ipv6(), Type::dgram(), None)?;
+    // Or it takes the IPv4 port too on some platforms.
+    socket.set_only_v6(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, get_broadcast_port())).into())?;
+    let socket = socket.into_udp_socket();
+    let mut joined = false;
+    for index in get_ipv6_interfaces() {
+        match socket.join_multicast_v6(&MULTICAST_GROUP_V6, index) {
+            Ok(()) => joined = true,
+            Err(err) => log::debug!(
+                "Failed to join {} on {}: {}",
+                MULTICAST_GROUP_V6,
+                index,
+                err
+            ),
+        }
+    }
+    if !joined {
+        bail!("Failed to join {} on any interface", MULTICAST_GROUP_V6);
+    }
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "ios"))]
+fn listen(socket: UdpSocket) -> ResultType<()> {
+    socket.set_read_timeout(Some(std::time::Duration::from_millis(1000)))?;
     loop {
         let mut buf = [0; 2048];
         if let Ok((len, addr)) = socket.recv_from(&mut buf) {
@@ -144,8 +199,8 @@ fn get_mac_by_ip(ip: &IpAddr) -> ResultType<String> {
 }
 
 // Mainly from https://github.com/shellrow/default-net/blob/cf7ca24e7e6e8e566ed32346c9cfddab3f47e2d6/src/interface/shared.rs#L4
-fn get_ipaddr_by_peer<A: ToSocketAddrs>(peer: A) -> Option<IpAddr> {
-    let socket = match UdpSocket::bind("0.0.0.0:0") {
+fn get_ipaddr_by_peer(peer: &SocketAddr) -> Option<IpAddr> {
+    let socket = match UdpSocket::bind(Config::get_any_listen_addr(peer.is_ipv4())) {
         Ok(s) => s,
         Err(_) => return None,
     };
@@ -185,11 +240,42 @@ fn create_broadcast_sockets() -> Vec<UdpSocket> {
     sockets
 }
 
-fn send_query() -> ResultType<Vec<UdpSocket>> {
-    let sockets = create_broadcast_sockets();
-    if sockets.is_empty() {
-        bail!("Found no bindable ipv4 addresses");
+// The indexes of the interfaces with an IPv6 address, 0 is the default one.
+fn get_ipv6_interfaces() -> Vec<u32> {
+    #[cfg(not(any(target_os = "ios")))]
+    let indexes: Vec<u32> = default_net::get_interfaces()
+        .iter()
+        .filter(|interface| interface.ipv6.iter().any(|x| !x.addr.is_loopback()))
+        .map(|interface| interface.index)
+        .collect();
+    #[cfg(any(target_os = "ios"))]
+    let indexes: Vec<u32> = Vec::new();
+    if indexes.is_empty() {
+        return vec![0];
+    }
+    indexes
+}
+
+fn send_query_v6(out: &[u8]) -> Option<UdpSocket> {
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))).ok()?;
+    let mut sent = false;
+    for index in get_ipv6_interfaces() {
+        // The scope id picks the interface of a link-local group.
+        let maddr = SocketAddrV6::new(MULTICAST_GROUP_V6, get_broadcast_port(), 0, index);
+        match socket.send_to(out, maddr) {
+            Ok(_) => sent = true,
+            Err(err) => log::debug!("Failed to send discover ping to {}: {}", maddr, err),
+        }
+    }
+    if sent {
+        Some(socket)
+    } else {
+        None
     }
+}
+
+fn send_query() -> ResultType<Vec<UdpSocket>> {
+    let mut sockets = create_broadcast_sockets();
 
     let mut msg_out = Message::new();
     // We may not be able to get the mac address on mobile platforms.
@@ -214,6 +300,10 @@ fn send_query() -> ResultType<Vec<UdpSocket>> {
     for socket in &sockets {
         allow_err!(socket.send_to(&out, maddr));
     }
+    sockets.extend(send_query_v6(&out));
+    if sockets.is_empty() {
+        bail!("Found no bindable addresses");
+    }
     log::info!("discover ping sent");
     Ok(sockets)
 }
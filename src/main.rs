@@ -42,6 +42,7 @@ fn main() {
     use hbb_common::log;
     let args = format!(
         "-p, --port-forward=[PORT-FORWARD-OPTIONS] 'Format: remote-id:local-port:remote-port[:remote-host]'
+        -t, --ssh-bridge=[SSH-BRIDGE-OPTIONS] 'Format: remote-id:local-port, ssh to the terminal of remote-id with a key of ~/.ssh/authorized_keys'
         -c, --connect=[REMOTE_ID] 'test only'
         -k, --key=[KEY] ''
        -s, --server=[] 'Start server'",
@@ -90,6 +91,22 @@ fn main() {
             key,
             token,
         );
+    } else if let Some(p) = matches.value_of("ssh-bridge") {
+        let options: Vec<String> = p.split(":").map(|x| x.to_owned()).collect();
+        if options.len() != 2 {
+            log::error!("Wrong ssh-bridge options");
+            return;
+        }
+        let port = match options[1].parse::<i32>() {
+            Ok(v) if v > 0 => v,
+            _ => {
+                log::error!("Wrong local-port");
+                return;
+            }
+        };
+        let key = matches.value_of("key").unwrap_or("").to_owned();
+        let token = LocalConfig::get_option("access_token");
+        cli::start_one_ssh_bridge(options[0].clone(), port, key, token);
     } else if let Some(p) = matches.value_of("connect") {
         common::test_rendezvous_server();
         common::test_nat_type();
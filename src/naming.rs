@@ -11,12 +11,14 @@ fn main() {
     let args: Vec<_> = std::env::args().skip(1).collect();
     let api = args.get(2).cloned().unwrap_or_default();
     let relay = args.get(3).cloned().unwrap_or_default();
+    let pins = args.get(4).cloned().unwrap_or_default();
     if args.len() >= 2 {
         match gen_name(&CustomServer {
             key: args[0].clone(),
             host: args[1].clone(),
             api,
             relay,
+            pins,
         }) {
             Ok(name) => println!("rustdesk-custom_serverd-{}.exe", name),
             Err(e) => println!("{:?}", e),
@@ -1538,6 +1538,7 @@ if exist \"{tmp_path}\\{app_name} Tray.lnk\" del /f /q \"{tmp_path}\\{app_name}
         Config::set_option("key".into(), lic.key);
         Config::set_option("custom-rendezvous-server".into(), lic.host);
         Config::set_option("api-server".into(), lic.api);
+        Config::set_option(config::keys::OPTION_TLS_PINS.into(), lic.pins);
     }
 
     // HibtDesk: Extract configuration from installation options
@@ -2174,6 +2175,7 @@ pub fn is_win_10_or_greater() -> bool {
 pub fn bootstrap() -> bool {
     if let Ok(lic) = get_license_from_exe_name() {
         *config::EXE_RENDEZVOUS_SERVER.write().unwrap() = lic.host.clone();
+        *config::EXE_TLS_PINS.write().unwrap() = lic.pins.clone();
     }
 
     #[cfg(debug_assertions)]
@@ -18,9 +18,10 @@ use hbb_common::{
     futures::future::join_all,
     log,
     protobuf::Message as _,
+    proxy,
     rendezvous_proto::*,
     sleep,
-    socket_client::{self, connect_tcp, is_ipv4, new_direct_udp_for, new_udp_for},
+    socket_client::{self, connect_tcp, is_ipv4, new_punch_udp_for, new_udp_for},
     tokio::{self, select, sync::Mutex, time::interval},
     udp::FramedSocket,
     AddrMangle, IntoTargetAddr, ResultType, Stream, TargetAddr,
@@ -399,12 +400,22 @@ impl RendezvousMediator {
     pub async fn start(server: ServerPtr, host: String) -> ResultType<()> {
         log::info!("start rendezvous mediator of {}", host);
         //If the investment agent type is http or https, then tcp forwarding is enabled.
+        let udp_proxy = proxy::auto::get_socks_for_target(&check_port(&host, RENDEZVOUS_PORT))
+            .await
+            .map(|conf| proxy::is_udp_capable(&conf.proxy));
         if (cfg!(debug_assertions) && option_env!("TEST_TCP").is_some())
-            || Config::is_proxy()
+            || udp_proxy == Some(false)
             || use_ws()
             || crate::is_udp_disabled()
         {
             Self::start_tcp(server, host).await
+        } else if udp_proxy.is_some() {
+            // Not every SOCKS5 server allows UDP ASSOCIATE, fall back to TCP then.
+            if let Err(err) = Self::start_udp(server.clone(), host.clone()).await {
+                log::warn!("UDP through the proxy failed, use TCP instead: {}", err);
+                return Self::start_tcp(server, host).await;
+            }
+            Ok(())
         } else {
             Self::start_udp(server, host).await
         }
@@ -428,6 +439,8 @@ impl RendezvousMediator {
             false,
             Default::default(),
             rr.control_permissions.clone().into_option(),
+            false,
+            false,
         )
         .await
     }
@@ -442,6 +455,8 @@ impl RendezvousMediator {
         initiate: bool,
         socket_addr_v6: bytes::Bytes,
         control_permissions: Option<ControlPermissions>,
+        quic: bool,
+        kcp_fec: bool,
     ) -> ResultType<()> {
         let peer_addr = AddrMangle::decode(&socket_addr);
         log::info!(
@@ -459,6 +474,8 @@ impl RendezvousMediator {
             socket_addr: socket_addr.into(),
             version: crate::VERSION.to_owned(),
             socket_addr_v6,
+            quic,
+            kcp_fec,
             ..Default::default()
         };
         if initiate {
@@ -481,6 +498,21 @@ impl RendezvousMediator {
         Ok(())
     }
 
+    // Set manually or detected for the rendezvous server.
+    async fn is_proxy(&self) -> bool {
+        proxy::auto::get_socks_for_target(&self.host)
+            .await
+            .is_some()
+    }
+
+    // Whether UDP hole punching can go through the proxy, with SOCKS5 UDP ASSOCIATE.
+    async fn is_proxy_udp_capable(&self) -> bool {
+        proxy::auto::get_socks_for_target(&self.host)
+            .await
+            .map(|conf| proxy::is_udp_capable(&conf.proxy))
+            .unwrap_or(false)
+    }
+
     async fn handle_intranet(&self, fla: FetchLocalAddr, server: ServerPtr) -> ResultType<()> {
         let addr = AddrMangle::decode(&fla.socket_addr);
         let last = *LAST_MSG.lock().await;
@@ -491,7 +523,8 @@ impl RendezvousMediator {
         }
         let peer_addr_v6 = hbb_common::AddrMangle::decode(&fla.socket_addr_v6);
         let relay_server = self.get_relay_server(fla.relay_server.clone());
-        let relay = use_ws() || Config::is_proxy();
+        let relay = use_ws() || self.is_proxy().await;
+        let quic = crate::accept_quic(fla.quic);
         let mut socket_addr_v6 = Default::default();
         if peer_addr_v6.port() > 0 && !relay {
             socket_addr_v6 = start_ipv6(
@@ -499,6 +532,8 @@ impl RendezvousMediator {
                 addr,
                 server.clone(),
                 fla.control_permissions.clone().into_option(),
+                quic,
+                false,
             )
             .await;
         }
@@ -527,6 +562,8 @@ impl RendezvousMediator {
             true,
             socket_addr_v6,
             fla.control_permissions.into_option(),
+            quic,
+            false,
         )
         .await
     }
@@ -577,15 +614,22 @@ impl RendezvousMediator {
             return Ok(());
         }
         let peer_addr_v6 = hbb_common::AddrMangle::decode(&ph.socket_addr_v6);
-        let relay = use_ws() || Config::is_proxy() || ph.force_relay;
+        let proxied = self.is_proxy().await;
+        // Only UDP hole punching goes through the proxy, the others need a direct route.
+        let udp_proxied = proxied && ph.udp_port > 0 && self.is_proxy_udp_capable().await;
+        let relay = use_ws() || (proxied && !udp_proxied) || ph.force_relay;
+        let quic = crate::accept_quic(ph.quic);
+        let kcp_fec = ph.kcp_fec;
         let mut socket_addr_v6 = Default::default();
         let control_permissions = ph.control_permissions.into_option();
-        if peer_addr_v6.port() > 0 && !relay {
+        if peer_addr_v6.port() > 0 && !relay && !proxied {
             socket_addr_v6 = start_ipv6(
                 peer_addr_v6,
                 peer_addr,
                 server.clone(),
                 control_permissions.clone(),
+                quic,
+                kcp_fec,
             )
             .await;
         }
@@ -607,6 +651,8 @@ impl RendezvousMediator {
                     true,
                     socket_addr_v6.clone(),
                     control_permissions,
+                    quic,
+                    kcp_fec,
                 )
                 .await;
         }
@@ -619,12 +665,21 @@ impl RendezvousMediator {
             nat_type: nat_type.into(),
             version: crate::VERSION.to_owned(),
             socket_addr_v6,
+            quic,
+            kcp_fec,
             ..Default::default()
         };
         if ph.udp_port > 0 {
             peer_addr.set_port(ph.udp_port as u16);
-            self.punch_udp_hole(peer_addr, server, msg_punch, control_permissions)
-                .await?;
+            self.punch_udp_hole(
+                peer_addr,
+                server,
+                msg_punch,
+                control_permissions,
+                quic,
+                kcp_fec,
+            )
+            .await?;
             return Ok(());
         }
         log::debug!("Punch tcp hole to {:?}", peer_addr);
@@ -651,10 +706,12 @@ impl RendezvousMediator {
         server: ServerPtr,
         msg_punch: PunchHoleSent,
         control_permissions: Option<ControlPermissions>,
+        quic: bool,
+        kcp_fec: bool,
     ) -> ResultType<()> {
         let mut msg_out = Message::new();
         msg_out.set_punch_hole_sent(msg_punch);
-        let (socket, addr) = new_direct_udp_for(&self.host).await?;
+        let (socket, addr) = new_punch_udp_for(&self.host, CONNECT_TIMEOUT).await?;
         let data = msg_out.write_to_bytes()?;
         socket.send_to(&data, addr).await?;
         let socket_cloned = socket.clone();
@@ -671,6 +728,8 @@ impl RendezvousMediator {
             peer_addr,
             server,
             control_permissions,
+            quic,
+            kcp_fec,
         )
         .await?;
         Ok(())
@@ -846,6 +905,8 @@ async fn start_ipv6(
     peer_addr_v4: SocketAddr,
     server: ServerPtr,
     control_permissions: Option<ControlPermissions>,
+    quic: bool,
+    kcp_fec: bool,
 ) -> bytes::Bytes {
     crate::test_ipv6().await;
     if let Some((socket, local_addr_v6)) = crate::get_ipv6_socket().await {
@@ -857,7 +918,9 @@ async fn start_ipv6(
                     peer_addr_v6,
                     peer_addr_v4,
                     server,
-                    control_permissions
+                    control_permissions,
+                    quic,
+                    kcp_fec,
                 )
                 .await
             );
@@ -873,16 +936,37 @@ async fn udp_nat_listen(
     peer_addr_v4: SocketAddr,
     server: ServerPtr,
     control_permissions: Option<ControlPermissions>,
+    quic: bool,
+    kcp_fec: bool,
 ) -> ResultType<()> {
     let tm = Instant::now();
     let socket_cloned = socket.clone();
+    let typ = if quic { "QUIC" } else { "KCP" };
     let func = async {
-        socket.connect(peer_addr).await?;
+        socket
+            .connect(socket_client::udp_peer_addr(&socket, peer_addr).await?)
+            .await?;
         let res = crate::punch_udp(socket.clone(), true).await?;
+        #[cfg(feature = "quic")]
+        if quic {
+            // The first packet got by punch_udp may be the QUIC initial packet,
+            // it is dropped here and recovered by the retransmission of the client.
+            let stream = hbb_common::quic::QuicStream::accept(socket, CONNECT_TIMEOUT as _).await?;
+            crate::server::create_tcp_connection(
+                server,
+                hbb_common::Stream::Quic(stream),
+                peer_addr_v4,
+                true,
+                control_permissions,
+            )
+            .await?;
+            return Ok(());
+        }
         let stream = crate::kcp_stream::KcpStream::accept(
             socket,
             Duration::from_millis(CONNECT_TIMEOUT as _),
             res,
+            kcp_fec,
         )
         .await?;
         crate::server::create_tcp_connection(
@@ -897,7 +981,7 @@ async fn udp_nat_listen(
     };
     func.await.map_err(|e: anyhow::Error| {
         anyhow::anyhow!(
-            "Stop listening on {:?} for remote {peer_addr} with KCP, {:?} elapsed: {e}",
+            "Stop listening on {:?} for remote {peer_addr} with {typ}, {:?} elapsed: {e}",
             socket_cloned.local_addr(),
             tm.elapsed()
         )
@@ -36,7 +36,13 @@ pub mod audio_service;
 #[cfg(target_os = "windows")]
 pub mod terminal_helper;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
+mod terminal_recorder;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+mod terminal_scrollback;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
 pub mod terminal_service;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+mod terminal_shell;
 cfg_if::cfg_if! {
 if #[cfg(not(target_os = "ios"))] {
 mod clipboard_service;
@@ -27,12 +27,14 @@ use hbb_common::platform::linux::run_cmds;
 #[cfg(target_os = "android")]
 use hbb_common::protobuf::EnumOrUnknown;
 use hbb_common::{
+    channel,
     config::{self, keys, Config, TrustedDevice},
-    fs::{self, can_enable_overwrite_detection, JobType},
+    fs::{self, can_enable_overwrite_detection, can_enable_pipelining, can_enable_sparse, JobType},
     futures::{SinkExt, StreamExt},
     get_time, get_version_number,
     message_proto::{option_message::BoolOption, permission_info::Permission},
     password_security::{self as password, ApproveMode},
+    resume::{self, ResumableStream},
     sha2::{Digest, Sha256},
     sleep, timeout,
     tokio::{
@@ -65,6 +67,7 @@ use windows::Win32::Foundation::{CloseHandle, HANDLE};
 #[cfg(windows)]
 use crate::virtual_display_manager;
 pub type Sender = mpsc::UnboundedSender<(Instant, Arc<Message>)>;
+type Receiver = mpsc::UnboundedReceiver<(Instant, Arc<Message>)>;
 
 lazy_static::lazy_static! {
     static ref LOGIN_FAILURES: [Arc::<Mutex<HashMap<String, (i32, i32, i32)>>>; 2] = Default::default();
@@ -203,11 +206,22 @@ impl TerminalUserToken {
             TerminalUserToken::CurrentLogonUser(token) => Some(*token),
         }
     }
+
+    // The user the shells run as, empty for the one of this process.
+    fn user(&self) -> String {
+        match self {
+            TerminalUserToken::SelfUser => String::new(),
+            #[cfg(target_os = "windows")]
+            TerminalUserToken::CurrentLogonUser(_) => crate::platform::get_active_username(),
+        }
+    }
 }
 pub struct Connection {
     inner: ConnInner,
     display_idx: usize,
     stream: super::Stream,
+    // Transports of the client reconnecting to this session, see `resume::hand_over`.
+    handover_rx: mpsc::UnboundedReceiver<resume::Handover>,
     server: super::ServerPtrWeak,
     hash: Hash,
     read_jobs: Vec<fs::TransferJob>,
@@ -253,6 +267,8 @@ pub struct Connection {
     tx_input: std_mpsc::Sender<MessageInput>,
     // handle input messages
     video_ack_required: bool,
+    // peer accepts messages reordered by hbb_common::channel
+    channels: bool,
     server_audit_conn: String,
     server_audit_file: String,
     lr: LoginRequest,
@@ -338,12 +354,61 @@ impl Subscriber for ConnInner {
     }
 }
 
+/// Messages from the services waiting to be written to the peer.
+///
+/// Old peers get them in arrival order. Peers supporting `Features::channels` get them through
+/// a weighted fair scheduler, so a backlog of one class doesn't hold up the others.
+struct Outgoing {
+    rx: Receiver,
+    rx_video: Receiver,
+    scheduler: channel::Scheduler<(bool, (Instant, Arc<Message>))>,
+}
+
+impl Outgoing {
+    fn new(rx: Receiver, rx_video: Receiver) -> Self {
+        Self {
+            rx,
+            rx_video,
+            scheduler: Default::default(),
+        }
+    }
+
+    // The bool tells whether the message came from the video channel.
+    async fn recv(&mut self, scheduled: bool) -> Option<(bool, (Instant, Arc<Message>))> {
+        if !scheduled || self.scheduler.is_empty() {
+            let v = tokio::select! {
+                Some(v) = self.rx_video.recv() => (true, v),
+                Some(v) = self.rx.recv() => (false, v),
+                else => return self.scheduler.pop(),
+            };
+            if !scheduled {
+                return Some(v);
+            }
+            self.push(v);
+        }
+        while let Ok(v) = self.rx_video.try_recv() {
+            self.push((true, v));
+        }
+        while let Ok(v) = self.rx.try_recv() {
+            self.push((false, v));
+        }
+        self.scheduler.pop()
+    }
+
+    fn push(&mut self, v: (bool, (Instant, Arc<Message>))) {
+        let msg: &Message = &(v.1).1;
+        let size = msg.compute_size() as usize;
+        self.scheduler.push(channel::Class::of(msg), v, size);
+    }
+}
+
 const TEST_DELAY_TIMEOUT: Duration = Duration::from_secs(1);
 const SEC30: Duration = Duration::from_secs(30);
 const H1: Duration = Duration::from_secs(3600);
 const MILLI1: Duration = Duration::from_millis(1);
 const SEND_TIMEOUT_VIDEO: u64 = 12_000;
 const SEND_TIMEOUT_OTHER: u64 = SEND_TIMEOUT_VIDEO * 10;
+const KCP_LOSS_PENALTY: f32 = 10.;
 const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
 
 impl Connection {
@@ -369,8 +434,9 @@ impl Connection {
         // holding tx_from_cm_holder to avoid cpu burning of rx_from_cm.recv when all sender closed
         let tx_from_cm = tx_from_cm_holder.clone();
         let (tx_to_cm, rx_to_cm) = mpsc::unbounded_channel::<ipc::Data>();
-        let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, Arc<Message>)>();
-        let (tx_video, mut rx_video) = mpsc::unbounded_channel::<(Instant, Arc<Message>)>();
+        let (tx, rx) = mpsc::unbounded_channel::<(Instant, Arc<Message>)>();
+        let (tx_video, rx_video) = mpsc::unbounded_channel::<(Instant, Arc<Message>)>();
+        let mut outgoing = Outgoing::new(rx, rx_video);
         let (tx_input, _rx_input) = std_mpsc::channel();
         let (tx_from_authed, mut rx_from_authed) = mpsc::unbounded_channel::<ipc::Data>();
         let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
@@ -397,7 +463,9 @@ impl Connection {
             },
             require_2fa: crate::auth_2fa::get_2fa(None),
             display_idx: *display_service::PRIMARY_DISPLAY_IDX,
-            stream,
+            stream: ResumableStream::wrap(stream),
+            // Closed until the session is resumable.
+            handover_rx: mpsc::unbounded_channel().1,
             server,
             hash,
             read_jobs: Vec::new(),
@@ -436,6 +504,7 @@ impl Connection {
             show_my_cursor: false,
             tx_input,
             video_ack_required: false,
+            channels: false,
             server_audit_conn: "".to_owned(),
             server_audit_file: "".to_owned(),
             lr: Default::default(),
@@ -553,6 +622,7 @@ impl Connection {
         }
 
         loop {
+            let channels = conn.channels;
             tokio::select! {
                 // biased; // video has higher priority // causing test_delay_timer failed while transferring big file
 
@@ -737,9 +807,9 @@ impl Connection {
                                 conn.handle_read_job_init_result(id, file_num, include_hidden, result).await;
                             }
                         }
-                        ipc::Data::FileBlockFromCM { id, file_num, data, compressed, conn_id } => {
+                        ipc::Data::FileBlockFromCM { id, file_num, data, compressed, hash, conn_id } => {
                             if conn_id == conn.inner.id() {
-                                conn.handle_file_block_from_cm(id, file_num, data, compressed).await;
+                                conn.handle_file_block_from_cm(id, file_num, data, compressed, hash).await;
                             }
                         }
                         ipc::Data::FileReadDone { id, file_num, conn_id } => {
@@ -752,9 +822,9 @@ impl Connection {
                                 conn.handle_file_read_error(id, file_num, err).await;
                             }
                         }
-                        ipc::Data::FileDigestFromCM { id, file_num, last_modified, file_size, is_resume, conn_id } => {
+                        ipc::Data::FileDigestFromCM { id, file_num, last_modified, file_size, is_resume, hash, conn_id } => {
                             if conn_id == conn.inner.id() {
-                                conn.handle_file_digest_from_cm(id, file_num, last_modified, file_size, is_resume).await;
+                                conn.handle_file_digest_from_cm(id, file_num, last_modified, file_size, is_resume, hash).await;
                             }
                         }
                         ipc::Data::AllFilesResult { id, conn_id, path, result } => {
@@ -819,18 +889,19 @@ impl Connection {
                         break;
                     }
                 }
-                Some((instant, value)) = rx_video.recv() => {
-                    if !conn.video_ack_required {
-                        if let Some(message::Union::VideoFrame(vf)) = &value.union {
-                            video_service::notify_video_frame_fetched(vf.display as usize, id, Some(instant.into()));
+                Some((by_video, (instant, value))) = outgoing.recv(channels) => {
+                    if by_video {
+                        if !conn.video_ack_required {
+                            if let Some(message::Union::VideoFrame(vf)) = &value.union {
+                                video_service::notify_video_frame_fetched(vf.display as usize, id, Some(instant.into()));
+                            }
                         }
+                        if let Err(err) = conn.stream.send(&value as &Message).await {
+                            conn.on_close(&err.to_string(), false).await;
+                            break;
+                        }
+                        continue;
                     }
-                    if let Err(err) = conn.stream.send(&value as &Message).await {
-                        conn.on_close(&err.to_string(), false).await;
-                        break;
-                    }
-                },
-                Some((instant, value)) = rx.recv() => {
                     let latency = instant.elapsed().as_millis() as i64;
                     #[allow(unused_mut)]
                     let mut msg = value;
@@ -891,6 +962,17 @@ impl Connection {
                         break;
                     }
                 },
+                Some(handover) = conn.handover_rx.recv() => {
+                    if let Some(s) = conn.stream.as_resumable() {
+                        match s.attach(handover.stream, handover.received).await {
+                            Ok(()) => {
+                                log::info!("#{} session resumed", id);
+                                last_recv_time = Instant::now();
+                            }
+                            Err(err) => log::warn!("#{} failed to resume session: {}", id, err),
+                        }
+                    }
+                }
                 Some(data) = rx_from_authed.recv() => {
                     match data {
                         #[cfg(all(target_os = "windows", feature = "flutter"))]
@@ -1546,9 +1628,17 @@ impl Connection {
             privacy_mode: privacy_mode::is_privacy_mode_supported(),
             #[cfg(not(any(target_os = "android", target_os = "ios")))]
             terminal,
+            channels: true,
+            file_pipelining: true,
+            file_metadata: true,
+            file_sparse: true,
             ..Default::default()
         })
         .into();
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        if self.terminal && terminal {
+            pi.terminal_shells = super::terminal_shell::available_shells();
+        }
 
         let mut sub_service = false;
         #[allow(unused_mut)]
@@ -1632,9 +1722,22 @@ impl Connection {
             }
             self.on_remote_authorized();
         }
+        let mut session_token = None;
+        if self.lr.features.resume && res.has_peer_info() && self.stream.is_ordered() {
+            let token = resume::new_token();
+            res.session_token = token.clone().into();
+            session_token = Some(token);
+        }
         let mut msg_out = Message::new();
         msg_out.set_login_response(res);
         self.send(msg_out).await;
+        if let Some(token) = session_token {
+            if let Some(s) = self.stream.as_resumable() {
+                if let Some(rx) = s.enable(token, true).await {
+                    self.handover_rx = rx;
+                }
+            }
+        }
         if let Some(o) = self.options_in_login.take() {
             self.update_options(&o).await;
         }
@@ -1829,6 +1932,24 @@ impl Connection {
         self.send_to_cm(ipc::Data::FS(data));
     }
 
+    // A client lost its transport and reconnected, continue its session on our stream.
+    async fn resume_session(&mut self, r: &SessionResume) {
+        if !resume::exists(&r.token) {
+            self.send_login_error("Session expired").await;
+            return;
+        }
+        if !self.stream.is_ordered() {
+            self.send_login_error("Sessions are not resumed over QUIC")
+                .await;
+            return;
+        }
+        let Some(stream) = self.stream.as_resumable().and_then(|s| s.detach()) else {
+            return;
+        };
+        log::info!("#{} hand over to the resumed session", self.inner.id());
+        allow_err!(resume::hand_over(&r.token, stream, r.received));
+    }
+
     async fn send_login_error<T: std::string::ToString>(&mut self, err: T) {
         let mut msg_out = Message::new();
         let mut res = LoginResponse::new();
@@ -2043,6 +2164,7 @@ impl Connection {
             }
         }
         self.video_ack_required = lr.video_ack_required;
+        self.channels = lr.features.channels;
     }
 
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -2091,6 +2213,10 @@ impl Connection {
         }
         // After handling CloseReason messages, proceed to process other message types
         if let Some(message::Union::LoginRequest(lr)) = msg.union {
+            if let Some(r) = lr.resume.as_ref() {
+                self.resume_session(r).await;
+                return false;
+            }
             self.handle_login_request_without_validation(&lr).await;
             if self.authorized {
                 return true;
@@ -2164,6 +2290,19 @@ impl Connection {
                             }
                         }
                     }
+                    // Joining the terminals of another connection, see `list_joinable_services`.
+                    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                    if let Some(user_token) = &self.terminal_user_token {
+                        if !terminal_service::may_join_service(
+                            &self.terminal_service_id,
+                            &user_token.user(),
+                        ) {
+                            self.send_login_error("The terminal service is of another user.")
+                                .await;
+                            sleep(1.).await;
+                            return false;
+                        }
+                    }
                 }
                 Some(login_request::Union::PortForward(mut pf)) => {
                     if !Self::permission(keys::OPTION_ENABLE_TUNNEL, &self.control_permissions) {
@@ -2352,7 +2491,13 @@ impl Connection {
             } else {
                 if let Some(tm) = self.last_test_delay {
                     self.last_test_delay = None;
-                    let new_delay = tm.elapsed().as_millis() as u32;
+                    // The KCP round trip is 0 on other transports.
+                    let mut new_delay = (tm.elapsed().as_millis() as u32).max(t.kcp_rtt);
+                    // The loss the client saw on our KCP packets, every packet FEC could not
+                    // rebuild or KCP had to send again costs at least one more round trip.
+                    let loss = t.loss.max(t.retransmits);
+                    let loss = loss as f32 / crate::kcp_stream::LOSS_FEEDBACK_SCALE;
+                    new_delay += (new_delay as f32 * loss * KCP_LOSS_PENALTY) as u32;
                     video_service::VIDEO_QOS
                         .lock()
                         .unwrap()
@@ -2700,10 +2845,20 @@ impl Connection {
                 Some(message::Union::FileAction(fa)) => {
                     let mut handle_fa = self.file_transfer.is_some();
                     if !handle_fa {
-                        if let Some(file_action::Union::Send(s)) = fa.union.as_ref() {
-                            if JobType::from_proto(s.file_type) == JobType::Printer {
-                                handle_fa = true;
+                        match fa.union.as_ref() {
+                            Some(file_action::Union::Send(s)) => {
+                                if JobType::from_proto(s.file_type) == JobType::Printer {
+                                    handle_fa = true;
+                                }
+                            }
+                            // The jobs this connection started, e.g. the terminal exports.
+                            Some(file_action::Union::Cancel(c)) => {
+                                handle_fa = fs::get_job_immutable(c.id, &self.read_jobs).is_some();
                             }
+                            Some(file_action::Union::Limit(l)) => {
+                                handle_fa = fs::get_job_immutable(l.id, &self.read_jobs).is_some();
+                            }
+                            _ => {}
                         }
                     }
                     if handle_fa {
@@ -2731,6 +2886,9 @@ impl Connection {
                                 Some(file_action::Union::RemoveDir(rd)) => {
                                     job_id = Some(rd.id);
                                 }
+                                Some(file_action::Union::Extract(e)) => {
+                                    job_id = Some(e.id);
+                                }
                                 _ => {}
                             }
                             if let Some(job_id) = job_id {
@@ -2746,6 +2904,12 @@ impl Connection {
                             Some(file_action::Union::ReadDir(rd)) => {
                                 self.read_dir(&rd.path, rd.include_hidden);
                             }
+                            Some(file_action::Union::Search(s)) => {
+                                self.send_fs(ipc::FS::SearchFiles {
+                                    search: s.write_to_bytes().unwrap_or_default(),
+                                    conn_id: self.inner.id(),
+                                });
+                            }
                             Some(file_action::Union::AllFiles(f)) => {
                                 if crate::common::need_fs_cm_send_files() {
                                     self.send_fs(ipc::FS::ReadAllFiles {
@@ -2798,11 +2962,15 @@ impl Connection {
                                                 include_hidden: s.include_hidden,
                                                 conn_id: self.inner.id(),
                                                 overwrite_detection: od,
+                                                preserve_metadata: s.preserve_metadata,
+                                                archive: s.archive.value(),
                                             });
                                         } else {
                                             // Handle file reading in Connection on non-Windows
-                                            let data_source =
-                                                fs::DataSource::FilePath(PathBuf::from(&path));
+                                            let data_source = fs::DataSource::from_path(
+                                                &path,
+                                                s.archive.enum_value_or_default(),
+                                            );
                                             self.create_and_start_read_job(
                                                 id,
                                                 job_type,
@@ -2810,6 +2978,7 @@ impl Connection {
                                                 s.file_num,
                                                 s.include_hidden,
                                                 od,
+                                                s.preserve_metadata,
                                                 path,
                                                 true, // check file count limit
                                             )
@@ -2834,6 +3003,7 @@ impl Connection {
                                                 s.file_num,
                                                 s.include_hidden,
                                                 true, // always enable overwrite detection for printer
+                                                false,
                                                 path,
                                                 false, // no file count limit for printer
                                             )
@@ -2857,6 +3027,16 @@ impl Connection {
                                     path: r.path.clone(),
                                     id: r.id,
                                     file_num: r.file_num,
+                                    metadata: r
+                                        .files
+                                        .iter()
+                                        .map(|f| {
+                                            f.metadata
+                                                .as_ref()
+                                                .and_then(|m| m.write_to_bytes().ok())
+                                                .unwrap_or_default()
+                                        })
+                                        .collect(),
                                     files: r
                                         .files
                                         .to_vec()
@@ -2921,6 +3101,19 @@ impl Connection {
                                     )));
                                 }
                             }
+                            Some(file_action::Union::Limit(l)) => {
+                                if let Some(job) = fs::get_job(l.id, &mut self.read_jobs) {
+                                    job.set_paused(l.paused);
+                                    job.set_rate_limit(l.rate_limit);
+                                } else if self.cm_read_job_ids.contains(&l.id) {
+                                    self.send_fs(ipc::FS::LimitRead {
+                                        id: l.id,
+                                        paused: l.paused,
+                                        rate_limit: l.rate_limit,
+                                        conn_id: self.inner.id(),
+                                    });
+                                }
+                            }
                             Some(file_action::Union::SendConfirm(r)) => {
                                 if let Some(job) = fs::get_job(r.id, &mut self.read_jobs) {
                                     job.confirm(&r).await;
@@ -2930,7 +3123,11 @@ impl Connection {
                                         id: r.id,
                                         file_num: r.file_num,
                                         skip: r.skip(),
-                                        offset_blk: r.offset_blk(),
+                                        offset: if r.has_offset() {
+                                            r.offset()
+                                        } else {
+                                            r.offset_blk() as u64
+                                        },
                                         conn_id: self.inner.id(),
                                     });
                                 } else {
@@ -2955,18 +3152,52 @@ impl Connection {
                                     .unwrap_or_default(),
                                 )));
                             }
+                            Some(file_action::Union::Extract(e)) => {
+                                self.send_fs(ipc::FS::ExtractArchive {
+                                    id: e.id,
+                                    path: e.path,
+                                    to: e.to,
+                                    conn_id: self.inner.id(),
+                                    progress: fs::can_report_extract_progress(
+                                        self.lr.features.as_ref(),
+                                    ),
+                                });
+                            }
                             _ => {}
                         }
                     }
                 }
                 Some(message::Union::FileResponse(fr)) => match fr.union {
                     Some(file_response::Union::Block(block)) => {
-                        self.send_fs(ipc::FS::WriteBlock {
-                            id: block.id,
-                            file_num: block.file_num,
-                            data: block.data,
-                            compressed: block.compressed,
-                        });
+                        let mut dictionary = block.dictionary.to_vec();
+                        if block.batch.is_empty() {
+                            self.send_fs(ipc::FS::WriteBlock {
+                                id: block.id,
+                                file_num: block.file_num,
+                                data: block.data,
+                                compressed: block.compressed,
+                                copy_blks: block.copy_blks,
+                                hash: block.hash.to_vec(),
+                                dictionary,
+                                with_dictionary: block.with_dictionary,
+                                hole: block.hole,
+                            });
+                        } else {
+                            // Small files sent together, written one by one.
+                            for entry in block.batch {
+                                self.send_fs(ipc::FS::WriteBlock {
+                                    id: block.id,
+                                    file_num: entry.file_num,
+                                    data: entry.data,
+                                    compressed: entry.compressed,
+                                    copy_blks: Vec::new(),
+                                    hash: entry.hash.to_vec(),
+                                    dictionary: std::mem::take(&mut dictionary),
+                                    with_dictionary: entry.with_dictionary,
+                                    hole: 0,
+                                });
+                            }
+                        }
                     }
                     Some(file_response::Union::Done(d)) => {
                         self.send_fs(ipc::FS::WriteDone {
@@ -2981,6 +3212,8 @@ impl Connection {
                         last_modified: d.last_modified,
                         is_upload: true,
                         is_resume: d.is_resume,
+                        delta: d.delta,
+                        hash: d.hash.to_vec(),
                     }),
                     Some(file_response::Union::Error(e)) => {
                         self.send_fs(ipc::FS::WriteError {
@@ -4195,6 +4428,7 @@ impl Connection {
         file_num: i32,
         data: bytes::Bytes,
         compressed: bool,
+        hash: Vec<u8>,
     ) {
         // Check if the job is still valid (not cancelled)
         if !self.cm_read_job_ids.contains(&id) {
@@ -4212,6 +4446,7 @@ impl Connection {
         block.file_num = file_num;
         block.data = data.to_vec().into();
         block.compressed = compressed;
+        block.hash = hash.into();
 
         let mut msg = Message::new();
         let mut fr = FileResponse::new();
@@ -4265,6 +4500,7 @@ impl Connection {
         last_modified: u64,
         file_size: u64,
         is_resume: bool,
+        hash: Vec<u8>,
     ) {
         // Check if the job is still valid (not cancelled)
         if !self.cm_read_job_ids.contains(&id) {
@@ -4284,6 +4520,7 @@ impl Connection {
         digest.file_size = file_size;
         digest.is_upload = false; // Server sending to client
         digest.is_resume = is_resume;
+        digest.hash = hash.into();
 
         let mut msg = Message::new();
         let mut fr = FileResponse::new();
@@ -4384,6 +4621,7 @@ impl Connection {
         file_num: i32,
         include_hidden: bool,
         overwrite_detection: bool,
+        preserve_metadata: bool,
         path: String,
         check_file_limit: bool,
     ) {
@@ -4396,19 +4634,23 @@ impl Connection {
             include_hidden,
             false,
             overwrite_detection,
+            preserve_metadata,
         ) {
             Err(err) => {
                 self.send(fs::new_error(id, err, 0)).await;
             }
-            Ok(job) => {
+            Ok(mut job) => {
                 if check_file_limit {
                     if let Err(msg) =
-                        crate::ui_cm_interface::check_file_count_limit(job.files().len())
+                        crate::ui_cm_interface::check_file_count_limit(job.file_count())
                     {
                         self.send(fs::new_error(id, msg, -1)).await;
                         return;
                     }
                 }
+                job.set_pipelining(can_enable_pipelining(self.lr.features.as_ref()));
+                job.set_sparse(can_enable_sparse(self.lr.features.as_ref()));
+                crate::ui_cm_interface::limit_read_job(&mut job);
                 self.process_new_read_job(job, path).await;
             }
         }
@@ -4616,7 +4858,15 @@ impl Connection {
         // This path is only used to identify the printer job.
         let path = format!("RustDesk://FsJob//Printer/{}", get_time());
 
-        let msg = fs::new_send(0, fs::JobType::Printer, path.clone(), 1, false);
+        let msg = fs::new_send(
+            0,
+            fs::JobType::Printer,
+            path.clone(),
+            1,
+            false,
+            false,
+            Default::default(),
+        );
         self.send(msg).await;
         self.printer_data
             .retain(|(t, _, _)| t.elapsed().as_secs() < 60);
@@ -4658,6 +4908,7 @@ impl Connection {
             self.terminal_service_id.clone(),
             self.terminal_persistent,
             user_token.to_terminal_service_token(),
+            user_token.user(),
         ));
         s.on_subscribe(self.inner.clone());
         self.terminal_generic_service = Some(s);
@@ -4674,13 +4925,90 @@ impl Connection {
             self.terminal_service_id.clone(),
             Some(self.terminal_persistent),
             user_token.to_terminal_service_token(),
+            self.inner.id(),
         );
 
+        if let Some(terminal_action::Union::ListServices(_)) = &action.union {
+            let mut response = TerminalResponse::new();
+            response.set_services(TerminalServices {
+                services: terminal_service::list_joinable_services(
+                    &user_token.user(),
+                    &self.terminal_service_id,
+                ),
+                ..Default::default()
+            });
+            let mut msg_out = Message::new();
+            msg_out.set_terminal_response(response);
+            self.send(msg_out).await;
+            return Ok(());
+        }
+
+        // Sent as a file, written by a job the client started.
+        if let Some(terminal_action::Union::Export(export)) = &action.union {
+            match proxy.export_history(export.terminal_id) {
+                Ok(snapshot) => {
+                    let len = snapshot.export_len();
+                    let reader = fs::BlockingReader::new(snapshot.export());
+                    self.create_and_start_read_job(
+                        export.id,
+                        fs::JobType::Generic,
+                        fs::DataSource::Reader(Some(reader), len),
+                        0,
+                        false,
+                        false,
+                        false,
+                        format!("terminal{}.txt", export.terminal_id),
+                        false,
+                    )
+                    .await;
+                }
+                Err(err) => self.send(fs::new_error(export.id, err, -1)).await,
+            }
+            return Ok(());
+        }
+
+        // Searched in a blocking task, answered when done.
+        if let Some(terminal_action::Union::Search(search)) = &action.union {
+            match proxy.search_history(search) {
+                Ok(search) => {
+                    let mut inner = self.inner.clone();
+                    tokio::task::spawn_blocking(move || {
+                        let mut msg_out = Message::new();
+                        msg_out.set_terminal_response(search.run());
+                        inner.send(msg_out.into());
+                    });
+                }
+                Err(err) => {
+                    let mut response = TerminalResponse::new();
+                    let mut error = TerminalError::new();
+                    error.terminal_id = search.terminal_id;
+                    error.message = err.to_string();
+                    response.set_error(error);
+                    let mut msg_out = Message::new();
+                    msg_out.set_terminal_response(response);
+                    self.send(msg_out).await;
+                }
+            }
+            return Ok(());
+        }
+
         match proxy.handle_action(&action) {
             Ok(Some(response)) => {
+                // Replay what the terminal shows to the connection attaching to it.
+                let buffered = match &response.union {
+                    Some(terminal_response::Union::Opened(opened)) if opened.success => {
+                        proxy.buffered_output(opened.terminal_id)
+                    }
+                    _ => None,
+                };
                 let mut msg_out = Message::new();
                 msg_out.set_terminal_response(response);
                 self.send(msg_out).await;
+                if let Some(response) = buffered {
+                    let mut msg_out = Message::new();
+                    msg_out.set_terminal_response(response);
+                    self.send(msg_out).await;
+                }
             }
             Ok(None) => {
                 // No response needed
@@ -4828,13 +5156,14 @@ async fn start_ipc(
                             // Note: Empty data (for empty files) is correctly handled. BytesCodec with
                             // raw=false adds a length prefix, so next_raw() returns empty BytesMut for
                             // zero-length frames. This mirrors the WriteBlock pattern below.
-                            ipc::Data::FileBlockFromCM { id, file_num, data: _, compressed, conn_id } => {
+                            ipc::Data::FileBlockFromCM { id, file_num, data: _, compressed, hash, conn_id } => {
                                 let raw_data = stream.next_raw().await?;
                                 tx_from_cm.send(ipc::Data::FileBlockFromCM {
                                     id,
                                     file_num,
                                     data: raw_data.into(),
                                     compressed,
+                                    hash,
                                     conn_id,
                                 })?;
                             }
@@ -4852,8 +5181,13 @@ async fn start_ipc(
                         if let Data::FS(ipc::FS::WriteBlock{id,
                             file_num,
                             data,
-                            compressed}) = data {
-                                stream.send(&Data::FS(ipc::FS::WriteBlock{id, file_num, data: Bytes::new(), compressed})).await?;
+                            compressed,
+                            copy_blks,
+                            hash,
+                            dictionary,
+                            with_dictionary,
+                            hole}) = data {
+                                stream.send(&Data::FS(ipc::FS::WriteBlock{id, file_num, data: Bytes::new(), compressed, copy_blks, hash, dictionary, with_dictionary, hole})).await?;
                                 stream.send_raw(data).await?;
                         } else {
                             stream.send(&data).await?;
@@ -5100,7 +5434,13 @@ impl Drop for Connection {
         self.release_pressed_modifiers();
 
         if let Some(s) = self.terminal_generic_service.as_ref() {
-            s.join();
+            // The service goes with the last connection attached to its terminals.
+            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+            terminal_service::detach(&self.terminal_service_id, self.inner.id());
+            s.on_unsubscribe(self.inner.id());
+            if !s.has_subscribes() {
+                s.join();
+            }
         }
 
         #[cfg(target_os = "windows")]
@@ -20,6 +20,7 @@
 //! - User token and SID handling
 //! - Helper process launching
 
+use super::terminal_shell::ShellSpec;
 use hbb_common::{
     anyhow::{anyhow, Context, Result},
     log,
@@ -680,15 +681,22 @@ pub fn launch_terminal_helper_with_token(
     terminal_id: i32,
     rows: u16,
     cols: u16,
+    spec: &ShellSpec,
 ) -> Result<HelperProcessInfo> {
     let exe_path =
         std::env::current_exe().map_err(|e| anyhow!("Failed to get current exe path: {}", e))?;
 
     // Build command line arguments (without exe path to avoid escaping issues)
     // lpApplicationName will contain the exe path separately
+    // The shell spec is base64 encoded, so it needs no escaping either.
     let cmd_args = format!(
-        "--terminal-helper {} {} {} {} {}",
-        input_pipe_name, output_pipe_name, rows, cols, terminal_id
+        "--terminal-helper {} {} {} {} {} {}",
+        input_pipe_name,
+        output_pipe_name,
+        rows,
+        cols,
+        terminal_id,
+        spec.encode()
     );
 
     log::debug!("Launching terminal helper for terminal {}", terminal_id);
@@ -788,7 +796,7 @@ pub fn is_helper_process_running(handle: HANDLE) -> bool {
 }
 
 /// Run terminal helper process
-/// Args: --terminal-helper <input_pipe_name> <output_pipe_name> <rows> <cols> <terminal_id>
+/// Args: --terminal-helper <input_pipe_name> <output_pipe_name> <rows> <cols> <terminal_id> [shell_spec]
 pub fn run_terminal_helper(args: &[String]) -> Result<()> {
     if args.len() < 5 {
         return Err(anyhow!(
@@ -830,10 +838,20 @@ pub fn run_terminal_helper(args: &[String]) -> Result<()> {
     let pty_system = portable_pty::native_pty_system();
     let pty_pair = pty_system.openpty(pty_size).context("Failed to open PTY")?;
 
-    let shell = get_default_shell();
+    // The service checked the spec against what the host allows.
+    let spec = match args.get(5) {
+        Some(arg) => ShellSpec::decode(arg)?,
+        None => ShellSpec::default(),
+    };
+    let shell = if spec.shell.is_empty() {
+        get_default_shell()
+    } else {
+        spec.shell.clone()
+    };
     log::debug!("Using shell: {}", shell);
 
-    let cmd = CommandBuilder::new(&shell);
+    let mut cmd = CommandBuilder::new(&shell);
+    spec.apply(&mut cmd);
     let mut child = pty_pair
         .slave
         .spawn_command(cmd)
@@ -0,0 +1,226 @@
+//! Recording of terminal sessions in the asciicast v2 format of asciinema, next to the video
+//! recordings, see <https://docs.asciinema.org/manual/asciicast/v2/>.
+//!
+//! The events are written in a thread of the recording, not to block the terminal on the disk.
+use hbb_common::{
+    chrono,
+    config::{self, keys, Config},
+    log,
+};
+use serde_json::json;
+use std::{
+    fs::File,
+    io::{self, LineWriter, Write},
+    path::PathBuf,
+    sync::mpsc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Whether incoming terminal sessions are recorded.
+pub fn is_enabled() -> bool {
+    config::option2bool(
+        keys::OPTION_ALLOW_AUTO_RECORD_TERMINAL,
+        &Config::get_option(keys::OPTION_ALLOW_AUTO_RECORD_TERMINAL),
+    )
+}
+
+/// Records a new terminal if enabled, `None` if disabled or the file can not be created.
+///
+/// `term` is the TERM the shell was started with, the default of the terminal if empty.
+pub fn start(
+    terminal_id: i32,
+    rows: u16,
+    cols: u16,
+    title: &str,
+    shell: &str,
+    term: &str,
+) -> Option<Recording> {
+    if !is_enabled() {
+        return None;
+    }
+    #[cfg(windows)]
+    let root = crate::platform::is_root();
+    #[cfg(not(windows))]
+    let root = false;
+    let dir = PathBuf::from(crate::ui_interface::video_save_directory(root));
+    let name = format!(
+        "incoming_{}{}terminal{}.cast",
+        Config::get_id(),
+        chrono::Local::now().format("_%Y%m%d%H%M%S%3f_"),
+        terminal_id
+    );
+    let path = dir.join(name);
+    let res = std::fs::create_dir_all(&dir)
+        .and_then(|_| File::create(&path))
+        .and_then(|f| Recorder::new(LineWriter::new(f), rows, cols, title, shell, term));
+    match res {
+        Ok(recorder) => {
+            log::info!("Recording terminal {} to {}", terminal_id, path.display());
+            Some(Recording::new(recorder, terminal_id))
+        }
+        Err(e) => {
+            log::error!("Failed to record terminal {}: {}", terminal_id, e);
+            None
+        }
+    }
+}
+
+pub enum Event {
+    Output(Vec<u8>),
+    Input(Vec<u8>),
+    // Rows and columns.
+    Resize(u16, u16),
+}
+
+/// Passes the events to the thread writing them, which stops on the first failure, not to
+/// fill the log, and once this is dropped.
+pub struct Recording {
+    tx: mpsc::Sender<(Instant, Event)>,
+}
+
+impl Recording {
+    fn new<W: Write + Send + 'static>(mut recorder: Recorder<W>, terminal_id: i32) -> Self {
+        let (tx, rx) = mpsc::channel::<(Instant, Event)>();
+        std::thread::spawn(move || {
+            for (at, event) in rx {
+                let res = match event {
+                    Event::Output(data) => recorder.output(at, &data),
+                    Event::Input(data) => recorder.input(at, &data),
+                    Event::Resize(rows, cols) => recorder.resize(at, rows, cols),
+                };
+                if let Err(e) = res {
+                    log::error!("Failed to record terminal {}: {}", terminal_id, e);
+                    break;
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// False once the recording has stopped.
+    pub fn send(&self, event: Event) -> bool {
+        self.tx.send((Instant::now(), event)).is_ok()
+    }
+}
+
+/// Writes the header, then an event per line, the time since the start, its code and data.
+pub struct Recorder<W: Write = LineWriter<File>> {
+    writer: W,
+    start: Instant,
+    // Of a character split between two reads.
+    output_pending: Vec<u8>,
+    input_pending: Vec<u8>,
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(
+        mut writer: W,
+        rows: u16,
+        cols: u16,
+        title: &str,
+        shell: &str,
+        term: &str,
+    ) -> io::Result<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let header = json!({
+            "version": 2,
+            "width": cols,
+            "height": rows,
+            "timestamp": timestamp,
+            "title": title,
+            "env": {
+                "SHELL": shell,
+                "TERM": if term.is_empty() { "xterm-256color" } else { term },
+            },
+        });
+        writeln!(writer, "{}", header)?;
+        Ok(Self {
+            writer,
+            start: Instant::now(),
+            output_pending: Vec::new(),
+            input_pending: Vec::new(),
+        })
+    }
+
+    // `at` is when it happened, it may be written later.
+    fn event(&mut self, at: Instant, code: &str, data: &str) -> io::Result<()> {
+        // In microseconds, as asciinema writes them.
+        let elapsed = at.saturating_duration_since(self.start);
+        let time = (elapsed.as_secs_f64() * 1e6).round() / 1e6;
+        writeln!(self.writer, "{}", json!([time, code, data]))
+    }
+
+    pub fn output(&mut self, at: Instant, data: &[u8]) -> io::Result<()> {
+        let data = decode(&mut self.output_pending, data);
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.event(at, "o", &data)
+    }
+
+    pub fn input(&mut self, at: Instant, data: &[u8]) -> io::Result<()> {
+        let data = decode(&mut self.input_pending, data);
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.event(at, "i", &data)
+    }
+
+    pub fn resize(&mut self, at: Instant, rows: u16, cols: u16) -> io::Result<()> {
+        self.event(at, "r", &format!("{}x{}", cols, rows))
+    }
+
+    #[cfg(test)]
+    fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// The text of `data` after what is pending, keeping an incomplete character at the end.
+fn decode(pending: &mut Vec<u8>, data: &[u8]) -> String {
+    pending.extend_from_slice(data);
+    let valid = match std::str::from_utf8(pending) {
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        _ => pending.len(),
+    };
+    let rest = pending.split_off(valid);
+    let text = String::from_utf8_lossy(pending).into_owned();
+    *pending = rest;
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_asciicast() {
+        let mut recorder =
+            Recorder::new(Vec::new(), 24, 80, "Terminal 1", "/bin/bash", "xterm").unwrap();
+        recorder.input(Instant::now(), b"ls\r").unwrap();
+        // "é" split between two reads.
+        recorder.output(Instant::now(), b"caf\xc3").unwrap();
+        recorder.output(Instant::now(), b"\xa9\r\n").unwrap();
+        recorder.resize(Instant::now(), 40, 120).unwrap();
+        let out = String::from_utf8(recorder.into_inner()).unwrap();
+        let lines: Vec<serde_json::Value> = out
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[0]["height"], 24);
+        assert_eq!(lines[0]["env"]["TERM"], "xterm");
+        assert_eq!(lines[1][1], "i");
+        assert_eq!(lines[1][2], "ls\r");
+        assert_eq!(lines[2][2], "caf");
+        assert_eq!(lines[3][2], "é\r\n");
+        assert_eq!(lines[4][1], "r");
+        assert_eq!(lines[4][2], "120x40");
+        assert!(lines[4][0].as_f64().unwrap() >= lines[1][0].as_f64().unwrap());
+    }
+}
@@ -0,0 +1,311 @@
+//! The scrollback of a terminal, its output as lines of text without escape sequences, kept
+//! compressed in blocks of lines, to search it and export it.
+use hbb_common::{
+    compress,
+    config::{keys, Config},
+    regex::Regex,
+};
+use std::{
+    collections::VecDeque,
+    io::{self, Read},
+    sync::Arc,
+};
+
+const BLOCK_LINES: usize = 256; // Lines compressed together
+const DEFAULT_MAX_LINES: usize = 100_000;
+const MAX_LINES: usize = 1_000_000;
+const MAX_LINE_LEN: usize = 16 * 1024; // Longer lines are wrapped
+
+/// Lines kept, of the option `terminal-scrollback-lines`.
+pub fn max_lines() -> usize {
+    Config::get_option(keys::OPTION_TERMINAL_SCROLLBACK_LINES)
+        .parse::<usize>()
+        .map(|n| n.min(MAX_LINES))
+        .unwrap_or(DEFAULT_MAX_LINES)
+}
+
+struct Block {
+    // The lines, each ended by a newline.
+    data: Vec<u8>,
+    lines: usize,
+    // Of the lines, not compressed.
+    len: usize,
+}
+
+// Where the output is, escape sequences are dropped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Text,
+    Escape,
+    // ESC ( and alike, a character set of one byte follows.
+    EscapeArg,
+    Csi,
+    // OSC, DCS and alike, until BEL or ST.
+    String,
+    StringEscape,
+}
+
+pub struct Scrollback {
+    max_lines: usize,
+    // Of the oldest line kept, counting from the start of the terminal.
+    first_line: u64,
+    // Shared with the snapshots being searched or exported.
+    blocks: VecDeque<Arc<Block>>,
+    tail: Vec<String>,
+    line: Vec<u8>,
+    state: State,
+    // A carriage return not followed by a newline, the line is written over.
+    carriage_return: bool,
+}
+
+impl Scrollback {
+    pub fn new(max_lines: usize) -> Self {
+        Self {
+            max_lines,
+            first_line: 0,
+            blocks: VecDeque::new(),
+            tail: Vec::new(),
+            line: Vec::new(),
+            state: State::Text,
+            carriage_return: false,
+        }
+    }
+
+    pub fn append(&mut self, data: &[u8]) {
+        for &b in data {
+            self.state = match (self.state, b) {
+                (State::Text, 0x1b) => State::Escape,
+                (State::Text, b'\n') => {
+                    self.carriage_return = false;
+                    self.end_line();
+                    State::Text
+                }
+                (State::Text, b'\r') => {
+                    self.carriage_return = true;
+                    State::Text
+                }
+                (State::Text, 0x08) => {
+                    self.line.pop();
+                    State::Text
+                }
+                (State::Text, b) if (b < 0x20 && b != b'\t') || b == 0x7f => State::Text,
+                (State::Text, b) => {
+                    if self.carriage_return {
+                        self.carriage_return = false;
+                        self.line.clear();
+                    }
+                    self.line.push(b);
+                    if self.line.len() >= MAX_LINE_LEN {
+                        self.end_line();
+                    }
+                    State::Text
+                }
+                (State::Escape, b'[') => State::Csi,
+                (State::Escape, b']' | b'P' | b'X' | b'^' | b'_') => State::String,
+                (State::Escape, b'(' | b')' | b'*' | b'+' | b'#' | b'%') => State::EscapeArg,
+                (State::Escape | State::EscapeArg, _) => State::Text,
+                (State::Csi, 0x40..=0x7e) => State::Text,
+                (State::Csi, _) => State::Csi,
+                (State::String, 0x07) => State::Text,
+                (State::String, 0x1b) => State::StringEscape,
+                (State::String, _) => State::String,
+                (State::StringEscape, b'\\') => State::Text,
+                (State::StringEscape, _) => State::String,
+            };
+        }
+    }
+
+    fn end_line(&mut self) {
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        self.tail.push(line);
+        if self.tail.len() >= BLOCK_LINES {
+            let mut data = Vec::new();
+            for line in self.tail.drain(..) {
+                data.extend_from_slice(line.as_bytes());
+                data.push(b'\n');
+            }
+            self.blocks.push_back(Arc::new(Block {
+                data: compress::compress(&data),
+                lines: BLOCK_LINES,
+                len: data.len(),
+            }));
+        }
+        // Whole blocks go, the lines kept are a block more at most.
+        while let Some(block) = self.blocks.front() {
+            if self.kept_lines() - block.lines < self.max_lines {
+                break;
+            }
+            self.first_line += block.lines as u64;
+            self.blocks.pop_front();
+        }
+    }
+
+    fn kept_lines(&self) -> usize {
+        self.blocks.iter().map(|b| b.lines).sum::<usize>() + self.tail.len()
+    }
+
+    /// The lines kept, to search or export them without holding the terminal, the blocks are
+    /// shared, the lines not in a block yet are copied.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut tail = self.tail.clone();
+        if !self.line.is_empty() {
+            tail.push(String::from_utf8_lossy(&self.line).into_owned());
+        }
+        Snapshot {
+            first_line: self.first_line,
+            blocks: self.blocks.clone(),
+            tail,
+        }
+    }
+}
+
+pub struct Snapshot {
+    first_line: u64,
+    blocks: VecDeque<Arc<Block>>,
+    // The last one may not be ended yet.
+    tail: Vec<String>,
+}
+
+impl Snapshot {
+    /// Of the oldest line kept.
+    pub fn first_line(&self) -> u64 {
+        self.first_line
+    }
+
+    /// Of the lines kept.
+    pub fn lines(&self) -> u64 {
+        (self.blocks.iter().map(|b| b.lines).sum::<usize>() + self.tail.len()) as u64
+    }
+
+    /// Calls `f` with each line kept and its number, while it returns true.
+    pub fn for_each_line(&self, mut f: impl FnMut(u64, &str) -> bool) {
+        let mut n = self.first_line;
+        for block in self.blocks.iter() {
+            let data = compress::decompress(&block.data);
+            for line in String::from_utf8_lossy(&data).lines() {
+                if !f(n, line) {
+                    return;
+                }
+                n += 1;
+            }
+        }
+        for line in self.tail.iter() {
+            if !f(n, line) {
+                return;
+            }
+            n += 1;
+        }
+    }
+
+    /// The line numbers and the columns in characters, start and end, of the first `max`
+    /// matches, and whether there are more.
+    pub fn search(&self, regex: &Regex, max: usize) -> (Vec<(u64, usize, usize, String)>, bool) {
+        let mut matches = Vec::new();
+        let mut truncated = false;
+        self.for_each_line(|n, line| {
+            for m in regex.find_iter(line) {
+                if matches.len() >= max {
+                    truncated = true;
+                    return false;
+                }
+                let start = line[..m.start()].chars().count();
+                let end = start + m.as_str().chars().count();
+                matches.push((n, start, end, line.to_owned()));
+            }
+            true
+        });
+        (matches, truncated)
+    }
+
+    /// The size of the export, the lines each ended by a newline.
+    pub fn export_len(&self) -> u64 {
+        let blocks: usize = self.blocks.iter().map(|b| b.len).sum();
+        let tail: usize = self.tail.iter().map(|l| l.len() + 1).sum();
+        (blocks + tail) as u64
+    }
+
+    /// Reads the export, a block decompressed at a time.
+    pub fn export(self) -> Export {
+        Export {
+            snapshot: self,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+pub struct Export {
+    snapshot: Snapshot,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for Export {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.buf.len() {
+            self.pos = 0;
+            self.buf = if let Some(block) = self.snapshot.blocks.pop_front() {
+                compress::decompress(&block.data)
+            } else if !self.snapshot.tail.is_empty() {
+                let mut data = Vec::new();
+                for line in self.snapshot.tail.drain(..) {
+                    data.extend_from_slice(line.as_bytes());
+                    data.push(b'\n');
+                }
+                data
+            } else {
+                return Ok(0);
+            };
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrollback() {
+        let mut scrollback = Scrollback::new(BLOCK_LINES * 2);
+        // Colors, a title, a progress written over and a line split between reads.
+        scrollback.append(b"\x1b[1;32mok\x1b[0m\r\n\x1b]0;title\x07$ ls\r\n");
+        scrollback.append(b"10%\r50%\r100%\r\nsplit ");
+        scrollback.append("lïne\r\n".as_bytes());
+        let snapshot = scrollback.snapshot();
+        let regex = Regex::new("l.ne").unwrap();
+        let (matches, truncated) = snapshot.search(&regex, 10);
+        assert!(!truncated);
+        assert_eq!(matches, vec![(3, 6, 10, "split lïne".to_owned())]);
+        let mut out = String::new();
+        assert_eq!(snapshot.export_len(), 25);
+        snapshot.export().read_to_string(&mut out).unwrap();
+        assert_eq!(out, "ok\n$ ls\n100%\nsplit lïne\n");
+
+        for i in 0..BLOCK_LINES * 4 {
+            scrollback.append(format!("line {}\n", i).as_bytes());
+        }
+        // Not ended yet.
+        scrollback.append(b"$ ");
+        let snapshot = scrollback.snapshot();
+        assert!(snapshot.first_line() > 0);
+        assert!(snapshot.lines() as usize <= BLOCK_LINES * 3 + 1);
+        let regex = Regex::new("^line 1023$").unwrap();
+        let (matches, _) = snapshot.search(&regex, 10);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].0, 4 + 1023);
+        let (matches, truncated) = snapshot.search(&Regex::new("line").unwrap(), 5);
+        assert_eq!(matches.len(), 5);
+        assert!(truncated);
+        assert_eq!(matches[0].0, snapshot.first_line());
+        let len = snapshot.export_len();
+        let mut out = String::new();
+        snapshot.export().read_to_string(&mut out).unwrap();
+        assert_eq!(out.len() as u64, len);
+        assert!(out.ends_with("line 1023\n$ \n"));
+    }
+}
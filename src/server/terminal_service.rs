@@ -2,10 +2,12 @@ use super::*;
 use hbb_common::{
     anyhow::{anyhow, Context, Result},
     compress,
+    config::keys,
+    regex,
 };
 use portable_pty::{Child, CommandBuilder, PtySize};
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     io::{Read, Write},
     ops::{Deref, DerefMut},
     sync::{
@@ -17,6 +19,11 @@ use std::{
     time::{Duration, Instant},
 };
 
+use super::{
+    terminal_scrollback::{Scrollback, Snapshot},
+    terminal_shell::ShellSpec,
+};
+
 // Windows-specific imports from terminal_helper module
 #[cfg(target_os = "windows")]
 use super::terminal_helper::{
@@ -32,6 +39,9 @@ const MAX_SERVICES: usize = 100; // Maximum number of persistent terminal servic
 const SERVICE_IDLE_TIMEOUT: Duration = Duration::from_secs(3600); // 1 hour idle timeout
 const CHANNEL_BUFFER_SIZE: usize = 100; // Number of messages to buffer in channel
 const COMPRESS_THRESHOLD: usize = 512; // Compress terminal data larger than this
+const ATTACH_BUFFER_SIZE: usize = 64 * 1024; // Output replayed to a connection attaching to a terminal
+const DEFAULT_SEARCH_MATCHES: usize = 100;
+const MAX_SEARCH_MATCHES: usize = 10000;
 
 lazy_static::lazy_static! {
     // Global registry of persistent terminal services indexed by service_id
@@ -59,7 +69,7 @@ pub fn generate_service_id() -> String {
     format!("ts_{}", uuid::Uuid::new_v4())
 }
 
-fn get_default_shell() -> String {
+pub(super) fn get_default_shell() -> String {
     #[cfg(target_os = "windows")]
     {
         // Use shared implementation from terminal_helper
@@ -91,11 +101,52 @@ pub fn is_service_specified_user(service_id: &str) -> Option<bool> {
     get_service(service_id).map(|s| s.lock().unwrap().is_specified_user)
 }
 
+/// Whether a connection whose shells run as `user` may log in to the service, if it exists.
+pub fn may_join_service(service_id: &str, user: &str) -> bool {
+    match get_service(service_id) {
+        Some(service) => service.lock().unwrap().may_join(user),
+        None => true,
+    }
+}
+
+/// The services with terminals a connection whose shells run as `user` may join, but `skip`,
+/// for it to log in to one of them.
+pub fn list_joinable_services(user: &str, skip: &str) -> Vec<TerminalServiceInfo> {
+    let services: Vec<_> = TERMINAL_SERVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(id, _)| id.as_str() != skip)
+        .map(|(_, service)| service.clone())
+        .collect();
+    services
+        .iter()
+        .filter_map(|service| {
+            let service = service.lock().unwrap();
+            if service.sessions.is_empty() || !service.may_join(user) {
+                return None;
+            }
+            let mut attached = HashSet::new();
+            for session in service.sessions.values() {
+                attached.extend(session.lock().unwrap().attached.iter().cloned());
+            }
+            Some(TerminalServiceInfo {
+                service_id: service.service_id.clone(),
+                terminal_ids: service.sessions.keys().cloned().collect(),
+                attached: attached.len() as _,
+                persistent: service.is_persistent,
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
 /// Get or create a persistent terminal service
 fn get_or_create_service(
     service_id: String,
     is_persistent: bool,
     is_specified_user: bool,
+    user: String,
 ) -> Result<Arc<Mutex<PersistentTerminalService>>> {
     let mut services = TERMINAL_SERVICES.lock().unwrap();
 
@@ -119,6 +170,7 @@ fn get_or_create_service(
                 service_id.clone(),
                 is_persistent,
                 is_specified_user,
+                user,
             )))
         })
         .clone();
@@ -126,7 +178,16 @@ fn get_or_create_service(
     // Ensure cleanup task is running
     ensure_cleanup_task();
 
-    service.lock().unwrap().reset_status(is_persistent);
+    // Another connection attaching changes nothing for those attached already.
+    {
+        let mut service = service.lock().unwrap();
+        if service.is_shared() {
+            // For it to restore the other terminals too.
+            service.needs_session_sync = true;
+        } else {
+            service.reset_status(is_persistent);
+        }
+    }
 
     Ok(service)
 }
@@ -318,29 +379,76 @@ pub fn get_service_name(source: VideoSource, idx: usize) -> String {
     format!("{}{}", source.service_name_prefix(), idx)
 }
 
+/// `user` is the one the shells run as, see `may_join_service`.
 pub fn new(
     service_id: String,
     is_persistent: bool,
     user_token: Option<UserToken>,
+    user: String,
 ) -> GenericService {
     // Create the service with initial persistence setting
-    allow_err!(get_or_create_service(
+    let service = get_or_create_service(
         service_id.clone(),
         is_persistent,
-        user_token.is_some()
-    ));
+        user_token.is_some(),
+        user,
+    );
+    // The connections attached to the same terminals share the one sending their output.
+    match &service {
+        Ok(service) => {
+            if let Some(sp) = service.lock().unwrap().sp.clone().filter(|sp| sp.active()) {
+                return sp;
+            }
+        }
+        Err(e) => log::error!("Failed to create terminal service {}: {}", service_id, e),
+    }
     let svc = TerminalService {
         sp: GenericService::new(service_id.clone(), false),
         user_token,
     };
     GenericService::run(&svc.clone(), move |sp| run(sp, service_id.clone()));
+    if let Ok(service) = &service {
+        service.lock().unwrap().sp = Some(svc.sp.clone());
+    }
     svc.sp
 }
 
+/// Detaches a connection gone from the terminals of the service, leaving them without writer
+/// if it wrote to them.
+pub fn detach(service_id: &str, conn_id: i32) {
+    if let Some(service) = get_service(service_id) {
+        service.lock().unwrap().detach(conn_id);
+    }
+}
+
+fn new_data_response(terminal_id: i32, data: Vec<u8>) -> TerminalResponse {
+    let mut response = TerminalResponse::new();
+    let mut terminal_data = TerminalData::new();
+    terminal_data.terminal_id = terminal_id;
+
+    // Compress data if it exceeds threshold
+    if data.len() > COMPRESS_THRESHOLD {
+        let compressed = compress::compress(&data);
+        if compressed.len() < data.len() {
+            terminal_data.data = bytes::Bytes::from(compressed);
+            terminal_data.compressed = true;
+        } else {
+            // Compression didn't help, send uncompressed
+            terminal_data.data = bytes::Bytes::from(data);
+        }
+    } else {
+        terminal_data.data = bytes::Bytes::from(data);
+    }
+
+    response.set_data(terminal_data);
+    response
+}
+
 fn run(sp: TerminalService, service_id: String) -> ResultType<()> {
     while sp.ok() {
-        let responses = TerminalServiceProxy::new(service_id.clone(), None, sp.user_token.clone())
-            .read_outputs();
+        let responses =
+            TerminalServiceProxy::new(service_id.clone(), None, sp.user_token.clone(), 0)
+                .read_outputs();
         for response in responses {
             let mut msg_out = Message::new();
             msg_out.set_terminal_response(response);
@@ -463,6 +571,13 @@ pub struct TerminalSession {
     reader_thread: Option<thread::JoinHandle<()>>,
     writer_thread: Option<thread::JoinHandle<()>>,
     output_buffer: OutputBuffer,
+    // All the output kept, as text, to search and export it.
+    scrollback: Scrollback,
+    // Input, output and resizes, if terminals are recorded.
+    recorder: Option<super::terminal_recorder::Recording>,
+    // The connection writing to the terminal, of those attached, the others only watch it.
+    writer: Option<i32>,
+    attached: HashSet<i32>,
     title: String,
     pid: u32,
     rows: u16,
@@ -491,6 +606,10 @@ impl TerminalSession {
             reader_thread: None,
             writer_thread: None,
             output_buffer: OutputBuffer::new(),
+            scrollback: Scrollback::new(super::terminal_scrollback::max_lines()),
+            recorder: None,
+            writer: None,
+            attached: HashSet::new(),
             title: format!("Terminal {}", terminal_id),
             pid: 0,
             rows,
@@ -508,6 +627,73 @@ impl TerminalSession {
         self.last_activity = Instant::now();
     }
 
+    fn is_writer(&self, conn_id: i32) -> bool {
+        self.writer == Some(conn_id)
+    }
+
+    // The connection writes to the terminal if nobody does, unless it only watches it.
+    // Returns whether it only watches it.
+    fn attach(&mut self, conn_id: i32, read_only: bool) -> bool {
+        self.attached.insert(conn_id);
+        if read_only {
+            if self.is_writer(conn_id) {
+                self.writer = None;
+            }
+        } else if self.writer.is_none() {
+            self.writer = Some(conn_id);
+        }
+        !self.is_writer(conn_id)
+    }
+
+    fn role(&self, terminal_id: i32, conn_id: i32) -> TerminalResponse {
+        let viewers = self
+            .attached
+            .iter()
+            .filter(|id| !self.is_writer(**id))
+            .count();
+        let mut response = TerminalResponse::new();
+        response.set_role(TerminalRole {
+            terminal_id,
+            read_only: !self.is_writer(conn_id),
+            viewers: viewers as _,
+            ..Default::default()
+        });
+        response
+    }
+
+    // Takes over writing to the terminal, from its writer only if `take_over`, or gives it up.
+    // Returns whether the writer changed.
+    fn hand_off(&mut self, conn_id: i32, take: bool, take_over: bool) -> bool {
+        let writer = self.writer;
+        if take {
+            if self.writer.is_none() || take_over {
+                self.writer = Some(conn_id);
+            }
+        } else if self.is_writer(conn_id) {
+            self.writer = None;
+        }
+        self.writer != writer
+    }
+
+    fn start_recording(&mut self, terminal_id: i32, shell: &str, term: &str) {
+        self.recorder = super::terminal_recorder::start(
+            terminal_id,
+            self.rows,
+            self.cols,
+            &self.title,
+            shell,
+            term,
+        );
+    }
+
+    fn record(&mut self, event: super::terminal_recorder::Event) {
+        if let Some(recorder) = self.recorder.as_ref() {
+            if !recorder.send(event) {
+                self.recorder = None;
+            }
+        }
+    }
+
     // This helper function is to ensure that the threads are joined before the child process is dropped.
     // Though this is not strictly necessary on macOS.
     fn stop(&mut self) {
@@ -623,10 +809,19 @@ pub struct PersistentTerminalService {
     pub is_persistent: bool,
     needs_session_sync: bool,
     is_specified_user: bool,
+    // The user the shells run as, empty for the one of this process.
+    user: String,
+    // Sends the output to the connections attached, see `new`.
+    sp: Option<GenericService>,
 }
 
 impl PersistentTerminalService {
-    pub fn new(service_id: String, is_persistent: bool, is_specified_user: bool) -> Self {
+    pub fn new(
+        service_id: String,
+        is_persistent: bool,
+        is_specified_user: bool,
+        user: String,
+    ) -> Self {
         Self {
             service_id,
             sessions: HashMap::new(),
@@ -635,6 +830,44 @@ impl PersistentTerminalService {
             is_persistent,
             needs_session_sync: false,
             is_specified_user,
+            user,
+            sp: None,
+        }
+    }
+
+    // Those of another user only if the host allows it.
+    fn may_join(&self, user: &str) -> bool {
+        self.user == user || Config::get_bool_option(keys::OPTION_ALLOW_JOIN_TERMINAL_OTHER_USER)
+    }
+
+    fn detach(&self, conn_id: i32) {
+        for (terminal_id, session) in self.sessions.iter() {
+            let mut session = session.lock().unwrap();
+            if session.attached.remove(&conn_id) {
+                if session.is_writer(conn_id) {
+                    session.writer = None;
+                }
+                self.notify_roles(*terminal_id, &session, conn_id);
+            }
+        }
+    }
+
+    // Other connections are attached to the terminals.
+    fn is_shared(&self) -> bool {
+        self.sp
+            .as_ref()
+            .is_some_and(|sp| sp.active() && sp.has_subscribes())
+    }
+
+    // Tells the connections attached to the terminal, but `skip`, whether they write to it.
+    fn notify_roles(&self, terminal_id: i32, session: &TerminalSession, skip: i32) {
+        let Some(sp) = self.sp.as_ref() else {
+            return;
+        };
+        for id in session.attached.iter().filter(|id| **id != skip) {
+            let mut msg_out = Message::new();
+            msg_out.set_terminal_response(session.role(terminal_id, *id));
+            sp.send_to(msg_out, *id);
         }
     }
 
@@ -684,15 +917,53 @@ impl PersistentTerminalService {
         for session in self.sessions.values() {
             let mut session = session.lock().unwrap();
             session.is_opened = false;
+            session.writer = None;
+            session.attached.clear();
         }
     }
 }
 
+/// A search of the scrollback, on a snapshot so that the output of the terminal is not held up.
+pub struct ScrollbackSearch {
+    terminal_id: i32,
+    snapshot: Snapshot,
+    regex: regex::Regex,
+    max: usize,
+}
+
+impl ScrollbackSearch {
+    /// Blocks as long as the scrollback takes to search.
+    pub fn run(self) -> TerminalResponse {
+        let (matches, truncated) = self.snapshot.search(&self.regex, self.max);
+        let mut response = TerminalResponse::new();
+        response.set_search_result(TerminalSearchResult {
+            terminal_id: self.terminal_id,
+            matches: matches
+                .into_iter()
+                .map(|(line, start, end, text)| TerminalSearchMatch {
+                    line,
+                    start: start as _,
+                    end: end as _,
+                    text,
+                    ..Default::default()
+                })
+                .collect(),
+            first_line: self.snapshot.first_line(),
+            lines: self.snapshot.lines(),
+            truncated,
+            ..Default::default()
+        });
+        response
+    }
+}
+
 pub struct TerminalServiceProxy {
     service_id: String,
     is_persistent: bool,
     #[cfg(target_os = "windows")]
     user_token: Option<UserToken>,
+    // The connection acting, attached to the terminals it opens.
+    conn_id: i32,
 }
 
 pub fn set_persistent(service_id: &str, is_persistent: bool) -> Result<()> {
@@ -709,6 +980,7 @@ impl TerminalServiceProxy {
         service_id: String,
         is_persistent: Option<bool>,
         _user_token: Option<UserToken>,
+        conn_id: i32,
     ) -> Self {
         // Get persistence from the service if it exists
         let is_persistent =
@@ -722,6 +994,7 @@ impl TerminalServiceProxy {
             is_persistent,
             #[cfg(target_os = "windows")]
             user_token: _user_token,
+            conn_id,
         }
     }
 
@@ -766,6 +1039,9 @@ impl TerminalServiceProxy {
             Some(terminal_action::Union::Close(close)) => {
                 self.handle_close(&mut service.lock().unwrap(), close)
             }
+            Some(terminal_action::Union::Handoff(handoff)) => {
+                self.handle_handoff(&service.lock().unwrap(), handoff)
+            }
             _ => Ok(None),
         }
     }
@@ -788,6 +1064,8 @@ impl TerminalServiceProxy {
             opened.message = "Reconnected to existing terminal".to_string();
             opened.pid = session.pid;
             opened.service_id = self.service_id.clone();
+            opened.read_only = session.attach(self.conn_id, open.read_only);
+            service.notify_roles(open.terminal_id, &session, self.conn_id);
             if service.needs_session_sync {
                 if service.sessions.len() > 1 {
                     // No need to include the current terminal in the list.
@@ -802,24 +1080,33 @@ impl TerminalServiceProxy {
                 service.needs_session_sync = false;
             }
             response.set_opened(opened);
-
-            // Send buffered output
-            let buffer = session.output_buffer.get_recent(4096);
-            if !buffer.is_empty() {
-                // We'll need to send this separately or extend the protocol
-                // For now, just acknowledge the reconnection
-            }
-
             return Ok(Some(response));
         }
 
+        // Nothing to watch.
+        if open.read_only {
+            let message = format!("Terminal {} not found", open.terminal_id);
+            return Ok(Some(self.open_failed(open.terminal_id, message)));
+        }
+        #[cfg(target_os = "windows")]
+        let user_token = self.user_token;
+        #[cfg(not(target_os = "windows"))]
+        let user_token = None;
+        let spec = match ShellSpec::new(open, user_token) {
+            Ok(spec) => spec,
+            Err(e) => {
+                log::warn!("Refused to open terminal {}: {}", open.terminal_id, e);
+                return Ok(Some(self.open_failed(open.terminal_id, e.to_string())));
+            }
+        };
+
         // Windows with user_token: use helper process to run shell as the logged-in user
         // This solves the ConPTY + CreateProcessAsUserW incompatibility issue where
         // vim, Claude Code, and other TUI applications hang when ConPTY is created
         // by SYSTEM service but shell runs as user via CreateProcessAsUserW.
         #[cfg(target_os = "windows")]
         if self.user_token.is_some() {
-            return self.handle_open_with_helper(service, open);
+            return self.handle_open_with_helper(service, open, &spec);
         }
 
         // Create new terminal session
@@ -842,11 +1129,9 @@ impl TerminalServiceProxy {
         let pty_system = portable_pty::native_pty_system();
         let pty_pair = pty_system.openpty(pty_size).context("Failed to open PTY")?;
 
-        // Use default shell for the platform
-        let shell = get_default_shell();
+        let shell = spec.shell.clone();
         log::debug!("Using shell: {}", shell);
 
-        #[allow(unused_mut)]
         let mut cmd = CommandBuilder::new(&shell);
 
         // macOS-specific terminal configuration
@@ -857,7 +1142,7 @@ impl TerminalServiceProxy {
         //    macOS terminfo uses hex naming: '78' = 'x' for xterm entries
         // Note: For Linux, `TERM` is set in src/platform/linux.rs try_start_server_()
         #[cfg(target_os = "macos")]
-        {
+        let term = {
             // Start as login shell to load user environment (PATH, etc.)
             cmd.arg("-l");
             log::debug!("Added -l flag for macOS login shell");
@@ -869,7 +1154,17 @@ impl TerminalServiceProxy {
             };
             cmd.env("TERM", term);
             log::debug!("Set TERM={} for macOS PTY", term);
-        }
+            term.to_owned()
+        };
+        #[cfg(not(target_os = "macos"))]
+        let term = std::env::var("TERM").unwrap_or_default();
+        spec.apply(&mut cmd);
+        // The one of the client replaces it, see `ShellSpec::apply`.
+        let term = if spec.term.is_empty() {
+            term
+        } else {
+            spec.term.clone()
+        };
 
         // Note: On Windows with user_token, we use helper mode (handle_open_with_helper)
         // which is dispatched earlier in this function. This code path is only reached
@@ -897,11 +1192,24 @@ impl TerminalServiceProxy {
         let (input_tx, input_rx) = mpsc::sync_channel::<Vec<u8>>(CHANNEL_BUFFER_SIZE);
         let (output_tx, output_rx) = mpsc::sync_channel::<Vec<u8>>(CHANNEL_BUFFER_SIZE);
 
+        #[cfg(not(target_os = "windows"))]
+        let master_fd = pty_pair.master.as_raw_fd();
+
         // Spawn writer thread
         let terminal_id = open.terminal_id;
         let writer_thread = thread::spawn(move || {
             let mut writer = writer;
             while let Ok(data) = input_rx.recv() {
+                // Empty for the end of the input, see `handle_data`.
+                #[cfg(not(target_os = "windows"))]
+                if data.is_empty() {
+                    if let Some(fd) = master_fd {
+                        if let Err(e) = end_input(fd, &mut writer) {
+                            log::error!("Terminal {} eof error: {}", terminal_id, e);
+                        }
+                    }
+                    continue;
+                }
                 if let Err(e) = writer.write_all(&data) {
                     log::error!("Terminal {} write error: {}", terminal_id, e);
                     break;
@@ -971,6 +1279,8 @@ impl TerminalServiceProxy {
         session.reader_thread = Some(reader_thread);
         session.writer_thread = Some(writer_thread);
         session.is_opened = true;
+        session.attach(self.conn_id, false);
+        session.start_recording(open.terminal_id, &shell, &term);
 
         let mut opened = TerminalOpened::new();
         opened.terminal_id = open.terminal_id;
@@ -1009,6 +1319,7 @@ impl TerminalServiceProxy {
         &self,
         service: &mut PersistentTerminalService,
         open: &OpenTerminal,
+        spec: &ShellSpec,
     ) -> Result<Option<TerminalResponse>> {
         let mut response = TerminalResponse::new();
 
@@ -1060,6 +1371,7 @@ impl TerminalServiceProxy {
             open.terminal_id,
             open.rows as u16,
             open.cols as u16,
+            spec,
         )?;
 
         // Use HelperProcessGuard for RAII cleanup - terminates process on error
@@ -1188,6 +1500,8 @@ impl TerminalServiceProxy {
         session.is_opened = true;
         session.is_helper_mode = true;
         session.helper_process_handle = Some(SendableHandle::new(helper_raw_handle));
+        session.attach(self.conn_id, false);
+        session.start_recording(open.terminal_id, &spec.shell, &spec.term);
 
         let mut opened = TerminalOpened::new();
         opened.terminal_id = open.terminal_id;
@@ -1223,9 +1537,15 @@ impl TerminalServiceProxy {
     ) -> Result<Option<TerminalResponse>> {
         if let Some(session_arc) = session {
             let mut session = session_arc.lock().unwrap();
+            // The viewers see the terminal as the writer sized it.
+            if !session.is_writer(self.conn_id) {
+                return Ok(None);
+            }
             session.update_activity();
             session.rows = resize.rows as u16;
             session.cols = resize.cols as u16;
+            let (rows, cols) = (session.rows, session.cols);
+            session.record(super::terminal_recorder::Event::Resize(rows, cols));
 
             // Windows: handle helper mode vs direct PTY mode
             #[cfg(target_os = "windows")]
@@ -1278,7 +1598,16 @@ impl TerminalServiceProxy {
     ) -> Result<Option<TerminalResponse>> {
         if let Some(session_arc) = session {
             let mut session = session_arc.lock().unwrap();
+            if !session.is_writer(self.conn_id) {
+                log::debug!(
+                    "Ignored input of connection {} watching terminal {}",
+                    self.conn_id,
+                    data.terminal_id
+                );
+                return Ok(None);
+            }
             session.update_activity();
+            session.record(super::terminal_recorder::Event::Input(data.data.to_vec()));
             if let Some(input_tx) = &session.input_tx {
                 // Encode data for helper mode or send raw for direct PTY mode
                 #[cfg(target_os = "windows")]
@@ -1291,12 +1620,19 @@ impl TerminalServiceProxy {
                 let msg = data.data.to_vec();
 
                 // Send data to writer thread
-                if let Err(e) = input_tx.send(msg) {
-                    log::error!(
-                        "Failed to send data to terminal {}: {}",
-                        data.terminal_id,
-                        e
-                    );
+                if !msg.is_empty() {
+                    if let Err(e) = input_tx.send(msg) {
+                        log::error!(
+                            "Failed to send data to terminal {}: {}",
+                            data.terminal_id,
+                            e
+                        );
+                    }
+                }
+                // After the data, in the order it is written.
+                #[cfg(not(target_os = "windows"))]
+                if data.eof {
+                    input_tx.send(Vec::new()).ok();
                 }
             }
         }
@@ -1311,6 +1647,22 @@ impl TerminalServiceProxy {
     ) -> Result<Option<TerminalResponse>> {
         let mut response = TerminalResponse::new();
 
+        // Watching or left without writer, the terminal is closed only for the connection while
+        // others are attached.
+        if let Some(session_arc) = service.sessions.get(&close.terminal_id) {
+            let mut session = session_arc.lock().unwrap();
+            if !session.is_writer(self.conn_id)
+                && session.attached.iter().any(|id| *id != self.conn_id)
+            {
+                session.attached.remove(&self.conn_id);
+                service.notify_roles(close.terminal_id, &session, self.conn_id);
+                let mut closed = TerminalClosed::new();
+                closed.terminal_id = close.terminal_id;
+                response.set_closed(closed);
+                return Ok(Some(response));
+            }
+        }
+
         // Always close and remove the terminal
         if let Some(session_arc) = service.sessions.remove(&close.terminal_id) {
             let mut session = session_arc.lock().unwrap();
@@ -1325,6 +1677,16 @@ impl TerminalServiceProxy {
             let mut closed = TerminalClosed::new();
             closed.terminal_id = close.terminal_id;
             closed.exit_code = exit_code;
+            // The others attached lose it too.
+            if let Some(sp) = service.sp.as_ref() {
+                for id in session.attached.iter().filter(|id| **id != self.conn_id) {
+                    let mut response = TerminalResponse::new();
+                    response.set_closed(closed.clone());
+                    let mut msg_out = Message::new();
+                    msg_out.set_terminal_response(response);
+                    sp.send_to(msg_out, *id);
+                }
+            }
             response.set_closed(closed);
             Ok(Some(response))
         } else {
@@ -1332,6 +1694,97 @@ impl TerminalServiceProxy {
         }
     }
 
+    fn open_failed(&self, terminal_id: i32, message: String) -> TerminalResponse {
+        let mut opened = TerminalOpened::new();
+        opened.terminal_id = terminal_id;
+        opened.message = message;
+        opened.service_id = self.service_id.clone();
+        let mut response = TerminalResponse::new();
+        response.set_opened(opened);
+        response
+    }
+
+    fn handle_handoff(
+        &self,
+        service: &PersistentTerminalService,
+        handoff: &TerminalHandoff,
+    ) -> Result<Option<TerminalResponse>> {
+        if let Some(session_arc) = service.sessions.get(&handoff.terminal_id) {
+            let mut session = session_arc.lock().unwrap();
+            if !session.attached.contains(&self.conn_id) {
+                return Ok(None);
+            }
+            let take_over = Config::get_bool_option(keys::OPTION_ALLOW_TERMINAL_TAKEOVER);
+            if !session.hand_off(self.conn_id, handoff.take, take_over) {
+                // Unchanged, a take may be refused, so tell the connection its role.
+                return Ok(Some(session.role(handoff.terminal_id, self.conn_id)));
+            }
+            log::info!(
+                "Terminal {} writer: {:?}, {} attached",
+                handoff.terminal_id,
+                session.writer,
+                session.attached.len()
+            );
+            service.notify_roles(handoff.terminal_id, &session, 0);
+        }
+        Ok(None)
+    }
+
+    /// The search `search` asks for in the scrollback of the terminal, for the connection to
+    /// run it off its task.
+    pub fn search_history(&self, search: &TerminalSearch) -> Result<ScrollbackSearch> {
+        let query = if search.regex {
+            search.query.clone()
+        } else {
+            regex::escape(&search.query)
+        };
+        let regex = regex::RegexBuilder::new(&query)
+            .case_insensitive(!search.case_sensitive)
+            .build()
+            .map_err(|e| anyhow!("Invalid search: {}", e))?;
+        let max = match search.max_matches as usize {
+            0 => DEFAULT_SEARCH_MATCHES,
+            n => n.min(MAX_SEARCH_MATCHES),
+        };
+        Ok(ScrollbackSearch {
+            terminal_id: search.terminal_id,
+            snapshot: self.export_history(search.terminal_id)?,
+            regex,
+            max,
+        })
+    }
+
+    /// The scrollback of the terminal, for the connection to send it as a file.
+    pub fn export_history(&self, terminal_id: i32) -> Result<Snapshot> {
+        let service = get_service(&self.service_id)
+            .ok_or_else(|| anyhow!("Terminal service {} not found", self.service_id))?;
+        let session = service
+            .lock()
+            .unwrap()
+            .sessions
+            .get(&terminal_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("Terminal {} not found", terminal_id))?;
+        let session = session.lock().unwrap();
+        if !session.attached.contains(&self.conn_id) {
+            return Err(anyhow!("Terminal {} not opened", terminal_id));
+        }
+        Ok(session.scrollback.snapshot())
+    }
+
+    /// The recent output of the terminal, to replay to a connection attaching to it.
+    pub fn buffered_output(&self, terminal_id: i32) -> Option<TerminalResponse> {
+        let service = get_service(&self.service_id)?;
+        let buffer = service
+            .lock()
+            .unwrap()
+            .get_terminal_buffer(terminal_id, ATTACH_BUFFER_SIZE)?;
+        if buffer.is_empty() {
+            return None;
+        }
+        Some(new_data_response(terminal_id, buffer))
+    }
+
     pub fn read_outputs(&self) -> Vec<TerminalResponse> {
         let service = match get_service(&self.service_id) {
             Some(s) => s,
@@ -1392,30 +1845,13 @@ impl TerminalServiceProxy {
                 // Update buffer after reading
                 for data in &received_data {
                     session.output_buffer.append(data);
+                    session.scrollback.append(data);
+                    session.record(super::terminal_recorder::Event::Output(data.clone()));
                 }
 
                 // Process received data for responses
                 for data in received_data {
-                    let mut response = TerminalResponse::new();
-                    let mut terminal_data = TerminalData::new();
-                    terminal_data.terminal_id = terminal_id;
-
-                    // Compress data if it exceeds threshold
-                    if data.len() > COMPRESS_THRESHOLD {
-                        let compressed = compress::compress(&data);
-                        if compressed.len() < data.len() {
-                            terminal_data.data = bytes::Bytes::from(compressed);
-                            terminal_data.compressed = true;
-                        } else {
-                            // Compression didn't help, send uncompressed
-                            terminal_data.data = bytes::Bytes::from(data);
-                        }
-                    } else {
-                        terminal_data.data = bytes::Bytes::from(data);
-                    }
-
-                    response.set_data(terminal_data);
-                    responses.push(response);
+                    responses.push(new_data_response(terminal_id, data));
                 }
 
                 if has_activity {
@@ -1477,3 +1913,179 @@ impl TerminalServiceProxy {
         }
     }
 }
+
+// Ends the input of what reads the terminal, even in raw mode where the EOF character is a byte
+// like any other: the line discipline goes canonical, for good, as nothing is written after it.
+#[cfg(not(target_os = "windows"))]
+fn end_input(fd: std::os::unix::io::RawFd, writer: &mut impl Write) -> std::io::Result<()> {
+    use hbb_common::libc;
+
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if termios.c_lflag & libc::ICANON == 0 {
+        termios.c_lflag |= libc::ICANON;
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    // Once to end a pending line, once more for the end itself.
+    let eof = termios.c_cc[libc::VEOF];
+    writer.write_all(&[eof, eof])?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Terminal 1, `writer` writing to it and `viewers` watching it.
+    fn new_service(writer: i32, viewers: &[i32]) -> PersistentTerminalService {
+        let mut service =
+            PersistentTerminalService::new("ts_test".to_owned(), false, false, String::new());
+        let mut session = TerminalSession::new(1, 24, 80);
+        assert!(!session.attach(writer, false));
+        for id in viewers {
+            assert!(session.attach(*id, true));
+        }
+        service.sessions.insert(1, Arc::new(Mutex::new(session)));
+        service
+    }
+
+    fn proxy(conn_id: i32) -> TerminalServiceProxy {
+        TerminalServiceProxy::new("ts_test".to_owned(), Some(false), None, conn_id)
+    }
+
+    // Returns the role answered if the writer did not change.
+    fn handoff(
+        service: &PersistentTerminalService,
+        conn_id: i32,
+        take: bool,
+    ) -> Option<TerminalResponse> {
+        let handoff = TerminalHandoff {
+            terminal_id: 1,
+            take,
+            ..Default::default()
+        };
+        proxy(conn_id).handle_handoff(service, &handoff).unwrap()
+    }
+
+    fn close(service: &mut PersistentTerminalService, conn_id: i32) {
+        let close = CloseTerminal {
+            terminal_id: 1,
+            ..Default::default()
+        };
+        let response = proxy(conn_id).handle_close(service, &close).unwrap();
+        assert!(response.is_some_and(|r| r.has_closed()));
+    }
+
+    fn writer(service: &PersistentTerminalService) -> Option<i32> {
+        service.sessions[&1].lock().unwrap().writer
+    }
+
+    #[test]
+    fn test_attach() {
+        let mut session = TerminalSession::new(1, 24, 80);
+        assert!(!session.attach(1, false));
+        assert!(session.is_writer(1));
+        // Another one only watches it while the first writes.
+        assert!(session.attach(2, false));
+        assert!(session.attach(3, true));
+        assert!(session.is_writer(1) && !session.is_writer(2));
+        // The writer attaching again to watch leaves it without writer.
+        assert!(session.attach(1, true));
+        assert_eq!(session.writer, None);
+        assert!(!session.attach(2, false));
+        assert!(session.is_writer(2));
+        assert_eq!(session.attached.len(), 3);
+    }
+
+    #[test]
+    fn test_hand_off() {
+        let mut session = TerminalSession::new(1, 24, 80);
+        session.attach(1, false);
+        session.attach(2, true);
+        // Taken from the writer only if the host allows it.
+        assert!(!session.hand_off(2, true, false));
+        assert!(session.is_writer(1));
+        assert!(session.hand_off(2, true, true));
+        assert!(session.is_writer(2));
+        // Only the writer gives it up, then anybody takes it.
+        assert!(!session.hand_off(1, false, false));
+        assert!(session.hand_off(2, false, false));
+        assert_eq!(session.writer, None);
+        assert!(session.hand_off(1, true, false));
+        assert!(session.is_writer(1));
+    }
+
+    #[test]
+    fn test_handoff() {
+        let service = new_service(1, &[2]);
+        // Those not attached can not take it.
+        assert!(handoff(&service, 3, true).is_none());
+        assert_eq!(writer(&service), Some(1));
+        // Only the writer gives it up.
+        assert!(handoff(&service, 2, false).is_some());
+        assert_eq!(writer(&service), Some(1));
+        if !Config::get_bool_option(keys::OPTION_ALLOW_TERMINAL_TAKEOVER) {
+            // The viewer refused is told it still only watches.
+            let role = handoff(&service, 2, true).unwrap();
+            assert!(role.role().read_only && role.role().viewers == 1);
+            assert_eq!(writer(&service), Some(1));
+        }
+        assert!(handoff(&service, 1, false).is_none());
+        assert_eq!(writer(&service), None);
+        assert!(handoff(&service, 2, true).is_none());
+        assert_eq!(writer(&service), Some(2));
+    }
+
+    #[test]
+    fn test_close() {
+        let mut service = new_service(1, &[2, 3]);
+        // A viewer closes it only for itself.
+        close(&mut service, 2);
+        assert!(service.sessions.contains_key(&1));
+        assert!(!service.sessions[&1].lock().unwrap().attached.contains(&2));
+        // As does one attached while nobody writes.
+        handoff(&service, 1, false);
+        close(&mut service, 3);
+        assert!(service.sessions.contains_key(&1));
+        // The writer closes it for all.
+        handoff(&service, 1, true);
+        service.sessions[&1].lock().unwrap().attach(4, true);
+        close(&mut service, 1);
+        assert!(service.sessions.is_empty());
+
+        // The last one attached closes it.
+        let mut service = new_service(1, &[2]);
+        handoff(&service, 1, false);
+        close(&mut service, 2);
+        close(&mut service, 1);
+        assert!(service.sessions.is_empty());
+    }
+
+    #[test]
+    fn test_detach() {
+        let service = new_service(1, &[2]);
+        // A viewer leaving changes nothing for the writer.
+        service.detach(2);
+        assert_eq!(writer(&service), Some(1));
+        service.sessions[&1].lock().unwrap().attach(2, true);
+        // The writer leaving leaves it without writer, for another to take it.
+        service.detach(1);
+        assert_eq!(writer(&service), None);
+        assert!(service.sessions[&1].lock().unwrap().attached.contains(&2));
+        handoff(&service, 2, true);
+        assert_eq!(writer(&service), Some(2));
+    }
+
+    #[test]
+    fn test_may_join() {
+        let service = new_service(1, &[]);
+        assert!(service.may_join(""));
+        if !Config::get_bool_option(keys::OPTION_ALLOW_JOIN_TERMINAL_OTHER_USER) {
+            assert!(!service.may_join("other"));
+        }
+    }
+}
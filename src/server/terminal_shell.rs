@@ -0,0 +1,327 @@
+//! The shells terminals may run, and how a client asks a new one to start: its shell, working
+//! directory, environment and TERM, within what the host allows.
+use super::terminal_service::{get_default_shell, UserToken};
+use hbb_common::{
+    anyhow::{bail, Result},
+    config::{keys, Config},
+    message_proto::OpenTerminal,
+};
+use portable_pty::CommandBuilder;
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashMap, path::Path};
+
+const MAX_ENV_VARS: usize = 64;
+const MAX_ENV_VALUE_LEN: usize = 4096;
+const MAX_TERM_LEN: usize = 64;
+// Changing how programs are loaded, not for the client to set.
+const DENIED_ENV_PREFIXES: [&str; 2] = ["LD_", "DYLD_"];
+
+/// The shells terminals may run, the default first. Those of the option `terminal-shells` if
+/// set, otherwise the default one and those installed.
+pub fn available_shells() -> Vec<String> {
+    let option = Config::get_option(keys::OPTION_TERMINAL_SHELLS);
+    let listed: Vec<String> = option
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty() && Path::new(s).exists())
+        .map(|s| s.to_owned())
+        .collect();
+    if !listed.is_empty() {
+        return listed;
+    }
+    let mut shells = vec![get_default_shell()];
+    for shell in installed_shells() {
+        if !shells.contains(&shell) {
+            shells.push(shell);
+        }
+    }
+    shells
+}
+
+#[cfg(not(target_os = "windows"))]
+fn installed_shells() -> Vec<String> {
+    std::fs::read_to_string("/etc/shells")
+        .unwrap_or_default()
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#') && Path::new(l).exists())
+        .map(|l| l.to_owned())
+        .collect()
+}
+
+#[cfg(target_os = "windows")]
+fn installed_shells() -> Vec<String> {
+    let root = std::env::var("SystemRoot").unwrap_or_else(|_| "C:\\Windows".to_string());
+    let program_files =
+        std::env::var("ProgramFiles").unwrap_or_else(|_| "C:\\Program Files".to_string());
+    [
+        format!("{}\\PowerShell\\7\\pwsh.exe", program_files),
+        format!(
+            "{}\\System32\\WindowsPowerShell\\v1.0\\powershell.exe",
+            root
+        ),
+        format!("{}\\System32\\cmd.exe", root),
+        format!("{}\\System32\\wsl.exe", root),
+    ]
+    .into_iter()
+    .filter(|s| Path::new(s).exists())
+    .collect()
+}
+
+/// How a new terminal starts.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShellSpec {
+    pub shell: String,
+    pub cwd: String,
+    pub env: Vec<(String, String)>,
+    pub term: String,
+}
+
+// What the options of the host let the client ask for.
+struct ShellPolicy {
+    shells: Vec<String>,
+    client_cwd: bool,
+    client_env: bool,
+    env_allowed: String,
+}
+
+impl ShellPolicy {
+    fn get() -> Self {
+        Self {
+            shells: available_shells(),
+            client_cwd: Config::get_bool_option(keys::OPTION_ENABLE_TERMINAL_CLIENT_CWD),
+            client_env: Config::get_bool_option(keys::OPTION_ENABLE_TERMINAL_CLIENT_ENV),
+            env_allowed: Config::get_option(keys::OPTION_TERMINAL_CLIENT_ENV_ALLOWED),
+        }
+    }
+}
+
+impl ShellSpec {
+    /// What `open` asks for, failing with the reason if the host does not allow it.
+    /// `user_token` is of the user the shell runs as, if not the one of this process.
+    pub fn new(open: &OpenTerminal, user_token: Option<UserToken>) -> Result<Self> {
+        Self::with_policy(open, user_token, ShellPolicy::get())
+    }
+
+    fn with_policy(
+        open: &OpenTerminal,
+        user_token: Option<UserToken>,
+        policy: ShellPolicy,
+    ) -> Result<Self> {
+        let shell = if open.shell.is_empty() {
+            policy
+                .shells
+                .into_iter()
+                .next()
+                .unwrap_or_else(get_default_shell)
+        } else if policy.shells.contains(&open.shell) {
+            open.shell.clone()
+        } else {
+            bail!("Shell {} is not allowed", open.shell);
+        };
+        if !open.cwd.is_empty() {
+            if !policy.client_cwd {
+                bail!("The working directory can not be set");
+            }
+            if !is_dir_as(&open.cwd, user_token) {
+                bail!("No directory {}", open.cwd);
+            }
+        }
+        if !open.env.is_empty() && !policy.client_env {
+            bail!("The environment can not be set");
+        }
+        check_env(&open.env, &policy.env_allowed)?;
+        if !open.term.is_empty() && !is_valid_term(&open.term) {
+            bail!("Invalid TERM {}", open.term);
+        }
+        let mut env: Vec<_> = open
+            .env
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        env.sort();
+        Ok(Self {
+            shell,
+            cwd: open.cwd.clone(),
+            env,
+            term: open.term.clone(),
+        })
+    }
+
+    /// Sets the working directory and environment asked, over the defaults of `cmd`.
+    pub fn apply(&self, cmd: &mut CommandBuilder) {
+        if !self.cwd.is_empty() {
+            cmd.cwd(&self.cwd);
+        }
+        for (name, value) in self.env.iter() {
+            cmd.env(name, value);
+        }
+        if !self.term.is_empty() {
+            cmd.env("TERM", &self.term);
+        }
+    }
+
+    /// In one argument of the command line of the helper starting the shell.
+    #[cfg(target_os = "windows")]
+    pub fn encode(&self) -> String {
+        crate::encode64(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    #[cfg(target_os = "windows")]
+    pub fn decode(arg: &str) -> Result<Self> {
+        Ok(serde_json::from_slice(&crate::decode64(arg)?)?)
+    }
+}
+
+// As the user the shell runs as, who may not see what this process does.
+#[cfg(target_os = "windows")]
+fn is_dir_as(path: &str, user_token: Option<UserToken>) -> bool {
+    use windows::Win32::{
+        Foundation::HANDLE,
+        Security::{ImpersonateLoggedOnUser, RevertToSelf},
+    };
+    let Some(token) = user_token else {
+        return Path::new(path).is_dir();
+    };
+    unsafe {
+        if let Err(e) = ImpersonateLoggedOnUser(HANDLE(token.as_raw() as _)) {
+            hbb_common::log::error!("Failed to impersonate the user of the terminal: {}", e);
+            return false;
+        }
+        let is_dir = Path::new(path).is_dir();
+        if let Err(e) = RevertToSelf() {
+            hbb_common::log::error!("Failed to revert the impersonation: {}", e);
+        }
+        is_dir
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_dir_as(path: &str, _user_token: Option<UserToken>) -> bool {
+    Path::new(path).is_dir()
+}
+
+// `allowed` is comma separated, the names or prefixes ending with "*", any if empty.
+fn check_env(env: &HashMap<String, String>, allowed: &str) -> Result<()> {
+    let allowed: Vec<&str> = allowed
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect();
+    if env.len() > MAX_ENV_VARS {
+        bail!("Too many environment variables");
+    }
+    for (name, value) in env.iter() {
+        let valid = name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && value.len() <= MAX_ENV_VALUE_LEN
+            && !value.contains('\0');
+        if !valid {
+            bail!("Invalid environment variable {}", name);
+        }
+        let upper = name.to_ascii_uppercase();
+        let listed = allowed.is_empty()
+            || allowed.iter().any(|a| match a.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == a,
+            });
+        if !listed || DENIED_ENV_PREFIXES.iter().any(|p| upper.starts_with(p)) {
+            bail!("Environment variable {} is not allowed", name);
+        }
+    }
+    Ok(())
+}
+
+fn is_valid_term(term: &str) -> bool {
+    term.len() <= MAX_TERM_LEN
+        && term
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._+".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_spec() {
+        let policy = || ShellPolicy {
+            shells: vec!["/bin/sh".to_owned(), "/bin/bash".to_owned()],
+            client_cwd: true,
+            client_env: true,
+            env_allowed: "LANG, LC_*".to_owned(),
+        };
+        let new = |open: &OpenTerminal| ShellSpec::with_policy(open, None, policy());
+        let mut open = OpenTerminal::new();
+        open.env.insert("LANG".to_owned(), "C.UTF-8".to_owned());
+        open.term = "xterm-256color".to_owned();
+        let spec = new(&open).unwrap();
+        assert_eq!(spec.shell, "/bin/sh");
+        assert_eq!(spec.env, vec![("LANG".to_owned(), "C.UTF-8".to_owned())]);
+        assert!(ShellSpec::with_policy(
+            &open,
+            None,
+            ShellPolicy {
+                client_env: false,
+                ..policy()
+            }
+        )
+        .is_err());
+
+        open.shell = "/bin/bash".to_owned();
+        assert_eq!(new(&open).unwrap().shell, "/bin/bash");
+        open.shell = "/no/such/shell".to_owned();
+        assert!(new(&open).is_err());
+        open.shell.clear();
+        open.cwd = std::env::temp_dir().to_string_lossy().into_owned();
+        assert!(new(&open).is_ok());
+        assert!(ShellSpec::with_policy(
+            &open,
+            None,
+            ShellPolicy {
+                client_cwd: false,
+                ..policy()
+            }
+        )
+        .is_err());
+        open.cwd = "/no/such/dir".to_owned();
+        assert!(new(&open).is_err());
+        open.cwd.clear();
+        open.term = "xterm; rm".to_owned();
+        assert!(new(&open).is_err());
+        open.term.clear();
+        open.env.insert("LC_ALL".to_owned(), "C".to_owned());
+        assert!(new(&open).is_ok());
+        open.env.insert("EDITOR".to_owned(), "vi".to_owned());
+        assert!(new(&open).is_err());
+        open.env.clear();
+        open.env
+            .insert("LD_PRELOAD".to_owned(), "/tmp/x.so".to_owned());
+        assert!(ShellSpec::with_policy(
+            &open,
+            None,
+            ShellPolicy {
+                env_allowed: "".to_owned(),
+                ..policy()
+            }
+        )
+        .is_err());
+        open.env.clear();
+        open.env.insert("1A".to_owned(), "".to_owned());
+        assert!(new(&open).is_err());
+    }
+
+    #[test]
+    fn test_env_allowed() {
+        let env = HashMap::from([("LC_ALL".to_owned(), "C".to_owned())]);
+        assert!(check_env(&env, "").is_ok());
+        assert!(check_env(&env, "LANG, LC_*").is_ok());
+        assert!(check_env(&env, "LANG").is_err());
+        // Not even if listed.
+        let env = HashMap::from([("LD_PRELOAD".to_owned(), "/tmp/x.so".to_owned())]);
+        assert!(check_env(&env, "LD_*").is_err());
+    }
+}
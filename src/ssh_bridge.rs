@@ -0,0 +1,996 @@
+//! A local SSH server bridging the session channels of standard `ssh`, `scp` and `sftp` clients
+//! to terminals of the peer, over a terminal connection logged in as any other one. It listens on
+//! the loopback interface only, and lets in the keys of `~/.ssh/authorized_keys` of the user
+//! running it, as a local sshd would. The peer is logged in to once a client let in asks for its
+//! first shell, command or subsystem, one connection to the peer per SSH connection.
+//! A command, as `scp` runs one, is typed into the shell of a new terminal, which has to be a
+//! POSIX one. The `sftp` subsystem runs the `sftp-server` of OpenSSH on the peer.
+use crate::client::{
+    handle_test_delay, hc_connection, Client, Data, Interface, LoginConfigHandler,
+};
+use hbb_common::{
+    allow_err, anyhow, bail,
+    bytes::Bytes,
+    compress,
+    config::{Config, READ_TIMEOUT},
+    futures::StreamExt,
+    log,
+    message_proto::{
+        login_response, message, terminal_response, CloseTerminal, Message, OpenTerminal,
+        ResizeTerminal, TerminalAction, TerminalData, TerminalResponse,
+    },
+    protobuf::Message as _,
+    rand::rngs::OsRng,
+    rendezvous_proto::ConnType,
+    tcp, timeout,
+    tokio::{
+        self,
+        net::{TcpListener, TcpStream},
+        sync::{mpsc, oneshot},
+    },
+    ResultType, Stream,
+};
+use russh::{
+    keys::{
+        ssh_key::{AuthorizedKeys, LineEnding},
+        Algorithm, PrivateKey, PublicKey,
+    },
+    server::{self, Auth, Handle, Msg, Session},
+    Channel, ChannelId, CryptoVec, Pty,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+const HOST_KEY_FILE: &str = "ssh_bridge_host_key";
+const DEFAULT_ROWS: u32 = 24;
+const DEFAULT_COLS: u32 = 80;
+// Printed right before the command of an exec channel runs, what comes before is the shell's.
+const EXEC_MARKER: &[u8] = b"\x1ehbb-ssh-bridge\x1e";
+// Where OpenSSH installs it on the common systems, rarely in the PATH.
+const SFTP_SERVER: &[u8] = b"sh -c 'for p in /usr/lib/openssh/sftp-server \
+    /usr/libexec/openssh/sftp-server /usr/lib/ssh/sftp-server /usr/libexec/sftp-server \
+    /usr/lib/sftp-server; do [ -x \"$p\" ] && exec \"$p\"; done; exit 127'";
+
+pub async fn listen(
+    id: String,
+    password: String,
+    port: i32,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+    lc: Arc<RwLock<LoginConfigHandler>>,
+) -> ResultType<()> {
+    let config = Arc::new(server::Config {
+        keys: vec![host_key()?],
+        inactivity_timeout: None,
+        auth_rejection_time: Duration::from_secs(1),
+        auth_rejection_time_initial: Some(Duration::from_secs(0)),
+        ..Default::default()
+    });
+    let authorized_keys = Config::get_home().join(".ssh").join("authorized_keys");
+    let listener = tcp::new_listener(format!("127.0.0.1:{}", port), true).await?;
+    log::info!("ssh bridge listening on {:?}", listener.local_addr()?);
+    let (connector, requests) = mpsc::unbounded_channel();
+    tokio::select! {
+        _ = accept(listener, config, connector, authorized_keys) => {}
+        _ = connect(id, password, interface, ui_receiver, key, token, lc, requests) => {}
+    }
+    Ok(())
+}
+
+// Lets the SSH connections in, each one asks `connector` for the peer once it needs it.
+async fn accept(
+    listener: TcpListener,
+    config: Arc<server::Config>,
+    connector: Connector,
+    authorized_keys: PathBuf,
+) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::error!("Failed to accept an ssh connection: {}", err);
+                continue;
+            }
+        };
+        log::info!("new ssh connection from {:?}", addr);
+        let config = config.clone();
+        let connector = connector.clone();
+        let authorized_keys = authorized_keys.clone();
+        tokio::spawn(async move {
+            if let Err(err) = run(config, socket, connector, authorized_keys).await {
+                log::error!("ssh connection from {:?} failed: {}", addr, err);
+            }
+            log::info!("ssh connection from {:?} closed", addr);
+        });
+    }
+}
+
+// Asks for a connection to the peer logged in, answered with `None` if it failed.
+type Connector = mpsc::UnboundedSender<oneshot::Sender<Option<Stream>>>;
+
+// Logs in to the peer for the SSH connections asking for it, one at a time as the prompts of the
+// ui go to the login running, until the ui is closed.
+async fn connect(
+    id: String,
+    password: String,
+    interface: impl Interface,
+    mut ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+    lc: Arc<RwLock<LoginConfigHandler>>,
+    mut requests: mpsc::UnboundedReceiver<oneshot::Sender<Option<Stream>>>,
+) {
+    loop {
+        tokio::select! {
+            Some(reply) = requests.recv() => {
+                // Terminals of its own, not those of the service kept for the peer.
+                lc.write().unwrap().forget_terminal_service_id();
+                let stream = match connect_and_login(&id, &password, &mut ui_receiver, interface.clone(), key, token).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        interface.on_establish_connection_error(err.to_string());
+                        None
+                    }
+                };
+                reply.send(stream).ok();
+            }
+            d = ui_receiver.recv() => {
+                match d {
+                    Some(Data::Close) | None => {
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+// Kept in the config directory, for the clients to know the bridge again.
+fn host_key() -> ResultType<PrivateKey> {
+    let path = Config::path(HOST_KEY_FILE);
+    if let Ok(key) = std::fs::read_to_string(&path) {
+        match PrivateKey::from_openssh(&key) {
+            Ok(key) => return Ok(key),
+            Err(err) => log::warn!("Invalid ssh bridge host key, generating one: {}", err),
+        }
+    }
+    let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519)?;
+    std::fs::write(&path, key.to_openssh(LineEnding::LF)?.as_bytes())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(key)
+}
+
+async fn connect_and_login(
+    id: &str,
+    password: &str,
+    ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
+    interface: impl Interface,
+    key: &str,
+    token: &str,
+) -> ResultType<Option<Stream>> {
+    let ((mut stream, direct, _pk, _kcp, _stream_type), (feedback, rendezvous_server)) =
+        Client::start(id, key, token, ConnType::TERMINAL, interface.clone()).await?;
+    interface.update_direct(Some(direct));
+    let mut received = false;
+
+    let _keep_it = hc_connection(feedback, rendezvous_server, token).await;
+
+    loop {
+        tokio::select! {
+            res = timeout(READ_TIMEOUT, stream.next()) => match res {
+                Err(_) => {
+                    bail!("Timeout");
+                }
+                Ok(Some(Ok(bytes))) => {
+                    if !received {
+                        received = true;
+                        interface.update_received(true);
+                    }
+                    let msg_in = Message::parse_from_bytes(&bytes)?;
+                    match msg_in.union {
+                        Some(message::Union::Hash(hash)) => {
+                            interface.handle_hash(password, hash, &mut stream).await;
+                        }
+                        Some(message::Union::LoginResponse(lr)) => match lr.union {
+                            Some(login_response::Union::Error(err)) => {
+                                if !interface.handle_login_error(&err) {
+                                    return Ok(None);
+                                }
+                            }
+                            Some(login_response::Union::PeerInfo(pi)) => {
+                                interface.handle_peer_info(pi);
+                                break;
+                            }
+                            _ => {}
+                        }
+                        Some(message::Union::TestDelay(t)) => {
+                            interface.handle_test_delay(t, &mut stream).await;
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(Some(Err(err))) => {
+                    bail!("Connection closed: {}", err);
+                }
+                _ => {
+                    bail!("Reset by the peer");
+                }
+            },
+            d = ui_receiver.recv() => {
+                match d {
+                    Some(Data::Login((os_username, os_password, password, remember))) => {
+                        interface.handle_login_from_ui(os_username, os_password, password, remember, &mut stream).await;
+                    }
+                    Some(Data::Message(msg)) => {
+                        allow_err!(stream.send(&msg).await);
+                    }
+                    _ => {}
+                }
+            },
+        }
+    }
+    Ok(Some(stream))
+}
+
+async fn run(
+    config: Arc<server::Config>,
+    socket: TcpStream,
+    connector: Connector,
+    authorized_keys: PathBuf,
+) -> ResultType<()> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let outputs = Outputs::default();
+    let bridge = Bridge {
+        sender,
+        outputs: outputs.clone(),
+        channels: HashMap::new(),
+        next_terminal_id: 1,
+        authorized_keys,
+    };
+    let session = server::run_stream(config, socket, bridge).await?;
+    let handle = session.handle();
+    tokio::select! {
+        res = session => res?,
+        res = forward(connector, receiver, handle, outputs) => res?,
+    }
+    Ok(())
+}
+
+// Sends the actions of the channels to the peer, and the responses of the peer to the channels.
+// Connected to the peer at the first action, the first terminal opened.
+async fn forward(
+    connector: Connector,
+    mut receiver: mpsc::UnboundedReceiver<TerminalAction>,
+    handle: Handle,
+    outputs: Outputs,
+) -> ResultType<()> {
+    let Some(action) = receiver.recv().await else {
+        return Ok(());
+    };
+    let (tx, rx) = oneshot::channel();
+    connector.send(tx).ok();
+    let Some(mut stream) = rx.await.ok().flatten() else {
+        // Every terminal opened fails, until the client gives up.
+        loop {
+            let channels: Vec<_> = outputs
+                .lock()
+                .unwrap()
+                .drain()
+                .map(|(_, o)| o.channel)
+                .collect();
+            for channel in channels {
+                let text = CryptoVec::from_slice(b"Failed to connect to the peer\r\n");
+                allow_err!(handle.extended_data(channel, 1, text).await);
+                close_channel(&handle, channel, 1).await;
+            }
+            if receiver.recv().await.is_none() {
+                return Ok(());
+            }
+        }
+    };
+    let mut msg_out = Message::new();
+    msg_out.set_terminal_action(action);
+    allow_err!(stream.send(&msg_out).await);
+    loop {
+        tokio::select! {
+            res = stream.next() => match res {
+                Some(Ok(bytes)) => {
+                    let msg_in = Message::parse_from_bytes(&bytes)?;
+                    match msg_in.union {
+                        Some(message::Union::TerminalResponse(response)) => {
+                            handle_response(response, &handle, &outputs, &mut stream).await;
+                        }
+                        Some(message::Union::TestDelay(t)) => {
+                            handle_test_delay(t, &mut stream).await;
+                        }
+                        _ => {}
+                    }
+                }
+                Some(Err(err)) => {
+                    bail!("Connection closed: {}", err);
+                }
+                None => {
+                    bail!("Reset by the peer");
+                }
+            },
+            action = receiver.recv() => match action {
+                Some(action) => {
+                    let mut msg_out = Message::new();
+                    msg_out.set_terminal_action(action);
+                    allow_err!(stream.send(&msg_out).await);
+                }
+                None => break,
+            },
+        }
+    }
+    Ok(())
+}
+
+async fn handle_response(
+    response: TerminalResponse,
+    handle: &Handle,
+    outputs: &Outputs,
+    stream: &mut Stream,
+) {
+    match response.union {
+        Some(terminal_response::Union::Opened(opened)) if !opened.success => {
+            let output = outputs.lock().unwrap().remove(&opened.terminal_id);
+            if let Some(output) = output {
+                let text = format!("{}\r\n", opened.message);
+                allow_err!(
+                    handle
+                        .extended_data(output.channel, 1, CryptoVec::from_slice(text.as_bytes()))
+                        .await
+                );
+                close_channel(handle, output.channel, 1).await;
+            }
+        }
+        Some(terminal_response::Union::Data(data)) => {
+            let mut bytes = if data.compressed {
+                compress::decompress(&data.data)
+            } else {
+                data.data.to_vec()
+            };
+            let mut input = None;
+            let channel = {
+                let mut outputs = outputs.lock().unwrap();
+                let Some(output) = outputs.get_mut(&data.terminal_id) else {
+                    return;
+                };
+                if let Some(prelude) = output.prelude.as_mut() {
+                    let Some(rest) = skip_prelude(prelude, &bytes) else {
+                        return;
+                    };
+                    output.prelude = None;
+                    bytes = rest;
+                    input = Some((std::mem::take(&mut output.input), output.eof));
+                }
+                output.channel
+            };
+            // The command runs, with the terminal raw, what the client sent so far can go.
+            if let Some((input, eof)) = input {
+                let mut actions = Vec::new();
+                if !input.is_empty() {
+                    actions.push(data_action(data.terminal_id, input, false));
+                }
+                if eof {
+                    actions.push(data_action(data.terminal_id, Vec::new(), true));
+                }
+                for action in actions {
+                    let mut msg_out = Message::new();
+                    msg_out.set_terminal_action(action);
+                    allow_err!(stream.send(&msg_out).await);
+                }
+            }
+            if !bytes.is_empty() {
+                allow_err!(handle.data(channel, CryptoVec::from_slice(&bytes)).await);
+            }
+        }
+        Some(terminal_response::Union::Closed(closed)) => {
+            let output = outputs.lock().unwrap().remove(&closed.terminal_id);
+            if let Some(output) = output {
+                close_channel(handle, output.channel, closed.exit_code as u32).await;
+            }
+        }
+        Some(terminal_response::Union::Error(err)) => {
+            let channel = outputs
+                .lock()
+                .unwrap()
+                .get(&err.terminal_id)
+                .map(|o| o.channel);
+            if let Some(channel) = channel {
+                let text = format!("{}\r\n", err.message);
+                allow_err!(
+                    handle
+                        .extended_data(channel, 1, CryptoVec::from_slice(text.as_bytes()))
+                        .await
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+async fn close_channel(handle: &Handle, channel: ChannelId, exit_code: u32) {
+    allow_err!(handle.exit_status_request(channel, exit_code).await);
+    allow_err!(handle.eof(channel).await);
+    allow_err!(handle.close(channel).await);
+}
+
+// What of `data` follows the marker, `None` until it is seen, keeping what may be its start.
+fn skip_prelude(prelude: &mut Vec<u8>, data: &[u8]) -> Option<Vec<u8>> {
+    prelude.extend_from_slice(data);
+    if let Some(pos) = prelude
+        .windows(EXEC_MARKER.len())
+        .position(|w| w == EXEC_MARKER)
+    {
+        return Some(prelude.split_off(pos + EXEC_MARKER.len()));
+    }
+    let start = prelude.len().saturating_sub(EXEC_MARKER.len() - 1);
+    prelude.drain(..start);
+    None
+}
+
+// Runs `command` in place of the shell the terminal starts with, the marker printed first.
+// Without a pty asked for, the terminal neither echoes nor translates what goes through it.
+fn exec_line(command: &[u8], pty: bool) -> Vec<u8> {
+    let mut line = Vec::new();
+    if !pty {
+        line.extend_from_slice(b"stty raw -echo 2>/dev/null; ");
+    }
+    line.extend_from_slice(b"printf '\\036hbb-ssh-bridge\\036'; exec ");
+    line.extend_from_slice(command);
+    line.push(b'\n');
+    line
+}
+
+fn data_action(terminal_id: i32, data: Vec<u8>, eof: bool) -> TerminalAction {
+    let mut action = TerminalAction::new();
+    action.set_data(TerminalData {
+        terminal_id,
+        data: Bytes::from(data),
+        eof,
+        ..Default::default()
+    });
+    action
+}
+
+// Entries with options, e.g. `command=` or `from=`, are not honored so they do not let in.
+fn is_authorized(path: &Path, key: &PublicKey) -> bool {
+    match AuthorizedKeys::read_file(path) {
+        Ok(entries) => entries
+            .iter()
+            .any(|e| e.config_opts().is_empty() && e.public_key().key_data() == key.key_data()),
+        Err(err) => {
+            log::warn!("Failed to read {}: {}", path.display(), err);
+            false
+        }
+    }
+}
+
+// Where the output of a terminal goes.
+struct Output {
+    channel: ChannelId,
+    // Of an exec channel until the marker.
+    prelude: Option<Vec<u8>>,
+    // What the client sends before the marker, held until the command runs with the terminal raw,
+    // the line discipline of the shell would mangle it.
+    input: Vec<u8>,
+    eof: bool,
+}
+
+type Outputs = Arc<Mutex<HashMap<i32, Output>>>;
+
+// A session channel, and the terminal it is bridged to once a shell or command is asked for.
+#[derive(Default)]
+struct ChannelState {
+    terminal_id: Option<i32>,
+    rows: u32,
+    cols: u32,
+    term: String,
+    env: HashMap<String, String>,
+    pty: bool,
+    exec: bool,
+}
+
+struct Bridge {
+    sender: mpsc::UnboundedSender<TerminalAction>,
+    outputs: Outputs,
+    channels: HashMap<ChannelId, ChannelState>,
+    next_terminal_id: i32,
+    authorized_keys: PathBuf,
+}
+
+impl Bridge {
+    fn send(&self, action: TerminalAction) {
+        self.sender.send(action).ok();
+    }
+
+    // Opens a terminal for `channel`, running `exec` if a command.
+    fn open(&mut self, channel: ChannelId, exec: Option<&[u8]>) -> bool {
+        let terminal_id = self.next_terminal_id;
+        let Some(state) = self.channels.get_mut(&channel) else {
+            return false;
+        };
+        if state.terminal_id.is_some() {
+            return false;
+        }
+        self.next_terminal_id += 1;
+        state.terminal_id = Some(terminal_id);
+        state.exec = exec.is_some();
+        let open = OpenTerminal {
+            terminal_id,
+            rows: state.rows,
+            cols: state.cols,
+            env: state.env.clone(),
+            term: state.term.clone(),
+            ..Default::default()
+        };
+        let pty = state.pty;
+        self.outputs.lock().unwrap().insert(
+            terminal_id,
+            Output {
+                channel,
+                prelude: exec.map(|_| Vec::new()),
+                input: Vec::new(),
+                eof: false,
+            },
+        );
+        let mut action = TerminalAction::new();
+        action.set_open(open);
+        self.send(action);
+        if let Some(command) = exec {
+            self.send(data_action(terminal_id, exec_line(command, pty), false));
+        }
+        true
+    }
+
+    // Held until the command runs, if it has not yet.
+    fn input(&self, terminal_id: i32, data: Vec<u8>, eof: bool) {
+        if let Some(output) = self.outputs.lock().unwrap().get_mut(&terminal_id) {
+            if output.prelude.is_some() {
+                output.input.extend(data);
+                output.eof |= eof;
+                return;
+            }
+        }
+        self.send(data_action(terminal_id, data, eof));
+    }
+
+    fn reply(&self, ok: bool, channel: ChannelId, session: &mut Session) -> ResultType<()> {
+        if ok {
+            session.channel_success(channel)?;
+        } else {
+            session.channel_failure(channel)?;
+        }
+        Ok(())
+    }
+}
+
+impl server::Handler for Bridge {
+    type Error = anyhow::Error;
+
+    async fn auth_publickey(
+        &mut self,
+        _user: &str,
+        public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        if is_authorized(&self.authorized_keys, public_key) {
+            Ok(Auth::Accept)
+        } else {
+            Ok(Auth::reject())
+        }
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.channels.insert(
+            channel.id(),
+            ChannelState {
+                rows: DEFAULT_ROWS,
+                cols: DEFAULT_COLS,
+                ..Default::default()
+            },
+        );
+        Ok(true)
+    }
+
+    async fn pty_request(
+        &mut self,
+        channel: ChannelId,
+        term: &str,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _modes: &[(Pty, u32)],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let ok = match self.channels.get_mut(&channel) {
+            Some(state) if state.terminal_id.is_none() => {
+                state.pty = true;
+                state.term = term.to_owned();
+                if col_width > 0 && row_height > 0 {
+                    state.cols = col_width;
+                    state.rows = row_height;
+                }
+                true
+            }
+            _ => false,
+        };
+        self.reply(ok, channel, session)
+    }
+
+    async fn env_request(
+        &mut self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let ok = match self.channels.get_mut(&channel) {
+            Some(state) if state.terminal_id.is_none() => {
+                state
+                    .env
+                    .insert(variable_name.to_owned(), variable_value.to_owned());
+                true
+            }
+            _ => false,
+        };
+        self.reply(ok, channel, session)
+    }
+
+    async fn shell_request(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let ok = self.open(channel, None);
+        self.reply(ok, channel, session)
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let ok = self.open(channel, Some(data));
+        self.reply(ok, channel, session)
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let ok = name == "sftp" && self.open(channel, Some(SFTP_SERVER));
+        if !ok {
+            log::warn!("ssh subsystem {} is not supported", name);
+        }
+        self.reply(ok, channel, session)
+    }
+
+    async fn window_change_request(
+        &mut self,
+        channel: ChannelId,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let Some(state) = self.channels.get_mut(&channel) else {
+            return Ok(());
+        };
+        if col_width == 0 || row_height == 0 {
+            return Ok(());
+        }
+        state.cols = col_width;
+        state.rows = row_height;
+        if let Some(terminal_id) = state.terminal_id {
+            let mut action = TerminalAction::new();
+            action.set_resize(ResizeTerminal {
+                terminal_id,
+                rows: row_height,
+                cols: col_width,
+                ..Default::default()
+            });
+            self.send(action);
+        }
+        Ok(())
+    }
+
+    async fn data(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if let Some(terminal_id) = self.channels.get(&channel).and_then(|s| s.terminal_id) {
+            self.input(terminal_id, data.to_vec(), false);
+        }
+        Ok(())
+    }
+
+    async fn channel_eof(
+        &mut self,
+        channel: ChannelId,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        // The end of the input of a shell, as typed. A command has its input raw, the peer ends it.
+        if let Some(state) = self.channels.get(&channel) {
+            if let Some(terminal_id) = state.terminal_id {
+                if state.exec && !state.pty {
+                    self.input(terminal_id, Vec::new(), true);
+                } else {
+                    self.input(terminal_id, vec![0x04], false);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn channel_close(
+        &mut self,
+        channel: ChannelId,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let terminal_id = self.channels.remove(&channel).and_then(|s| s.terminal_id);
+        if let Some(terminal_id) = terminal_id {
+            if self.outputs.lock().unwrap().remove(&terminal_id).is_some() {
+                let mut action = TerminalAction::new();
+                action.set_close(CloseTerminal {
+                    terminal_id,
+                    ..Default::default()
+                });
+                self.send(action);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::message_proto::TerminalClosed;
+    use russh::{client, keys::PrivateKeyWithHashAlg, ChannelMsg};
+
+    #[test]
+    fn test_exec_prelude() {
+        let line = exec_line(b"scp -t /tmp", false);
+        assert!(line.starts_with(b"stty raw -echo"));
+        assert!(line.ends_with(b"exec scp -t /tmp\n"));
+        let line = exec_line(SFTP_SERVER, false);
+        assert!(line.ends_with(b"done; exit 127'\n"));
+        assert!(!line.windows(2).any(|w| w == b"\\\n"));
+        // The echoed line does not hold the marker, what the shell prints of it does.
+        assert!(!line.windows(EXEC_MARKER.len()).any(|w| w == EXEC_MARKER));
+
+        let mut prelude = Vec::new();
+        assert_eq!(skip_prelude(&mut prelude, &line), None);
+        assert_eq!(skip_prelude(&mut prelude, b"$ \x1ehbb-ssh"), None);
+        assert!(prelude.len() < EXEC_MARKER.len());
+        assert_eq!(
+            skip_prelude(&mut prelude, b"-bridge\x1e\x00C0644"),
+            Some(b"\x00C0644".to_vec())
+        );
+    }
+
+    struct TestClient;
+
+    impl client::Handler for TestClient {
+        type Error = anyhow::Error;
+
+        async fn check_server_key(&mut self, _key: &PublicKey) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+    }
+
+    async fn next_action(peer: &mut Stream) -> TerminalAction {
+        let bytes = timeout(5000, peer.next()).await.unwrap().unwrap().unwrap();
+        Message::parse_from_bytes(&bytes)
+            .unwrap()
+            .take_terminal_action()
+    }
+
+    async fn respond(peer: &mut Stream, response: TerminalResponse) {
+        let mut msg_out = Message::new();
+        msg_out.set_terminal_response(response);
+        peer.send(&msg_out).await.unwrap();
+    }
+
+    async fn respond_data(peer: &mut Stream, terminal_id: i32, data: &'static [u8]) {
+        let mut response = TerminalResponse::new();
+        response.set_data(TerminalData {
+            terminal_id,
+            data: Bytes::from_static(data),
+            ..Default::default()
+        });
+        respond(peer, response).await;
+    }
+
+    // One SSH connection to the bridge, its logins to the peer asked for through the receiver.
+    async fn start(
+        authorized_keys: PathBuf,
+    ) -> (
+        std::net::SocketAddr,
+        mpsc::UnboundedReceiver<oneshot::Sender<Option<Stream>>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(server::Config {
+            keys: vec![PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap()],
+            ..Default::default()
+        });
+        let (connector, requests) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            run(config, socket, connector, authorized_keys).await.ok();
+        });
+        (addr, requests)
+    }
+
+    #[test]
+    fn test_exec_round_trip() {
+        test_exec_round_trip_async();
+    }
+
+    // `ssh host cat` with its input sent at once, the test playing the peer and the login to it.
+    #[tokio::main(flavor = "current_thread")]
+    async fn test_exec_round_trip_async() {
+        let dir = std::env::temp_dir().join(format!("hbb_ssh_bridge_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let authorized_keys = dir.join("authorized_keys");
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let other_key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        std::fs::write(&authorized_keys, key.public_key().to_openssh().unwrap()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stream, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let stream = Stream::from(stream.unwrap(), addr);
+        let mut peer = Stream::from(accepted.unwrap().0, addr);
+
+        let (ssh_addr, mut requests) = start(authorized_keys.clone()).await;
+        let mut session =
+            client::connect(Arc::new(client::Config::default()), ssh_addr, TestClient)
+                .await
+                .unwrap();
+        let auth = session
+            .authenticate_publickey(
+                "user",
+                PrivateKeyWithHashAlg::new(Arc::new(other_key), None),
+            )
+            .await
+            .unwrap();
+        assert!(!auth.success());
+        let auth = session
+            .authenticate_publickey("user", PrivateKeyWithHashAlg::new(Arc::new(key), None))
+            .await
+            .unwrap();
+        assert!(auth.success());
+        let mut channel = session.channel_open_session().await.unwrap();
+        // Not logged in to the peer before a command is asked for.
+        assert!(timeout(200, requests.recv()).await.is_err());
+        channel.exec(true, "cat").await.unwrap();
+        let login = timeout(5000, requests.recv()).await.unwrap().unwrap();
+        login.send(Some(stream)).ok();
+        channel.data(&b"hello"[..]).await.unwrap();
+        channel.eof().await.unwrap();
+
+        let open = next_action(&mut peer).await;
+        assert!(open.has_open());
+        let terminal_id = open.open().terminal_id;
+        let line = next_action(&mut peer).await;
+        assert_eq!(&line.data().data[..], &exec_line(b"cat", false)[..]);
+        // Nothing reaches the shell before the command runs, with the terminal raw.
+        assert!(timeout(200, peer.next()).await.is_err());
+        respond_data(
+            &mut peer,
+            terminal_id,
+            b"$ stty raw -echo 2>/dev/null; \x1ehbb-ssh",
+        )
+        .await;
+        respond_data(&mut peer, terminal_id, b"-bridge\x1e").await;
+        let input = next_action(&mut peer).await;
+        assert_eq!(&input.data().data[..], b"hello");
+        assert!(next_action(&mut peer).await.data().eof);
+        respond_data(&mut peer, terminal_id, b"hello").await;
+        let mut response = TerminalResponse::new();
+        response.set_closed(TerminalClosed {
+            terminal_id,
+            exit_code: 0,
+            ..Default::default()
+        });
+        respond(&mut peer, response).await;
+
+        let mut out = Vec::new();
+        let mut exit_status = None;
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { data } => out.extend_from_slice(&data),
+                ChannelMsg::ExitStatus { exit_status: s } => exit_status = Some(s),
+                _ => {}
+            }
+        }
+        assert_eq!(out, b"hello");
+        assert_eq!(exit_status, Some(0));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_login_failed() {
+        test_login_failed_async();
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn test_login_failed_async() {
+        let dir = std::env::temp_dir().join(format!("hbb_ssh_bridge_login_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let authorized_keys = dir.join("authorized_keys");
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        std::fs::write(&authorized_keys, key.public_key().to_openssh().unwrap()).unwrap();
+
+        let (ssh_addr, mut requests) = start(authorized_keys).await;
+        let mut session =
+            client::connect(Arc::new(client::Config::default()), ssh_addr, TestClient)
+                .await
+                .unwrap();
+        let auth = session
+            .authenticate_publickey("user", PrivateKeyWithHashAlg::new(Arc::new(key), None))
+            .await
+            .unwrap();
+        assert!(auth.success());
+        let mut channel = session.channel_open_session().await.unwrap();
+        channel.request_shell(true).await.unwrap();
+        let login = timeout(5000, requests.recv()).await.unwrap().unwrap();
+        login.send(None).ok();
+
+        let mut err = Vec::new();
+        let mut exit_status = None;
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::ExtendedData { data, ext: 1 } => err.extend_from_slice(&data),
+                ChannelMsg::ExitStatus { exit_status: s } => exit_status = Some(s),
+                _ => {}
+            }
+        }
+        assert_eq!(err, b"Failed to connect to the peer\r\n");
+        assert_eq!(exit_status, Some(1));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_authorized_keys() {
+        let dir = std::env::temp_dir().join(format!("hbb_ssh_bridge_keys_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("authorized_keys");
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let key = key.public_key();
+        assert!(!is_authorized(&path, key));
+        let line = key.to_openssh().unwrap();
+        std::fs::write(&path, format!("# comment\n{}\n", line)).unwrap();
+        assert!(is_authorized(&path, key));
+        // Not to run something else than asked for.
+        std::fs::write(&path, format!("command=\"/bin/true\" {}\n", line)).unwrap();
+        assert!(!is_authorized(&path, key));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -12,7 +12,13 @@ use hbb_common::fs::serialize_transfer_job;
 use hbb_common::tokio::sync::mpsc::unbounded_channel;
 use hbb_common::{
     allow_err, bail,
-    config::{keys::OPTION_FILE_TRANSFER_MAX_FILES, Config},
+    config::{
+        keys::{
+            OPTION_FILE_TRANSFER_MAX_FILES, OPTION_FILE_TRANSFER_PRESERVE_OWNER,
+            OPTION_FILE_TRANSFER_RATE_LIMIT, OPTION_FILE_TRANSFER_WINDOW,
+        },
+        Config,
+    },
     fs::{self, get_string, is_write_need_confirmation, new_send_confirm, DigestCheckResult},
     log,
     message_proto::*,
@@ -40,7 +46,7 @@ use std::{
     collections::HashMap,
     ops::{Deref, DerefMut},
     sync::{
-        atomic::{AtomicI64, Ordering},
+        atomic::{AtomicBool, AtomicI64, Ordering},
         RwLock,
     },
 };
@@ -124,6 +130,118 @@ pub fn check_file_count_limit(file_count: usize) -> Result<(), String> {
     }
 }
 
+/// Apply the rate limit and time window options to a job sending files.
+///
+/// Used by `start_read_job()` and by `Connection` for connection-side read jobs.
+#[cfg(not(any(target_os = "ios")))]
+pub fn limit_read_job(job: &mut fs::TransferJob) {
+    fs::limit::set_global_rate_limit(fs::limit::parse_rate_limit(&Config::get_option(
+        OPTION_FILE_TRANSFER_RATE_LIMIT,
+    )));
+    job.set_window(fs::limit::TimeWindow::parse(&Config::get_option(
+        OPTION_FILE_TRANSFER_WINDOW,
+    )));
+}
+
+#[cfg(not(any(target_os = "ios")))]
+const JOB_JOURNAL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+// Of a previous run, not resumed yet.
+#[cfg(not(any(target_os = "ios")))]
+const MAX_JOURNAL_JOBS: usize = 100;
+
+#[cfg(not(any(target_os = "ios")))]
+#[derive(Default)]
+struct JobJournal {
+    // By connection id, 0 for the jobs of a previous run.
+    jobs: HashMap<i32, Vec<fs::TransferJobMeta>>,
+    saved: Option<std::time::Instant>,
+}
+
+#[cfg(not(any(target_os = "ios")))]
+impl JobJournal {
+    fn path() -> PathBuf {
+        Config::path("transfer_jobs.json")
+    }
+
+    fn load() -> Self {
+        let mut jobs: Vec<fs::TransferJobMeta> = std::fs::read_to_string(Self::path())
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+        jobs.truncate(MAX_JOURNAL_JOBS);
+        Self {
+            jobs: HashMap::from_iter([(0, jobs)]),
+            saved: None,
+        }
+    }
+
+    fn save(&mut self) {
+        self.saved = Some(std::time::Instant::now());
+        let jobs: Vec<&fs::TransferJobMeta> = self.jobs.values().flatten().collect();
+        if let Err(e) = std::fs::write(
+            Self::path(),
+            serde_json::to_string(&jobs).unwrap_or_default(),
+        ) {
+            log::error!("Failed to save the transfer jobs: {}", e);
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "ios")))]
+lazy_static::lazy_static! {
+    // The jobs of the CM, to resume their progress if it, the app or the machine restarts
+    // while they run, see `journal_jobs` and `restore_job`.
+    static ref JOB_JOURNAL: std::sync::Mutex<JobJournal> =
+        std::sync::Mutex::new(JobJournal::load());
+}
+
+/// Journals the jobs of the connection `conn_id`, at most every few seconds unless one is
+/// added or done. Those of a connection closed are kept to be resumed by the next one.
+#[cfg(not(any(target_os = "ios")))]
+fn journal_jobs(conn_id: i32, write_jobs: &[fs::TransferJob], read_jobs: &[fs::TransferJob]) {
+    let metas: Vec<fs::TransferJobMeta> = write_jobs
+        .iter()
+        .chain(read_jobs.iter())
+        .filter(|job| job.r#type == fs::JobType::Generic)
+        .map(|job| job.gen_meta())
+        .collect();
+    let mut journal = JOB_JOURNAL.lock().unwrap();
+    let old = journal
+        .jobs
+        .get(&conn_id)
+        .map(|v| v.as_slice())
+        .unwrap_or_default();
+    if old == metas.as_slice() {
+        return;
+    }
+    let changed =
+        old.len() != metas.len() || old.iter().zip(metas.iter()).any(|(a, b)| a.id != b.id);
+    if metas.is_empty() {
+        journal.jobs.remove(&conn_id);
+    } else {
+        journal.jobs.insert(conn_id, metas);
+    }
+    let due = !journal
+        .saved
+        .is_some_and(|t| t.elapsed() < JOB_JOURNAL_INTERVAL);
+    if changed || due {
+        journal.save();
+    }
+}
+
+/// Restores the progress of a job of a connection closed or of a previous run, the files
+/// done and their hashes. The offset in the current file is resumed by the sender's digest.
+#[cfg(not(any(target_os = "ios")))]
+fn restore_job(job: &mut fs::TransferJob) {
+    let mut journal = JOB_JOURNAL.lock().unwrap();
+    for metas in journal.jobs.values_mut() {
+        if let Some(i) = metas.iter().position(|meta| meta.is_of(job)) {
+            job.restore(&metas.remove(i));
+            return;
+        }
+    }
+}
+
 #[derive(Serialize, Clone)]
 pub struct Client {
     pub id: i32,
@@ -171,6 +289,13 @@ lazy_static::lazy_static! {
     static ref CLIENTS: RwLock<HashMap<i32, Client>> = Default::default();
 }
 
+#[cfg(not(any(target_os = "ios")))]
+lazy_static::lazy_static! {
+    // The file searches and archive extractions running, by connection and id.
+    static ref FILE_TASKS: std::sync::Mutex<HashMap<(i32, i32), std::sync::Arc<AtomicBool>>> =
+        Default::default();
+}
+
 static CLICK_TIME: AtomicI64 = AtomicI64::new(0);
 
 #[derive(Clone)]
@@ -531,9 +656,9 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
                                     self.cm.new_message(self.conn_id, text);
                                 }
                                 Data::FS(mut fs) => {
-                                    if let ipc::FS::WriteBlock { id, file_num, data: _, compressed } = fs {
+                                    if let ipc::FS::WriteBlock { id, file_num, data: _, compressed, copy_blks, hash, dictionary, with_dictionary, hole } = fs {
                                         if let Ok(bytes) = self.stream.next_raw().await {
-                                            fs = ipc::FS::WriteBlock{id, file_num, data:bytes.into(), compressed};
+                                            fs = ipc::FS::WriteBlock{id, file_num, data:bytes.into(), compressed, copy_blks, hash, dictionary, with_dictionary, hole};
                                             handle_fs(fs, &mut write_jobs, &mut self.read_jobs, &self.tx, Some(&tx_log), self.conn_id).await;
                                         }
                                     } else {
@@ -545,6 +670,7 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
                                     if !self.read_jobs.is_empty() {
                                         file_timer = crate::rustdesk_interval(time::interval(MILLI5));
                                     }
+                                    journal_jobs(self.conn_id, &write_jobs, &self.read_jobs);
                                     let log = fs::serialize_transfer_jobs(&write_jobs);
                                     self.cm.ui_handler.file_transfer_log("transfer", &log);
                                 }
@@ -654,13 +780,14 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
                     // Note: Empty data (for empty files) is correctly handled. BytesCodec with raw=false
                     // (the default for IPC connections) adds a length prefix, so send_raw(Bytes::new())
                     // sends a 1-byte frame that next_raw() can correctly receive as empty data.
-                    if let Data::FileBlockFromCM { id, file_num, ref data, compressed, conn_id } = data {
+                    if let Data::FileBlockFromCM { id, file_num, ref data, compressed, ref hash, conn_id } = data {
                         // Send metadata first (data field is skipped by serde), then raw data bytes
                         if let Err(e) = self.stream.send(&Data::FileBlockFromCM {
                             id,
                             file_num,
                             data: bytes::Bytes::new(), // placeholder, skipped by serde
                             compressed,
+                            hash: hash.clone(),
                             conn_id,
                         }).await {
                             log::error!("error sending FileBlockFromCM metadata: {}", e);
@@ -728,6 +855,7 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
                         if let Err(e) = handle_read_jobs_tick(&mut self.read_jobs, &self.tx, conn_id).await {
                             log::error!("Error processing read jobs: {}", e);
                         }
+                        journal_jobs(conn_id, &write_jobs, &self.read_jobs);
                         let log = fs::serialize_transfer_jobs(&self.read_jobs);
                         self.cm.ui_handler.file_transfer_log("transfer", &log);
                     } else {
@@ -870,6 +998,7 @@ pub async fn start_listen<T: InvokeUiCM>(
                     current_id,
                 )
                 .await;
+                journal_jobs(current_id, &write_jobs, &read_jobs_placeholder);
             }
             Some(Data::Close) => {
                 break;
@@ -932,6 +1061,7 @@ async fn handle_fs(
             id,
             file_num,
             mut files,
+            metadata,
             overwrite_detection,
             total_size,
             conn_id,
@@ -948,9 +1078,15 @@ async fn handle_fs(
             // Convert files to FileEntry
             let file_entries: Vec<FileEntry> = files
                 .drain(..)
-                .map(|f| FileEntry {
+                .enumerate()
+                .map(|(i, f)| FileEntry {
                     name: f.0,
                     modified_time: f.1,
+                    metadata: metadata
+                        .get(i)
+                        .filter(|m| !m.is_empty())
+                        .and_then(|m| FileMetadata::parse_from_bytes(m).ok())
+                        .into(),
                     ..Default::default()
                 })
                 .collect();
@@ -970,6 +1106,8 @@ async fn handle_fs(
             );
             job.total_size = total_size;
             job.conn_id = conn_id;
+            job.set_preserve_owner(Config::get_bool_option(OPTION_FILE_TRANSFER_PRESERVE_OWNER));
+            restore_job(&mut job);
             write_jobs.push(job);
         }
         ipc::FS::CancelWrite { id } => {
@@ -1000,6 +1138,11 @@ async fn handle_fs(
             file_num,
             data,
             compressed,
+            copy_blks,
+            hash,
+            dictionary,
+            with_dictionary,
+            hole,
         } => {
             if let Some(job) = fs::get_job(id, write_jobs) {
                 if let Err(err) = job
@@ -1008,6 +1151,11 @@ async fn handle_fs(
                         file_num,
                         data,
                         compressed,
+                        copy_blks,
+                        hash: hash.into(),
+                        dictionary: dictionary.into(),
+                        with_dictionary,
+                        hole,
                         ..Default::default()
                     })
                     .await
@@ -1023,6 +1171,8 @@ async fn handle_fs(
             last_modified,
             is_upload,
             is_resume,
+            delta,
+            hash,
         } => {
             if let Some(job) = fs::get_job(id, write_jobs) {
                 let mut req = FileTransferSendConfirmRequest {
@@ -1036,6 +1186,7 @@ async fn handle_fs(
                     file_num,
                     last_modified,
                     file_size,
+                    hash: hash.into(),
                     ..Default::default()
                 };
                 if let Some(file) = job.files().get(file_num as usize) {
@@ -1043,7 +1194,8 @@ async fn handle_fs(
                         let path = get_string(&fs::TransferJob::join(p, &file.name));
                         match is_write_need_confirmation(is_resume, &path, &digest) {
                             Ok(digest_result) => {
-                                job.set_digest(file_size, last_modified);
+                                job.set_digest(file_num, file_size, last_modified, &digest.hash);
+                                job.set_peer_delta(delta);
                                 match digest_result {
                                     DigestCheckResult::IsSame => {
                                         req.set_skip(true);
@@ -1053,6 +1205,11 @@ async fn handle_fs(
                                     DigestCheckResult::NeedConfirm(mut digest) => {
                                         // upload to server, but server has the same file, request
                                         digest.is_upload = is_upload;
+                                        // Used by the sender if the file is overwritten.
+                                        if !digest.is_identical {
+                                            digest.signatures =
+                                                job.delta_signatures(file_num).await.into();
+                                        }
                                         let mut msg_out = Message::new();
                                         let mut fr = FileResponse::new();
                                         fr.set_digest(digest);
@@ -1090,6 +1247,8 @@ async fn handle_fs(
             include_hidden,
             conn_id,
             overwrite_detection,
+            preserve_metadata,
+            archive,
         } => {
             start_read_job(
                 path,
@@ -1098,16 +1257,22 @@ async fn handle_fs(
                 id,
                 conn_id,
                 overwrite_detection,
+                preserve_metadata,
+                archive,
                 read_jobs,
                 tx,
             )
             .await;
         }
-        // Cancel an ongoing read job (file transfer from server to client).
-        // Note: This only cancels jobs in `read_jobs`. It does NOT cancel `ReadAllFiles`
-        // operations, which are one-shot directory scans that complete quickly and don't
-        // have persistent job tracking.
-        ipc::FS::CancelRead { id, conn_id: _ } => {
+        // Cancel an ongoing read job (file transfer from server to client), file search or
+        // archive extraction.
+        // Note: This only cancels jobs in `read_jobs`, searches and extractions. It does NOT cancel
+        // `ReadAllFiles` operations, which are one-shot directory scans that complete quickly
+        // and don't have persistent job tracking.
+        ipc::FS::CancelRead { id, conn_id } => {
+            if let Some(cancel) = FILE_TASKS.lock().unwrap().remove(&(conn_id, id)) {
+                cancel.store(true, Ordering::SeqCst);
+            }
             if let Some(job) = fs::remove_job(id, read_jobs) {
                 if let Some(tx) = tx_log {
                     if let Err(e) = tx.send(serialize_transfer_job(&job, false, true, "")) {
@@ -1116,11 +1281,22 @@ async fn handle_fs(
                 }
             }
         }
+        ipc::FS::LimitRead {
+            id,
+            paused,
+            rate_limit,
+            conn_id: _,
+        } => {
+            if let Some(job) = fs::get_job(id, read_jobs) {
+                job.set_paused(paused);
+                job.set_rate_limit(rate_limit);
+            }
+        }
         ipc::FS::SendConfirmForRead {
             id,
             file_num: _,
             skip,
-            offset_blk,
+            offset,
             conn_id: _,
         } => {
             if let Some(job) = fs::get_job(id, read_jobs) {
@@ -1130,9 +1306,7 @@ async fn handle_fs(
                     union: if skip {
                         Some(file_transfer_send_confirm_request::Union::Skip(true))
                     } else {
-                        Some(file_transfer_send_confirm_request::Union::OffsetBlk(
-                            offset_blk,
-                        ))
+                        Some(file_transfer_send_confirm_request::Union::Offset(offset))
                     },
                     ..Default::default()
                 };
@@ -1151,6 +1325,19 @@ async fn handle_fs(
         } => {
             read_all_files(path, include_hidden, id, conn_id, tx).await;
         }
+        ipc::FS::SearchFiles { search, conn_id } => match FileSearch::parse_from_bytes(&search) {
+            Ok(search) => search_files(search, conn_id, tx),
+            Err(err) => log::error!("Failed to parse the file search: {}", err),
+        },
+        ipc::FS::ExtractArchive {
+            id,
+            path,
+            to,
+            conn_id,
+            progress,
+        } => {
+            extract_archive(path, to, id, conn_id, progress, tx);
+        }
         _ => {}
     }
 }
@@ -1239,12 +1426,16 @@ async fn start_read_job(
     id: i32,
     conn_id: i32,
     overwrite_detection: bool,
+    preserve_metadata: bool,
+    archive: i32,
     read_jobs: &mut Vec<fs::TransferJob>,
     tx: &UnboundedSender<Data>,
 ) {
+    use hbb_common::protobuf::Enum;
     let path_clone = path.clone();
     let result = spawn_blocking(move || -> ResultType<fs::TransferJob> {
-        let data_source = fs::DataSource::FilePath(PathBuf::from(&path));
+        let archive = file_transfer_send_request::Archive::from_i32(archive).unwrap_or_default();
+        let data_source = fs::DataSource::from_path(&path, archive);
         fs::TransferJob::new_read(
             id,
             fs::JobType::Generic,
@@ -1254,6 +1445,7 @@ async fn start_read_job(
             include_hidden,
             true,
             overwrite_detection,
+            preserve_metadata,
         )
     })
     .await;
@@ -1263,7 +1455,7 @@ async fn start_read_job(
             // Optional: enforce file count limit for CM-side jobs to avoid
             // excessive I/O. This is applied on the job's file list produced
             // by `new_read`, similar to how AllFiles uses the same helper.
-            if let Err(msg) = check_file_count_limit(job.files().len()) {
+            if let Err(msg) = check_file_count_limit(job.file_count()) {
                 if let Err(e) = tx.send(Data::ReadJobInitResult {
                     id,
                     file_num,
@@ -1275,6 +1467,7 @@ async fn start_read_job(
                 }
                 return;
             }
+            limit_read_job(&mut job);
 
             // Build FileDirectory from the job's file list and serialize
             let files = job.files().to_owned();
@@ -1311,6 +1504,7 @@ async fn start_read_job(
 
             // Attach connection id so CM can route read blocks back correctly
             job.conn_id = conn_id;
+            restore_job(&mut job);
             read_jobs.push(job);
         }
         Ok(Err(e)) => {
@@ -1351,9 +1545,12 @@ async fn handle_read_jobs_tick(
     conn_id: i32,
 ) -> ResultType<()> {
     let mut finished = Vec::new();
+    if !fs::limit::global_ready() {
+        return Ok(());
+    }
 
     for job in jobs.iter_mut() {
-        if job.is_last_job {
+        if job.is_last_job || !job.can_send() {
             continue;
         }
 
@@ -1388,11 +1585,13 @@ async fn handle_read_jobs_tick(
                 finished.push(job.id);
             }
             Ok(Some(block)) => {
+                job.consume(block.data.len() as u64);
                 if let Err(e) = tx.send(Data::FileBlockFromCM {
                     id: block.id,
                     file_num: block.file_num,
                     data: block.data,
                     compressed: block.compressed,
+                    hash: block.hash.to_vec(),
                     conn_id,
                 }) {
                     log::error!("error sending FileBlockFromCM via IPC: {}", e);
@@ -1452,6 +1651,7 @@ async fn init_read_job_for_cm(
     // Initialize data stream and get digest info if overwrite detection is needed
     match job.init_data_stream_for_cm().await? {
         Some((last_modified, file_size)) => {
+            let hash = job.current_file_hash(file_size).await;
             // Send digest via IPC for overwrite detection
             if let Err(e) = tx.send(Data::FileDigestFromCM {
                 id: job.id,
@@ -1459,6 +1659,7 @@ async fn init_read_job_for_cm(
                 last_modified,
                 file_size,
                 is_resume: job.is_resume,
+                hash,
                 conn_id,
             }) {
                 log::error!("error sending FileDigestFromCM via IPC: {}", e);
@@ -1513,6 +1714,66 @@ async fn read_all_files(
     }
 }
 
+// The flag `CancelRead` sets to stop the task `id` of the connection, a task of the same id
+// still running is stopped.
+#[cfg(not(any(target_os = "ios")))]
+fn start_file_task(conn_id: i32, id: i32) -> std::sync::Arc<AtomicBool> {
+    let cancel: std::sync::Arc<AtomicBool> = Default::default();
+    if let Some(old) = FILE_TASKS
+        .lock()
+        .unwrap()
+        .insert((conn_id, id), cancel.clone())
+    {
+        old.store(true, Ordering::SeqCst);
+    }
+    cancel
+}
+
+#[cfg(not(any(target_os = "ios")))]
+fn end_file_task(conn_id: i32, id: i32, cancel: &std::sync::Arc<AtomicBool>) {
+    let mut tasks = FILE_TASKS.lock().unwrap();
+    if matches!(tasks.get(&(conn_id, id)), Some(c) if std::sync::Arc::ptr_eq(c, cancel)) {
+        tasks.remove(&(conn_id, id));
+    }
+}
+
+// Runs in the background, streaming the files found until done or cancelled by `CancelRead`.
+#[cfg(not(any(target_os = "ios")))]
+fn search_files(search: FileSearch, conn_id: i32, tx: &UnboundedSender<Data>) {
+    let id = search.id;
+    let cancel = start_file_task(conn_id, id);
+    let tx = tx.clone();
+    spawn_blocking(move || {
+        let res = fs::search::search(&search, &cancel, |entries, done| {
+            let mut msg_out = Message::new();
+            let mut file_response = FileResponse::new();
+            file_response.set_search(FileSearchResult {
+                id,
+                path: search.path.clone(),
+                entries,
+                done,
+                ..Default::default()
+            });
+            msg_out.set_file_response(file_response);
+            // Stops once the connection is gone.
+            let sent = match msg_out.write_to_bytes() {
+                Ok(bytes) => tx.send(Data::RawMessage(bytes)).is_ok(),
+                Err(err) => {
+                    log::error!("Failed to serialize the files found: {}", err);
+                    false
+                }
+            };
+            if !sent {
+                cancel.store(true, Ordering::SeqCst);
+            }
+        });
+        if let Err(err) = res {
+            send_raw(fs::new_error(id, err, -1), &tx);
+        }
+        end_file_task(conn_id, id, &cancel);
+    });
+}
+
 #[cfg(not(any(target_os = "ios")))]
 async fn read_empty_dirs(dir: &str, include_hidden: bool, tx: &UnboundedSender<Data>) {
     let path = dir.to_owned();
@@ -1613,6 +1874,50 @@ async fn rename_file(path: String, new_name: String, id: i32, tx: &UnboundedSend
     .await;
 }
 
+// Runs in the background, extracted in place, into the directory of the archive, if `to` is
+// empty, until done or cancelled by `CancelRead`.
+#[cfg(not(any(target_os = "ios")))]
+fn extract_archive(
+    path: String,
+    to: String,
+    id: i32,
+    conn_id: i32,
+    progress: bool,
+    tx: &UnboundedSender<Data>,
+) {
+    let cancel = start_file_task(conn_id, id);
+    let tx = tx.clone();
+    spawn_blocking(move || {
+        let mut last = (std::time::Instant::now(), 0);
+        let res = fs::archive::extract(&path, &to, &cancel, |entries, read| {
+            let elapsed = last.0.elapsed();
+            if !progress || elapsed < std::time::Duration::from_secs(1) {
+                return;
+            }
+            let mut msg_out = Message::new();
+            let mut file_response = FileResponse::new();
+            file_response.set_extract(FileArchiveExtractProgress {
+                id,
+                entries: entries as _,
+                extracted_size: read,
+                speed: ((read - last.1) as f64 / elapsed.as_secs_f64()) as _,
+                ..Default::default()
+            });
+            msg_out.set_file_response(file_response);
+            send_raw(msg_out, &tx);
+            last = (std::time::Instant::now(), read);
+        });
+        end_file_task(conn_id, id, &cancel);
+        match res {
+            Ok(n) => {
+                log::info!("Extracted {} entries of {}", n, path);
+                send_raw(fs::new_done(id, 0), &tx);
+            }
+            Err(err) => send_raw(fs::new_error(id, err, 0), &tx),
+        }
+    });
+}
+
 #[cfg(not(any(target_os = "ios")))]
 async fn remove_dir(path: String, id: i32, recursive: bool, tx: &UnboundedSender<Data>) {
     let path = fs::get_path(&path);
@@ -1768,6 +2073,78 @@ mod tests {
         });
     }
 
+    #[test]
+    #[cfg(not(any(target_os = "ios")))]
+    fn extract_archive_cancelled() {
+        use hbb_common::message_proto::{file_response, file_transfer_send_request::Archive};
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (tx, mut rx) = unbounded_channel();
+            let dir = std::env::temp_dir().join("rustdesk_extract_cancel_test");
+            let _ = fs::remove_dir_all(&dir);
+            let src = dir.join("src");
+            fs::create_dir_all(&src).unwrap();
+            // Big enough to be cancelled while it is written.
+            let size = 64 << 20;
+            let big: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            fs::write(src.join("big.bin"), &big).unwrap();
+            let files = hbb_common::fs::get_recursive_files(&src.to_string_lossy(), false).unwrap();
+            let mut reader =
+                hbb_common::fs::archive::ChunkReader::archive(src.clone(), files, Archive::TarZst);
+            let mut archive = Vec::new();
+            let mut buf = vec![0; 128 * 1024];
+            loop {
+                let n = reader.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                archive.extend_from_slice(&buf[..n]);
+            }
+            let path = dir.join("src.tar.zst");
+            fs::write(&path, archive).unwrap();
+
+            let (mut write_jobs, mut read_jobs) = (Vec::new(), Vec::new());
+            let out = dir.join("out");
+            let action = ipc::FS::ExtractArchive {
+                id: 1,
+                path: path.to_string_lossy().to_string(),
+                to: out.to_string_lossy().to_string(),
+                conn_id: 2,
+                progress: true,
+            };
+            handle_fs(action, &mut write_jobs, &mut read_jobs, &tx, None, 2).await;
+            // handle_fs is back while it runs, so the cancel is not queued behind it.
+            let extracted = out.join("src").join("big.bin");
+            hbb_common::timeout(10_000, async {
+                while fs::metadata(&extracted).map_or(true, |m| m.len() == 0) {
+                    hbb_common::tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                }
+            })
+            .await
+            .unwrap();
+            let action = ipc::FS::CancelRead { id: 1, conn_id: 2 };
+            handle_fs(action, &mut write_jobs, &mut read_jobs, &tx, None, 2).await;
+
+            loop {
+                let Data::RawMessage(bytes) = rx.recv().await.unwrap() else {
+                    panic!("unexpected data");
+                };
+                let msg = Message::parse_from_bytes(&bytes).unwrap();
+                match &msg.file_response().union {
+                    Some(file_response::Union::Error(e)) => {
+                        assert_eq!(e.id, 1);
+                        break;
+                    }
+                    Some(file_response::Union::Done(_)) => panic!("not cancelled"),
+                    _ => {}
+                }
+            }
+            assert!(fs::metadata(&extracted).unwrap().len() < size as u64);
+            assert!(!FILE_TASKS.lock().unwrap().contains_key(&(2, 1)));
+            let _ = fs::remove_dir_all(&dir);
+        });
+    }
+
     #[test]
     #[cfg(not(any(target_os = "ios")))]
     fn validate_file_name_security() {
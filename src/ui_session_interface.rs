@@ -784,14 +784,11 @@ impl<T: InvokeUiSession> Session<T> {
     }
 
     // Terminal methods
-    pub fn open_terminal(&self, terminal_id: i32, rows: u32, cols: u32) {
+    // `open.read_only` to only watch a terminal another connection writes to, `open.shell`
+    // one of `PeerInfo.terminal_shells`.
+    pub fn open_terminal(&self, open: OpenTerminal) {
         let mut action = TerminalAction::new();
-        action.set_open(OpenTerminal {
-            terminal_id,
-            rows,
-            cols,
-            ..Default::default()
-        });
+        action.set_open(open);
         let mut msg_out = Message::new();
         msg_out.set_terminal_action(action);
         self.send(Data::Message(msg_out));
@@ -822,6 +819,66 @@ impl<T: InvokeUiSession> Session<T> {
         self.send(Data::Message(msg_out));
     }
 
+    // Matches are answered with the lines of the scrollback of the terminal they are on.
+    pub fn search_terminal(
+        &self,
+        terminal_id: i32,
+        query: String,
+        regex: bool,
+        case_sensitive: bool,
+        max_matches: u32,
+    ) {
+        let mut action = TerminalAction::new();
+        action.set_search(TerminalSearch {
+            terminal_id,
+            query,
+            regex,
+            case_sensitive,
+            max_matches,
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_terminal_action(action);
+        self.send(Data::Message(msg_out));
+    }
+
+    // Writes the scrollback of the terminal to `to`, as the file transfer job `id`.
+    pub fn export_terminal(&self, id: i32, terminal_id: i32, to: String) {
+        self.send(Data::ExportTerminal((id, terminal_id, to)));
+    }
+
+    // Takes over writing to a shared terminal, or leaves it to the others attached.
+    pub fn handoff_terminal(&self, terminal_id: i32, take: bool) {
+        let mut action = TerminalAction::new();
+        action.set_handoff(TerminalHandoff {
+            terminal_id,
+            take,
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_terminal_action(action);
+        self.send(Data::Message(msg_out));
+    }
+
+    // Answered with the services of the peer this session may join, see `join_terminal_service`.
+    pub fn list_terminal_services(&self) {
+        let mut action = TerminalAction::new();
+        action.set_list_services(TerminalListServices::new());
+        let mut msg_out = Message::new();
+        msg_out.set_terminal_action(action);
+        self.send(Data::Message(msg_out));
+    }
+
+    // Reconnects to the terminals of the service, one listed or the id another user shared.
+    pub fn join_terminal_service(&self, service_id: String) {
+        {
+            let mut lc = self.lc.write().unwrap();
+            let key = lc.get_key_terminal_service_id().to_owned();
+            lc.set_option(key, service_id);
+        }
+        self.reconnect(false);
+    }
+
     pub fn close_terminal(&self, terminal_id: i32) {
         let mut action = TerminalAction::new();
         action.set_close(CloseTerminal {
@@ -1397,8 +1454,8 @@ impl<T: InvokeUiSession> Session<T> {
         self.send(Data::Close);
     }
 
-    fn try_auto_start_job_str(is_reconnected: bool, job_str: &str) -> Option<String> {
-        if is_reconnected {
+    fn try_auto_start_job_str(auto_start: bool, job_str: &str) -> Option<String> {
+        if auto_start {
             let job_str = job_str.trim();
             if let Some(stripped) = job_str.strip_suffix('}') {
                 format!(r#"{},"auto_start": true}}"#, stripped).into()
@@ -1415,6 +1472,11 @@ impl<T: InvokeUiSession> Session<T> {
         }
     }
 
+    // The job was running when it was saved, before the app or the machine restarted.
+    fn is_running_job_str(job_str: &str) -> bool {
+        serde_json::from_str::<fs::TransferJobMeta>(job_str).is_ok_and(|meta| meta.running)
+    }
+
     pub fn load_last_jobs(&self) {
         self.clear_all_jobs();
         let pc = self.load_config();
@@ -1428,12 +1490,13 @@ impl<T: InvokeUiSession> Session<T> {
         let mut cnt = 1;
         for job_str in pc.transfer.read_jobs.iter() {
             if !job_str.is_empty() {
+                let auto_start = is_reconnected || Self::is_running_job_str(job_str);
                 self.load_last_job(
                     cnt,
-                    Self::try_auto_start_job_str(is_reconnected, job_str)
+                    Self::try_auto_start_job_str(auto_start, job_str)
                         .as_deref()
                         .unwrap_or(job_str),
-                    is_reconnected,
+                    auto_start,
                 );
                 cnt += 1;
                 log::info!("restore read_job: {:?}", job_str);
@@ -1441,12 +1504,13 @@ impl<T: InvokeUiSession> Session<T> {
         }
         for job_str in pc.transfer.write_jobs.iter() {
             if !job_str.is_empty() {
+                let auto_start = is_reconnected || Self::is_running_job_str(job_str);
                 self.load_last_job(
                     cnt,
-                    Self::try_auto_start_job_str(is_reconnected, job_str)
+                    Self::try_auto_start_job_str(auto_start, job_str)
                         .as_deref()
                         .unwrap_or(job_str),
-                    is_reconnected,
+                    auto_start,
                 );
                 cnt += 1;
                 log::info!("restore write_job: {:?}", job_str);
@@ -1722,6 +1786,7 @@ pub trait InvokeUiSession: Send + Sync + Clone + 'static + Sized + Default {
     fn is_multi_ui_session(&self) -> bool;
     fn update_record_status(&self, start: bool);
     fn update_empty_dirs(&self, _res: ReadEmptyDirsResponse) {}
+    fn update_file_search(&self, _res: FileSearchResult) {}
     fn printer_request(&self, id: i32, path: String);
     fn handle_screenshot_resp(&self, sid: String, msg: String);
     fn handle_terminal_response(&self, response: TerminalResponse);